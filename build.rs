@@ -0,0 +1,24 @@
+use std::{env, process::Command};
+
+/// Provides the commit the server is built from as `GIT_SHA`, unless it is set already, e.g. by
+/// a container build without the repository.
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+
+    if env::var_os("GIT_SHA").is_some() {
+        return;
+    }
+
+    let sha = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+
+    if let Some(sha) = sha {
+        println!("cargo:rustc-env=GIT_SHA={}", sha.trim());
+    }
+}
@@ -0,0 +1,7 @@
+DROP TABLE message_attachments;
+DROP TABLE attachments;
+DROP TABLE messages;
+
+ALTER TABLE addresses
+DROP COLUMN attachment_policy,
+DROP COLUMN attachment_size_limit;
@@ -0,0 +1,36 @@
+ALTER TABLE addresses
+ADD COLUMN attachment_policy VARCHAR NOT NULL DEFAULT 'forward',
+ADD COLUMN attachment_size_limit BIGINT;
+
+CREATE TABLE messages (
+  id          SERIAL PRIMARY KEY,
+  address_id  INTEGER REFERENCES addresses (id) ON DELETE CASCADE,
+  message_id  VARCHAR,
+  sender      VARCHAR,
+  subject     VARCHAR,
+  body_text   TEXT,
+  body_html   TEXT,
+  received_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
+
+-- Attachment contents are stored once per unique SHA-256 digest.
+CREATE TABLE attachments (
+  id           SERIAL PRIMARY KEY,
+  sha256       BYTEA UNIQUE NOT NULL,
+  size         BIGINT NOT NULL,
+  data         BYTEA NOT NULL,
+  created_at   TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
+
+CREATE TABLE message_attachments (
+  id              SERIAL PRIMARY KEY,
+  message_id      INTEGER REFERENCES messages (id) ON DELETE CASCADE,
+  attachment_id   INTEGER REFERENCES attachments (id) ON DELETE RESTRICT,
+  filename        VARCHAR,
+  content_type    VARCHAR NOT NULL,
+  size            BIGINT NOT NULL,
+  stripped_reason VARCHAR
+);
+
+CREATE INDEX messages_address_id_idx ON messages (address_id);
+CREATE INDEX message_attachments_message_id_idx ON message_attachments (message_id);
@@ -0,0 +1,11 @@
+DROP INDEX messages_pending_idx;
+
+ALTER TABLE messages
+DROP COLUMN status,
+DROP COLUMN attempts,
+DROP COLUMN next_attempt_at,
+DROP COLUMN forwarded_at,
+DROP COLUMN last_error;
+
+ALTER TABLE addresses
+DROP COLUMN remove_trackers;
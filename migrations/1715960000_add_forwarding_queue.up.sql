@@ -0,0 +1,11 @@
+ALTER TABLE addresses
+ADD COLUMN remove_trackers BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE messages
+ADD COLUMN status VARCHAR NOT NULL DEFAULT 'pending',
+ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0,
+ADD COLUMN next_attempt_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+ADD COLUMN forwarded_at TIMESTAMP WITH TIME ZONE,
+ADD COLUMN last_error VARCHAR;
+
+CREATE INDEX messages_pending_idx ON messages (next_attempt_at) WHERE status = 'pending';
@@ -0,0 +1,3 @@
+ALTER TABLE users
+DROP COLUMN pgp_public_key,
+DROP COLUMN pgp_fingerprint;
@@ -0,0 +1,3 @@
+ALTER TABLE users
+ADD COLUMN pgp_public_key TEXT,
+ADD COLUMN pgp_fingerprint VARCHAR;
@@ -0,0 +1,10 @@
+DROP TABLE spam_training;
+DROP TABLE spam_tokens;
+
+ALTER TABLE messages
+DROP COLUMN spam_score,
+DROP COLUMN spam_verdict;
+
+ALTER TABLE addresses
+DROP COLUMN spam_tag_threshold,
+DROP COLUMN spam_quarantine_threshold;
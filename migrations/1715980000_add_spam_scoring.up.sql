@@ -0,0 +1,21 @@
+ALTER TABLE addresses
+ADD COLUMN spam_tag_threshold DOUBLE PRECISION,
+ADD COLUMN spam_quarantine_threshold DOUBLE PRECISION;
+
+ALTER TABLE messages
+ADD COLUMN spam_score DOUBLE PRECISION,
+ADD COLUMN spam_verdict VARCHAR;
+
+CREATE TABLE spam_tokens (
+  user_id    INTEGER REFERENCES users (id) ON DELETE CASCADE,
+  token      VARCHAR NOT NULL,
+  spam_count INTEGER NOT NULL DEFAULT 0,
+  ham_count  INTEGER NOT NULL DEFAULT 0,
+  PRIMARY KEY (user_id, token)
+);
+
+CREATE TABLE spam_training (
+  user_id       INTEGER PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+  spam_messages INTEGER NOT NULL DEFAULT 0,
+  ham_messages  INTEGER NOT NULL DEFAULT 0
+);
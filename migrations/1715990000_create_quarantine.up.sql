@@ -0,0 +1,8 @@
+CREATE TABLE quarantine (
+  message_id     INTEGER PRIMARY KEY REFERENCES messages (id) ON DELETE CASCADE,
+  reason         VARCHAR NOT NULL,
+  details        VARCHAR,
+  quarantined_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX quarantine_quarantined_at_idx ON quarantine (quarantined_at);
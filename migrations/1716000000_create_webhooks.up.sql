@@ -0,0 +1,29 @@
+CREATE TABLE webhooks (
+  id         SERIAL PRIMARY KEY,
+  user_id    INTEGER REFERENCES users (id) ON DELETE CASCADE,
+  url        VARCHAR NOT NULL,
+  secret     VARCHAR NOT NULL,
+  events     VARCHAR [] NOT NULL,
+  enabled    BOOLEAN NOT NULL DEFAULT TRUE,
+  created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
+
+-- Outbox of webhook events, which doubles as the delivery log.
+CREATE TABLE webhook_deliveries (
+  id              SERIAL PRIMARY KEY,
+  webhook_id      INTEGER REFERENCES webhooks (id) ON DELETE CASCADE,
+  event           VARCHAR NOT NULL,
+  payload         JSONB NOT NULL,
+  status          VARCHAR NOT NULL DEFAULT 'pending',
+  attempts        INTEGER NOT NULL DEFAULT 0,
+  next_attempt_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+  response_status INTEGER,
+  last_error      VARCHAR,
+  created_at      TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+  delivered_at    TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX webhooks_user_id_idx ON webhooks (user_id);
+CREATE INDEX webhook_deliveries_webhook_id_idx ON webhook_deliveries (webhook_id);
+CREATE INDEX webhook_deliveries_pending_idx ON webhook_deliveries (next_attempt_at)
+WHERE status = 'pending';
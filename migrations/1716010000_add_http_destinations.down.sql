@@ -0,0 +1,6 @@
+ALTER TABLE messages
+DROP COLUMN headers;
+
+ALTER TABLE addresses
+DROP COLUMN destination_url,
+DROP COLUMN destination_secret;
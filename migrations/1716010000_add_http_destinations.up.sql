@@ -0,0 +1,6 @@
+ALTER TABLE addresses
+ADD COLUMN destination_url VARCHAR,
+ADD COLUMN destination_secret VARCHAR;
+
+ALTER TABLE messages
+ADD COLUMN headers JSONB NOT NULL DEFAULT '[]';
@@ -0,0 +1,2 @@
+DROP INDEX addresses_user_id_created_at_idx;
+DROP INDEX addresses_search_idx;
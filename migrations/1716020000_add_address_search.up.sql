@@ -0,0 +1,4 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX addresses_search_idx ON addresses USING gin (address gin_trgm_ops, description gin_trgm_ops);
+CREATE INDEX addresses_user_id_created_at_idx ON addresses (user_id, created_at, id);
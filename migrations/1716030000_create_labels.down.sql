@@ -0,0 +1,5 @@
+ALTER TABLE addresses
+DROP COLUMN forward_to;
+
+DROP TABLE address_labels;
+DROP TABLE labels;
@@ -0,0 +1,19 @@
+CREATE TABLE labels (
+  id         SERIAL PRIMARY KEY,
+  user_id    INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  name       VARCHAR NOT NULL,
+  created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+  UNIQUE (user_id, name)
+);
+
+CREATE TABLE address_labels (
+  address_id INTEGER REFERENCES addresses (id) ON DELETE CASCADE,
+  label_id   INTEGER REFERENCES labels (id) ON DELETE CASCADE,
+  PRIMARY KEY (address_id, label_id)
+);
+
+CREATE INDEX address_labels_label_id_idx ON address_labels (label_id);
+
+-- The mailbox mail is forwarded to instead of the e-mail address of the owner.
+ALTER TABLE addresses
+ADD COLUMN forward_to VARCHAR;
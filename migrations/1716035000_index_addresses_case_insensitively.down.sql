@@ -0,0 +1 @@
+DROP INDEX addresses_lower_address_domain_id_idx;
@@ -0,0 +1,2 @@
+-- Local parts are matched case-insensitively, so they must also be unique regardless of case.
+CREATE UNIQUE INDEX addresses_lower_address_domain_id_idx ON addresses (LOWER(address), domain_id);
@@ -0,0 +1,4 @@
+DELETE FROM addresses WHERE deleted_at IS NOT NULL;
+
+ALTER TABLE addresses
+DROP COLUMN deleted_at;
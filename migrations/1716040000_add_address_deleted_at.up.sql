@@ -0,0 +1,3 @@
+-- Deleted addresses are kept so that their local part is never issued again.
+ALTER TABLE addresses
+ADD COLUMN deleted_at TIMESTAMP WITH TIME ZONE;
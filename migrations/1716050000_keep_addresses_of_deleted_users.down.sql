@@ -0,0 +1,6 @@
+DELETE FROM addresses WHERE user_id IS NULL;
+
+ALTER TABLE addresses
+DROP CONSTRAINT addresses_user_id_fkey,
+ADD CONSTRAINT addresses_user_id_fkey
+FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE;
@@ -0,0 +1,5 @@
+-- The addresses of deleted accounts are kept as tombstones so that they are never issued again.
+ALTER TABLE addresses
+DROP CONSTRAINT addresses_user_id_fkey,
+ADD CONSTRAINT addresses_user_id_fkey
+FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE SET NULL;
@@ -0,0 +1,7 @@
+DROP INDEX users_subject_idx;
+
+ALTER TABLE users
+DROP COLUMN session_key,
+DROP COLUMN access_token_expires_at,
+DROP COLUMN refresh_token,
+DROP COLUMN subject;
@@ -0,0 +1,8 @@
+ALTER TABLE users
+ADD COLUMN subject VARCHAR,
+ADD COLUMN refresh_token BYTEA,
+ADD COLUMN access_token_expires_at TIMESTAMP WITH TIME ZONE,
+-- Sessions are bound to this key, so replacing it ends all sessions of the user.
+ADD COLUMN session_key VARCHAR NOT NULL DEFAULT GEN_RANDOM_UUID()::TEXT;
+
+CREATE INDEX users_subject_idx ON users (subject);
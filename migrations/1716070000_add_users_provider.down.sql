@@ -0,0 +1,5 @@
+DROP INDEX users_provider_subject_idx;
+CREATE INDEX users_subject_idx ON users (subject);
+
+ALTER TABLE users
+DROP COLUMN provider;
@@ -0,0 +1,5 @@
+ALTER TABLE users
+ADD COLUMN provider VARCHAR;
+
+DROP INDEX users_subject_idx;
+CREATE INDEX users_provider_subject_idx ON users (provider, subject);
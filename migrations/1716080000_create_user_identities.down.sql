@@ -0,0 +1,6 @@
+ALTER TABLE users
+ADD COLUMN subject VARCHAR;
+
+CREATE INDEX users_provider_subject_idx ON users (provider, subject);
+
+DROP TABLE user_identities;
@@ -0,0 +1,17 @@
+CREATE TABLE user_identities (
+  id         SERIAL PRIMARY KEY,
+  user_id    INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  issuer     VARCHAR NOT NULL,
+  subject    VARCHAR NOT NULL,
+  created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+  UNIQUE (issuer, subject)
+);
+
+CREATE INDEX user_identities_user_id_idx ON user_identities (user_id);
+
+-- The issuer of the stored subjects is unknown, so existing users are linked to their identity by
+-- their verified e-mail address when they log in the next time.
+DROP INDEX users_provider_subject_idx;
+
+ALTER TABLE users
+DROP COLUMN subject;
@@ -0,0 +1,11 @@
+-- Sessions are stored by tower-sessions without a reference to the user, so the sessions of each
+-- user are tracked here to list and revoke them.
+CREATE TABLE user_sessions (
+  id         SERIAL PRIMARY KEY,
+  user_id    INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  session_id VARCHAR NOT NULL UNIQUE,
+  user_agent VARCHAR,
+  created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX user_sessions_user_id_idx ON user_sessions (user_id);
@@ -0,0 +1,27 @@
+CREATE TABLE api_tokens (
+  id           SERIAL PRIMARY KEY,
+  user_id      INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  name         VARCHAR NOT NULL,
+  token_hash   BYTEA NOT NULL UNIQUE,
+  scopes       VARCHAR [] NOT NULL,
+  created_at   TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+  last_used_at TIMESTAMP WITH TIME ZONE,
+  expires_at   TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX api_tokens_user_id_idx ON api_tokens (user_id);
+
+-- Pending OAuth 2.0 device authorization requests. The device code is only stored hashed, since
+-- it is exchanged for an API token.
+CREATE TABLE device_authorizations (
+  id               SERIAL PRIMARY KEY,
+  device_code_hash BYTEA NOT NULL UNIQUE,
+  user_code        VARCHAR NOT NULL UNIQUE,
+  client_name      VARCHAR,
+  scopes           VARCHAR [] NOT NULL,
+  status           VARCHAR NOT NULL DEFAULT 'pending',
+  user_id          INTEGER REFERENCES users (id) ON DELETE CASCADE,
+  last_polled_at   TIMESTAMP WITH TIME ZONE,
+  created_at       TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+  expires_at       TIMESTAMP WITH TIME ZONE NOT NULL
+);
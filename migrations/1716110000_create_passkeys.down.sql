@@ -0,0 +1,4 @@
+DROP TABLE passkeys;
+
+ALTER TABLE users
+DROP COLUMN webauthn_user_id;
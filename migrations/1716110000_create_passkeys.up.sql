@@ -0,0 +1,14 @@
+-- The user handle passkeys are registered for, which must not contain personal information.
+ALTER TABLE users
+ADD COLUMN webauthn_user_id UUID NOT NULL DEFAULT GEN_RANDOM_UUID();
+
+CREATE TABLE passkeys (
+  id           SERIAL PRIMARY KEY,
+  user_id      INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  name         VARCHAR NOT NULL,
+  credential   JSONB NOT NULL,
+  created_at   TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+  last_used_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX passkeys_user_id_idx ON passkeys (user_id);
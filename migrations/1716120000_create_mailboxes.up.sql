@@ -0,0 +1,13 @@
+-- Mailboxes other than the account e-mail address that mail can be forwarded to. A mailbox is
+-- only used once it was verified through the confirmation link sent to it, whose token is only
+-- stored hashed.
+CREATE TABLE mailboxes (
+  id               SERIAL PRIMARY KEY,
+  user_id          INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  email            VARCHAR NOT NULL,
+  token_hash       BYTEA UNIQUE,
+  token_expires_at TIMESTAMP WITH TIME ZONE,
+  verified_at      TIMESTAMP WITH TIME ZONE,
+  created_at       TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+  UNIQUE (user_id, email)
+);
@@ -1,2 +1,5 @@
 pub mod auth;
+pub mod error;
+pub mod extract;
+pub mod health;
 pub mod v1;
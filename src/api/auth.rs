@@ -1,30 +1,145 @@
-use axum::{routing::get, Router};
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+    routing::{get, post},
+    Router,
+};
+use rand::distributions::{Alphanumeric, DistString};
+use time::OffsetDateTime;
+use tower_sessions::Session;
+
+use crate::{api::error::ApiError, auth::AuthSession, config::AuthConfig, Config};
 
 pub const NEXT_URL_KEY: &str = "auth.next-url";
 pub const CSRF_STATE_KEY: &str = "auth.csrf-state";
 pub const NONCE_KEY: &str = "auth.nonce";
+pub const PROVIDER_KEY: &str = "auth.provider";
+pub const ID_TOKEN_KEY: &str = "auth.id-token";
+/// The session key of the time the user last authenticated with a passkey or at the provider.
+pub const AUTHENTICATED_AT_KEY: &str = "auth.authenticated-at";
+/// The session key of the time the user logged in.
+pub const LOGGED_IN_AT_KEY: &str = "auth.logged-in-at";
+/// The session key of the token that protects requests authenticated with the session cookie
+/// against cross-site request forgery.
+pub const CSRF_TOKEN_KEY: &str = "auth.csrf-token";
+
+/// The length of generated CSRF tokens.
+const CSRF_TOKEN_LENGTH: usize = 32;
+
+/// The path at which users approve device authorization requests.
+pub const DEVICE_VERIFY_PATH: &str = "/api/auth/device/verify";
+/// The grant type of device access token requests.
+const DEVICE_CODE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
 
 pub fn router() -> Router<crate::http::AppState> {
     Router::new()
+        .route("/providers", get(handlers::providers))
+        .route("/csrf-token", get(handlers::csrf_token))
         .route("/login", get(handlers::login))
         .route("/logout", get(handlers::logout))
         .route("/callback", get(handlers::callback))
-        .route("/userinfo", get(handlers::userinfo))
+        .route("/backchannel-logout", post(handlers::backchannel_logout))
+        .route("/device", post(handlers::device_authorization))
+        .route("/device/token", post(handlers::device_token))
+        .route(
+            "/device/verify",
+            get(handlers::verify_device).post(handlers::decide_device),
+        )
+        .route("/mock/authorize", get(handlers::mock_authorize))
+        .route("/mock/token", post(handlers::mock_token))
+        .route("/mock/jwks", get(handlers::mock_jwks))
+        .route("/mock/logout", get(handlers::mock_logout))
+        .route(
+            "/mock/.well-known/openid-configuration",
+            get(handlers::mock_discovery),
+        )
+}
+
+/// Returns the CSRF token of `session`, generating one if the session has none yet.
+pub async fn csrf_token(session: &Session) -> Result<String, ApiError> {
+    if let Some(token) = session.get::<String>(CSRF_TOKEN_KEY).await? {
+        return Ok(token);
+    }
+
+    let token = Alphanumeric.sample_string(&mut rand::thread_rng(), CSRF_TOKEN_LENGTH);
+    session.insert(CSRF_TOKEN_KEY, &token).await?;
+
+    Ok(token)
+}
+
+/// Returns whether the user of `session` authenticated with a passkey or at the provider within
+/// the configured step-up age.
+pub async fn recently_authenticated(
+    session: &Session,
+    config: &AuthConfig,
+) -> Result<bool, ApiError> {
+    let authenticated_at = session.get::<OffsetDateTime>(AUTHENTICATED_AT_KEY).await?;
+
+    Ok(authenticated_at.is_some_and(|authenticated_at| {
+        authenticated_at + config.step_up_max_age > OffsetDateTime::now_utc()
+    }))
+}
+
+/// Logs the user out once the configured session lifetime has passed since logging in, however
+/// recently the session was used.
+pub(crate) async fn expire_session(
+    State(config): State<Config>,
+    session: Session,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if let Some(auth_session) = request.extensions_mut().get_mut::<AuthSession>() {
+        if auth_session.user.is_some() {
+            let logged_in_at = session.get::<OffsetDateTime>(LOGGED_IN_AT_KEY).await?;
+
+            // Sessions without a login time predate it being recorded and are expired as well.
+            let expired = logged_in_at.is_none_or(|logged_in_at| {
+                logged_in_at + config.session.lifetime <= OffsetDateTime::now_utc()
+            });
+
+            if expired {
+                auth_session.logout().await?;
+            }
+        }
+    }
+
+    Ok(next.run(request).await)
 }
 
 mod handlers {
     use axum::{
-        extract::Query,
-        http::StatusCode,
-        response::{IntoResponse, Redirect},
+        extract::State,
+        http::{
+            header::{CACHE_CONTROL, USER_AGENT},
+            HeaderMap, StatusCode,
+        },
+        response::{Html, IntoResponse, Redirect, Response},
+        Json,
     };
-    use openidconnect::CsrfToken;
-    use serde::Deserialize;
+    use openidconnect::{core::CoreIdToken, CsrfToken};
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+    use subtle::ConstantTimeEq;
+    use time::OffsetDateTime;
     use tower_sessions::Session;
+    use url::form_urlencoded;
 
     use tracing::{debug, error, instrument, trace};
 
-    use crate::auth::AuthSession;
+    use crate::{
+        api::{
+            error::{ApiError, ErrorKind},
+            extract::{Form, Query},
+            v1::{api_token::ApiTokenScope, session::create_user_session},
+        },
+        auth::{
+            device::{self, DeviceAuthorization, PollResult},
+            mock::{AuthorizeRequest, EndSessionRequest},
+            AuthSession, Authenticator, MockIssuer,
+        },
+        http::AppState,
+    };
 
     #[derive(Debug, Deserialize)]
     pub struct AuthResponse {
@@ -32,6 +147,74 @@ mod handlers {
         state: CsrfToken,
     }
 
+    #[derive(Debug, Deserialize)]
+    pub struct BackchannelLogout {
+        logout_token: String,
+    }
+
+    /// Selects a provider by name, falling back to the default provider.
+    #[derive(Debug, Deserialize)]
+    pub struct SelectProvider {
+        provider: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct DeviceAuthorizationRequest {
+        /// The name of the client, which is shown to the user.
+        client_id: Option<String>,
+        scope: Option<String>,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct DeviceAuthorizationResponse {
+        device_code: String,
+        user_code: String,
+        verification_uri: String,
+        verification_uri_complete: String,
+        expires_in: u64,
+        interval: u64,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct DeviceTokenRequest {
+        grant_type: String,
+        device_code: String,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct DeviceTokenResponse {
+        access_token: String,
+        token_type: &'static str,
+        scope: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expires_in: Option<u64>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct VerifyDevice {
+        user_code: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum DeviceDecision {
+        Approve,
+        Deny,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct DecideDevice {
+        user_code: String,
+        decision: DeviceDecision,
+        csrf_token: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct MockTokenRequest {
+        grant_type: String,
+        code: String,
+    }
+
     // This allows us to extract the "next" field from the query string. We use this
     // to redirect after log in.
     #[derive(Debug, Deserialize)]
@@ -39,99 +222,647 @@ mod handlers {
         next: Option<String>,
     }
 
+    /// Asks the provider to authenticate the user again, e.g. before a sensitive operation.
+    #[derive(Debug, Deserialize)]
+    pub struct Reauthenticate {
+        #[serde(default)]
+        reauthenticate: bool,
+    }
+
+    /// Lists the names of the providers users can log in with, starting with the default.
+    #[instrument(skip_all)]
+    pub(super) async fn providers(
+        State(AppState { authenticator, .. }): State<AppState>,
+    ) -> Json<Vec<String>> {
+        Json(authenticator.provider_names().map(str::to_string).collect())
+    }
+
+    /// Returns the CSRF token that state-changing API requests authenticated with the session
+    /// cookie send in the `X-CSRF-Token` header.
+    #[instrument(skip_all)]
+    pub(super) async fn csrf_token(session: Session) -> Result<impl IntoResponse, ApiError> {
+        let csrf_token = super::csrf_token(&session).await?;
+
+        Ok((
+            [(CACHE_CONTROL, "no-store")],
+            Json(json!({ "csrf_token": csrf_token })),
+        ))
+    }
+
     #[instrument(skip_all)]
     pub(super) async fn login(
         auth_session: AuthSession,
         session: Session,
         Query(NextUrl { next }): Query<NextUrl>,
-    ) -> impl IntoResponse {
-        trace!("creating authorize url");
-        let (auth_url, csrf_state, nonce) = auth_session.backend.authorize_url();
+        Query(SelectProvider { provider }): Query<SelectProvider>,
+        Query(Reauthenticate { reauthenticate }): Query<Reauthenticate>,
+    ) -> Result<Redirect, ApiError> {
+        let provider = auth_session
+            .backend
+            .provider(provider.as_deref())
+            .ok_or_else(|| ApiError::bad_request("unknown provider"))?;
+
+        trace!(provider = %provider.name(), "creating authorize url");
+        let (auth_url, csrf_state, nonce) = provider.authorize_url(reauthenticate);
 
         trace!("setting auth session state");
 
+        session.insert(super::PROVIDER_KEY, provider.name()).await?;
+
         session
             .insert(super::CSRF_STATE_KEY, csrf_state.secret())
-            .await
-            .expect("unable to serialize");
+            .await?;
 
-        session
-            .insert(super::NONCE_KEY, nonce.secret())
-            .await
-            .expect("unable to serialize");
+        session.insert(super::NONCE_KEY, nonce.secret()).await?;
 
         match next {
             Some(ref next) if next.starts_with('/') => {
-                session
-                    .insert(super::NEXT_URL_KEY, next)
-                    .await
-                    .expect("unable to serialize");
+                session.insert(super::NEXT_URL_KEY, next).await?;
             }
             _ => {}
         }
 
-        Redirect::to(auth_url.as_str())
+        Ok(Redirect::to(auth_url.as_str()))
     }
 
+    /// Ends the session and, if the provider supports OpenID Connect RP-Initiated Logout,
+    /// redirects to the provider to log out there as well.
     #[instrument(skip_all)]
-    pub(super) async fn logout(mut auth_session: AuthSession) -> impl IntoResponse {
-        match auth_session.logout().await {
-            Ok(_) => Redirect::to("/").into_response(),
-            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    pub(super) async fn logout(
+        mut auth_session: AuthSession,
+        session: Session,
+        State(AppState { config, .. }): State<AppState>,
+    ) -> Result<Redirect, ApiError> {
+        let id_token = session.get::<CoreIdToken>(super::ID_TOKEN_KEY).await?;
+
+        let Some(user) = auth_session.logout().await? else {
+            return Ok(Redirect::to("/"));
+        };
+
+        let end_session_url = auth_session
+            .backend
+            .provider(user.provider.as_deref())
+            .and_then(|provider| {
+                provider.end_session_url(
+                    id_token.as_ref(),
+                    config.auth.post_logout_redirect_url.as_ref(),
+                )
+            });
+
+        match end_session_url {
+            Some(url) => Ok(Redirect::to(url.as_str())),
+            None => Ok(Redirect::to("/")),
         }
     }
 
+    /// Ends the sessions of a user on request of the identity provider, as specified by OpenID
+    /// Connect Back-Channel Logout.
     #[instrument(skip_all)]
-    pub async fn userinfo(auth_session: AuthSession) -> impl IntoResponse {
-        match auth_session.user {
-            Some(user) => format!("{user:?}").into_response(),
-            None => (StatusCode::INTERNAL_SERVER_ERROR, "no user").into_response(),
-        }
+    pub(super) async fn backchannel_logout(
+        auth_session: AuthSession,
+        Query(SelectProvider { provider }): Query<SelectProvider>,
+        Form(BackchannelLogout { logout_token }): Form<BackchannelLogout>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        auth_session
+            .backend
+            .backchannel_logout(provider.as_deref(), &logout_token)
+            .await?;
+
+        Ok([(CACHE_CONTROL, "no-store")])
     }
 
     #[instrument(skip_all)]
     pub async fn callback(
         mut auth_session: AuthSession,
         session: Session,
+        State(AppState { database, .. }): State<AppState>,
+        headers: HeaderMap,
         Query(AuthResponse {
             state: new_state,
             code,
         }): Query<AuthResponse>,
-    ) -> impl IntoResponse {
-        let Ok(Some(old_state)) = session.get::<CsrfToken>(super::CSRF_STATE_KEY).await else {
-            return (StatusCode::BAD_REQUEST, "missing csrf state").into_response();
+    ) -> Result<Redirect, ApiError> {
+        let Some(old_state) = session.get::<CsrfToken>(super::CSRF_STATE_KEY).await? else {
+            return Err(ApiError::bad_request("missing csrf state"));
         };
 
-        let Ok(Some(nonce)) = session.get(super::NONCE_KEY).await else {
-            return (StatusCode::BAD_REQUEST, "missing nonce").into_response();
+        let Some(nonce) = session.get(super::NONCE_KEY).await? else {
+            return Err(ApiError::bad_request("missing nonce"));
         };
 
         debug!(old_state = %old_state.secret(), new_state = %new_state.secret(), "states");
 
+        let provider = session.get(super::PROVIDER_KEY).await?;
+
         let creds = crate::auth::Credentials {
+            provider,
             code,
             nonce,
             old_state,
             new_state,
         };
 
-        let user = match auth_session.authenticate(creds).await {
-            Ok(Some(user)) => user,
-            Ok(None) => return (StatusCode::UNAUTHORIZED, "invalid csrf state").into_response(),
-            Err(err) => {
-                error!(?err, "could not authenticate user");
-                return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
-            }
+        let Some(user) = auth_session.authenticate(creds).await? else {
+            return Err(ApiError::new(ErrorKind::Unauthorized, "invalid csrf state"));
         };
 
-        if auth_session.login(&user).await.is_err() {
-            return (StatusCode::INTERNAL_SERVER_ERROR, "login failed").into_response();
+        auth_session.login(&user).await?;
+
+        session
+            .insert(super::LOGGED_IN_AT_KEY, OffsetDateTime::now_utc())
+            .await?;
+
+        if let Some(ref id_token) = user.id_token {
+            session.insert(super::ID_TOKEN_KEY, id_token).await?;
+        }
+
+        if let Some(auth_time) = user.auth_time {
+            session
+                .insert(super::AUTHENTICATED_AT_KEY, auth_time)
+                .await?;
+        }
+
+        // The login replaced the session id, so the session is saved to assign the new id.
+        session.save().await?;
+
+        if let Some(session_id) = session.id() {
+            let user_agent = headers
+                .get(USER_AGENT)
+                .and_then(|user_agent| user_agent.to_str().ok());
+
+            create_user_session(user.id, &session_id.to_string(), user_agent, &database).await?;
         }
 
         if let Ok(Some(url)) = session.remove::<String>(super::NEXT_URL_KEY).await {
-            Redirect::to(&url).into_response()
+            Ok(Redirect::to(&url))
         } else {
-            Redirect::to("/").into_response()
+            Ok(Redirect::to("/"))
+        }
+    }
+
+    /// Returns an error response in the format of the OAuth token endpoint.
+    fn oauth_error(error: &str) -> Response {
+        (StatusCode::BAD_REQUEST, Json(json!({ "error": error }))).into_response()
+    }
+
+    /// Starts an OAuth 2.0 device authorization request, for clients without a browser.
+    ///
+    /// Only the `read` scope is requested if the client requests no scope.
+    #[instrument(skip_all)]
+    pub(super) async fn device_authorization(
+        State(AppState {
+            database, config, ..
+        }): State<AppState>,
+        Form(request): Form<DeviceAuthorizationRequest>,
+    ) -> Result<Response, ApiError> {
+        let scopes = match request.scope.as_deref() {
+            None => vec![ApiTokenScope::Read],
+            Some(scope) => match ApiTokenScope::parse_all(scope) {
+                Some(scopes) if !scopes.is_empty() => scopes,
+                _ => return Ok(oauth_error("invalid_scope")),
+            },
+        };
+
+        let codes = device::create_device_authorization(
+            request.client_id.as_deref(),
+            scopes,
+            config.auth.device.code_lifetime,
+            &database,
+        )
+        .await?;
+
+        let verification_uri = config
+            .auth
+            .redirect_url
+            .join(super::DEVICE_VERIFY_PATH)
+            .map_err(|_| ApiError::internal())?;
+        let mut verification_uri_complete = verification_uri.clone();
+        verification_uri_complete
+            .query_pairs_mut()
+            .append_pair("user_code", &codes.user_code);
+
+        let response = DeviceAuthorizationResponse {
+            device_code: codes.device_code,
+            user_code: codes.user_code,
+            verification_uri: verification_uri.to_string(),
+            verification_uri_complete: verification_uri_complete.to_string(),
+            expires_in: config.auth.device.code_lifetime.as_secs(),
+            interval: config.auth.device.poll_interval.as_secs(),
+        };
+
+        Ok(([(CACHE_CONTROL, "no-store")], Json(response)).into_response())
+    }
+
+    /// Exchanges an approved device code for an API token.
+    ///
+    /// Errors are returned in the OAuth format rather than as problem details, since this
+    /// endpoint is polled by the OAuth client.
+    #[instrument(skip_all)]
+    pub(super) async fn device_token(
+        State(AppState {
+            database, config, ..
+        }): State<AppState>,
+        Form(request): Form<DeviceTokenRequest>,
+    ) -> Result<Response, ApiError> {
+        if request.grant_type != super::DEVICE_CODE_GRANT_TYPE {
+            return Ok(oauth_error("unsupported_grant_type"));
         }
+
+        let result = device::poll_device_authorization(
+            &request.device_code,
+            config.auth.device.poll_interval,
+            config.auth.device.token_lifetime,
+            &database,
+        )
+        .await?;
+
+        let error = match result {
+            None => "invalid_grant",
+            Some(PollResult::Pending) => "authorization_pending",
+            Some(PollResult::SlowDown) => "slow_down",
+            Some(PollResult::Denied) => "access_denied",
+            Some(PollResult::Expired) => "expired_token",
+            Some(PollResult::Approved(api_token, access_token)) => {
+                debug!(
+                    user_id = api_token.user_id,
+                    token_id = api_token.id,
+                    "issued api token"
+                );
+
+                let scopes: Vec<_> = api_token.scopes.iter().map(ApiTokenScope::as_str).collect();
+                let response = DeviceTokenResponse {
+                    access_token,
+                    token_type: "Bearer",
+                    scope: scopes.join(" "),
+                    expires_in: config
+                        .auth
+                        .device
+                        .token_lifetime
+                        .map(|lifetime| lifetime.as_secs()),
+                };
+
+                return Ok(([(CACHE_CONTROL, "no-store")], Json(response)).into_response());
+            }
+        };
+
+        Ok(oauth_error(error))
+    }
+
+    /// Shows the device authorization request with `user_code` for the user to approve, or a
+    /// form asking for the code. Users are logged in first.
+    #[instrument(skip_all)]
+    pub(super) async fn verify_device(
+        auth_session: AuthSession,
+        session: Session,
+        State(AppState {
+            database, config, ..
+        }): State<AppState>,
+        Query(VerifyDevice { user_code }): Query<VerifyDevice>,
+    ) -> Result<Response, ApiError> {
+        if auth_session.user.is_none() {
+            return Ok(device_login_redirect(user_code.as_deref(), false).into_response());
+        }
+
+        let Some(user_code) = user_code else {
+            return Ok(Html(device_code_form(None)).into_response());
+        };
+
+        match device::get_pending_device_authorization(&user_code, &database).await? {
+            Some(authorization)
+                if authorization.scopes.contains(&ApiTokenScope::Write)
+                    && !super::recently_authenticated(&session, &config.auth).await? =>
+            {
+                Ok(device_login_redirect(Some(&user_code), true).into_response())
+            }
+            Some(authorization) => {
+                let csrf_token = super::csrf_token(&session).await?;
+
+                Ok(Html(device_approval_form(&authorization, &csrf_token)).into_response())
+            }
+            None => Ok(Html(device_code_form(Some(INVALID_USER_CODE))).into_response()),
+        }
+    }
+
+    /// Approves or denies a device authorization request.
+    #[instrument(skip_all)]
+    pub(super) async fn decide_device(
+        auth_session: AuthSession,
+        session: Session,
+        State(AppState {
+            database, config, ..
+        }): State<AppState>,
+        Form(DecideDevice {
+            user_code,
+            decision,
+            csrf_token,
+        }): Form<DecideDevice>,
+    ) -> Result<Response, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        // The token is compared in constant time, so that it cannot be guessed from response times.
+        let valid = session
+            .get::<String>(super::CSRF_TOKEN_KEY)
+            .await?
+            .is_some_and(|expected| bool::from(expected.as_bytes().ct_eq(csrf_token.as_bytes())));
+
+        if !valid {
+            return Err(ApiError::forbidden("invalid csrf token"));
+        }
+
+        let approved = matches!(decision, DeviceDecision::Approve);
+
+        // Tokens that can change the account are only issued after a recent authentication, like
+        // other sensitive operations, so that a stolen session cannot be turned into one.
+        if approved {
+            let Some(authorization) =
+                device::get_pending_device_authorization(&user_code, &database).await?
+            else {
+                return Ok(Html(device_code_form(Some(INVALID_USER_CODE))).into_response());
+            };
+
+            if authorization.scopes.contains(&ApiTokenScope::Write)
+                && !super::recently_authenticated(&session, &config.auth).await?
+            {
+                return Ok(device_login_redirect(Some(&user_code), true).into_response());
+            }
+        }
+
+        if !device::decide_device_authorization(&user_code, user.id, approved, &database).await? {
+            return Ok(Html(device_code_form(Some(INVALID_USER_CODE))).into_response());
+        }
+
+        let message = if approved {
+            "The device is now logged in. You can close this window."
+        } else {
+            "The request was denied."
+        };
+
+        Ok(Html(html_page("Device login", &format!("<p>{message}</p>"))).into_response())
+    }
+
+    /// Redirects to the login, returning to the device verification page with `user_code`
+    /// afterwards. With `reauthenticate`, the provider is asked to authenticate the user again.
+    fn device_login_redirect(user_code: Option<&str>, reauthenticate: bool) -> Redirect {
+        let mut next = super::DEVICE_VERIFY_PATH.to_string();
+
+        if let Some(user_code) = user_code {
+            next.push('?');
+            next.push_str(
+                &form_urlencoded::Serializer::new(String::new())
+                    .append_pair("user_code", user_code)
+                    .finish(),
+            );
+        }
+
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        query.append_pair("next", &next);
+
+        if reauthenticate {
+            query.append_pair("reauthenticate", "true");
+        }
+
+        Redirect::to(&format!("/api/auth/login?{}", query.finish()))
+    }
+
+    const INVALID_USER_CODE: &str = "The code is invalid or has expired.";
+
+    /// Renders a form asking for the user code shown on the device.
+    fn device_code_form(error: Option<&str>) -> String {
+        let error = error
+            .map(|error| format!("<p>{}</p>\n", escape_html(error)))
+            .unwrap_or_default();
+
+        html_page(
+            "Device login",
+            &format!(
+                r#"{error}<form method="get">
+<label>Code shown on your device <input name="user_code" required autofocus></label>
+<button type="submit">Continue</button>
+</form>"#
+            ),
+        )
+    }
+
+    /// Renders a form asking the user to approve or deny a device authorization request.
+    fn device_approval_form(authorization: &DeviceAuthorization, csrf_token: &str) -> String {
+        let client = authorization.client_name.as_deref().unwrap_or("A device");
+        let access = if authorization.scopes.contains(&ApiTokenScope::Write) {
+            "read and change your account"
+        } else {
+            "read your account"
+        };
+
+        html_page(
+            "Device login",
+            &format!(
+                r#"<p>{} wants to {access}. Only continue if your device shows the code
+<strong>{}</strong>.</p>
+<form method="post">
+<input type="hidden" name="user_code" value="{}">
+<input type="hidden" name="csrf_token" value="{}">
+<button type="submit" name="decision" value="approve">Approve</button>
+<button type="submit" name="decision" value="deny">Deny</button>
+</form>"#,
+                escape_html(client),
+                escape_html(&authorization.user_code),
+                escape_html(&authorization.user_code),
+                escape_html(csrf_token),
+            ),
+        )
+    }
+
+    fn html_page(title: &str, body: &str) -> String {
+        format!(
+            r#"<!DOCTYPE html>
+<html>
+<head><title>{title}</title></head>
+<body>
+{body}
+</body>
+</html>
+"#
+        )
+    }
+
+    fn mock_issuer(authenticator: &Authenticator) -> Result<&MockIssuer, ApiError> {
+        authenticator
+            .mock()
+            .ok_or_else(|| ApiError::not_found("the mock issuer is disabled"))
+    }
+
+    /// Logs in as the e-mail address given as `login_hint`, or shows a form asking for it.
+    #[instrument(skip_all)]
+    pub(super) async fn mock_authorize(
+        State(AppState { authenticator, .. }): State<AppState>,
+        Query(request): Query<AuthorizeRequest>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let issuer = mock_issuer(&authenticator)?;
+
+        let Some(ref email) = request.login_hint else {
+            return Ok(Html(mock_login_form(&request)).into_response());
+        };
+
+        let url = issuer
+            .authorize(&request, email)
+            .ok_or_else(|| ApiError::bad_request("invalid redirect uri"))?;
+
+        Ok(Redirect::to(url.as_str()).into_response())
+    }
+
+    /// Renders a form that repeats the authorization request with the entered e-mail address.
+    fn mock_login_form(request: &AuthorizeRequest) -> String {
+        let hidden = |name: &str, value: &str| {
+            format!(
+                r#"<input type="hidden" name="{name}" value="{}">"#,
+                escape_html(value)
+            )
+        };
+
+        format!(
+            r#"<!DOCTYPE html>
+<html>
+<head><title>Mock login</title></head>
+<body>
+<form method="get">
+{}{}{}
+<label>E-mail address <input type="email" name="login_hint" required autofocus></label>
+<button type="submit">Log in</button>
+</form>
+</body>
+</html>
+"#,
+            hidden("redirect_uri", request.redirect_uri.as_str()),
+            hidden("state", &request.state),
+            request
+                .nonce
+                .as_deref()
+                .map(|nonce| hidden("nonce", nonce))
+                .unwrap_or_default(),
+        )
+    }
+
+    fn escape_html(value: &str) -> String {
+        value
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+            .replace('\'', "&#39;")
+    }
+
+    /// Exchanges an authorization code issued by the mock issuer for tokens.
+    ///
+    /// Errors are returned in the OAuth format rather than as problem details, since this
+    /// endpoint is called by the OAuth client.
+    #[instrument(skip_all)]
+    pub(super) async fn mock_token(
+        State(AppState { authenticator, .. }): State<AppState>,
+        Form(request): Form<MockTokenRequest>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let issuer = mock_issuer(&authenticator)?;
+
+        if request.grant_type != "authorization_code" {
+            return Ok(oauth_error("unsupported_grant_type"));
+        }
+
+        let response = issuer.token(&request.code).map_err(|err| {
+            error!(?err, "could not sign mock id token");
+
+            ApiError::internal()
+        })?;
+
+        match response {
+            Some(response) => Ok(([(CACHE_CONTROL, "no-store")], Json(response)).into_response()),
+            None => Ok(oauth_error("invalid_grant")),
+        }
+    }
+
+    /// Redirects back after logging out at the mock issuer, which keeps no sessions.
+    #[instrument(skip_all)]
+    pub(super) async fn mock_logout(
+        State(AppState { authenticator, .. }): State<AppState>,
+        Query(request): Query<EndSessionRequest>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let issuer = mock_issuer(&authenticator)?;
+
+        if request.post_logout_redirect_uri.is_none() {
+            return Ok(Html("<!DOCTYPE html>\n<p>You have been logged out.</p>\n").into_response());
+        }
+
+        let url = issuer
+            .end_session(&request)
+            .ok_or_else(|| ApiError::bad_request("invalid post logout redirect uri"))?;
+
+        Ok(Redirect::to(url.as_str()).into_response())
+    }
+
+    /// Returns the empty key set of the mock issuer, whose tokens are signed with the client
+    /// secret.
+    #[instrument(skip_all)]
+    pub(super) async fn mock_jwks(
+        State(AppState { authenticator, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        mock_issuer(&authenticator)?;
+
+        Ok(Json(json!({ "keys": [] })))
+    }
+
+    #[instrument(skip_all)]
+    pub(super) async fn mock_discovery(
+        State(AppState { authenticator, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let issuer = mock_issuer(&authenticator)?;
+
+        Ok(Json(issuer.metadata()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use figment::{
+        providers::{Format, Toml},
+        Figment,
+    };
+    use tower_sessions::MemoryStore;
+
+    use super::*;
+
+    fn auth_config() -> AuthConfig {
+        Figment::new()
+            .merge(Toml::string(
+                r#"
+                redirect_url = "http://localhost:3000/api/auth/callback"
+                token_key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+                step_up_max_age = "5m"
+                "#,
+            ))
+            .extract()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn requires_an_authentication_within_the_step_up_age() {
+        let config = auth_config();
+        let session = Session::new(None, Arc::new(MemoryStore::default()), None);
+
+        assert!(!recently_authenticated(&session, &config).await.unwrap());
+
+        let authenticated_at = OffsetDateTime::now_utc() - Duration::from_secs(6 * 60);
+        session
+            .insert(AUTHENTICATED_AT_KEY, authenticated_at)
+            .await
+            .unwrap();
+        assert!(!recently_authenticated(&session, &config).await.unwrap());
+
+        let authenticated_at = OffsetDateTime::now_utc() - Duration::from_secs(60);
+        session
+            .insert(AUTHENTICATED_AT_KEY, authenticated_at)
+            .await
+            .unwrap();
+        assert!(recently_authenticated(&session, &config).await.unwrap());
     }
 }
@@ -0,0 +1,308 @@
+//! JSON error responses
+//!
+//! Every API error is rendered as a problem details object (RFC 9457) so clients can rely on a
+//! single error format. Internal errors are logged, but their details are never sent to the
+//! client; the `request_id` lets operators find the corresponding log entries instead.
+
+use axum::{
+    extract::{
+        rejection::{FormRejection, JsonRejection, PathRejection, QueryRejection},
+        Request,
+    },
+    http::{header::CONTENT_TYPE, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::Serialize;
+use tracing::{error, warn};
+use utoipa::ToSchema;
+
+use crate::auth::{Authenticator, BackendError};
+
+/// The header carrying the id of a request, both on requests from a proxy and on responses.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// The length of generated request ids.
+const REQUEST_ID_LENGTH: usize = 16;
+/// The maximum length of request ids accepted from the client or a proxy.
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The kind of an API error, serialized as the `type` of the problem details.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    /// The operation requires a recent authentication with a passkey or at the provider.
+    StepUpRequired,
+    NotFound,
+    Conflict,
+    /// The request body exceeds the size limit.
+    PayloadTooLarge,
+    /// The request body is not in a format the endpoint accepts.
+    UnsupportedMediaType,
+    /// The request body is well-formed, but does not have the expected fields.
+    UnprocessableEntity,
+    BadGateway,
+    Internal,
+}
+
+impl ErrorKind {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorKind::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorKind::Forbidden | ErrorKind::StepUpRequired => StatusCode::FORBIDDEN,
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::Conflict => StatusCode::CONFLICT,
+            ErrorKind::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorKind::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorKind::UnprocessableEntity => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorKind::BadGateway => StatusCode::BAD_GATEWAY,
+            ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            ErrorKind::BadRequest => "Bad request",
+            ErrorKind::Unauthorized => "Unauthorized",
+            ErrorKind::Forbidden => "Forbidden",
+            ErrorKind::StepUpRequired => "Recent authentication required",
+            ErrorKind::NotFound => "Not found",
+            ErrorKind::Conflict => "Conflict",
+            ErrorKind::PayloadTooLarge => "Payload too large",
+            ErrorKind::UnsupportedMediaType => "Unsupported media type",
+            ErrorKind::UnprocessableEntity => "Unprocessable entity",
+            ErrorKind::BadGateway => "Upstream request failed",
+            ErrorKind::Internal => "Internal server error",
+        }
+    }
+}
+
+/// The body of error responses.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ProblemDetails {
+    #[serde(rename = "type")]
+    pub kind: ErrorKind,
+    /// A short, human-readable summary of the kind of error.
+    pub title: String,
+    /// A human-readable explanation specific to this occurrence of the error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// The id of the request, as sent in the `X-Request-Id` response header.
+    pub request_id: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    kind: ErrorKind,
+    detail: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(kind: ErrorKind, detail: impl Into<String>) -> Self {
+        ApiError {
+            kind,
+            detail: Some(detail.into()),
+        }
+    }
+
+    pub fn bad_request(detail: impl Into<String>) -> Self {
+        ApiError::new(ErrorKind::BadRequest, detail)
+    }
+
+    pub fn unauthorized() -> Self {
+        ApiError {
+            kind: ErrorKind::Unauthorized,
+            detail: None,
+        }
+    }
+
+    pub fn forbidden(detail: impl Into<String>) -> Self {
+        ApiError::new(ErrorKind::Forbidden, detail)
+    }
+
+    pub fn step_up_required() -> Self {
+        ApiError::new(
+            ErrorKind::StepUpRequired,
+            "confirm with a passkey or log in again to continue",
+        )
+    }
+
+    pub fn not_found(detail: impl Into<String>) -> Self {
+        ApiError::new(ErrorKind::NotFound, detail)
+    }
+
+    pub fn conflict(detail: impl Into<String>) -> Self {
+        ApiError::new(ErrorKind::Conflict, detail)
+    }
+
+    pub fn bad_gateway(detail: impl Into<String>) -> Self {
+        ApiError::new(ErrorKind::BadGateway, detail)
+    }
+
+    /// An error for the rejection of an extractor with `status`, keeping the status so that e.g.
+    /// a body of the wrong type is still answered with 415.
+    fn rejection(status: StatusCode, detail: String) -> Self {
+        let kind = match status {
+            StatusCode::PAYLOAD_TOO_LARGE => ErrorKind::PayloadTooLarge,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => ErrorKind::UnsupportedMediaType,
+            StatusCode::UNPROCESSABLE_ENTITY => ErrorKind::UnprocessableEntity,
+            status if status.is_server_error() => {
+                error!(%status, detail, "extractor failed");
+
+                return ApiError::internal();
+            }
+            _ => ErrorKind::BadRequest,
+        };
+
+        ApiError::new(kind, detail)
+    }
+
+    /// An error without details, for failures that must not be disclosed to the client.
+    ///
+    /// The cause should be logged before returning this error.
+    pub fn internal() -> Self {
+        ApiError {
+            kind: ErrorKind::Internal,
+            detail: None,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ProblemDetails {
+            kind: self.kind,
+            title: self.kind.title().to_string(),
+            detail: self.detail,
+            request_id: REQUEST_ID.try_with(Clone::clone).ok(),
+        };
+
+        let mut response = (self.kind.status(), Json(body)).into_response();
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/problem+json"),
+        );
+
+        response
+    }
+}
+
+impl From<crate::Error> for ApiError {
+    fn from(err: crate::Error) -> Self {
+        error!(?err, "request failed");
+
+        ApiError::internal()
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(err: sqlx::Error) -> Self {
+        crate::Error::from(err).into()
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        ApiError::rejection(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<FormRejection> for ApiError {
+    fn from(rejection: FormRejection) -> Self {
+        ApiError::rejection(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        ApiError::rejection(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::rejection(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<BackendError> for ApiError {
+    fn from(err: BackendError) -> Self {
+        match err {
+            BackendError::Sqlx(_) | BackendError::TokenEncryption(_) => {
+                error!(?err, "authentication backend failed");
+
+                ApiError::internal()
+            }
+            BackendError::TokenExchangeFailed(_) => {
+                warn!(?err, "could not exchange authorization code");
+
+                ApiError::bad_gateway(err.to_string())
+            }
+            BackendError::InvalidToken
+            | BackendError::InvalidTokenNonce(_)
+            | BackendError::InvalidAccessToken => {
+                warn!(?err, "rejected token from identity provider");
+
+                ApiError::new(ErrorKind::Unauthorized, err.to_string())
+            }
+            BackendError::MalformedLogoutToken | BackendError::InvalidLogoutToken(_) => {
+                warn!(?err, "rejected logout token from identity provider");
+
+                ApiError::bad_request(err.to_string())
+            }
+            BackendError::UnknownProvider => ApiError::bad_request(err.to_string()),
+            BackendError::MissingEmail
+            | BackendError::UnverifiedEmail
+            | BackendError::EmailInUse => ApiError::forbidden(err.to_string()),
+        }
+    }
+}
+
+impl From<axum_login::Error<Authenticator>> for ApiError {
+    fn from(err: axum_login::Error<Authenticator>) -> Self {
+        match err {
+            axum_login::Error::Backend(err) => err.into(),
+            axum_login::Error::Session(err) => err.into(),
+        }
+    }
+}
+
+impl From<tower_sessions::session::Error> for ApiError {
+    fn from(err: tower_sessions::session::Error) -> Self {
+        error!(?err, "session store failed");
+
+        ApiError::internal()
+    }
+}
+
+/// Assigns an id to every request, which is included in error responses and returned in the
+/// `X-Request-Id` header.
+///
+/// Ids set by a reverse proxy are kept, so that log entries can be correlated across both.
+pub async fn request_id(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH)
+        .map(str::to_string)
+        .unwrap_or_else(|| Alphanumeric.sample_string(&mut rand::thread_rng(), REQUEST_ID_LENGTH));
+
+    let mut response = REQUEST_ID.scope(id.clone(), next.run(request)).await;
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+
+    response
+}
@@ -0,0 +1,111 @@
+//! Extractors that reject requests with problem details
+//!
+//! The extractors of axum reject malformed requests with plain text bodies, so these wrap them to
+//! render their rejections as [`ApiError`]s like every other error of the API.
+
+use axum::{
+    extract::{FromRequest, FromRequestParts},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+use super::error::ApiError;
+
+/// A JSON request or response body.
+#[derive(Debug, Clone, Copy, Default, FromRequest)]
+#[from_request(via(axum::Json), rejection(ApiError))]
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+/// A URL-encoded form request body.
+#[derive(Debug, Clone, Copy, Default, FromRequest)]
+#[from_request(via(axum::Form), rejection(ApiError))]
+pub struct Form<T>(pub T);
+
+/// The parameters of the matched route.
+#[derive(Debug, Clone, Copy, Default, FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(ApiError))]
+pub struct Path<T>(pub T);
+
+/// The query string of a request.
+#[derive(Debug, Clone, Copy, Default, FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(ApiError))]
+pub struct Query<T>(pub T);
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header::CONTENT_TYPE, Request, StatusCode},
+    };
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Page {
+        #[allow(dead_code)]
+        limit: u32,
+    }
+
+    fn assert_problem(response: Response, status: StatusCode) {
+        assert_eq!(response.status(), status);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_json_with_problem_details() {
+        let request = Request::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from("{\"limit\": -1}"))
+            .unwrap();
+        let rejection = Json::<Page>::from_request(request, &()).await.unwrap_err();
+
+        assert_problem(rejection.into_response(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_status_of_unsupported_media_types() {
+        let request = Request::builder()
+            .header(CONTENT_TYPE, "text/plain")
+            .body(Body::from("{\"limit\": 1}"))
+            .unwrap();
+        let rejection = Json::<Page>::from_request(request, &()).await.unwrap_err();
+
+        assert_problem(
+            rejection.into_response(),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_forms_with_problem_details() {
+        let request = Request::builder()
+            .method("POST")
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from("limit=many"))
+            .unwrap();
+        let rejection = Form::<Page>::from_request(request, &()).await.unwrap_err();
+
+        assert_problem(rejection.into_response(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_queries_with_problem_details() {
+        let (mut parts, _) = Request::builder()
+            .uri("/addresses?limit=many")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let rejection = Query::<Page>::from_request_parts(&mut parts, &())
+            .await
+            .unwrap_err();
+
+        assert_problem(rejection.into_response(), StatusCode::BAD_REQUEST);
+    }
+}
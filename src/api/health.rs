@@ -0,0 +1,139 @@
+//! Health, readiness, build information and metrics endpoints for the orchestrator
+//!
+//! These routes are served outside of the session, auth and trace layers, so that probes and
+//! scrapes neither create sessions nor fill the logs. Metrics are served on a listener of their
+//! own rather than on the addresses of the API.
+
+use axum::{routing::get, Router};
+
+use crate::{http::AppState, metrics::Metrics};
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/healthz", get(handlers::healthz))
+        .route("/readyz", get(handlers::readyz))
+        .route("/version", get(handlers::version))
+}
+
+pub fn metrics_router() -> Router<Metrics> {
+    Router::new().route("/metrics", get(handlers::metrics))
+}
+
+mod handlers {
+    use std::{collections::BTreeMap, future::Future, time::Duration};
+
+    use axum::{
+        extract::State,
+        http::{header::CONTENT_TYPE, StatusCode},
+        response::IntoResponse,
+        Json,
+    };
+    use prometheus::{Encoder as _, TextEncoder};
+    use serde::Serialize;
+    use tracing::{error, warn};
+
+    use crate::{
+        api::error::ApiError, database, forwarding, http::AppState, metrics::Metrics, Error,
+    };
+
+    /// The time after which a readiness check counts as failed.
+    const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[derive(Debug, Serialize)]
+    pub struct Readiness {
+        ready: bool,
+        /// Whether each check passed, by name.
+        checks: BTreeMap<&'static str, bool>,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct Version {
+        version: &'static str,
+        /// The commit the server was built from, if known at build time.
+        git_sha: Option<&'static str>,
+    }
+
+    /// Runs a readiness check, logging why it failed.
+    async fn check(name: &str, check: impl Future<Output = Result<bool, Error>>) -> bool {
+        match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+            Ok(Ok(passed)) => passed,
+            Ok(Err(err)) => {
+                warn!(?err, check = name, "readiness check failed");
+                false
+            }
+            Err(_) => {
+                warn!(check = name, "readiness check timed out");
+                false
+            }
+        }
+    }
+
+    /// Responds as long as the process is alive.
+    pub(super) async fn healthz() -> &'static str {
+        "ok"
+    }
+
+    /// Responds with `503 Service Unavailable` unless the server can handle requests: the
+    /// database is reachable and migrated, the metadata of the OpenID Connect providers has been
+    /// discovered and the SMTP relay accepts connections.
+    pub(super) async fn readyz(
+        State(AppState {
+            database,
+            authenticator,
+            config,
+            ..
+        }): State<AppState>,
+    ) -> impl IntoResponse {
+        let (reachable, migrated, providers, relay) = tokio::join!(
+            check("database", database::ping(&database)),
+            check("migrations", database::migrations_applied(&database)),
+            check("providers", async {
+                Ok(authenticator.provider_names().next().is_some())
+            }),
+            check("relay", forwarding::relay_reachable(&config.forwarding)),
+        );
+
+        let checks = BTreeMap::from([
+            ("database", reachable),
+            ("migrations", migrated),
+            ("providers", providers),
+            ("relay", relay),
+        ]);
+        let ready = checks.values().all(|&passed| passed);
+
+        let status = if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+
+        (status, Json(Readiness { ready, checks }))
+    }
+
+    /// Returns the version and commit of the server.
+    pub(super) async fn version() -> Json<Version> {
+        Json(Version {
+            version: env!("CARGO_PKG_VERSION"),
+            git_sha: option_env!("GIT_SHA"),
+        })
+    }
+
+    /// Returns the metrics in the Prometheus text format.
+    pub(super) async fn metrics(
+        State(metrics): State<Metrics>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(registry) = metrics.registry() else {
+            return Err(ApiError::not_found("metrics are disabled"));
+        };
+
+        let encoder = TextEncoder::new();
+        let body = encoder
+            .encode_to_string(&registry.gather())
+            .map_err(|err| {
+                error!(?err, "could not encode metrics");
+                ApiError::internal()
+            })?;
+
+        Ok(([(CONTENT_TYPE, encoder.format_type().to_string())], body))
+    }
+}
@@ -1,16 +1,48 @@
 use axum::{
     async_trait,
-    extract::FromRequestParts,
-    http::{header::AUTHORIZATION, request::Parts, StatusCode},
-    routing::{get, post},
-    Router,
+    extract::{FromRequestParts, Request, State},
+    http::{header::AUTHORIZATION, request::Parts},
+    middleware::Next,
+    response::Response,
+    routing::{delete, get, post, put},
+    Json, Router,
 };
-use axum_login::login_required;
+use axum_login::{login_required, AuthnBackend};
+use subtle::ConstantTimeEq;
+use tower_sessions::Session;
+use utoipa::{
+    openapi::security::{ApiKey, ApiKeyValue, SecurityScheme},
+    Modify, OpenApi,
+};
+
+use crate::{
+    auth::{AuthSession, Authenticator},
+    http::AppState,
+};
+
+use super::error::ApiError;
 
-use crate::auth::Authenticator;
+/// The session key of an ongoing passkey registration.
+const PASSKEY_REGISTRATION_KEY: &str = "passkey.registration";
+/// The session key of an ongoing passkey authentication.
+const PASSKEY_AUTHENTICATION_KEY: &str = "passkey.authentication";
+/// The header carrying the CSRF token of the session.
+const CSRF_TOKEN_HEADER: &str = "x-csrf-token";
 
-mod address;
+mod account;
+pub(crate) mod address;
+pub(crate) mod api_token;
+pub(crate) mod attachment;
 mod domain;
+mod label;
+mod mailbox;
+pub(crate) mod message;
+mod passkey;
+mod pgp_key;
+pub(crate) mod quarantine;
+pub(crate) mod session;
+mod transfer;
+pub(crate) mod webhook;
 
 pub fn router() -> Router<crate::http::AppState> {
     Router::new()
@@ -21,17 +53,338 @@ pub fn router() -> Router<crate::http::AppState> {
         )
         .route(
             "/addresses/:id",
-            get(handlers::get_address).delete(handlers::delete_address),
+            get(handlers::get_address)
+                .patch(handlers::update_address)
+                .delete(handlers::delete_address),
+        )
+        .route("/addresses/:id/restore", post(handlers::restore_address))
+        .route("/addresses/batch", post(handlers::batch_addresses))
+        .route("/addresses/export", get(handlers::export_addresses))
+        .route("/addresses/import", post(handlers::import_addresses))
+        .route(
+            "/addresses/:id/destination",
+            put(handlers::set_address_destination).delete(handlers::delete_address_destination),
+        )
+        .route("/addresses/:id/labels", get(handlers::list_address_labels))
+        .route(
+            "/addresses/:id/labels/:label_id",
+            put(handlers::add_address_label).delete(handlers::remove_address_label),
+        )
+        .route(
+            "/labels",
+            get(handlers::list_labels).post(handlers::create_label),
+        )
+        .route("/labels/:id", delete(handlers::delete_label))
+        .route("/labels/:id/enable", post(handlers::enable_label_addresses))
+        .route(
+            "/labels/:id/disable",
+            post(handlers::disable_label_addresses),
+        )
+        .route(
+            "/labels/:id/mailbox",
+            put(handlers::set_label_mailbox).delete(handlers::delete_label_mailbox),
+        )
+        .route(
+            "/mailboxes",
+            get(handlers::list_mailboxes).post(handlers::add_mailbox),
+        )
+        .route("/mailboxes/:id", delete(handlers::delete_mailbox))
+        .route("/messages/:id/report", post(handlers::report_message))
+        .route("/quarantine", get(handlers::list_quarantine))
+        .route(
+            "/quarantine/:id",
+            get(handlers::get_quarantined_message).delete(handlers::delete_quarantined_message),
+        )
+        .route(
+            "/quarantine/:id/release",
+            post(handlers::release_quarantined_message),
+        )
+        .route("/attachments/:id", get(handlers::download_attachment))
+        .route(
+            "/webhooks",
+            get(handlers::list_webhooks).post(handlers::create_webhook),
+        )
+        .route("/webhooks/:id", delete(handlers::delete_webhook))
+        .route(
+            "/webhooks/:id/deliveries",
+            get(handlers::list_webhook_deliveries),
+        )
+        .route(
+            "/pgp-key",
+            get(handlers::get_pgp_key)
+                .put(handlers::upload_pgp_key)
+                .delete(handlers::delete_pgp_key),
+        )
+        .route("/pgp-key/discover", post(handlers::discover_pgp_key))
+        .route(
+            "/me",
+            get(handlers::get_profile).delete(handlers::delete_account),
+        )
+        .route("/me/export", get(handlers::export_account))
+        .route(
+            "/me/sessions",
+            get(handlers::list_sessions).delete(handlers::delete_sessions),
+        )
+        .route("/me/sessions/:id", delete(handlers::delete_session))
+        .route("/me/tokens", get(handlers::list_api_tokens))
+        .route("/me/tokens/:id", delete(handlers::delete_api_token))
+        .route(
+            "/me/passkeys",
+            get(handlers::list_passkeys).post(handlers::register_passkey),
         )
+        .route(
+            "/me/passkeys/challenge",
+            post(handlers::start_passkey_registration),
+        )
+        .route("/me/passkeys/:id", delete(handlers::delete_passkey))
+        .route("/me/step-up", post(handlers::step_up))
+        .route("/me/step-up/challenge", post(handlers::start_step_up))
         // The routes following this layer do not require login
         .route_layer(login_required!(
             Authenticator,
             login_url = "/api/auth/login"
         ))
+        .route_layer(axum::middleware::from_fn(verify_csrf_token))
         .route("/domains", get(handlers::list_domains))
         .route("/domains/:id", get(handlers::get_domain))
+        // The token of the confirmation link replaces the login
+        .route("/mailboxes/verify", get(handlers::verify_mailbox))
+        // Signed links replace the login for attachments posted to http destinations
+        .route(
+            "/attachments/:id/signed",
+            get(handlers::download_signed_attachment),
+        )
         // The ingress route implements its own auth check
         .route("/ingestion", post(handlers::ingest))
+        .route("/openapi.json", get(openapi))
+}
+
+/// The OpenAPI description of the v1 API.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Masked Mails API", version = "1"),
+    servers((url = "/api/v1")),
+    paths(
+        handlers::list_addresses,
+        handlers::create_address,
+        handlers::get_address,
+        handlers::update_address,
+        handlers::delete_address,
+        handlers::restore_address,
+        handlers::batch_addresses,
+        handlers::export_addresses,
+        handlers::import_addresses,
+        handlers::set_address_destination,
+        handlers::delete_address_destination,
+        handlers::list_address_labels,
+        handlers::add_address_label,
+        handlers::remove_address_label,
+        handlers::list_labels,
+        handlers::create_label,
+        handlers::delete_label,
+        handlers::enable_label_addresses,
+        handlers::disable_label_addresses,
+        handlers::set_label_mailbox,
+        handlers::delete_label_mailbox,
+        handlers::list_mailboxes,
+        handlers::add_mailbox,
+        handlers::verify_mailbox,
+        handlers::delete_mailbox,
+        handlers::report_message,
+        handlers::list_quarantine,
+        handlers::get_quarantined_message,
+        handlers::release_quarantined_message,
+        handlers::delete_quarantined_message,
+        handlers::download_attachment,
+        handlers::download_signed_attachment,
+        handlers::list_webhooks,
+        handlers::create_webhook,
+        handlers::delete_webhook,
+        handlers::list_webhook_deliveries,
+        handlers::get_pgp_key,
+        handlers::upload_pgp_key,
+        handlers::delete_pgp_key,
+        handlers::discover_pgp_key,
+        handlers::get_profile,
+        handlers::export_account,
+        handlers::delete_account,
+        handlers::list_sessions,
+        handlers::delete_session,
+        handlers::delete_sessions,
+        handlers::list_api_tokens,
+        handlers::delete_api_token,
+        handlers::list_passkeys,
+        handlers::start_passkey_registration,
+        handlers::register_passkey,
+        handlers::delete_passkey,
+        handlers::start_step_up,
+        handlers::step_up,
+        handlers::list_domains,
+        handlers::get_domain,
+        handlers::ingest,
+    ),
+    modifiers(&SecurityAddon)
+)]
+pub struct ApiDoc;
+
+struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+
+        components.add_security_scheme(
+            "session",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::with_description(
+                "id",
+                "Session cookie set after logging in at `/api/auth/login`. Requests other than \
+                 `GET` also send the token from `/api/auth/csrf-token` in the `X-CSRF-Token` \
+                 header.",
+            ))),
+        );
+        components.add_security_scheme(
+            "api_token",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                "Authorization",
+                "An API token issued at `/api/auth/device`, as `Bearer <token>`. Tokens without \
+                 the `write` scope can only make `GET` requests. Operations requiring a recent \
+                 authentication cannot be made with tokens.",
+            ))),
+        );
+        components.add_security_scheme(
+            "ingestion_token",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                "Authorization",
+                "The configured ingestion token, as `Token <token>`",
+            ))),
+        );
+    }
+}
+
+async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// Authenticates requests with an API token in the `Authorization: Bearer` header in place of
+/// the session.
+///
+/// Tokens without the `write` scope are limited to safe requests.
+pub(crate) async fn authenticate_api_token(
+    State(AppState {
+        database,
+        authenticator,
+        ..
+    }): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+
+    let Some(token) = token else {
+        return Ok(next.run(request).await);
+    };
+
+    let api_token = api_token::find_api_token(&token, &database)
+        .await?
+        .ok_or_else(ApiError::unauthorized)?;
+
+    if !api_token.allows(request.method()) {
+        return Err(ApiError::forbidden(
+            "the api token does not have the write scope",
+        ));
+    }
+
+    let user = authenticator
+        .get_user(&api_token.user_id)
+        .await?
+        .ok_or_else(ApiError::unauthorized)?;
+
+    if let Some(auth_session) = request.extensions_mut().get_mut::<AuthSession>() {
+        auth_session.user = Some(user);
+    }
+
+    request.extensions_mut().insert(api_token);
+
+    Ok(next.run(request).await)
+}
+
+/// Rejects state-changing requests authenticated with the session cookie that do not carry the
+/// CSRF token of the session in the `X-CSRF-Token` header.
+///
+/// Other sites cannot read the token, so they cannot make these requests on behalf of the user
+/// even where the SameSite attribute of the cookie lets the cookie through. Requests authenticated
+/// with an API token by [`authenticate_api_token`] do not rely on the cookie, but any other
+/// `Authorization` header is not a reason to skip the check.
+async fn verify_csrf_token(
+    auth_session: AuthSession,
+    session: Session,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if request.method().is_safe()
+        || auth_session.user.is_none()
+        || request.extensions().get::<api_token::ApiToken>().is_some()
+    {
+        return Ok(next.run(request).await);
+    }
+
+    let csrf_token = session.get::<String>(super::auth::CSRF_TOKEN_KEY).await?;
+    let header = request
+        .headers()
+        .get(CSRF_TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok());
+
+    // The token is compared in constant time, so that it cannot be guessed from response times.
+    match (csrf_token, header) {
+        (Some(csrf_token), Some(header))
+            if bool::from(csrf_token.as_bytes().ct_eq(header.as_bytes())) =>
+        {
+            Ok(next.run(request).await)
+        }
+        _ => Err(ApiError::forbidden(
+            "missing or invalid csrf token, see /api/auth/csrf-token",
+        )),
+    }
+}
+
+/// Requires that the user recently authenticated with a passkey or at the provider.
+///
+/// Sensitive operations take this extractor, so that a stolen session alone does not suffice for
+/// them. Requests with an API token are refused, since tokens cannot authenticate recently.
+#[derive(Debug)]
+struct RecentAuthentication;
+
+#[async_trait]
+impl FromRequestParts<AppState> for RecentAuthentication {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        // The session cookie of a request with an API token may belong to anyone, so its
+        // authentication time says nothing about the owner of the token.
+        if parts.extensions.get::<api_token::ApiToken>().is_some() {
+            return Err(ApiError::forbidden(
+                "this operation requires a session and cannot be made with an api token",
+            ));
+        }
+
+        let session = Session::from_request_parts(parts, state)
+            .await
+            .map_err(|_| ApiError::step_up_required())?;
+
+        if super::auth::recently_authenticated(&session, &state.config.auth).await? {
+            Ok(RecentAuthentication)
+        } else {
+            Err(ApiError::step_up_required())
+        }
+    }
 }
 
 struct ExtractAuthToken(String);
@@ -41,7 +394,7 @@ impl<S> FromRequestParts<S> for ExtractAuthToken
 where
     S: Send + Sync,
 {
-    type Rejection = (StatusCode, &'static str);
+    type Rejection = ApiError;
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
         if let Some(auth) = parts.headers.get(AUTHORIZATION) {
@@ -52,10 +405,10 @@ where
 
                 Ok(ExtractAuthToken(token.into_owned()))
             } else {
-                Err((StatusCode::BAD_REQUEST, "invalid authorization scheme"))
+                Err(ApiError::bad_request("invalid authorization scheme"))
             }
         } else {
-            Err((StatusCode::BAD_REQUEST, "authorization token is missing"))
+            Err(ApiError::bad_request("authorization token is missing"))
         }
     }
 }
@@ -64,220 +417,3079 @@ mod handlers {
     use std::collections::HashMap;
 
     use axum::{
-        extract::{Json, Path, State},
-        http::StatusCode,
+        body::Bytes,
+        extract::State,
+        http::{
+            header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+            StatusCode,
+        },
         response::IntoResponse,
     };
     use base64::prelude::{Engine, BASE64_STANDARD};
     use mail_parser::MessageParser;
-    use serde::Deserialize;
-    use tracing::{debug, error, instrument};
+    use serde::{Deserialize, Serialize};
+    use time::OffsetDateTime;
+    use tower_sessions::Session;
+    use tracing::{debug, error, instrument, warn};
+    use utoipa::{IntoParams, ToSchema};
+    use webauthn_rs::prelude::{
+        PasskeyAuthentication, PasskeyRegistration, PublicKeyCredential,
+        RegisterPublicKeyCredential, WebauthnError,
+    };
+
+    use crate::{
+        auth::AuthSession,
+        forwarding::{self, pgp},
+        http::AppState,
+        metrics,
+        spam::{self, bayes, SpamAction},
+        webhooks,
+    };
 
-    use crate::{auth::AuthSession, http::AppState};
+    use crate::api::{
+        error::{ApiError, ErrorKind, ProblemDetails},
+        extract::{Json, Path, Query},
+    };
 
-    use super::{address, domain, ExtractAuthToken};
+    use super::{
+        account, address, api_token, attachment, domain, label, mailbox, message, passkey, pgp_key,
+        quarantine, session, transfer,
+        webhook::{self, AddressEvent, MessageEvent, WebhookEvent},
+        ExtractAuthToken, RecentAuthentication,
+    };
 
-    #[derive(Clone, Deserialize, Debug)]
+    #[derive(Clone, Deserialize, Debug, ToSchema)]
     pub struct CreateAddressRequest {
         pub domain_id: i32,
         pub description: Option<String>,
     }
 
+    #[utoipa::path(
+        get,
+        path = "/addresses",
+        tag = "addresses",
+        params(address::ListAddresses),
+        responses(
+            (
+                status = OK,
+                description = "The addresses of the current user, or a page of them if a `limit` \
+                    or `cursor` is given",
+                body = address::AddressListing,
+            ),
+            (status = BAD_REQUEST, description = "Invalid cursor", body = ProblemDetails),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
     #[instrument]
     pub(super) async fn list_addresses(
         auth_session: AuthSession,
         State(AppState { database, .. }): State<AppState>,
-    ) -> impl IntoResponse {
-        match auth_session.user {
-            Some(user) => {
-                if let Ok(addrs) = address::get_user_addresses(user.id, &database).await {
-                    Json(addrs).into_response()
-                } else {
-                    (StatusCode::INTERNAL_SERVER_ERROR).into_response()
-                }
-            }
-            None => (StatusCode::UNAUTHORIZED).into_response(),
-        }
+        Query(query): Query<address::ListAddresses>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let cursor = match query.cursor {
+            Some(ref cursor) => Some(
+                address::AddressCursor::decode(cursor, query.sort, query.order)
+                    .ok_or_else(|| ApiError::bad_request("invalid cursor"))?,
+            ),
+            None => None,
+        };
+        let limit = query.page_limit();
+
+        let page = address::list_user_addresses(user.id, &query, cursor.as_ref(), limit, &database)
+            .await?;
+
+        Ok(Json(match limit {
+            Some(_) => address::AddressListing::Page(page),
+            None => address::AddressListing::All(page.addresses),
+        }))
     }
 
+    #[utoipa::path(
+        get,
+        path = "/addresses/{id}",
+        tag = "addresses",
+        params(("id" = i32, Path, description = "Address id")),
+        responses(
+            (status = OK, description = "The address", body = address::Address),
+            (status = NOT_FOUND, description = "Address not found", body = ProblemDetails),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
     #[instrument]
     pub(super) async fn get_address(
         Path(address_id): Path<i32>,
         auth_session: AuthSession,
         State(AppState { database, .. }): State<AppState>,
-    ) -> impl IntoResponse {
-        match auth_session.user {
-            Some(user) => match address::get_user_address(user.id, address_id, &database).await {
-                Ok(Some(addr)) => (StatusCode::OK, Json(addr)).into_response(),
-                Ok(None) => (StatusCode::NOT_FOUND).into_response(),
-                Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
-            },
-            None => (StatusCode::UNAUTHORIZED).into_response(),
-        }
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let addr = address::get_user_address(user.id, address_id, &database)
+            .await?
+            .ok_or_else(|| ApiError::not_found("address not found"))?;
+
+        Ok(Json(addr))
     }
 
+    #[utoipa::path(
+        post,
+        path = "/addresses",
+        tag = "addresses",
+        request_body = CreateAddressRequest,
+        responses(
+            (status = OK, description = "The created address", body = address::Address),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
     #[instrument]
     #[axum::debug_handler]
     pub(super) async fn create_address(
         auth_session: AuthSession,
         State(AppState { database, .. }): State<AppState>,
         Json(request): Json<CreateAddressRequest>,
-    ) -> impl IntoResponse {
-        match auth_session.user {
-            Some(user) => {
-                let random_addr =
-                    match address::generate_domain_address(request.domain_id, &database).await {
-                        Ok(addr) => addr,
-                        Err(err) => {
-                            error!(?err, "could not generate random address for domain");
-                            return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
-                        }
-                    };
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
 
-                let addr = address::CreateAddress {
-                    address: random_addr,
-                    description: request.description,
-                    enabled: true,
-                    domain_id: request.domain_id,
-                    user_id: user.id,
-                };
+        let mut tx = database.begin().await?;
+        let random_addr = address::generate_domain_address(request.domain_id, &mut tx).await?;
 
-                match address::create_address(addr, &database).await {
-                    Ok(addr) => (StatusCode::OK, Json(addr)).into_response(),
-                    Err(err) => {
-                        error!(?err, "could not create address");
+        let addr = address::CreateAddress {
+            address: random_addr,
+            description: request.description,
+            enabled: true,
+            domain_id: request.domain_id,
+            user_id: user.id,
+        };
 
-                        (StatusCode::INTERNAL_SERVER_ERROR).into_response()
-                    }
-                }
-            }
-            None => (StatusCode::UNAUTHORIZED).into_response(),
-        }
+        let addr = address::create_address(addr, &mut tx).await?;
+
+        webhook::emit(
+            user.id,
+            WebhookEvent::AddressCreated,
+            &AddressEvent::new(&addr),
+            &mut tx,
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(Json(addr))
     }
 
+    #[utoipa::path(
+        patch,
+        path = "/addresses/{id}",
+        tag = "addresses",
+        params(("id" = i32, Path, description = "Address id")),
+        request_body = address::UpdateAddress,
+        responses(
+            (status = OK, description = "The updated address", body = address::Address),
+            (status = NOT_FOUND, description = "Address not found", body = ProblemDetails),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn update_address(
+        Path(address_id): Path<i32>,
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+        Json(request): Json<address::UpdateAddress>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let addr = address::update_user_address(user.id, address_id, request, &database)
+            .await?
+            .ok_or_else(|| ApiError::not_found("address not found"))?;
+
+        Ok(Json(addr))
+    }
+
+    #[utoipa::path(
+        delete,
+        path = "/addresses/{id}",
+        tag = "addresses",
+        params(("id" = i32, Path, description = "Address id")),
+        responses(
+            (status = OK, description = "The deleted address", body = address::Address),
+            (status = NOT_FOUND, description = "Address not found", body = ProblemDetails),
+            (
+                status = FORBIDDEN,
+                description = "Recent authentication required",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
     #[instrument]
     pub(super) async fn delete_address(
         Path(address_id): Path<i32>,
         auth_session: AuthSession,
+        _: RecentAuthentication,
         State(AppState { database, .. }): State<AppState>,
-    ) -> impl IntoResponse {
-        match auth_session.user {
-            Some(user) => {
-                match address::delete_user_address(user.id, address_id, &database).await {
-                    Ok(Some(addr)) => (StatusCode::OK, Json(addr)).into_response(),
-                    Ok(None) => (StatusCode::NOT_FOUND).into_response(),
-                    Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
-                }
-            }
-            None => (StatusCode::UNAUTHORIZED).into_response(),
-        }
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let mut tx = database.begin().await?;
+        let addr = address::delete_user_address(user.id, address_id, &mut tx)
+            .await?
+            .ok_or_else(|| ApiError::not_found("address not found"))?;
+
+        webhook::emit(
+            user.id,
+            WebhookEvent::AddressDeleted,
+            &AddressEvent::new(&addr),
+            &mut tx,
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(Json(addr))
     }
 
+    /// Creates, enables, disables, deletes or moves many addresses in a single transaction.
+    ///
+    /// Nothing is changed if any of the addresses is not found.
+    #[utoipa::path(
+        post,
+        path = "/addresses/batch",
+        tag = "addresses",
+        request_body = address::AddressBatch,
+        responses(
+            (
+                status = OK,
+                description = "The result for each address",
+                body = Vec<address::BatchResult>,
+            ),
+            (
+                status = BAD_REQUEST,
+                description = "Too many addresses or invalid or unverified mailbox",
+                body = ProblemDetails,
+            ),
+            (
+                status = FORBIDDEN,
+                description = "Recent authentication required to delete or move addresses",
+                body = ProblemDetails,
+            ),
+            (
+                status = NOT_FOUND,
+                description = "Some of the addresses were not found",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
     #[instrument]
-    pub(super) async fn list_domains(
+    pub(super) async fn batch_addresses(
+        auth_session: AuthSession,
+        recent_authentication: Option<RecentAuthentication>,
         State(AppState { database, .. }): State<AppState>,
-    ) -> impl IntoResponse {
-        match domain::get_domains(&database).await {
-            Ok(domains) => (StatusCode::OK, Json(domains)).into_response(),
-            Err(err) => {
-                error!(?err, "could not fetch list of domains");
+        Json(mut request): Json<address::AddressBatch>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        // Only deleting and moving addresses is sensitive enough to require a step-up.
+        if matches!(
+            request,
+            address::AddressBatch::Delete { .. } | address::AddressBatch::Move { .. }
+        ) && recent_authentication.is_none()
+        {
+            return Err(ApiError::step_up_required());
+        }
+
+        if request.size() > address::MAX_BATCH_SIZE {
+            return Err(ApiError::bad_request(format!(
+                "a batch can contain at most {} addresses",
+                address::MAX_BATCH_SIZE
+            )));
+        }
+
+        if let address::AddressBatch::Move {
+            mailbox: Some(ref mut mailbox),
+            ..
+        } = request
+        {
+            let Ok(parsed) = mailbox.trim().parse::<lettre::Address>() else {
+                return Err(ApiError::bad_request("mailbox must be an e-mail address"));
+            };
+
+            if !mailbox::is_verified_user_mailbox(user.id, parsed.as_ref(), &database).await? {
+                return Err(ApiError::bad_request(
+                    "mailbox has not been verified, add it at /mailboxes first",
+                ));
+            }
+
+            *mailbox = parsed.to_string();
+        }
+
+        let event = match request {
+            address::AddressBatch::Create { .. } => Some(WebhookEvent::AddressCreated),
+            address::AddressBatch::Delete { .. } => Some(WebhookEvent::AddressDeleted),
+            _ => None,
+        };
+
+        let mut tx = database.begin().await?;
+        let results = address::apply_user_batch(user.id, request, &mut tx).await?;
+        let missing: Vec<String> = results
+            .iter()
+            .filter(|result| result.status == address::BatchStatus::NotFound)
+            .map(|result| result.id.to_string())
+            .collect();
+
+        // Dropping the transaction rolls back the addresses that were found.
+        if !missing.is_empty() {
+            return Err(ApiError::not_found(format!(
+                "addresses not found: {}",
+                missing.join(", ")
+            )));
+        }
 
-                (StatusCode::INTERNAL_SERVER_ERROR).into_response()
+        if let Some(event) = event {
+            let addrs = results.iter().filter_map(|result| result.address.as_ref());
+
+            for addr in addrs {
+                webhook::emit(user.id, event, &AddressEvent::new(addr), &mut tx).await?;
             }
         }
+
+        tx.commit().await?;
+
+        Ok(Json(results))
     }
 
+    /// Exports all addresses of the current user as CSV or JSON.
+    #[utoipa::path(
+        get,
+        path = "/addresses/export",
+        tag = "addresses",
+        params(transfer::ExportQuery),
+        responses(
+            (
+                status = OK,
+                description = "The addresses of the current user",
+                content(
+                    (Vec<transfer::ExportedAddress> = "application/json"),
+                    (String = "text/csv"),
+                ),
+            ),
+            (
+                status = FORBIDDEN,
+                description = "Recent authentication required",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
     #[instrument]
-    pub(super) async fn get_domain(
-        Path(domain_id): Path<i32>,
+    pub(super) async fn export_addresses(
+        auth_session: AuthSession,
+        _: RecentAuthentication,
         State(AppState { database, .. }): State<AppState>,
-    ) -> impl IntoResponse {
-        match domain::get_domain(domain_id, &database).await {
-            Ok(Some(domain)) => (StatusCode::OK, Json(domain)).into_response(),
-            Ok(None) => (StatusCode::NOT_FOUND).into_response(),
-            Err(err) => {
-                error!(?err, %domain_id, "could not fetch domain");
+        Query(query): Query<transfer::ExportQuery>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let addrs = transfer::export_user_addresses(user.id, &database).await?;
 
-                (StatusCode::INTERNAL_SERVER_ERROR).into_response()
+        match query.format {
+            transfer::ExportFormat::Json => Ok(Json(addrs).into_response()),
+            transfer::ExportFormat::Csv => {
+                let csv = transfer::to_csv(&addrs).map_err(|err| {
+                    error!(?err, "could not serialize addresses as csv");
+
+                    ApiError::internal()
+                })?;
+
+                Ok((
+                    [
+                        (CONTENT_TYPE, "text/csv; charset=utf-8"),
+                        (
+                            CONTENT_DISPOSITION,
+                            "attachment; filename=\"addresses.csv\"",
+                        ),
+                    ],
+                    csv,
+                )
+                    .into_response())
             }
         }
     }
 
-    #[derive(Debug, Clone, Deserialize)]
-    pub struct MailMetadata {
-        /// The intended recipient, if known.
-        pub to: Option<String>,
-        /// The sender, if known.
-        pub from: Option<String>,
-        /// E-mail headers, if known.
-        pub headers: HashMap<String, String>,
+    /// Imports addresses from our CSV export or the CSV exports of SimpleLogin and AnonAddy.
+    #[utoipa::path(
+        post,
+        path = "/addresses/import",
+        tag = "addresses",
+        params(transfer::ImportQuery),
+        request_body(content = String, content_type = "text/csv"),
+        responses(
+            (
+                status = OK,
+                description = "The result for each row",
+                body = Vec<transfer::ImportResult>,
+            ),
+            (
+                status = BAD_REQUEST,
+                description = "The file could not be parsed",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument(skip(body))]
+    pub(super) async fn import_addresses(
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+        Query(query): Query<transfer::ImportQuery>,
+        body: Bytes,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let rows = transfer::parse_import(query.format, &body)
+            .map_err(|err| ApiError::bad_request(err.to_string()))?;
+
+        let mut tx = database.begin().await?;
+        let results = transfer::import_user_addresses(user.id, rows, &mut tx).await?;
+
+        for addr in results.iter().filter_map(|result| result.address.as_ref()) {
+            webhook::emit(
+                user.id,
+                WebhookEvent::AddressCreated,
+                &AddressEvent::new(addr),
+                &mut tx,
+            )
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(Json(results))
     }
 
-    #[derive(Debug, Clone, Deserialize)]
-    pub struct Mail {
-        /// The raw contents of the e-mail, encoded with base64.
-        pub raw: String,
-        /// The size of the (decoded) raw contents.
-        pub raw_size: usize,
-        /// Information about the e-mail that was known prior to parsing.
-        pub metadata: MailMetadata,
+    /// Restores a deleted address, which is possible for a while after it was deleted.
+    #[utoipa::path(
+        post,
+        path = "/addresses/{id}/restore",
+        tag = "addresses",
+        params(("id" = i32, Path, description = "Address id")),
+        responses(
+            (status = OK, description = "The restored address", body = address::Address),
+            (
+                status = NOT_FOUND,
+                description = "Address not found or can no longer be restored",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn restore_address(
+        Path(address_id): Path<i32>,
+        auth_session: AuthSession,
+        State(AppState {
+            database, config, ..
+        }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let addr = address::restore_user_address(
+            user.id,
+            address_id,
+            config.addresses.restore_period,
+            &database,
+        )
+        .await?
+        .ok_or_else(|| ApiError::not_found("address not found or can no longer be restored"))?;
+
+        Ok(Json(addr))
     }
 
-    #[derive(Debug, Clone, Deserialize)]
-    pub struct MailIngestionRequest {
-        pub mails: Vec<Mail>,
-        pub started_at: String, // FIXME: this should be deserialized to a time
+    #[utoipa::path(
+        get,
+        path = "/addresses/{id}/labels",
+        tag = "labels",
+        params(("id" = i32, Path, description = "Address id")),
+        responses(
+            (status = OK, description = "Labels of the address", body = Vec<label::Label>),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn list_address_labels(
+        Path(address_id): Path<i32>,
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let labels = label::get_user_address_labels(user.id, address_id, &database).await?;
+
+        Ok(Json(labels))
     }
 
-    #[instrument(skip_all)]
-    pub(super) async fn ingest(
-        State(AppState { config, .. }): State<AppState>,
-        ExtractAuthToken(token): ExtractAuthToken,
-        Json(payload): Json<MailIngestionRequest>,
-    ) -> impl IntoResponse {
-        if token != config.ingestion.api_token {
-            debug!("received ingestion request with invalid token");
+    #[utoipa::path(
+        put,
+        path = "/addresses/{id}/labels/{label_id}",
+        tag = "labels",
+        params(
+            ("id" = i32, Path, description = "Address id"),
+            ("label_id" = i32, Path, description = "Label id"),
+        ),
+        responses(
+            (status = NO_CONTENT, description = "The label was attached to the address"),
+            (
+                status = NOT_FOUND,
+                description = "Address or label not found",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn add_address_label(
+        Path((address_id, label_id)): Path<(i32, i32)>,
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
 
-            return (StatusCode::UNAUTHORIZED, "invalid authorization token").into_response();
+        if !label::add_user_address_label(user.id, address_id, label_id, &database).await? {
+            return Err(ApiError::not_found("address or label not found"));
         }
 
-        let mail_parser = MessageParser::new()
-            .with_mime_headers()
-            .with_date_headers()
-            .with_address_headers()
-            .with_message_ids();
+        Ok(StatusCode::NO_CONTENT)
+    }
 
-        for mail in &payload.mails {
-            let decoded = match BASE64_STANDARD.decode(&mail.raw) {
-                Ok(data) => data,
-                Err(_) => continue,
-            };
+    #[utoipa::path(
+        delete,
+        path = "/addresses/{id}/labels/{label_id}",
+        tag = "labels",
+        params(
+            ("id" = i32, Path, description = "Address id"),
+            ("label_id" = i32, Path, description = "Label id"),
+        ),
+        responses(
+            (status = NO_CONTENT, description = "The label was removed from the address"),
+            (
+                status = NOT_FOUND,
+                description = "The address does not have the label",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn remove_address_label(
+        Path((address_id, label_id)): Path<(i32, i32)>,
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
 
-            match mail_parser.parse(&decoded[..]) {
-                Some(parsed) => {
-                    debug!(?parsed, "parsed email");
-                }
-                None => {
-                    error!("could not parse email");
-                }
-            }
+        if !label::remove_user_address_label(user.id, address_id, label_id, &database).await? {
+            return Err(ApiError::not_found("the address does not have the label"));
         }
 
-        ().into_response()
+        Ok(StatusCode::NO_CONTENT)
+    }
 
-        // debug!(%raw, %raw_size, %to, %from, "received email");
+    #[utoipa::path(
+        get,
+        path = "/labels",
+        tag = "labels",
+        responses(
+            (status = OK, description = "Labels of the current user", body = Vec<label::Label>),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn list_labels(
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
 
-        // match MessageParser::default().parse(&raw) {
-        //     Some(_parsed) => {
-        //         debug!("parsed email");
+        let labels = label::get_user_labels(user.id, &database).await?;
 
-        //         ().into_response()
-        //     }
-        //     None => {
-        //         error!("could not parse email");
+        Ok(Json(labels))
+    }
 
-        //         (StatusCode::INTERNAL_SERVER_ERROR).into_response()
-        //     }
-        // }
+    #[utoipa::path(
+        post,
+        path = "/labels",
+        tag = "labels",
+        request_body = label::CreateLabel,
+        responses(
+            (status = OK, description = "The created label", body = label::Label),
+            (status = BAD_REQUEST, description = "Invalid name", body = ProblemDetails),
+            (status = CONFLICT, description = "Label already exists", body = ProblemDetails),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn create_label(
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+        Json(mut request): Json<label::CreateLabel>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        request.name = request.name.trim().to_string();
+
+        if request.name.is_empty() {
+            return Err(ApiError::bad_request("label name must not be empty"));
+        }
+
+        let label = label::create_user_label(user.id, request, &database)
+            .await?
+            .ok_or_else(|| ApiError::conflict("a label with this name already exists"))?;
+
+        Ok(Json(label))
+    }
+
+    #[utoipa::path(
+        delete,
+        path = "/labels/{id}",
+        tag = "labels",
+        params(("id" = i32, Path, description = "Label id")),
+        responses(
+            (status = OK, description = "The deleted label", body = label::Label),
+            (status = NOT_FOUND, description = "Label not found", body = ProblemDetails),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn delete_label(
+        Path(label_id): Path<i32>,
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let label = label::delete_user_label(user.id, label_id, &database)
+            .await?
+            .ok_or_else(|| ApiError::not_found("label not found"))?;
+
+        Ok(Json(label))
+    }
+
+    /// Enables all addresses with a label.
+    #[utoipa::path(
+        post,
+        path = "/labels/{id}/enable",
+        tag = "labels",
+        params(("id" = i32, Path, description = "Label id")),
+        responses(
+            (status = OK, description = "The updated addresses", body = Vec<address::Address>),
+            (status = NOT_FOUND, description = "Label not found", body = ProblemDetails),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn enable_label_addresses(
+        Path(label_id): Path<i32>,
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        label::get_user_label(user.id, label_id, &database)
+            .await?
+            .ok_or_else(|| ApiError::not_found("label not found"))?;
+
+        let addrs = label::set_label_addresses_enabled(user.id, label_id, true, &database).await?;
+
+        Ok(Json(addrs))
+    }
+
+    /// Disables all addresses with a label.
+    #[utoipa::path(
+        post,
+        path = "/labels/{id}/disable",
+        tag = "labels",
+        params(("id" = i32, Path, description = "Label id")),
+        responses(
+            (status = OK, description = "The updated addresses", body = Vec<address::Address>),
+            (status = NOT_FOUND, description = "Label not found", body = ProblemDetails),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn disable_label_addresses(
+        Path(label_id): Path<i32>,
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        label::get_user_label(user.id, label_id, &database)
+            .await?
+            .ok_or_else(|| ApiError::not_found("label not found"))?;
+
+        let addrs = label::set_label_addresses_enabled(user.id, label_id, false, &database).await?;
+
+        Ok(Json(addrs))
+    }
+
+    /// Forwards mail received on all addresses with a label to a different mailbox.
+    ///
+    /// The mailbox must have been verified, see `/mailboxes`.
+    #[utoipa::path(
+        put,
+        path = "/labels/{id}/mailbox",
+        tag = "labels",
+        params(("id" = i32, Path, description = "Label id")),
+        request_body = label::SetLabelMailbox,
+        responses(
+            (status = OK, description = "The updated addresses", body = Vec<address::Address>),
+            (
+                status = BAD_REQUEST,
+                description = "Invalid or unverified mailbox",
+                body = ProblemDetails,
+            ),
+            (status = NOT_FOUND, description = "Label not found", body = ProblemDetails),
+            (
+                status = FORBIDDEN,
+                description = "Recent authentication required",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn set_label_mailbox(
+        Path(label_id): Path<i32>,
+        auth_session: AuthSession,
+        _: RecentAuthentication,
+        State(AppState { database, .. }): State<AppState>,
+        Json(request): Json<label::SetLabelMailbox>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let Ok(mailbox) = request.mailbox.trim().parse::<lettre::Address>() else {
+            return Err(ApiError::bad_request("mailbox must be an e-mail address"));
+        };
+
+        label::get_user_label(user.id, label_id, &database)
+            .await?
+            .ok_or_else(|| ApiError::not_found("label not found"))?;
+
+        if !mailbox::is_verified_user_mailbox(user.id, mailbox.as_ref(), &database).await? {
+            return Err(ApiError::bad_request(
+                "mailbox has not been verified, add it at /mailboxes first",
+            ));
+        }
+
+        let addrs = label::set_label_addresses_mailbox(
+            user.id,
+            label_id,
+            Some(mailbox.as_ref()),
+            &database,
+        )
+        .await?;
+
+        Ok(Json(addrs))
+    }
+
+    /// Restores forwarding to the account e-mail address for all addresses with a label.
+    #[utoipa::path(
+        delete,
+        path = "/labels/{id}/mailbox",
+        tag = "labels",
+        params(("id" = i32, Path, description = "Label id")),
+        responses(
+            (status = OK, description = "The updated addresses", body = Vec<address::Address>),
+            (status = NOT_FOUND, description = "Label not found", body = ProblemDetails),
+            (
+                status = FORBIDDEN,
+                description = "Recent authentication required",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn delete_label_mailbox(
+        Path(label_id): Path<i32>,
+        auth_session: AuthSession,
+        _: RecentAuthentication,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        label::get_user_label(user.id, label_id, &database)
+            .await?
+            .ok_or_else(|| ApiError::not_found("label not found"))?;
+
+        let addrs = label::set_label_addresses_mailbox(user.id, label_id, None, &database).await?;
+
+        Ok(Json(addrs))
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/mailboxes",
+        tag = "mailboxes",
+        responses(
+            (
+                status = OK,
+                description = "Mailboxes of the current user",
+                body = Vec<mailbox::Mailbox>,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn list_mailboxes(
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let mailboxes = mailbox::get_user_mailboxes(user.id, &database).await?;
+
+        Ok(Json(mailboxes))
+    }
+
+    /// Adds a mailbox that mail can be forwarded to and sends a confirmation link to it.
+    ///
+    /// Adding a mailbox that has not been verified yet sends a new confirmation link.
+    #[utoipa::path(
+        post,
+        path = "/mailboxes",
+        tag = "mailboxes",
+        request_body = mailbox::AddMailbox,
+        responses(
+            (status = OK, description = "The unverified mailbox", body = mailbox::Mailbox),
+            (status = BAD_REQUEST, description = "Invalid e-mail address", body = ProblemDetails),
+            (status = CONFLICT, description = "Mailbox already verified", body = ProblemDetails),
+            (
+                status = BAD_GATEWAY,
+                description = "The confirmation link could not be sent",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn add_mailbox(
+        auth_session: AuthSession,
+        State(AppState {
+            database, config, ..
+        }): State<AppState>,
+        Json(request): Json<mailbox::AddMailbox>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let Ok(email) = request.email.trim().parse::<lettre::Address>() else {
+            return Err(ApiError::bad_request("email must be an e-mail address"));
+        };
+
+        let (mailbox, token) = mailbox::create_user_mailbox(user.id, email.as_ref(), &database)
+            .await?
+            .ok_or_else(|| ApiError::conflict("mailbox is already verified"))?;
+
+        let mut link = config
+            .forwarding
+            .public_url
+            .as_ref()
+            .unwrap_or(&config.auth.redirect_url)
+            .join("/api/v1/mailboxes/verify")
+            .map_err(|err| {
+                error!(?err, "could not build mailbox confirmation link");
+
+                ApiError::internal()
+            })?;
+        link.query_pairs_mut().append_pair("token", &token);
+
+        if let Err(err) =
+            forwarding::send_mailbox_confirmation(&config.forwarding, &mailbox.email, &link).await
+        {
+            warn!(?err, "could not send mailbox confirmation link");
+
+            return Err(ApiError::bad_gateway(
+                "the confirmation link could not be sent",
+            ));
+        }
+
+        Ok(Json(mailbox))
+    }
+
+    /// Verifies the mailbox that a confirmation link was sent to.
+    ///
+    /// This is the target of the confirmation link, so it does not require a login.
+    #[utoipa::path(
+        get,
+        path = "/mailboxes/verify",
+        tag = "mailboxes",
+        params(mailbox::VerifyMailbox),
+        responses(
+            (status = OK, description = "The verified mailbox", body = mailbox::Mailbox),
+            (
+                status = NOT_FOUND,
+                description = "Unknown or expired confirmation link",
+                body = ProblemDetails,
+            ),
+        ),
+    )]
+    #[instrument(skip(query))]
+    pub(super) async fn verify_mailbox(
+        State(AppState { database, .. }): State<AppState>,
+        Query(query): Query<mailbox::VerifyMailbox>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let mailbox = mailbox::verify_mailbox(&query.token, &database)
+            .await?
+            .ok_or_else(|| ApiError::not_found("unknown or expired confirmation link"))?;
+
+        Ok(Json(mailbox))
+    }
+
+    /// Deletes a mailbox. Addresses forwarded to it are forwarded to the account e-mail address
+    /// again.
+    #[utoipa::path(
+        delete,
+        path = "/mailboxes/{id}",
+        tag = "mailboxes",
+        params(("id" = i32, Path, description = "Mailbox id")),
+        responses(
+            (status = OK, description = "The deleted mailbox", body = mailbox::Mailbox),
+            (status = NOT_FOUND, description = "Mailbox not found", body = ProblemDetails),
+            (
+                status = FORBIDDEN,
+                description = "Recent authentication required",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn delete_mailbox(
+        Path(mailbox_id): Path<i32>,
+        auth_session: AuthSession,
+        _: RecentAuthentication,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let mailbox = mailbox::delete_user_mailbox(user.id, mailbox_id, &database)
+            .await?
+            .ok_or_else(|| ApiError::not_found("mailbox not found"))?;
+
+        Ok(Json(mailbox))
+    }
+
+    /// Reports a message as spam or ham and trains the owner's spam classifier with it.
+    #[utoipa::path(
+        post,
+        path = "/messages/{id}/report",
+        tag = "messages",
+        params(("id" = i32, Path, description = "Message id")),
+        request_body = message::ReportMessage,
+        responses(
+            (status = OK, description = "The reported message", body = message::Message),
+            (status = NOT_FOUND, description = "Message not found", body = ProblemDetails),
+            (
+                status = CONFLICT,
+                description = "Message has already been reported",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn report_message(
+        Path(message_id): Path<i32>,
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+        Json(request): Json<message::ReportMessage>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let mut tx = database.begin().await?;
+
+        let Some(msg) =
+            message::set_spam_verdict(user.id, message_id, request.verdict, &mut tx).await?
+        else {
+            return match message::get_user_message(user.id, message_id, &database).await? {
+                Some(_) => Err(ApiError::conflict("message has already been reported")),
+                None => Err(ApiError::not_found("message not found")),
+            };
+        };
+
+        let tokens = bayes::tokenize(
+            msg.sender.as_deref(),
+            msg.subject
+                .as_deref()
+                .map(|subject| subject.trim_start_matches(spam::SUBJECT_TAG)),
+            msg.body_text.as_deref(),
+        );
+        let is_spam = request.verdict == message::SpamVerdict::Spam;
+
+        bayes::train(user.id, tokens, is_spam, &mut tx).await?;
+        tx.commit().await?;
+
+        Ok(Json(msg))
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/quarantine",
+        tag = "quarantine",
+        responses(
+            (
+                status = OK,
+                description = "Quarantined messages of the current user",
+                body = Vec<quarantine::QuarantinedMessage>,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn list_quarantine(
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let messages = quarantine::list_user_quarantine(user.id, &database).await?;
+
+        Ok(Json(messages))
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/quarantine/{id}",
+        tag = "quarantine",
+        params(("id" = i32, Path, description = "Message id")),
+        responses(
+            (
+                status = OK,
+                description = "The quarantined message",
+                body = quarantine::QuarantinePreview,
+            ),
+            (
+                status = NOT_FOUND,
+                description = "Quarantined message not found",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn get_quarantined_message(
+        Path(message_id): Path<i32>,
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let preview = quarantine::get_user_quarantined_message(user.id, message_id, &database)
+            .await?
+            .ok_or_else(|| ApiError::not_found("quarantined message not found"))?;
+
+        Ok(Json(preview))
+    }
+
+    /// Releases a quarantined message to the forwarding queue.
+    #[utoipa::path(
+        post,
+        path = "/quarantine/{id}/release",
+        tag = "quarantine",
+        params(("id" = i32, Path, description = "Message id")),
+        responses(
+            (status = OK, description = "The released message", body = message::Message),
+            (
+                status = NOT_FOUND,
+                description = "Quarantined message not found",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn release_quarantined_message(
+        Path(message_id): Path<i32>,
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let msg = quarantine::release_user_quarantined_message(user.id, message_id, &database)
+            .await?
+            .ok_or_else(|| ApiError::not_found("quarantined message not found"))?;
+
+        Ok(Json(msg))
+    }
+
+    #[utoipa::path(
+        delete,
+        path = "/quarantine/{id}",
+        tag = "quarantine",
+        params(("id" = i32, Path, description = "Message id")),
+        responses(
+            (status = NO_CONTENT, description = "The message was deleted"),
+            (
+                status = NOT_FOUND,
+                description = "Quarantined message not found",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn delete_quarantined_message(
+        Path(message_id): Path<i32>,
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        quarantine::delete_user_quarantined_message(user.id, message_id, &database)
+            .await?
+            .ok_or_else(|| ApiError::not_found("quarantined message not found"))?;
+
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/webhooks",
+        tag = "webhooks",
+        responses(
+            (
+                status = OK,
+                description = "Webhooks of the current user",
+                body = Vec<webhook::Webhook>,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn list_webhooks(
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let webhooks = webhook::get_user_webhooks(user.id, &database).await?;
+
+        Ok(Json(webhooks))
+    }
+
+    /// Registers a webhook for the current user.
+    ///
+    /// The response is the only place the secret the payloads are signed with is returned.
+    #[utoipa::path(
+        post,
+        path = "/webhooks",
+        tag = "webhooks",
+        request_body = webhook::CreateWebhook,
+        responses(
+            (
+                status = OK,
+                description = "The created webhook with its signing secret",
+                body = webhook::CreatedWebhook,
+            ),
+            (
+                status = BAD_REQUEST,
+                description = "Invalid or non-public url or no events",
+                body = ProblemDetails,
+            ),
+            (
+                status = FORBIDDEN,
+                description = "Recent authentication required",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn create_webhook(
+        auth_session: AuthSession,
+        _: RecentAuthentication,
+        State(AppState { database, .. }): State<AppState>,
+        Json(request): Json<webhook::CreateWebhook>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        if !matches!(request.url.scheme(), "http" | "https") {
+            return Err(ApiError::bad_request("webhook url must use http or https"));
+        }
+
+        if !webhooks::resolver::is_public_url(&request.url).await {
+            return Err(ApiError::bad_request(
+                "webhook url must point at a public address",
+            ));
+        }
+
+        if request.events.is_empty() {
+            return Err(ApiError::bad_request(
+                "webhook must subscribe to at least one event",
+            ));
+        }
+
+        let webhook = webhook::create_user_webhook(user.id, request, &database).await?;
+
+        Ok(Json(webhook::CreatedWebhook::new(webhook)))
+    }
+
+    #[utoipa::path(
+        delete,
+        path = "/webhooks/{id}",
+        tag = "webhooks",
+        params(("id" = i32, Path, description = "Webhook id")),
+        responses(
+            (status = OK, description = "The deleted webhook", body = webhook::Webhook),
+            (status = NOT_FOUND, description = "Webhook not found", body = ProblemDetails),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn delete_webhook(
+        Path(webhook_id): Path<i32>,
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let webhook = webhook::delete_user_webhook(user.id, webhook_id, &database)
+            .await?
+            .ok_or_else(|| ApiError::not_found("webhook not found"))?;
+
+        Ok(Json(webhook))
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/webhooks/{id}/deliveries",
+        tag = "webhooks",
+        params(("id" = i32, Path, description = "Webhook id")),
+        responses(
+            (
+                status = OK,
+                description = "The most recent deliveries of the webhook",
+                body = Vec<webhook::WebhookDelivery>,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn list_webhook_deliveries(
+        Path(webhook_id): Path<i32>,
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let deliveries =
+            webhook::get_user_webhook_deliveries(user.id, webhook_id, &database).await?;
+
+        Ok(Json(deliveries))
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/attachments/{id}",
+        tag = "attachments",
+        params(("id" = i32, Path, description = "Attachment id")),
+        responses(
+            (
+                status = OK,
+                description = "The attachment contents",
+                content_type = "application/octet-stream",
+            ),
+            (status = NOT_FOUND, description = "Attachment not found", body = ProblemDetails),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn download_attachment(
+        Path(attachment_id): Path<i32>,
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let contents = attachment::get_user_attachment_contents(user.id, attachment_id, &database)
+            .await?
+            .ok_or_else(|| ApiError::not_found("attachment not found"))?;
+
+        Ok(attachment_response(contents))
+    }
+
+    #[derive(Debug, Deserialize, IntoParams)]
+    #[into_params(parameter_in = Query)]
+    pub(super) struct SignedAttachmentQuery {
+        expires: i64,
+        signature: String,
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/attachments/{id}/signed",
+        tag = "attachments",
+        params(("id" = i32, Path, description = "Attachment id"), SignedAttachmentQuery),
+        responses(
+            (
+                status = OK,
+                description = "The attachment contents",
+                content_type = "application/octet-stream",
+            ),
+            (
+                status = FORBIDDEN,
+                description = "The link is invalid or has expired",
+                body = ProblemDetails,
+            ),
+            (status = NOT_FOUND, description = "Attachment not found", body = ProblemDetails),
+        ),
+    )]
+    #[instrument(skip(query))]
+    pub(super) async fn download_signed_attachment(
+        Path(attachment_id): Path<i32>,
+        Query(query): Query<SignedAttachmentQuery>,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let signed = attachment::get_signed_attachment_contents(attachment_id, &database)
+            .await?
+            .ok_or_else(|| ApiError::not_found("attachment not found"))?;
+        let Some(ref secret) = signed.destination_secret else {
+            return Err(ApiError::forbidden("invalid download link"));
+        };
+
+        match webhooks::verify_expiring(
+            secret,
+            query.expires,
+            attachment_id.to_string().as_bytes(),
+            &query.signature,
+        ) {
+            Ok(()) => {}
+            Err(webhooks::SignatureError::Expired) => {
+                return Err(ApiError::forbidden("download link has expired"));
+            }
+            Err(webhooks::SignatureError::Invalid) => {
+                return Err(ApiError::forbidden("invalid download link"));
+            }
+        }
+
+        Ok(attachment_response(signed.contents))
+    }
+
+    /// Returns `contents` as a download of the attachment.
+    fn attachment_response(contents: attachment::AttachmentContents) -> impl IntoResponse {
+        (
+            StatusCode::OK,
+            [
+                (CONTENT_DISPOSITION, contents.content_disposition()),
+                (CONTENT_TYPE, contents.content_type),
+            ],
+            contents.data,
+        )
+    }
+
+    /// Posts mail received on an address as JSON to a URL instead of forwarding it by e-mail.
+    ///
+    /// The response is the only place the secret the requests are signed with is returned.
+    #[utoipa::path(
+        put,
+        path = "/addresses/{id}/destination",
+        tag = "addresses",
+        params(("id" = i32, Path, description = "Address id")),
+        request_body = address::SetDestination,
+        responses(
+            (
+                status = OK,
+                description = "The updated address with the signing secret",
+                body = address::AddressDestination,
+            ),
+            (
+                status = BAD_REQUEST,
+                description = "Invalid or non-public url",
+                body = ProblemDetails,
+            ),
+            (status = NOT_FOUND, description = "Address not found", body = ProblemDetails),
+            (
+                status = FORBIDDEN,
+                description = "Recent authentication required",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn set_address_destination(
+        Path(address_id): Path<i32>,
+        auth_session: AuthSession,
+        _: RecentAuthentication,
+        State(AppState { database, .. }): State<AppState>,
+        Json(request): Json<address::SetDestination>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        if !matches!(request.url.scheme(), "http" | "https") {
+            return Err(ApiError::bad_request(
+                "destination url must use http or https",
+            ));
+        }
+
+        if !webhooks::resolver::is_public_url(&request.url).await {
+            return Err(ApiError::bad_request(
+                "destination url must point at a public address",
+            ));
+        }
+
+        let addr = address::set_user_address_destination(
+            user.id,
+            address_id,
+            Some(request.url.as_str()),
+            &database,
+        )
+        .await?
+        .ok_or_else(|| ApiError::not_found("address not found"))?;
+
+        Ok(Json(address::AddressDestination::new(addr)))
+    }
+
+    /// Restores forwarding by e-mail for an address.
+    #[utoipa::path(
+        delete,
+        path = "/addresses/{id}/destination",
+        tag = "addresses",
+        params(("id" = i32, Path, description = "Address id")),
+        responses(
+            (status = OK, description = "The updated address", body = address::Address),
+            (status = NOT_FOUND, description = "Address not found", body = ProblemDetails),
+            (
+                status = FORBIDDEN,
+                description = "Recent authentication required",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn delete_address_destination(
+        Path(address_id): Path<i32>,
+        auth_session: AuthSession,
+        _: RecentAuthentication,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let addr = address::set_user_address_destination(user.id, address_id, None, &database)
+            .await?
+            .ok_or_else(|| ApiError::not_found("address not found"))?;
+
+        Ok(Json(addr))
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/pgp-key",
+        tag = "pgp",
+        responses(
+            (
+                status = OK,
+                description = "The OpenPGP key of the current user",
+                body = pgp_key::PgpKey,
+            ),
+            (status = NOT_FOUND, description = "Key not found", body = ProblemDetails),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn get_pgp_key(
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let key = pgp_key::get_user_pgp_key(user.id, &database)
+            .await?
+            .ok_or_else(|| ApiError::not_found("no pgp key has been uploaded"))?;
+
+        Ok(Json(key))
+    }
+
+    #[utoipa::path(
+        put,
+        path = "/pgp-key",
+        tag = "pgp",
+        request_body = pgp_key::UploadPgpKey,
+        responses(
+            (status = OK, description = "The stored key", body = pgp_key::PgpKey),
+            (status = BAD_REQUEST, description = "Invalid key", body = ProblemDetails),
+            (
+                status = FORBIDDEN,
+                description = "Recent authentication required",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument(skip(request))]
+    pub(super) async fn upload_pgp_key(
+        auth_session: AuthSession,
+        _: RecentAuthentication,
+        State(AppState { database, .. }): State<AppState>,
+        Json(request): Json<pgp_key::UploadPgpKey>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let (fingerprint, armored) = match pgp::parse_public_key(&request.public_key)
+            .and_then(|key| Ok((pgp::fingerprint(&key), pgp::armor(&key)?)))
+        {
+            Ok(key) => key,
+            Err(err) => {
+                debug!(?err, "received invalid pgp key");
+
+                return Err(ApiError::bad_request(err.to_string()));
+            }
+        };
+
+        let key = pgp_key::set_user_pgp_key(user.id, fingerprint, armored, &database).await?;
+
+        Ok(Json(key))
+    }
+
+    #[utoipa::path(
+        delete,
+        path = "/pgp-key",
+        tag = "pgp",
+        responses(
+            (status = OK, description = "The deleted key", body = pgp_key::PgpKey),
+            (status = NOT_FOUND, description = "Key not found", body = ProblemDetails),
+            (
+                status = FORBIDDEN,
+                description = "Recent authentication required",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn delete_pgp_key(
+        auth_session: AuthSession,
+        _: RecentAuthentication,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let key = pgp_key::delete_user_pgp_key(user.id, &database)
+            .await?
+            .ok_or_else(|| ApiError::not_found("no pgp key has been uploaded"))?;
+
+        Ok(Json(key))
+    }
+
+    /// Looks up the public key of the user's e-mail address in a Web Key Directory and stores it.
+    #[utoipa::path(
+        post,
+        path = "/pgp-key/discover",
+        tag = "pgp",
+        responses(
+            (status = OK, description = "The discovered key", body = pgp_key::PgpKey),
+            (
+                status = NOT_FOUND,
+                description = "No key was published for the e-mail address",
+                body = ProblemDetails,
+            ),
+            (
+                status = BAD_GATEWAY,
+                description = "The Web Key Directory lookup failed",
+                body = ProblemDetails,
+            ),
+            (
+                status = FORBIDDEN,
+                description = "Recent authentication required",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn discover_pgp_key(
+        auth_session: AuthSession,
+        _: RecentAuthentication,
+        State(AppState {
+            database, config, ..
+        }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let key = match pgp::discover(&user.email, config.forwarding.wkd_url.as_ref()).await {
+            Ok(Some(key)) => key,
+            Ok(None) => {
+                return Err(ApiError::not_found(
+                    "no pgp key was published for the e-mail address",
+                ))
+            }
+            Err(err) => {
+                debug!(?err, "web key directory lookup failed");
+
+                return Err(ApiError::bad_gateway("the web key directory lookup failed"));
+            }
+        };
+
+        let armored = pgp::armor(&key).map_err(|err| {
+            error!(?err, "could not armor discovered pgp key");
+
+            ApiError::internal()
+        })?;
+
+        let key =
+            pgp_key::set_user_pgp_key(user.id, pgp::fingerprint(&key), armored, &database).await?;
+
+        Ok(Json(key))
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/me",
+        tag = "account",
+        responses(
+            (status = OK, description = "The current user", body = account::Profile),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn get_profile(
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let profile = account::get_profile(user.id, &database)
+            .await?
+            .ok_or_else(ApiError::unauthorized)?;
+
+        Ok(Json(profile))
+    }
+
+    /// Exports all data stored about the current user as a zip archive.
+    #[utoipa::path(
+        get,
+        path = "/me/export",
+        tag = "account",
+        responses(
+            (
+                status = OK,
+                description = "A zip archive of all data stored about the current user",
+                content_type = "application/zip",
+                body = Vec<u8>,
+            ),
+            (
+                status = FORBIDDEN,
+                description = "Recent authentication required",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn export_account(
+        auth_session: AuthSession,
+        _: RecentAuthentication,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let archive = account::export_account(user.id, &database)
+            .await?
+            .ok_or_else(ApiError::unauthorized)?;
+
+        Ok((
+            [
+                (CONTENT_TYPE, "application/zip"),
+                (CONTENT_DISPOSITION, "attachment; filename=\"account.zip\""),
+            ],
+            archive,
+        ))
+    }
+
+    /// Deletes the account of the current user and logs out.
+    ///
+    /// Received messages are deleted and the addresses are kept without their settings, so that
+    /// they are never issued again. Other sessions of the user are no longer valid afterwards.
+    #[utoipa::path(
+        delete,
+        path = "/me",
+        tag = "account",
+        responses(
+            (status = NO_CONTENT, description = "The account was deleted"),
+            (
+                status = FORBIDDEN,
+                description = "Recent authentication required",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn delete_account(
+        mut auth_session: AuthSession,
+        _: RecentAuthentication,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user.clone() else {
+            return Err(ApiError::unauthorized());
+        };
+
+        if !account::delete_account(user.id, &database).await? {
+            return Err(ApiError::unauthorized());
+        }
+
+        auth_session.logout().await?;
+
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/me/sessions",
+        tag = "account",
+        responses(
+            (
+                status = OK,
+                description = "The active sessions of the current user",
+                body = Vec<session::UserSession>,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn list_sessions(
+        auth_session: AuthSession,
+        current_session: Session,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let current_session_id = current_session.id().map(|id| id.to_string());
+        let sessions =
+            session::get_user_sessions(user.id, current_session_id.as_deref(), &database).await?;
+
+        Ok(Json(sessions))
+    }
+
+    /// Logs out the session with the given id, e.g. of a lost device.
+    #[utoipa::path(
+        delete,
+        path = "/me/sessions/{id}",
+        tag = "account",
+        params(("id" = i32, Path, description = "Session id")),
+        responses(
+            (status = NO_CONTENT, description = "The session was logged out"),
+            (status = NOT_FOUND, description = "Session not found", body = ProblemDetails),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn delete_session(
+        Path(session_id): Path<i32>,
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        if !session::delete_user_session(user.id, session_id, &database).await? {
+            return Err(ApiError::not_found("session not found"));
+        }
+
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    /// Logs out all sessions of the current user, including this one, and revokes all of their
+    /// API tokens.
+    #[utoipa::path(
+        delete,
+        path = "/me/sessions",
+        tag = "account",
+        responses(
+            (
+                status = NO_CONTENT,
+                description = "All sessions were logged out and API tokens revoked",
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn delete_sessions(
+        mut auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user.clone() else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let sessions = session::delete_user_sessions(user.id, &database).await?;
+        debug!(user_id = user.id, sessions, "logged out everywhere");
+
+        auth_session.logout().await?;
+
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    /// Lists the API tokens of the current user, including expired ones.
+    #[utoipa::path(
+        get,
+        path = "/me/tokens",
+        tag = "account",
+        responses(
+            (
+                status = OK,
+                description = "The API tokens of the current user",
+                body = Vec<api_token::ApiToken>,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn list_api_tokens(
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let api_tokens = api_token::get_user_api_tokens(user.id, &database).await?;
+
+        Ok(Json(api_tokens))
+    }
+
+    #[utoipa::path(
+        delete,
+        path = "/me/tokens/{id}",
+        tag = "account",
+        params(("id" = i32, Path, description = "API token id")),
+        responses(
+            (status = OK, description = "The revoked API token", body = api_token::ApiToken),
+            (status = NOT_FOUND, description = "API token not found", body = ProblemDetails),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn delete_api_token(
+        Path(token_id): Path<i32>,
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let api_token = api_token::delete_user_api_token(user.id, token_id, &database)
+            .await?
+            .ok_or_else(|| ApiError::not_found("api token not found"))?;
+
+        Ok(Json(api_token))
+    }
+
+    #[derive(Debug, Deserialize, ToSchema)]
+    pub struct RegisterPasskey {
+        /// A name for the passkey, e.g. the device it is stored on.
+        pub name: String,
+        /// The response of the authenticator to the registration challenge.
+        #[schema(value_type = Object)]
+        pub credential: RegisterPublicKeyCredential,
+    }
+
+    #[derive(Debug, Deserialize, ToSchema)]
+    pub struct StepUp {
+        /// The response of the authenticator to the step-up challenge.
+        #[schema(value_type = Object)]
+        pub credential: PublicKeyCredential,
+    }
+
+    fn passkey_error(err: WebauthnError) -> ApiError {
+        warn!(?err, "passkey verification failed");
+
+        ApiError::bad_request("passkey verification failed")
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/me/passkeys",
+        tag = "account",
+        responses(
+            (
+                status = OK,
+                description = "The passkeys of the current user",
+                body = Vec<passkey::UserPasskey>,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn list_passkeys(
+        auth_session: AuthSession,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let passkeys = passkey::get_user_passkeys(user.id, &database).await?;
+
+        Ok(Json(passkeys))
+    }
+
+    /// Starts registering a passkey and returns the challenge for the authenticator.
+    #[utoipa::path(
+        post,
+        path = "/me/passkeys/challenge",
+        tag = "account",
+        responses(
+            (
+                status = OK,
+                description = "The options to pass to `navigator.credentials.create()`",
+                body = Object,
+            ),
+            (
+                status = FORBIDDEN,
+                description = "Recent authentication required",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn start_passkey_registration(
+        auth_session: AuthSession,
+        _: RecentAuthentication,
+        session: Session,
+        State(AppState {
+            database,
+            authenticator,
+            ..
+        }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let webauthn_user_id = passkey::get_webauthn_user_id(user.id, &database)
+            .await?
+            .ok_or_else(ApiError::unauthorized)?;
+        // Authenticators refuse to register a second passkey for the same account.
+        let registered = passkey::get_user_passkeys(user.id, &database)
+            .await?
+            .iter()
+            .map(|passkey| passkey.credential.cred_id().clone())
+            .collect();
+
+        let (challenge, registration) = authenticator
+            .webauthn()
+            .start_passkey_registration(
+                webauthn_user_id,
+                &user.email,
+                &user.email,
+                Some(registered),
+            )
+            .map_err(passkey_error)?;
+
+        session
+            .insert(super::PASSKEY_REGISTRATION_KEY, registration)
+            .await?;
+
+        Ok(Json(challenge))
+    }
+
+    /// Finishes registering a passkey with the response of the authenticator.
+    #[utoipa::path(
+        post,
+        path = "/me/passkeys",
+        tag = "account",
+        request_body = RegisterPasskey,
+        responses(
+            (status = OK, description = "The registered passkey", body = passkey::UserPasskey),
+            (
+                status = BAD_REQUEST,
+                description = "No registration was started or the response is invalid",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn register_passkey(
+        auth_session: AuthSession,
+        session: Session,
+        State(AppState {
+            database,
+            authenticator,
+            ..
+        }): State<AppState>,
+        Json(request): Json<RegisterPasskey>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        if request.name.trim().is_empty() {
+            return Err(ApiError::bad_request("name must not be empty"));
+        }
+
+        let Some(registration) = session
+            .remove::<PasskeyRegistration>(super::PASSKEY_REGISTRATION_KEY)
+            .await?
+        else {
+            return Err(ApiError::bad_request("no passkey registration was started"));
+        };
+
+        let credential = authenticator
+            .webauthn()
+            .finish_passkey_registration(&request.credential, &registration)
+            .map_err(passkey_error)?;
+
+        let passkey =
+            passkey::create_user_passkey(user.id, request.name.trim(), credential, &database)
+                .await?;
+
+        Ok(Json(passkey))
+    }
+
+    #[utoipa::path(
+        delete,
+        path = "/me/passkeys/{id}",
+        tag = "account",
+        params(("id" = i32, Path, description = "Passkey id")),
+        responses(
+            (status = OK, description = "The deleted passkey", body = passkey::UserPasskey),
+            (status = NOT_FOUND, description = "Passkey not found", body = ProblemDetails),
+            (
+                status = FORBIDDEN,
+                description = "Recent authentication required",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn delete_passkey(
+        Path(passkey_id): Path<i32>,
+        auth_session: AuthSession,
+        _: RecentAuthentication,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let passkey = passkey::delete_user_passkey(user.id, passkey_id, &database)
+            .await?
+            .ok_or_else(|| ApiError::not_found("passkey not found"))?;
+
+        Ok(Json(passkey))
+    }
+
+    /// Starts confirming a sensitive operation with a passkey and returns the challenge for the
+    /// authenticator.
+    #[utoipa::path(
+        post,
+        path = "/me/step-up/challenge",
+        tag = "account",
+        responses(
+            (
+                status = OK,
+                description = "The options to pass to `navigator.credentials.get()`",
+                body = Object,
+            ),
+            (status = BAD_REQUEST, description = "No passkey is registered", body = ProblemDetails),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn start_step_up(
+        auth_session: AuthSession,
+        session: Session,
+        State(AppState {
+            database,
+            authenticator,
+            ..
+        }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let credentials: Vec<_> = passkey::get_user_passkeys(user.id, &database)
+            .await?
+            .into_iter()
+            .map(|passkey| passkey.credential.0)
+            .collect();
+
+        if credentials.is_empty() {
+            return Err(ApiError::bad_request("no passkey is registered"));
+        }
+
+        let (challenge, authentication) = authenticator
+            .webauthn()
+            .start_passkey_authentication(&credentials)
+            .map_err(passkey_error)?;
+
+        session
+            .insert(super::PASSKEY_AUTHENTICATION_KEY, authentication)
+            .await?;
+
+        Ok(Json(challenge))
+    }
+
+    /// Confirms sensitive operations for a while with the response of the authenticator.
+    ///
+    /// Logging in again at `/api/auth/login?reauthenticate=true` has the same effect.
+    #[utoipa::path(
+        post,
+        path = "/me/step-up",
+        tag = "account",
+        request_body = StepUp,
+        responses(
+            (status = NO_CONTENT, description = "Sensitive operations are allowed for a while"),
+            (
+                status = BAD_REQUEST,
+                description = "No step-up was started or the response is invalid",
+                body = ProblemDetails,
+            ),
+            (status = UNAUTHORIZED, description = "Not logged in", body = ProblemDetails),
+        ),
+        security(("session" = [])),
+    )]
+    #[instrument]
+    pub(super) async fn step_up(
+        auth_session: AuthSession,
+        session: Session,
+        State(AppState {
+            database,
+            authenticator,
+            ..
+        }): State<AppState>,
+        Json(request): Json<StepUp>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let Some(user) = auth_session.user else {
+            return Err(ApiError::unauthorized());
+        };
+
+        let Some(authentication) = session
+            .remove::<PasskeyAuthentication>(super::PASSKEY_AUTHENTICATION_KEY)
+            .await?
+        else {
+            return Err(ApiError::bad_request("no step-up was started"));
+        };
+
+        let result = authenticator
+            .webauthn()
+            .finish_passkey_authentication(&request.credential, &authentication)
+            .map_err(passkey_error)?;
+
+        if !passkey::record_user_passkey_use(user.id, &result, &database).await? {
+            return Err(ApiError::bad_request("passkey verification failed"));
+        }
+
+        session
+            .insert(
+                crate::api::auth::AUTHENTICATED_AT_KEY,
+                OffsetDateTime::now_utc(),
+            )
+            .await?;
+
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/domains",
+        tag = "domains",
+        responses(
+            (status = OK, description = "All domains", body = Vec<domain::Domain>),
+        ),
+    )]
+    #[instrument]
+    pub(super) async fn list_domains(
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let domains = domain::get_domains(&database).await?;
+
+        Ok(Json(domains))
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/domains/{id}",
+        tag = "domains",
+        params(("id" = i32, Path, description = "Domain id")),
+        responses(
+            (status = OK, description = "The domain", body = domain::Domain),
+            (status = NOT_FOUND, description = "Domain not found", body = ProblemDetails),
+        ),
+    )]
+    #[instrument]
+    pub(super) async fn get_domain(
+        Path(domain_id): Path<i32>,
+        State(AppState { database, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let domain = domain::get_domain(domain_id, &database)
+            .await?
+            .ok_or_else(|| ApiError::not_found("domain not found"))?;
+
+        Ok(Json(domain))
+    }
+
+    #[derive(Debug, Clone, Deserialize, ToSchema)]
+    pub struct MailMetadata {
+        /// The intended recipient, if known.
+        pub to: Option<String>,
+        /// The sender, if known.
+        pub from: Option<String>,
+        /// E-mail headers, if known.
+        pub headers: HashMap<String, String>,
+    }
+
+    #[derive(Debug, Clone, Deserialize, ToSchema)]
+    pub struct Mail {
+        /// The raw contents of the e-mail, encoded with base64.
+        pub raw: String,
+        /// The size of the (decoded) raw contents.
+        pub raw_size: usize,
+        /// Information about the e-mail that was known prior to parsing.
+        pub metadata: MailMetadata,
+    }
+
+    #[derive(Debug, Clone, Deserialize, ToSchema)]
+    pub struct MailIngestionRequest {
+        pub mails: Vec<Mail>,
+        pub started_at: String, // FIXME: this should be deserialized to a time
+    }
+
+    /// What happened to an ingested mail.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+    #[serde(rename_all = "snake_case")]
+    pub enum IngestionStatus {
+        /// The mail was stored for forwarding or quarantined.
+        Accepted,
+        /// The mail was discarded, e.g. because it could not be parsed or its address is unknown.
+        /// Mail to disabled addresses is quarantined instead.
+        Dropped,
+        /// The address of the mail was deleted, so the mail should be rejected permanently.
+        Rejected,
+        /// The mail could not be stored because of a temporary error and should be sent again.
+        /// The other mails of the request are not affected.
+        Failed,
+    }
+
+    #[derive(Debug, Clone, Serialize, ToSchema)]
+    pub struct MailIngestionResponse {
+        /// The status of each mail, in the order of the request.
+        pub mails: Vec<IngestionStatus>,
+    }
+
+    #[utoipa::path(
+        post,
+        path = "/ingestion",
+        tag = "ingestion",
+        request_body = MailIngestionRequest,
+        responses(
+            (
+                status = OK,
+                description = "The outcome of each mail",
+                body = MailIngestionResponse,
+            ),
+            (status = UNAUTHORIZED, description = "Invalid ingestion token", body = ProblemDetails),
+        ),
+        security(("ingestion_token" = [])),
+    )]
+    #[instrument(skip_all)]
+    pub(super) async fn ingest(
+        State(AppState {
+            config,
+            database,
+            spam_filter,
+            ..
+        }): State<AppState>,
+        ExtractAuthToken(token): ExtractAuthToken,
+        Json(payload): Json<MailIngestionRequest>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        if token != config.ingestion.api_token {
+            debug!("received ingestion request with invalid token");
+
+            return Err(ApiError::new(
+                ErrorKind::Unauthorized,
+                "invalid authorization token",
+            ));
+        }
+
+        let mail_parser = MessageParser::new()
+            .with_mime_headers()
+            .with_date_headers()
+            .with_address_headers()
+            .with_message_ids();
+
+        let mut statuses = Vec::with_capacity(payload.mails.len());
+
+        debug!(
+            started_at = %payload.started_at,
+            mails = payload.mails.len(),
+            "received ingestion request"
+        );
+
+        for mail in &payload.mails {
+            metrics::record_mail_received();
+            debug!(
+                from = ?mail.metadata.from,
+                raw_size = mail.raw_size,
+                headers = mail.metadata.headers.len(),
+                "received email"
+            );
+
+            let decoded = match BASE64_STANDARD.decode(&mail.raw) {
+                Ok(data) => data,
+                Err(_) => {
+                    metrics::record_mail_ingested("dropped", "invalid_encoding");
+                    statuses.push(IngestionStatus::Dropped);
+                    continue;
+                }
+            };
+
+            let Some(parsed) = mail_parser.parse(&decoded[..]) else {
+                error!("could not parse email");
+                metrics::record_mail_ingested("dropped", "unparseable");
+                statuses.push(IngestionStatus::Dropped);
+                continue;
+            };
+
+            debug!(?parsed, "parsed email");
+
+            let recipient = mail.metadata.to.clone().or_else(|| {
+                parsed
+                    .to()
+                    .and_then(|to| to.first())
+                    .and_then(|addr| addr.address())
+                    .map(str::to_string)
+            });
+            let Some(recipient) = recipient else {
+                debug!("received email without recipient");
+                metrics::record_mail_ingested("dropped", "missing_recipient");
+                statuses.push(IngestionStatus::Dropped);
+                continue;
+            };
+
+            let (addr, user_id) = match address::find_address(&recipient, &database).await {
+                Ok(Some(addr)) if addr.deleted_at.is_some() => {
+                    debug!(%recipient, "rejecting email for deleted address");
+                    metrics::record_mail_ingested("rejected", "deleted_address");
+                    statuses.push(IngestionStatus::Rejected);
+                    continue;
+                }
+                Ok(Some(
+                    addr @ address::Address {
+                        user_id: Some(user_id),
+                        ..
+                    },
+                )) => (addr, user_id),
+                Ok(_) => {
+                    debug!(%recipient, "received email for unknown address");
+                    metrics::record_mail_ingested("dropped", "unknown_address");
+                    statuses.push(IngestionStatus::Dropped);
+                    continue;
+                }
+                Err(err) => {
+                    error!(?err, %recipient, "could not look up address");
+                    metrics::record_mail_ingested("failed", "database_error");
+                    statuses.push(IngestionStatus::Failed);
+                    continue;
+                }
+            };
+
+            let mut msg = message::CreateMessage::from_parsed(&parsed);
+            let score = spam_filter
+                .score(&spam::Mail {
+                    raw: &decoded,
+                    parsed: &parsed,
+                    user_id,
+                })
+                .await;
+            let action = SpamAction::for_score(
+                score,
+                addr.spam_tag_threshold.unwrap_or(spam_filter.tag_threshold),
+                addr.spam_quarantine_threshold
+                    .unwrap_or(spam_filter.quarantine_threshold),
+            );
+
+            debug!(%score, ?action, "scored email");
+            msg.apply_spam_score(score, action);
+
+            if spam::headers::dmarc_failed(&parsed, config.spam.authserv_id.as_deref()) {
+                msg.quarantine(
+                    quarantine::QuarantineReason::AuthenticationFailure,
+                    Some("DMARC authentication failed".to_string()),
+                );
+            }
+
+            if !addr.enabled {
+                msg.quarantine(
+                    quarantine::QuarantineReason::DisabledAddress,
+                    Some("address is disabled".to_string()),
+                );
+            }
+
+            match store_message(&addr, user_id, msg, &database).await {
+                Ok(msg) => {
+                    let reason = match msg.status {
+                        message::MessageStatus::Quarantined => "quarantined",
+                        _ => "queued",
+                    };
+
+                    metrics::record_mail_ingested("accepted", reason);
+                    statuses.push(IngestionStatus::Accepted);
+                }
+                // Each message is stored in its own transaction, so failing to store one must not
+                // fail the request after earlier messages were stored, or a retry of the whole
+                // request would store them twice.
+                Err(err) => {
+                    error!(?err, "could not store email");
+                    metrics::record_mail_ingested("failed", "database_error");
+                    statuses.push(IngestionStatus::Failed);
+                }
+            }
+        }
+
+        Ok(Json(MailIngestionResponse { mails: statuses }))
+
+        // debug!(%raw, %raw_size, %to, %from, "received email");
+
+        // match MessageParser::default().parse(&raw) {
+        //     Some(_parsed) => {
+        //         debug!("parsed email");
+
+        //         ().into_response()
+        //     }
+        //     None => {
+        //         error!("could not parse email");
+
+        //         (StatusCode::INTERNAL_SERVER_ERROR).into_response()
+        //     }
+        // }
+    }
+
+    /// Stores `msg` received on `addr` along with the webhook event announcing it in a single
+    /// transaction.
+    async fn store_message(
+        addr: &address::Address,
+        user_id: i32,
+        msg: message::CreateMessage,
+        database: &crate::Database,
+    ) -> Result<message::Message, crate::Error> {
+        let blocked_reason = msg.quarantine_details.clone();
+        let mut tx = database.begin().await?;
+        let (msg, attachments) = message::create_message(addr, msg, &mut tx).await?;
+
+        debug!(
+            message_id = msg.id,
+            attachments = attachments.len(),
+            "stored email"
+        );
+
+        let (event, reason) = match msg.status {
+            message::MessageStatus::Quarantined => (WebhookEvent::MailBlocked, blocked_reason),
+            _ => (WebhookEvent::MailReceived, None),
+        };
+
+        webhook::emit(user_id, event, &MessageEvent::new(&msg, reason), &mut tx).await?;
+        tx.commit().await?;
+
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, time::Duration};
+
+    use axum::{
+        body::Body,
+        extract::FromRequestParts,
+        http::{
+            header::{AUTHORIZATION, CONTENT_TYPE},
+            Method, Request, StatusCode,
+        },
+        middleware::{self, Next},
+        response::IntoResponse,
+        routing::post,
+        Router,
+    };
+    use axum_login::AuthManagerLayerBuilder;
+    use base64::prelude::{Engine, BASE64_STANDARD};
+    use figment::{
+        providers::{Format, Toml},
+        Figment,
+    };
+    use sqlx::postgres::PgPoolOptions;
+    use time::OffsetDateTime;
+    use tower::ServiceExt;
+    use tower_sessions::{MemoryStore, Session, SessionManagerLayer};
+    use tower_sessions_sqlx_store::PostgresStore;
+    use utoipa::OpenApi;
+
+    use super::{
+        api_token::{ApiToken, ApiTokenScope},
+        router, verify_csrf_token, ApiDoc, AuthSession, RecentAuthentication,
+    };
+    use crate::{
+        auth::{Authenticator, User},
+        database::{create_test_address, create_test_user, test_database},
+        http::AppState,
+        metrics,
+        spam::SpamFilter,
+        Config,
+    };
+
+    /// Every route of the router, which must be kept in sync with `router()`.
+    const ROUTES: &[(Method, &str)] = &[
+        (Method::GET, "/addresses"),
+        (Method::POST, "/addresses"),
+        (Method::GET, "/addresses/:id"),
+        (Method::PATCH, "/addresses/:id"),
+        (Method::DELETE, "/addresses/:id"),
+        (Method::POST, "/addresses/:id/restore"),
+        (Method::POST, "/addresses/batch"),
+        (Method::GET, "/addresses/export"),
+        (Method::POST, "/addresses/import"),
+        (Method::PUT, "/addresses/:id/destination"),
+        (Method::DELETE, "/addresses/:id/destination"),
+        (Method::GET, "/addresses/:id/labels"),
+        (Method::PUT, "/addresses/:id/labels/:label_id"),
+        (Method::DELETE, "/addresses/:id/labels/:label_id"),
+        (Method::GET, "/labels"),
+        (Method::POST, "/labels"),
+        (Method::DELETE, "/labels/:id"),
+        (Method::POST, "/labels/:id/enable"),
+        (Method::POST, "/labels/:id/disable"),
+        (Method::PUT, "/labels/:id/mailbox"),
+        (Method::DELETE, "/labels/:id/mailbox"),
+        (Method::GET, "/mailboxes"),
+        (Method::POST, "/mailboxes"),
+        (Method::DELETE, "/mailboxes/:id"),
+        (Method::POST, "/messages/:id/report"),
+        (Method::GET, "/quarantine"),
+        (Method::GET, "/quarantine/:id"),
+        (Method::DELETE, "/quarantine/:id"),
+        (Method::POST, "/quarantine/:id/release"),
+        (Method::GET, "/attachments/:id"),
+        (Method::GET, "/webhooks"),
+        (Method::POST, "/webhooks"),
+        (Method::DELETE, "/webhooks/:id"),
+        (Method::GET, "/webhooks/:id/deliveries"),
+        (Method::GET, "/pgp-key"),
+        (Method::PUT, "/pgp-key"),
+        (Method::DELETE, "/pgp-key"),
+        (Method::POST, "/pgp-key/discover"),
+        (Method::GET, "/me"),
+        (Method::DELETE, "/me"),
+        (Method::GET, "/me/export"),
+        (Method::GET, "/me/sessions"),
+        (Method::DELETE, "/me/sessions"),
+        (Method::DELETE, "/me/sessions/:id"),
+        (Method::GET, "/me/tokens"),
+        (Method::DELETE, "/me/tokens/:id"),
+        (Method::GET, "/me/passkeys"),
+        (Method::POST, "/me/passkeys"),
+        (Method::POST, "/me/passkeys/challenge"),
+        (Method::DELETE, "/me/passkeys/:id"),
+        (Method::POST, "/me/step-up"),
+        (Method::POST, "/me/step-up/challenge"),
+        (Method::GET, "/domains"),
+        (Method::GET, "/domains/:id"),
+        (Method::GET, "/mailboxes/verify"),
+        (Method::GET, "/attachments/:id/signed"),
+        (Method::POST, "/ingestion"),
+        (Method::GET, "/openapi.json"),
+    ];
+
+    /// Routes that are intentionally left out of the API description.
+    const UNDOCUMENTED: &[(Method, &str)] = &[(Method::GET, "/openapi.json")];
+
+    /// Turns the documented `path` into the syntax of the router, e.g. `/labels/{id}` into
+    /// `/labels/:id`.
+    fn route_path(path: &str) -> String {
+        path.split('/')
+            .map(|segment| match segment.strip_prefix('{') {
+                Some(param) => format!(":{}", param.trim_end_matches('}')),
+                None => segment.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Builds the state of the router for a server whose database cannot be reached, so that
+    /// requests fail fast once they reach a handler.
+    async fn app_state() -> AppState {
+        let db = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(100))
+            .connect_lazy("postgres://127.0.0.1:1/masked_mails")
+            .unwrap();
+
+        app_state_with_database(db).await
+    }
+
+    async fn app_state_with_database(db: crate::Database) -> AppState {
+        let config: Config = Figment::new()
+            .merge(Toml::string(
+                r#"
+                [database]
+                url = "postgres://127.0.0.1:1/masked_mails"
+
+                [auth]
+                redirect_url = "http://localhost:3000/api/auth/callback"
+                token_key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+                mock = true
+
+                [ingestion]
+                api_token = "secret"
+
+                [tracing]
+                enabled = false
+
+                [metrics]
+                enabled = false
+                "#,
+            ))
+            .extract()
+            .unwrap();
+
+        AppState {
+            authenticator: Authenticator::discover(db.clone(), &config.auth)
+                .await
+                .unwrap(),
+            session_store: PostgresStore::new(db.clone()),
+            spam_filter: SpamFilter::new(&config.spam, db.clone()),
+            metrics: metrics::init(&config.metrics).unwrap(),
+            database: db,
+            config,
+        }
+    }
+
+    #[test]
+    fn documents_every_route() {
+        let documented: BTreeSet<(String, String)> = ApiDoc::openapi()
+            .paths
+            .paths
+            .into_iter()
+            .flat_map(|(path, item)| {
+                let path = route_path(&path);
+
+                [
+                    (Method::GET, item.get),
+                    (Method::PUT, item.put),
+                    (Method::POST, item.post),
+                    (Method::DELETE, item.delete),
+                    (Method::PATCH, item.patch),
+                ]
+                .into_iter()
+                .filter(|(_, operation)| operation.is_some())
+                .map(move |(method, _)| (method.to_string(), path.clone()))
+            })
+            .collect();
+        let routed: BTreeSet<(String, String)> = ROUTES
+            .iter()
+            .filter(|route| !UNDOCUMENTED.contains(route))
+            .map(|(method, path)| (method.to_string(), path.to_string()))
+            .collect();
+
+        assert_eq!(documented, routed);
+    }
+
+    #[tokio::test]
+    async fn refuses_sensitive_operations_with_api_tokens() {
+        let state = app_state().await;
+        let (mut parts, _) = Request::builder().body(()).unwrap().into_parts();
+        parts.extensions.insert(ApiToken {
+            id: 1,
+            user_id: 1,
+            name: "laptop".to_string(),
+            scopes: vec![ApiTokenScope::Read, ApiTokenScope::Write],
+            created_at: OffsetDateTime::now_utc(),
+            last_used_at: None,
+            expires_at: None,
+        });
+
+        let rejection = RecentAuthentication::from_request_parts(&mut parts, &state)
+            .await
+            .unwrap_err();
+
+        assert_eq!(rejection.into_response().status(), StatusCode::FORBIDDEN);
+    }
+
+    /// The CSRF token of the sessions logged in by [`log_in`].
+    const CSRF_TOKEN: &str = "csrf";
+
+    /// Stands in for a login of `user_id` with the session cookie, which did not recently
+    /// authenticate.
+    async fn log_in(
+        user_id: i32,
+        mut request: Request<Body>,
+        next: Next,
+    ) -> axum::response::Response {
+        if let Some(session) = request.extensions().get::<Session>() {
+            session
+                .insert(crate::api::auth::CSRF_TOKEN_KEY, CSRF_TOKEN)
+                .await
+                .unwrap();
+        }
+
+        if let Some(auth_session) = request.extensions_mut().get_mut::<AuthSession>() {
+            auth_session.user = Some(User {
+                id: user_id,
+                email: "joe.doe@example.org".to_string(),
+                access_token: "access".to_string(),
+                provider: None,
+                refresh_token: None,
+                access_token_expires_at: None,
+                session_key: "key".to_string(),
+                id_token: None,
+                auth_time: None,
+            });
+        }
+
+        next.run(request).await
+    }
+
+    /// Layers `router` with sessions kept in memory that are logged in by [`log_in`].
+    fn with_login(router: Router, state: &AppState) -> Router {
+        with_login_as(router, state, 1)
+    }
+
+    fn with_login_as(router: Router, state: &AppState, user_id: i32) -> Router {
+        let session_layer = SessionManagerLayer::new(MemoryStore::default());
+
+        router
+            .layer(middleware::from_fn(move |request, next| {
+                log_in(user_id, request, next)
+            }))
+            .layer(AuthManagerLayerBuilder::new(state.authenticator.clone(), session_layer).build())
+    }
+
+    #[tokio::test]
+    async fn requires_a_csrf_token_without_an_api_token() {
+        let state = app_state().await;
+        let router = with_login(
+            Router::new()
+                .route("/", post(|| async {}))
+                .route_layer(middleware::from_fn(verify_csrf_token)),
+            &state,
+        );
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header(AUTHORIZATION, "Basic am9lOmRvZQ==")
+            .body(Body::empty())
+            .unwrap();
+        let status = router.oneshot(request).await.unwrap().status();
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_address_cursors() {
+        let state = app_state().await;
+        let router = with_login(router().with_state(state.clone()), &state);
+        // A cursor of the first page sorted by address, which is stale when sorting by creation
+        let stale = "eyJzb3J0IjoiYWRkcmVzcyIsIm9yZGVyIjoiZGVzYyIsInZhbHVlIjoic2hvcCIsImlkIjoxfQ";
+
+        for cursor in ["not-a-cursor", stale] {
+            let request = Request::builder()
+                .uri(format!("/addresses?sort=created_at&cursor={cursor}"))
+                .body(Body::empty())
+                .unwrap();
+            let status = router.clone().oneshot(request).await.unwrap().status();
+
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{cursor}");
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a database at DATABASE_URL"]
+    async fn rolls_back_batches_with_unknown_addresses() {
+        let db = test_database().await;
+        let user_id = create_test_user(&db).await;
+        let other_user_id = create_test_user(&db).await;
+        let own = create_test_address(&db, user_id).await;
+        let foreign = create_test_address(&db, other_user_id).await;
+        let state = app_state_with_database(db.clone()).await;
+        let router = with_login_as(router().with_state(state.clone()), &state, user_id);
+
+        for ids in [[own.id, foreign.id], [own.id, i32::MAX]] {
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri("/addresses/batch")
+                .header(CONTENT_TYPE, "application/json")
+                .header(super::CSRF_TOKEN_HEADER, CSRF_TOKEN)
+                .body(Body::from(
+                    serde_json::json!({ "action": "disable", "ids": ids }).to_string(),
+                ))
+                .unwrap();
+            let status = router.clone().oneshot(request).await.unwrap().status();
+
+            assert_eq!(status, StatusCode::NOT_FOUND, "{ids:?}");
+        }
+
+        for address_id in [own.id, foreign.id] {
+            let (enabled,): (bool,) = sqlx::query_as("SELECT enabled FROM addresses WHERE id = $1")
+                .bind(address_id)
+                .fetch_one(&db)
+                .await
+                .unwrap();
+
+            assert!(enabled, "{address_id}");
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a database at DATABASE_URL"]
+    async fn rejects_mail_to_deleted_addresses() {
+        let db = test_database().await;
+        let user_id = create_test_user(&db).await;
+        let address = create_test_address(&db, user_id).await;
+        let (domain,): (String,) = sqlx::query_as(
+            "UPDATE addresses SET deleted_at = NOW() FROM domains \
+             WHERE domains.id = addresses.domain_id AND addresses.id = $1 RETURNING domains.name",
+        )
+        .bind(address.id)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        let router = router().with_state(app_state_with_database(db).await);
+        let raw = b"From: sender@example.com\r\nSubject: Hello\r\n\r\nHi\r\n";
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/ingestion")
+            .header(AUTHORIZATION, "Token secret")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::json!({
+                    "started_at": "2024-05-01T12:00:00Z",
+                    "mails": [{
+                        "raw": BASE64_STANDARD.encode(raw),
+                        "raw_size": raw.len(),
+                        "metadata": {
+                            "to": format!("{}@{domain}", address.address),
+                            "from": "sender@example.com",
+                            "headers": {},
+                        },
+                    }],
+                })
+                .to_string(),
+            ))
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({ "mails": ["rejected"] })
+        );
+    }
+
+    #[tokio::test]
+    async fn requires_a_step_up_to_change_the_delivery() {
+        let state = app_state().await;
+        let router = with_login(router().with_state(state.clone()), &state);
+
+        for (method, uri) in [
+            (Method::PUT, "/pgp-key"),
+            (Method::DELETE, "/pgp-key"),
+            (Method::POST, "/pgp-key/discover"),
+            (Method::DELETE, "/mailboxes/1"),
+        ] {
+            let request = Request::builder()
+                .method(&method)
+                .uri(uri)
+                .header(super::CSRF_TOKEN_HEADER, CSRF_TOKEN)
+                .body(Body::empty())
+                .unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{method} {uri}");
+
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+
+            assert!(
+                String::from_utf8_lossy(&body).contains("Recent authentication required"),
+                "{method} {uri}"
+            );
+        }
+    }
+
+    /// Routes behind the login are layered as a whole, so a request with a method they do not
+    /// route is refused before it could be answered with 405. The methods of those routes are
+    /// only checked against the API description.
+    #[tokio::test]
+    async fn routes_every_listed_route() {
+        let router = router().with_state(app_state().await);
+
+        for (method, path) in ROUTES {
+            let uri: String = path
+                .split('/')
+                .map(|segment| {
+                    if segment.starts_with(':') {
+                        "1"
+                    } else {
+                        segment
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("/");
+            let request = Request::builder()
+                .method(method)
+                .uri(&uri)
+                .body(Body::empty())
+                .unwrap();
+            let status = router.clone().oneshot(request).await.unwrap().status();
+
+            assert!(
+                status != StatusCode::NOT_FOUND && status != StatusCode::METHOD_NOT_ALLOWED,
+                "{method} {path} is not routed: {status}"
+            );
+        }
     }
 }
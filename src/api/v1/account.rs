@@ -0,0 +1,429 @@
+//! Account self-service
+//!
+//! Users can download everything stored about them as a zip archive, and delete their account.
+//! The addresses of deleted accounts are kept as tombstones, stripped of everything but the
+//! address itself, so that they are never issued to anyone else.
+
+use std::io::{self, Cursor, Write};
+
+use serde::Serialize;
+use sqlx::FromRow;
+use time::OffsetDateTime;
+use utoipa::ToSchema;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::Error;
+
+use super::{
+    address::Address,
+    api_token::ApiToken,
+    attachment::{self, MessageAttachment},
+    label::Label,
+    mailbox::Mailbox,
+    message::Message,
+    passkey::UserPasskey,
+    webhook::Webhook,
+};
+
+/// The account of the authenticated user.
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema)]
+pub struct Profile {
+    pub id: i32,
+    pub email: String,
+    /// The fingerprint of the OpenPGP key forwarded mail is encrypted with.
+    pub pgp_fingerprint: Option<String>,
+}
+
+/// A label along with the ids of the addresses it is attached to.
+#[derive(Debug, Clone, FromRow, Serialize)]
+struct ExportedLabel {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    label: Label,
+    address_ids: Vec<i32>,
+}
+
+/// An identity at a provider that the user logged in with.
+#[derive(Debug, Clone, FromRow, Serialize)]
+struct ExportedIdentity {
+    issuer: String,
+    subject: String,
+    created_at: Option<OffsetDateTime>,
+}
+
+/// Everything stored about a user, as written to the export archive.
+#[derive(Debug)]
+struct AccountExport {
+    profile: Profile,
+    addresses: Vec<Address>,
+    labels: Vec<ExportedLabel>,
+    webhooks: Vec<Webhook>,
+    mailboxes: Vec<Mailbox>,
+    passkeys: Vec<UserPasskey>,
+    api_tokens: Vec<ApiToken>,
+    identities: Vec<ExportedIdentity>,
+    messages: Vec<Message>,
+    attachments: Vec<MessageAttachment>,
+    pgp_public_key: Option<String>,
+    /// The contents of the attachments along with their paths in the archive.
+    attachment_data: Vec<(String, Vec<u8>)>,
+}
+
+/// Returns the profile of `user_id`.
+pub async fn get_profile(user_id: i32, db: &crate::Database) -> Result<Option<Profile>, Error> {
+    let profile = sqlx::query_as("SELECT id, email, pgp_fingerprint FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(db)
+        .await?;
+
+    Ok(profile)
+}
+
+/// Returns a zip archive of all data stored about `user_id`.
+///
+/// The archive contains the profile, the addresses including deleted ones, labels, webhooks,
+/// mailboxes, passkeys, API tokens, the identities at the providers the user logged in with,
+/// received messages and the contents of their attachments.
+pub async fn export_account(user_id: i32, db: &crate::Database) -> Result<Option<Vec<u8>>, Error> {
+    let Some(profile) = get_profile(user_id, db).await? else {
+        return Ok(None);
+    };
+
+    let pgp_key = super::pgp_key::get_user_pgp_key(user_id, db).await?;
+
+    let addresses: Vec<Address> =
+        sqlx::query_as("SELECT * FROM addresses WHERE user_id = $1 ORDER BY id")
+            .bind(user_id)
+            .fetch_all(db)
+            .await?;
+
+    let labels: Vec<ExportedLabel> = sqlx::query_as(
+        r"
+        SELECT
+            labels.*,
+            COALESCE(
+                ARRAY_AGG(address_labels.address_id ORDER BY address_labels.address_id)
+                FILTER (WHERE address_labels.address_id IS NOT NULL),
+                '{}'
+            ) AS address_ids
+        FROM labels
+        LEFT JOIN address_labels ON address_labels.label_id = labels.id
+        WHERE labels.user_id = $1
+        GROUP BY labels.id
+        ORDER BY labels.name
+        ",
+    )
+    .bind(user_id)
+    .fetch_all(db)
+    .await?;
+
+    let identities: Vec<ExportedIdentity> = sqlx::query_as(
+        "SELECT issuer, subject, created_at FROM user_identities WHERE user_id = $1 ORDER BY id",
+    )
+    .bind(user_id)
+    .fetch_all(db)
+    .await?;
+
+    let messages: Vec<Message> = sqlx::query_as(
+        r"
+        SELECT messages.* FROM messages
+        INNER JOIN addresses ON addresses.id = messages.address_id
+        WHERE addresses.user_id = $1
+        ORDER BY messages.id
+        ",
+    )
+    .bind(user_id)
+    .fetch_all(db)
+    .await?;
+
+    let attachments: Vec<MessageAttachment> = sqlx::query_as(
+        r"
+        SELECT message_attachments.* FROM message_attachments
+        INNER JOIN messages ON messages.id = message_attachments.message_id
+        INNER JOIN addresses ON addresses.id = messages.address_id
+        WHERE addresses.user_id = $1
+        ORDER BY message_attachments.id
+        ",
+    )
+    .bind(user_id)
+    .fetch_all(db)
+    .await?;
+
+    let mut attachment_data = Vec::new();
+
+    for attachment in &attachments {
+        let Some(attachment_id) = attachment.attachment_id else {
+            continue;
+        };
+        let Some(data) = super::attachment::get_attachment_data(attachment_id, db).await? else {
+            continue;
+        };
+
+        attachment_data.push((attachment_path(attachment), data));
+    }
+
+    let export = AccountExport {
+        profile,
+        addresses,
+        labels,
+        webhooks: super::webhook::get_user_webhooks(user_id, db).await?,
+        mailboxes: super::mailbox::get_user_mailboxes(user_id, db).await?,
+        passkeys: super::passkey::get_user_passkeys(user_id, db).await?,
+        api_tokens: super::api_token::get_user_api_tokens(user_id, db).await?,
+        identities,
+        messages,
+        attachments,
+        pgp_public_key: pgp_key.map(|key| key.pgp_public_key),
+        attachment_data,
+    };
+
+    export.into_archive().map(Some)
+}
+
+impl AccountExport {
+    /// Writes the export as a zip archive.
+    fn into_archive(self) -> Result<Vec<u8>, Error> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+        write_json(&mut zip, "profile.json", &self.profile, options)?;
+        write_json(&mut zip, "addresses.json", &self.addresses, options)?;
+        write_json(&mut zip, "labels.json", &self.labels, options)?;
+        write_json(&mut zip, "webhooks.json", &self.webhooks, options)?;
+        write_json(&mut zip, "mailboxes.json", &self.mailboxes, options)?;
+        write_json(&mut zip, "passkeys.json", &self.passkeys, options)?;
+        write_json(&mut zip, "api_tokens.json", &self.api_tokens, options)?;
+        write_json(&mut zip, "identities.json", &self.identities, options)?;
+        write_json(&mut zip, "messages.json", &self.messages, options)?;
+        write_json(&mut zip, "attachments.json", &self.attachments, options)?;
+
+        if let Some(key) = &self.pgp_public_key {
+            write_file(&mut zip, "pgp_public_key.asc", key.as_bytes(), options)?;
+        }
+
+        for (path, data) in &self.attachment_data {
+            write_file(&mut zip, path, data, options)?;
+        }
+
+        let archive = zip.finish().map_err(Error::AccountExportFailed)?;
+
+        Ok(archive.into_inner())
+    }
+}
+
+/// Writes `value` as JSON to the file `name` of the archive.
+fn write_json<T: Serialize>(
+    zip: &mut ZipWriter<Cursor<Vec<u8>>>,
+    name: &str,
+    value: &T,
+    options: SimpleFileOptions,
+) -> Result<(), Error> {
+    let data = serde_json::to_vec_pretty(value)
+        .map_err(|err| Error::AccountExportFailed(io::Error::from(err).into()))?;
+
+    write_file(zip, name, &data, options)
+}
+
+/// Writes `data` to the file `name` of the archive.
+fn write_file(
+    zip: &mut ZipWriter<Cursor<Vec<u8>>>,
+    name: &str,
+    data: &[u8],
+    options: SimpleFileOptions,
+) -> Result<(), Error> {
+    zip.start_file(name, options)
+        .and_then(|()| Ok(zip.write_all(data)?))
+        .map_err(Error::AccountExportFailed)
+}
+
+/// Returns the path of the contents of `attachment` in the export archive.
+///
+/// The filename is chosen by the sender, so path separators are replaced to keep every
+/// attachment inside the `attachments` directory.
+fn attachment_path(attachment: &MessageAttachment) -> String {
+    let filename = attachment
+        .filename
+        .as_deref()
+        .unwrap_or("unnamed")
+        .replace(['/', '\\'], "_");
+
+    format!("attachments/{}-{}", attachment.id, filename)
+}
+
+/// Deletes the account of `user_id` and returns whether it existed.
+///
+/// Received messages and attachments no longer referenced by other messages are deleted, the
+/// addresses are turned into tombstones and everything else owned by the user is removed along
+/// with the user by the database.
+pub async fn delete_account(user_id: i32, db: &crate::Database) -> Result<bool, Error> {
+    let mut tx = db.begin().await?;
+
+    let attachment_ids: Vec<(i32,)> = sqlx::query_as(
+        r"
+        SELECT DISTINCT message_attachments.attachment_id FROM message_attachments
+        INNER JOIN messages ON messages.id = message_attachments.message_id
+        INNER JOIN addresses ON addresses.id = messages.address_id
+        WHERE addresses.user_id = $1 AND message_attachments.attachment_id IS NOT NULL
+        ",
+    )
+    .bind(user_id)
+    .fetch_all(&mut *tx)
+    .await?;
+    let attachment_ids: Vec<i32> = attachment_ids.into_iter().map(|(id,)| id).collect();
+
+    sqlx::query(
+        r"
+        DELETE FROM messages
+        USING addresses
+        WHERE addresses.id = messages.address_id AND addresses.user_id = $1
+        ",
+    )
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+
+    attachment::delete_orphaned_attachments(&attachment_ids, &mut tx).await?;
+
+    sqlx::query(
+        r"
+        UPDATE addresses SET
+            description = NULL,
+            enabled = FALSE,
+            destination_url = NULL,
+            destination_secret = NULL,
+            forward_to = NULL,
+            updated_at = NOW(),
+            deleted_at = COALESCE(deleted_at, NOW())
+        WHERE user_id = $1
+        ",
+    )
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+
+    let result = sqlx::query("DELETE FROM users WHERE id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use zip::ZipArchive;
+
+    use super::*;
+    use crate::{
+        api::v1::{
+            api_token::{self, ApiTokenScope},
+            mailbox,
+        },
+        database::{create_test_user, test_database},
+    };
+
+    /// Returns the contents of the file `name` in `archive`.
+    fn read_file(archive: &[u8], name: &str) -> String {
+        let mut zip = ZipArchive::new(Cursor::new(archive)).unwrap();
+        let mut contents = String::new();
+
+        zip.by_name(name)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+
+        contents
+    }
+
+    #[test]
+    fn writes_every_part_of_the_account() {
+        let export = AccountExport {
+            profile: Profile {
+                id: 1,
+                email: "user@example.com".to_string(),
+                pgp_fingerprint: None,
+            },
+            addresses: Vec::new(),
+            labels: Vec::new(),
+            webhooks: Vec::new(),
+            mailboxes: Vec::new(),
+            passkeys: Vec::new(),
+            api_tokens: Vec::new(),
+            identities: vec![ExportedIdentity {
+                issuer: "https://accounts.example.com".to_string(),
+                subject: "1234".to_string(),
+                created_at: None,
+            }],
+            messages: Vec::new(),
+            attachments: Vec::new(),
+            pgp_public_key: Some("-----BEGIN PGP PUBLIC KEY BLOCK-----".to_string()),
+            attachment_data: vec![("attachments/1-invoice.pdf".to_string(), b"%PDF".to_vec())],
+        };
+
+        let archive = export.into_archive().unwrap();
+        let names: Vec<String> = ZipArchive::new(Cursor::new(&archive))
+            .unwrap()
+            .file_names()
+            .map(str::to_string)
+            .collect();
+
+        for name in [
+            "profile.json",
+            "addresses.json",
+            "labels.json",
+            "webhooks.json",
+            "mailboxes.json",
+            "passkeys.json",
+            "api_tokens.json",
+            "identities.json",
+            "messages.json",
+            "attachments.json",
+            "pgp_public_key.asc",
+            "attachments/1-invoice.pdf",
+        ] {
+            assert!(names.iter().any(|n| n == name), "{name} is missing");
+        }
+
+        assert!(read_file(&archive, "identities.json").contains("\"subject\": \"1234\""));
+        assert_eq!(read_file(&archive, "attachments/1-invoice.pdf"), "%PDF");
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a database at DATABASE_URL"]
+    async fn exports_mailboxes_api_tokens_and_identities() {
+        let db = test_database().await;
+        let user_id = create_test_user(&db).await;
+
+        mailbox::create_user_mailbox(user_id, "inbox@example.org", &db)
+            .await
+            .unwrap();
+        api_token::create_api_token(
+            user_id,
+            "laptop",
+            vec![ApiTokenScope::Read],
+            None,
+            &mut db.acquire().await.unwrap(),
+        )
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO user_identities (user_id, issuer, subject) VALUES ($1, $2, $3)")
+            .bind(user_id)
+            .bind("https://accounts.example.com")
+            .bind(format!("subject-{user_id}"))
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let archive = export_account(user_id, &db).await.unwrap().unwrap();
+
+        assert!(read_file(&archive, "mailboxes.json").contains("inbox@example.org"));
+        assert!(read_file(&archive, "api_tokens.json").contains("\"name\": \"laptop\""));
+        assert!(!read_file(&archive, "api_tokens.json").contains("token_hash"));
+        assert!(read_file(&archive, "identities.json").contains(&format!("subject-{user_id}")));
+        assert_eq!(read_file(&archive, "passkeys.json"), "[]");
+    }
+}
@@ -1,21 +1,73 @@
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
 use rand::{
     distributions::{Alphanumeric, DistString},
     Rng,
 };
-use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{collections::HashMap, time::Duration};
+
+use sqlx::{FromRow, PgConnection, QueryBuilder};
+use time::format_description::well_known::Rfc3339;
+use utoipa::{IntoParams, ToSchema};
 
 use crate::Error;
 
-#[derive(Debug, Clone, FromRow, Serialize)]
+use super::attachment::AttachmentPolicy;
+
+/// The number of addresses returned per page if only a cursor is given.
+pub const DEFAULT_PAGE_SIZE: i64 = 50;
+/// The maximum number of addresses returned per page.
+pub const MAX_PAGE_SIZE: i64 = 200;
+/// The maximum number of addresses created or modified by a single batch.
+pub const MAX_BATCH_SIZE: usize = 100;
+/// The time during which a deleted address can be restored.
+pub const DEFAULT_RESTORE_PERIOD: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema)]
 pub struct Address {
     pub id: i32,
     pub address: String,
     pub description: Option<String>,
     pub enabled: bool,
     pub domain_id: i32,
+    /// The owner of the address, which is `None` for addresses of deleted accounts.
+    #[serde(skip)]
+    pub user_id: Option<i32>,
+    pub attachment_policy: AttachmentPolicy,
+    pub attachment_size_limit: Option<i64>,
+    pub remove_trackers: bool,
+    pub spam_tag_threshold: Option<f64>,
+    pub spam_quarantine_threshold: Option<f64>,
+    /// The URL received mail is posted to as JSON instead of being forwarded by e-mail.
+    pub destination_url: Option<String>,
+    /// The secret used to sign the requests sent to `destination_url`, which is only returned
+    /// when the destination is set.
+    #[serde(skip)]
+    pub destination_secret: Option<String>,
+    /// The mailbox mail is forwarded to instead of the e-mail address of the owner.
+    pub forward_to: Option<String>,
     pub created_at: time::OffsetDateTime,
     pub updated_at: time::OffsetDateTime,
+    /// When the address was deleted. Deleted addresses are kept so that they are never issued
+    /// again, and can be restored for a while.
+    pub deleted_at: Option<time::OffsetDateTime>,
+}
+
+/// An address along with the secret used to sign the requests sent to its HTTP destination.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct AddressDestination {
+    #[serde(flatten)]
+    pub address: Address,
+    pub destination_secret: Option<String>,
+}
+
+impl AddressDestination {
+    pub fn new(mut address: Address) -> Self {
+        AddressDestination {
+            destination_secret: address.destination_secret.take(),
+            address,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -27,33 +79,298 @@ pub struct CreateAddress {
     pub user_id: i32,
 }
 
+/// The changes to an address. Fields that are left out are not changed.
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct UpdateAddress {
+    pub description: Option<String>,
+    pub enabled: Option<bool>,
+    pub attachment_policy: Option<AttachmentPolicy>,
+    /// Set to `null` to remove the limit.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[schema(value_type = Option<i64>)]
+    pub attachment_size_limit: Option<Option<i64>>,
+    pub remove_trackers: Option<bool>,
+    /// Set to `null` to use the default threshold.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[schema(value_type = Option<f64>)]
+    pub spam_tag_threshold: Option<Option<f64>>,
+    /// Set to `null` to use the default threshold.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[schema(value_type = Option<f64>)]
+    pub spam_quarantine_threshold: Option<Option<f64>>,
+}
+
+/// Deserializes a field that is present into `Some`, so that a field set to `null` can be told
+/// apart from one that is left out.
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+/// The field addresses are sorted by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressSort {
+    #[default]
+    CreatedAt,
+    UpdatedAt,
+    Address,
+}
+
+impl AddressSort {
+    fn column(&self) -> &'static str {
+        match self {
+            AddressSort::CreatedAt => "created_at",
+            AddressSort::UpdatedAt => "updated_at",
+            AddressSort::Address => "address",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// The filters, sorting and page of an address listing.
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListAddresses {
+    /// Only return enabled or disabled addresses.
+    pub enabled: Option<bool>,
+    /// Only return addresses on this domain.
+    pub domain_id: Option<i32>,
+    /// Only return addresses with this label.
+    pub label_id: Option<i32>,
+    /// Return deleted addresses instead of active ones.
+    #[serde(default)]
+    pub deleted: bool,
+    /// Only return addresses created at or after this time.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub created_after: Option<time::OffsetDateTime>,
+    /// Only return addresses created before this time.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub created_before: Option<time::OffsetDateTime>,
+    /// Only return addresses whose address or description contains this text.
+    pub q: Option<String>,
+    #[serde(default)]
+    #[param(inline)]
+    pub sort: AddressSort,
+    #[serde(default)]
+    #[param(inline)]
+    pub order: SortOrder,
+    /// The maximum number of addresses to return, up to 200. All addresses are returned as a
+    /// plain array unless a `limit` or `cursor` is given.
+    pub limit: Option<i64>,
+    /// The `next_cursor` of the previous page.
+    pub cursor: Option<String>,
+}
+
+impl ListAddresses {
+    /// Returns the number of addresses on a page, clamped to [`MAX_PAGE_SIZE`], or `None` if all
+    /// addresses are listed.
+    pub fn page_limit(&self) -> Option<i64> {
+        (self.limit.is_some() || self.cursor.is_some()).then(|| {
+            self.limit
+                .unwrap_or(DEFAULT_PAGE_SIZE)
+                .clamp(1, MAX_PAGE_SIZE)
+        })
+    }
+}
+
+/// The addresses of a listing, which are only paginated when asked for.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum AddressListing {
+    All(Vec<Address>),
+    Page(AddressPage),
+}
+
+/// A page of addresses.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct AddressPage {
+    pub addresses: Vec<Address>,
+    /// The cursor of the next page, or `None` if this is the last page.
+    pub next_cursor: Option<String>,
+}
+
+/// The position after the last address of a page.
+///
+/// Cursors are only valid for the sort field and order they were created with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddressCursor {
+    sort: AddressSort,
+    order: SortOrder,
+    value: String,
+    id: i32,
+}
+
+impl AddressCursor {
+    fn after(addr: &Address, sort: AddressSort, order: SortOrder) -> Self {
+        let value = match sort {
+            AddressSort::CreatedAt => addr.created_at.format(&Rfc3339),
+            AddressSort::UpdatedAt => addr.updated_at.format(&Rfc3339),
+            AddressSort::Address => Ok(addr.address.clone()),
+        }
+        .expect("timestamps can be formatted as rfc3339");
+
+        AddressCursor {
+            sort,
+            order,
+            value,
+            id: addr.id,
+        }
+    }
+
+    /// Decodes `cursor`, returning `None` if it is malformed or was created for a different
+    /// sort field or order.
+    pub fn decode(cursor: &str, sort: AddressSort, order: SortOrder) -> Option<Self> {
+        let json = BASE64_URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        let cursor: AddressCursor = serde_json::from_slice(&json).ok()?;
+
+        // The value is cast to a timestamp by the query, which would fail on anything else.
+        if cursor.sort != AddressSort::Address {
+            time::OffsetDateTime::parse(&cursor.value, &Rfc3339).ok()?;
+        }
+
+        (cursor.sort == sort && cursor.order == order).then_some(cursor)
+    }
+
+    pub fn encode(&self) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).expect("cursor is serializable"))
+    }
+}
+
+/// Escapes the wildcards of a `LIKE` pattern in `text`.
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
 /// Returns the address with `address_id` that belongs to `user_id`.
 pub async fn get_user_address(
     user_id: i32,
     address_id: i32,
     db: &crate::Database,
 ) -> Result<Option<Address>, Error> {
-    let addr = sqlx::query_as("SELECT * FROM addresses WHERE user_id = $1 AND id = $2")
-        .bind(user_id)
-        .bind(address_id)
-        .fetch_optional(db)
-        .await?;
+    let addr = sqlx::query_as(
+        "SELECT * FROM addresses WHERE user_id = $1 AND id = $2 AND deleted_at IS NULL",
+    )
+    .bind(user_id)
+    .bind(address_id)
+    .fetch_optional(db)
+    .await?;
 
     Ok(addr)
 }
 
-/// Returns a list of all addresses belonging to `user_id`.
-pub async fn get_user_addresses(user_id: i32, db: &crate::Database) -> Result<Vec<Address>, Error> {
-    let addrs: Vec<Address> = sqlx::query_as("SELECT * FROM addresses WHERE user_id = $1")
-        .bind(user_id)
-        .fetch_all(db)
-        .await?;
+/// Returns a page of at most `limit` addresses belonging to `user_id` that match `query`,
+/// starting after `cursor`, or all of them if there is no `limit`.
+pub async fn list_user_addresses(
+    user_id: i32,
+    query: &ListAddresses,
+    cursor: Option<&AddressCursor>,
+    limit: Option<i64>,
+    db: &crate::Database,
+) -> Result<AddressPage, Error> {
+    let mut builder = QueryBuilder::new("SELECT * FROM addresses WHERE user_id = ");
+    builder.push_bind(user_id);
 
-    Ok(addrs)
+    if query.deleted {
+        builder.push(" AND deleted_at IS NOT NULL");
+    } else {
+        builder.push(" AND deleted_at IS NULL");
+    }
+
+    if let Some(enabled) = query.enabled {
+        builder.push(" AND enabled = ").push_bind(enabled);
+    }
+
+    if let Some(domain_id) = query.domain_id {
+        builder.push(" AND domain_id = ").push_bind(domain_id);
+    }
+
+    if let Some(label_id) = query.label_id {
+        builder
+            .push(" AND id IN (SELECT address_id FROM address_labels WHERE label_id = ")
+            .push_bind(label_id)
+            .push(")");
+    }
+
+    if let Some(created_after) = query.created_after {
+        builder.push(" AND created_at >= ").push_bind(created_after);
+    }
+
+    if let Some(created_before) = query.created_before {
+        builder.push(" AND created_at < ").push_bind(created_before);
+    }
+
+    // Both columns are covered by a trigram index, which also serves ILIKE.
+    if let Some(search) = query.q.as_deref().map(str::trim).filter(|q| !q.is_empty()) {
+        let pattern = format!("%{}%", escape_like(search));
+
+        builder
+            .push(" AND (address ILIKE ")
+            .push_bind(pattern.clone())
+            .push(" OR description ILIKE ")
+            .push_bind(pattern)
+            .push(")");
+    }
+
+    let column = query.sort.column();
+    let (comparison, direction) = match query.order {
+        SortOrder::Asc => (">", "ASC"),
+        SortOrder::Desc => ("<", "DESC"),
+    };
+
+    if let Some(cursor) = cursor {
+        builder
+            .push(format!(" AND ({column}, id) {comparison} ("))
+            .push_bind(cursor.value.clone());
+
+        if cursor.sort != AddressSort::Address {
+            builder.push("::timestamptz");
+        }
+
+        builder.push(", ").push_bind(cursor.id).push(")");
+    }
+
+    builder.push(format!(" ORDER BY {column} {direction}, id {direction}"));
+
+    // One more row than requested is fetched to find out whether there is a next page.
+    if let Some(limit) = limit {
+        builder.push(" LIMIT ").push_bind(limit + 1);
+    }
+
+    let mut addresses: Vec<Address> = builder.build_query_as().fetch_all(db).await?;
+
+    let next_cursor = if let Some(limit) = limit.filter(|&limit| addresses.len() as i64 > limit) {
+        addresses.truncate(limit as usize);
+        addresses
+            .last()
+            .map(|addr| AddressCursor::after(addr, query.sort, query.order).encode())
+    } else {
+        None
+    };
+
+    Ok(AddressPage {
+        addresses,
+        next_cursor,
+    })
 }
 
-/// Returns a list of all addresses belonging to `user_id`.
-pub async fn create_address(addr: CreateAddress, db: &crate::Database) -> Result<Address, Error> {
+/// Creates an address.
+pub async fn create_address(
+    addr: CreateAddress,
+    conn: &mut PgConnection,
+) -> Result<Address, Error> {
     let result = sqlx::query_as(
         r"
         INSERT INTO addresses (address, description, enabled, domain_id, user_id) VALUES (
@@ -66,24 +383,166 @@ pub async fn create_address(addr: CreateAddress, db: &crate::Database) -> Result
     .bind(addr.enabled)
     .bind(addr.domain_id)
     .bind(addr.user_id)
-    .fetch_one(db)
+    .fetch_one(conn)
     .await?;
 
     Ok(result)
 }
 
+/// Updates the address with `address_id` that belongs to `user_id` and returns the updated
+/// address, if any.
+///
+/// Fields that are `None` are left unchanged, while overrides that are `Some(None)` are cleared.
+pub async fn update_user_address(
+    user_id: i32,
+    address_id: i32,
+    update: UpdateAddress,
+    db: &crate::Database,
+) -> Result<Option<Address>, Error> {
+    let addr = sqlx::query_as(
+        r"
+        UPDATE addresses SET
+            description = COALESCE($3, description),
+            enabled = COALESCE($4, enabled),
+            attachment_policy = COALESCE($5, attachment_policy),
+            attachment_size_limit = CASE WHEN $6 THEN $7 ELSE attachment_size_limit END,
+            remove_trackers = COALESCE($8, remove_trackers),
+            spam_tag_threshold = CASE WHEN $9 THEN $10 ELSE spam_tag_threshold END,
+            spam_quarantine_threshold = CASE
+                WHEN $11 THEN $12
+                ELSE spam_quarantine_threshold
+            END,
+            updated_at = NOW()
+        WHERE user_id = $1 AND id = $2 AND deleted_at IS NULL
+        RETURNING *
+        ",
+    )
+    .bind(user_id)
+    .bind(address_id)
+    .bind(update.description)
+    .bind(update.enabled)
+    .bind(update.attachment_policy)
+    .bind(update.attachment_size_limit.is_some())
+    .bind(update.attachment_size_limit.flatten())
+    .bind(update.remove_trackers)
+    .bind(update.spam_tag_threshold.is_some())
+    .bind(update.spam_tag_threshold.flatten())
+    .bind(update.spam_quarantine_threshold.is_some())
+    .bind(update.spam_quarantine_threshold.flatten())
+    .fetch_optional(db)
+    .await?;
+
+    Ok(addr)
+}
+
+/// Returns the address matching the full e-mail address `recipient`, e.g. `abc@masked.rwx.im`.
+///
+/// Local parts are compared ignoring case, since senders do not preserve it reliably.
+pub async fn find_address(recipient: &str, db: &crate::Database) -> Result<Option<Address>, Error> {
+    let Some((local_part, domain)) = recipient.trim().rsplit_once('@') else {
+        return Ok(None);
+    };
+
+    let addr = sqlx::query_as(
+        r"
+        SELECT addresses.* FROM addresses
+        INNER JOIN domains ON domains.id = addresses.domain_id
+        WHERE LOWER(addresses.address) = LOWER($1) AND domains.name = $2
+        ",
+    )
+    .bind(local_part)
+    .bind(domain.to_ascii_lowercase())
+    .fetch_optional(db)
+    .await?;
+
+    Ok(addr)
+}
+
 /// Deletes the address with the given `address_id` belonging to `user_id` and returns the address
 /// that was deleted, if any.
+///
+/// The address is only marked as deleted, so that its local part is never issued again.
 pub async fn delete_user_address(
     user_id: i32,
     address_id: i32,
+    conn: &mut PgConnection,
+) -> Result<Option<Address>, Error> {
+    let addr = sqlx::query_as(
+        r"
+        UPDATE addresses SET deleted_at = NOW()
+        WHERE user_id = $1 AND id = $2 AND deleted_at IS NULL
+        RETURNING *
+        ",
+    )
+    .bind(user_id)
+    .bind(address_id)
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(addr)
+}
+
+/// Restores the address with `address_id` belonging to `user_id` if it was deleted less than
+/// `restore_period` ago.
+pub async fn restore_user_address(
+    user_id: i32,
+    address_id: i32,
+    restore_period: Duration,
+    db: &crate::Database,
+) -> Result<Option<Address>, Error> {
+    let addr = sqlx::query_as(
+        r"
+        UPDATE addresses SET
+            deleted_at = NULL,
+            updated_at = NOW()
+        WHERE user_id = $1 AND id = $2 AND deleted_at > NOW() - $3
+        RETURNING *
+        ",
+    )
+    .bind(user_id)
+    .bind(address_id)
+    .bind(restore_period)
+    .fetch_optional(db)
+    .await?;
+
+    Ok(addr)
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct SetDestination {
+    pub url: url::Url,
+}
+
+/// Posts mail received on the address with `address_id` to `url` instead of forwarding it by
+/// e-mail, or restores forwarding by e-mail if `url` is `None`.
+///
+/// A signing secret is generated the first time a URL is set and kept when the URL changes.
+pub async fn set_user_address_destination(
+    user_id: i32,
+    address_id: i32,
+    url: Option<&str>,
     db: &crate::Database,
 ) -> Result<Option<Address>, Error> {
-    let addr = sqlx::query_as("DELETE FROM addresses WHERE user_id = $1 AND id = $2 RETURNING *")
-        .bind(user_id)
-        .bind(address_id)
-        .fetch_optional(db)
-        .await?;
+    let secret = url.map(|_| Alphanumeric.sample_string(&mut rand::thread_rng(), 32));
+    let addr = sqlx::query_as(
+        r"
+        UPDATE addresses SET
+            destination_url = $3,
+            destination_secret = CASE
+                WHEN $3 IS NULL THEN NULL
+                ELSE COALESCE(destination_secret, $4)
+            END,
+            updated_at = NOW()
+        WHERE user_id = $1 AND id = $2 AND deleted_at IS NULL
+        RETURNING *
+        ",
+    )
+    .bind(user_id)
+    .bind(address_id)
+    .bind(url)
+    .bind(secret)
+    .fetch_optional(db)
+    .await?;
 
     Ok(addr)
 }
@@ -92,7 +551,7 @@ pub async fn delete_user_address(
 // FIXME: avoid TOFU
 pub async fn generate_domain_address(
     domain_id: i32,
-    db: &crate::Database,
+    conn: &mut PgConnection,
 ) -> Result<String, Error> {
     const MAX_ROUNDS: usize = 10;
 
@@ -103,13 +562,14 @@ pub async fn generate_domain_address(
             Alphanumeric.sample_string(&mut rng, length)
         };
 
-        // Check that the address doesn't exist
+        // Check that the address doesn't exist. Deleted addresses are included, so that mail
+        // meant for their previous owner is never delivered to someone else.
         match sqlx::query_as::<_, (i32,)>(
-            "SELECT id FROM addresses WHERE domain_id = $1 AND address = $2",
+            "SELECT id FROM addresses WHERE domain_id = $1 AND LOWER(address) = LOWER($2)",
         )
         .bind(domain_id)
         .bind(&addr)
-        .fetch_optional(db)
+        .fetch_optional(&mut *conn)
         .await?
         {
             Some(_) => {}
@@ -121,3 +581,340 @@ pub async fn generate_domain_address(
 
     Err(Error::NameCollisionLimit)
 }
+
+/// An operation on many addresses at once.
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum AddressBatch {
+    /// Creates `count` addresses on a domain.
+    Create {
+        domain_id: i32,
+        count: usize,
+        description: Option<String>,
+    },
+    Enable {
+        ids: Vec<i32>,
+    },
+    Disable {
+        ids: Vec<i32>,
+    },
+    Delete {
+        ids: Vec<i32>,
+    },
+    /// Forwards mail received on the addresses to `mailbox`, or to the e-mail address of the owner
+    /// if it is `null`. The mailbox must have been verified, see `/mailboxes`.
+    Move {
+        ids: Vec<i32>,
+        mailbox: Option<String>,
+    },
+}
+
+impl AddressBatch {
+    /// Returns the number of addresses the batch creates or modifies.
+    pub fn size(&self) -> usize {
+        match self {
+            AddressBatch::Create { count, .. } => *count,
+            AddressBatch::Enable { ids }
+            | AddressBatch::Disable { ids }
+            | AddressBatch::Delete { ids }
+            | AddressBatch::Move { ids, .. } => ids.len(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchStatus {
+    Ok,
+    NotFound,
+}
+
+/// The outcome of a batch for a single address.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BatchResult {
+    /// The id of the address, or the id of the created address.
+    pub id: i32,
+    pub status: BatchStatus,
+    /// The address after the operation was applied, or the deleted address.
+    pub address: Option<Address>,
+}
+
+impl BatchResult {
+    /// Returns the result for each of `ids` in order, given the addresses the operation applied to.
+    fn for_ids(ids: &[i32], addrs: Vec<Address>) -> Vec<Self> {
+        let addrs: HashMap<i32, Address> = addrs.into_iter().map(|addr| (addr.id, addr)).collect();
+
+        ids.iter()
+            .map(|&id| match addrs.get(&id) {
+                Some(addr) => BatchResult {
+                    id,
+                    status: BatchStatus::Ok,
+                    address: Some(addr.clone()),
+                },
+                None => BatchResult {
+                    id,
+                    status: BatchStatus::NotFound,
+                    address: None,
+                },
+            })
+            .collect()
+    }
+}
+
+/// Applies `batch` to the addresses of `user_id`.
+///
+/// Ids that do not belong to `user_id` are reported as not found. `conn` should be a transaction,
+/// so that any other failure rolls back the whole batch.
+pub async fn apply_user_batch(
+    user_id: i32,
+    batch: AddressBatch,
+    conn: &mut PgConnection,
+) -> Result<Vec<BatchResult>, Error> {
+    let results = match batch {
+        AddressBatch::Create {
+            domain_id,
+            count,
+            description,
+        } => {
+            let mut results = Vec::with_capacity(count);
+
+            for _ in 0..count {
+                let address = generate_domain_address(domain_id, &mut *conn).await?;
+                let addr = CreateAddress {
+                    address,
+                    description: description.clone(),
+                    enabled: true,
+                    domain_id,
+                    user_id,
+                };
+                let addr = create_address(addr, &mut *conn).await?;
+
+                results.push(BatchResult {
+                    id: addr.id,
+                    status: BatchStatus::Ok,
+                    address: Some(addr),
+                });
+            }
+
+            results
+        }
+        AddressBatch::Enable { ids } => {
+            let addrs = set_addresses_enabled(user_id, &ids, true, &mut *conn).await?;
+
+            BatchResult::for_ids(&ids, addrs)
+        }
+        AddressBatch::Disable { ids } => {
+            let addrs = set_addresses_enabled(user_id, &ids, false, &mut *conn).await?;
+
+            BatchResult::for_ids(&ids, addrs)
+        }
+        AddressBatch::Delete { ids } => {
+            let addrs = sqlx::query_as(
+                r"
+                UPDATE addresses SET deleted_at = NOW()
+                WHERE user_id = $1 AND id = ANY($2) AND deleted_at IS NULL
+                RETURNING *
+                ",
+            )
+            .bind(user_id)
+            .bind(&ids)
+            .fetch_all(&mut *conn)
+            .await?;
+
+            BatchResult::for_ids(&ids, addrs)
+        }
+        AddressBatch::Move { ids, mailbox } => {
+            let addrs = sqlx::query_as(
+                r"
+                UPDATE addresses SET
+                    forward_to = $3,
+                    updated_at = NOW()
+                WHERE user_id = $1 AND id = ANY($2) AND deleted_at IS NULL
+                RETURNING *
+                ",
+            )
+            .bind(user_id)
+            .bind(&ids)
+            .bind(mailbox)
+            .fetch_all(&mut *conn)
+            .await?;
+
+            BatchResult::for_ids(&ids, addrs)
+        }
+    };
+
+    Ok(results)
+}
+
+async fn set_addresses_enabled(
+    user_id: i32,
+    ids: &[i32],
+    enabled: bool,
+    conn: &mut PgConnection,
+) -> Result<Vec<Address>, Error> {
+    let addrs = sqlx::query_as(
+        r"
+        UPDATE addresses SET
+            enabled = $3,
+            updated_at = NOW()
+        WHERE user_id = $1 AND id = ANY($2) AND deleted_at IS NULL
+        RETURNING *
+        ",
+    )
+    .bind(user_id)
+    .bind(ids)
+    .bind(enabled)
+    .fetch_all(conn)
+    .await?;
+
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{create_test_address, create_test_user, test_database};
+
+    #[test]
+    fn tells_cleared_overrides_from_missing_ones() {
+        let update: UpdateAddress =
+            serde_json::from_str(r#"{"attachment_size_limit": null, "spam_tag_threshold": 3.5}"#)
+                .unwrap();
+
+        assert_eq!(update.attachment_size_limit, Some(None));
+        assert_eq!(update.spam_tag_threshold, Some(Some(3.5)));
+        assert_eq!(update.spam_quarantine_threshold, None);
+    }
+
+    fn list_query(json: &str) -> ListAddresses {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn clamps_the_page_size() {
+        assert_eq!(list_query("{}").page_limit(), None);
+        assert_eq!(
+            list_query(r#"{"cursor": "abc"}"#).page_limit(),
+            Some(DEFAULT_PAGE_SIZE)
+        );
+        assert_eq!(list_query(r#"{"limit": 10}"#).page_limit(), Some(10));
+        assert_eq!(list_query(r#"{"limit": 0}"#).page_limit(), Some(1));
+        assert_eq!(
+            list_query(r#"{"limit": 100000}"#).page_limit(),
+            Some(MAX_PAGE_SIZE)
+        );
+    }
+
+    #[test]
+    fn rejects_stale_and_invalid_cursors() {
+        let cursor = AddressCursor {
+            sort: AddressSort::CreatedAt,
+            order: SortOrder::Desc,
+            value: "2024-05-01T12:00:00Z".to_string(),
+            id: 1,
+        }
+        .encode();
+
+        assert!(AddressCursor::decode(&cursor, AddressSort::CreatedAt, SortOrder::Desc).is_some());
+        assert!(AddressCursor::decode(&cursor, AddressSort::CreatedAt, SortOrder::Asc).is_none());
+        assert!(AddressCursor::decode(&cursor, AddressSort::Address, SortOrder::Desc).is_none());
+        assert!(
+            AddressCursor::decode("not a cursor", AddressSort::CreatedAt, SortOrder::Desc)
+                .is_none()
+        );
+
+        let cursor = AddressCursor {
+            sort: AddressSort::UpdatedAt,
+            order: SortOrder::Asc,
+            value: "yesterday".to_string(),
+            id: 1,
+        }
+        .encode();
+
+        assert!(AddressCursor::decode(&cursor, AddressSort::UpdatedAt, SortOrder::Asc).is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a database at DATABASE_URL"]
+    async fn restores_addresses_within_the_restore_period() {
+        let db = test_database().await;
+        let user_id = create_test_user(&db).await;
+        let other_user_id = create_test_user(&db).await;
+        let address = create_test_address(&db, user_id).await;
+        let delete = |deleted_ago: &'static str| {
+            sqlx::query("UPDATE addresses SET deleted_at = NOW() - $2::interval WHERE id = $1")
+                .bind(address.id)
+                .bind(deleted_ago)
+                .execute(&db)
+        };
+
+        delete("1 day").await.unwrap();
+
+        assert!(
+            restore_user_address(other_user_id, address.id, DEFAULT_RESTORE_PERIOD, &db)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        let restored = restore_user_address(user_id, address.id, DEFAULT_RESTORE_PERIOD, &db)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(restored.deleted_at, None);
+
+        delete("31 days").await.unwrap();
+
+        assert!(
+            restore_user_address(user_id, address.id, DEFAULT_RESTORE_PERIOD, &db)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a database at DATABASE_URL"]
+    async fn pages_across_ties_on_the_sort_key() {
+        let db = test_database().await;
+        let user_id = create_test_user(&db).await;
+
+        for _ in 0..5 {
+            create_test_address(&db, user_id).await;
+        }
+
+        sqlx::query("UPDATE addresses SET created_at = '2024-05-01T12:00:00Z' WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let query = list_query(r#"{"limit": 2}"#);
+        let mut cursor = None;
+        let mut ids = Vec::new();
+
+        loop {
+            let page = list_user_addresses(user_id, &query, cursor.as_ref(), Some(2), &db)
+                .await
+                .unwrap();
+
+            assert!(page.addresses.len() <= 2);
+            ids.extend(page.addresses.iter().map(|addr| addr.id));
+
+            let Some(next_cursor) = page.next_cursor else {
+                break;
+            };
+
+            cursor = AddressCursor::decode(&next_cursor, query.sort, query.order);
+            assert!(cursor.is_some());
+        }
+
+        let mut expected = ids.clone();
+        expected.sort_unstable_by(|a, b| b.cmp(a));
+        expected.dedup();
+
+        assert_eq!(ids.len(), 5);
+        assert_eq!(ids, expected);
+    }
+}
@@ -0,0 +1,236 @@
+//! API tokens
+//!
+//! API tokens authenticate requests with an `Authorization: Bearer <token>` header instead of a
+//! session cookie, e.g. from the command line client. They are issued by the device authorization
+//! flow and only stored hashed, so the token itself is shown once.
+
+use std::time::Duration;
+
+use axum::http::Method;
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{
+    postgres::{PgHasArrayType, PgTypeInfo},
+    FromRow, PgConnection,
+};
+use time::OffsetDateTime;
+use utoipa::ToSchema;
+
+use crate::Error;
+
+/// The prefix of API tokens, which makes leaked tokens easy to recognize.
+const TOKEN_PREFIX: &str = "mm_";
+/// The length of API tokens without the prefix.
+const TOKEN_LENGTH: usize = 40;
+
+/// The access granted by an API token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+pub enum ApiTokenScope {
+    /// Read the account, e.g. list addresses.
+    Read,
+    /// Read and change the account, e.g. create and delete addresses.
+    Write,
+}
+
+impl PgHasArrayType for ApiTokenScope {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_varchar")
+    }
+}
+
+impl ApiTokenScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiTokenScope::Read => "read",
+            ApiTokenScope::Write => "write",
+        }
+    }
+
+    /// Parses a space-separated OAuth `scope` parameter, returning `None` for unknown scopes.
+    pub fn parse_all(scope: &str) -> Option<Vec<ApiTokenScope>> {
+        let mut scopes = Vec::new();
+
+        for scope in scope.split_whitespace() {
+            let scope = match scope {
+                "read" => ApiTokenScope::Read,
+                "write" => ApiTokenScope::Write,
+                _ => return None,
+            };
+
+            if !scopes.contains(&scope) {
+                scopes.push(scope);
+            }
+        }
+
+        Some(scopes)
+    }
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema)]
+pub struct ApiToken {
+    pub id: i32,
+    #[serde(skip)]
+    pub user_id: i32,
+    /// The name of the token, describing the client it was issued to.
+    pub name: String,
+    pub scopes: Vec<ApiTokenScope>,
+    pub created_at: OffsetDateTime,
+    pub last_used_at: Option<OffsetDateTime>,
+    pub expires_at: Option<OffsetDateTime>,
+}
+
+impl ApiToken {
+    /// Returns whether the token may be used for a request with `method`.
+    ///
+    /// Safe requests need the `read` or `write` scope, since changing the account implies being
+    /// able to see it. All other requests need the `write` scope.
+    pub fn allows(&self, method: &Method) -> bool {
+        self.scopes.contains(&ApiTokenScope::Write)
+            || (method.is_safe() && self.scopes.contains(&ApiTokenScope::Read))
+    }
+}
+
+fn hash_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+/// Issues a new API token for `user_id` and returns it along with the secret token.
+pub async fn create_api_token(
+    user_id: i32,
+    name: &str,
+    scopes: Vec<ApiTokenScope>,
+    lifetime: Option<Duration>,
+    conn: &mut PgConnection,
+) -> Result<(ApiToken, String), Error> {
+    let token = format!(
+        "{TOKEN_PREFIX}{}",
+        Alphanumeric.sample_string(&mut rand::thread_rng(), TOKEN_LENGTH)
+    );
+    let expires_at = lifetime.map(|lifetime| OffsetDateTime::now_utc() + lifetime);
+
+    let api_token = sqlx::query_as(
+        r"
+        INSERT INTO api_tokens (user_id, name, token_hash, scopes, expires_at)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING *
+        ",
+    )
+    .bind(user_id)
+    .bind(name)
+    .bind(hash_token(&token))
+    .bind(scopes)
+    .bind(expires_at)
+    .fetch_one(conn)
+    .await?;
+
+    Ok((api_token, token))
+}
+
+/// Returns the API token `token` if it is valid, and records that it was used.
+pub async fn find_api_token(token: &str, db: &crate::Database) -> Result<Option<ApiToken>, Error> {
+    let api_token = sqlx::query_as(
+        r"
+        UPDATE api_tokens SET last_used_at = NOW()
+        WHERE token_hash = $1 AND (expires_at IS NULL OR expires_at > NOW())
+        RETURNING *
+        ",
+    )
+    .bind(hash_token(token))
+    .fetch_optional(db)
+    .await?;
+
+    Ok(api_token)
+}
+
+/// Returns all API tokens of `user_id`, including expired ones.
+pub async fn get_user_api_tokens(
+    user_id: i32,
+    db: &crate::Database,
+) -> Result<Vec<ApiToken>, Error> {
+    let api_tokens =
+        sqlx::query_as("SELECT * FROM api_tokens WHERE user_id = $1 ORDER BY created_at DESC")
+            .bind(user_id)
+            .fetch_all(db)
+            .await?;
+
+    Ok(api_tokens)
+}
+
+/// Revokes the API token with `token_id` belonging to `user_id`.
+pub async fn delete_user_api_token(
+    user_id: i32,
+    token_id: i32,
+    db: &crate::Database,
+) -> Result<Option<ApiToken>, Error> {
+    let api_token =
+        sqlx::query_as("DELETE FROM api_tokens WHERE user_id = $1 AND id = $2 RETURNING *")
+            .bind(user_id)
+            .bind(token_id)
+            .fetch_optional(db)
+            .await?;
+
+    Ok(api_token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_token(scopes: Vec<ApiTokenScope>) -> ApiToken {
+        ApiToken {
+            id: 1,
+            user_id: 1,
+            name: "laptop".to_string(),
+            scopes,
+            created_at: OffsetDateTime::now_utc(),
+            last_used_at: None,
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn parses_scopes() {
+        assert_eq!(
+            ApiTokenScope::parse_all("read write read"),
+            Some(vec![ApiTokenScope::Read, ApiTokenScope::Write])
+        );
+        assert_eq!(
+            ApiTokenScope::parse_all("  write "),
+            Some(vec![ApiTokenScope::Write])
+        );
+        assert_eq!(ApiTokenScope::parse_all(""), Some(Vec::new()));
+        assert_eq!(ApiTokenScope::parse_all("read admin"), None);
+        assert_eq!(ApiTokenScope::parse_all("READ"), None);
+    }
+
+    #[test]
+    fn allows_reading_with_the_read_scope() {
+        let token = api_token(vec![ApiTokenScope::Read]);
+
+        assert!(token.allows(&Method::GET));
+        assert!(token.allows(&Method::HEAD));
+        assert!(!token.allows(&Method::POST));
+        assert!(!token.allows(&Method::DELETE));
+    }
+
+    #[test]
+    fn allows_reading_and_writing_with_the_write_scope() {
+        let token = api_token(vec![ApiTokenScope::Write]);
+
+        assert!(token.allows(&Method::GET));
+        assert!(token.allows(&Method::POST));
+        assert!(token.allows(&Method::PATCH));
+        assert!(token.allows(&Method::DELETE));
+    }
+
+    #[test]
+    fn allows_nothing_without_scopes() {
+        let token = api_token(Vec::new());
+
+        assert!(!token.allows(&Method::GET));
+        assert!(!token.allows(&Method::POST));
+    }
+}
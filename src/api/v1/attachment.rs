@@ -0,0 +1,447 @@
+use mail_parser::{MessagePart, MimeHeaders};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgConnection};
+use utoipa::ToSchema;
+
+use crate::Error;
+
+/// The size limit used by [`AttachmentPolicy::StripLarge`] when an address has no explicit limit.
+pub const DEFAULT_SIZE_LIMIT: i64 = 10 * 1024 * 1024;
+
+/// File extensions that are commonly used to deliver executables or macros.
+const DANGEROUS_EXTENSIONS: &[&str] = &[
+    "ade", "adp", "apk", "appx", "bat", "cab", "chm", "cmd", "com", "cpl", "dll", "dmg", "docm",
+    "dotm", "exe", "hta", "iso", "jar", "js", "jse", "lnk", "msc", "msi", "msp", "pif", "potm",
+    "ppam", "ppsm", "pptm", "ps1", "reg", "scr", "sct", "sldm", "vb", "vbe", "vbs", "vhd", "wsc",
+    "wsf", "wsh", "xlam", "xlsb", "xlsm", "xltm",
+];
+
+/// MIME types that are commonly used to deliver executables or macros.
+const DANGEROUS_CONTENT_TYPES: &[&str] = &[
+    "application/hta",
+    "application/java-archive",
+    "application/javascript",
+    "application/vnd.microsoft.portable-executable",
+    "application/vnd.ms-excel.sheet.macroenabled.12",
+    "application/vnd.ms-powerpoint.presentation.macroenabled.12",
+    "application/vnd.ms-word.document.macroenabled.12",
+    "application/x-dosexec",
+    "application/x-msdos-program",
+    "application/x-msdownload",
+    "application/x-ms-installer",
+    "application/x-sh",
+];
+
+/// Determines what happens to the attachments of mail received on an address.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+pub enum AttachmentPolicy {
+    /// Keep all attachments.
+    #[default]
+    Forward,
+    /// Strip attachments that are larger than the size limit of the address.
+    StripLarge,
+    /// Strip attachments that look like executables or documents with macros.
+    StripDangerous,
+}
+
+/// The reason an attachment was stripped from a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+pub enum StrippedReason {
+    /// The attachment exceeded the size limit.
+    TooLarge,
+    /// The attachment has a dangerous file type.
+    Dangerous,
+}
+
+impl AttachmentPolicy {
+    /// Returns the reason the given attachment should be stripped, or `None` if it should be kept.
+    pub fn evaluate(
+        &self,
+        attachment: &NewAttachment,
+        size_limit: Option<i64>,
+    ) -> Option<StrippedReason> {
+        match self {
+            Self::Forward => None,
+            Self::StripLarge => {
+                let limit = size_limit.unwrap_or(DEFAULT_SIZE_LIMIT);
+
+                (attachment.size() > limit).then_some(StrippedReason::TooLarge)
+            }
+            Self::StripDangerous => attachment
+                .is_dangerous()
+                .then_some(StrippedReason::Dangerous),
+        }
+    }
+}
+
+/// An attachment of a message.
+///
+/// Stripped attachments have no contents and are replaced with a notice when forwarded.
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema)]
+pub struct MessageAttachment {
+    pub id: i32,
+    pub message_id: i32,
+    #[serde(skip)]
+    pub attachment_id: Option<i32>,
+    pub filename: Option<String>,
+    pub content_type: String,
+    pub size: i64,
+    pub stripped_reason: Option<StrippedReason>,
+}
+
+impl MessageAttachment {
+    /// Returns the text of the notice part that replaces this attachment, if it was stripped.
+    pub fn notice(&self) -> Option<String> {
+        let reason = match self.stripped_reason? {
+            StrippedReason::TooLarge => "it exceeds the size limit for this address",
+            StrippedReason::Dangerous => "its file type may contain executable code",
+        };
+
+        Some(format!(
+            "The attachment \"{}\" ({}, {} bytes) was removed because {}.",
+            self.filename.as_deref().unwrap_or("unnamed"),
+            self.content_type,
+            self.size,
+            reason
+        ))
+    }
+}
+
+/// The contents of an attachment that can be downloaded.
+#[derive(Debug, Clone, FromRow)]
+pub struct AttachmentContents {
+    pub filename: Option<String>,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+impl AttachmentContents {
+    /// Returns the `Content-Disposition` header value that downloads the attachment under its
+    /// filename.
+    ///
+    /// Characters that would end the quoted filename or the header are replaced, since the
+    /// filename is chosen by the sender.
+    pub fn content_disposition(&self) -> String {
+        let filename = self
+            .filename
+            .as_deref()
+            .unwrap_or("attachment")
+            .replace(['"', '\\', '\r', '\n'], "_");
+
+        format!("attachment; filename=\"{filename}\"")
+    }
+}
+
+/// The contents of an attachment along with the secret its download links are signed with.
+#[derive(Debug, Clone, FromRow)]
+pub struct SignedAttachmentContents {
+    #[sqlx(flatten)]
+    pub contents: AttachmentContents,
+    pub destination_secret: Option<String>,
+}
+
+/// An attachment extracted from a parsed message that has not been stored yet.
+#[derive(Debug, Clone)]
+pub struct NewAttachment {
+    pub filename: Option<String>,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+impl NewAttachment {
+    /// Extracts the attachment from a MIME part.
+    pub fn from_part(part: &MessagePart<'_>) -> Self {
+        let content_type = part
+            .content_type()
+            .map(|ct| match ct.subtype() {
+                Some(subtype) => format!("{}/{}", ct.ctype(), subtype),
+                None => ct.ctype().to_string(),
+            })
+            .unwrap_or_else(|| "application/octet-stream".to_string())
+            .to_ascii_lowercase();
+
+        NewAttachment {
+            filename: part.attachment_name().map(str::to_string),
+            content_type,
+            data: part.contents().to_vec(),
+        }
+    }
+
+    /// Returns the size of the attachment contents, in bytes.
+    pub fn size(&self) -> i64 {
+        self.data.len() as i64
+    }
+
+    /// Returns `true` if the attachment looks like an executable or a document with macros.
+    pub fn is_dangerous(&self) -> bool {
+        let extension = self
+            .filename
+            .as_deref()
+            .and_then(|name| name.rsplit_once('.'))
+            .map(|(_, ext)| ext.to_ascii_lowercase());
+
+        DANGEROUS_CONTENT_TYPES.contains(&self.content_type.as_str())
+            || extension.is_some_and(|ext| DANGEROUS_EXTENSIONS.contains(&ext.as_str()))
+    }
+}
+
+/// Stores `attachment` as part of `message_id`, or records it as stripped if `stripped_reason` is
+/// set.
+///
+/// Attachment contents are deduplicated by their SHA-256 digest.
+pub async fn create_message_attachment(
+    message_id: i32,
+    attachment: NewAttachment,
+    stripped_reason: Option<StrippedReason>,
+    conn: &mut PgConnection,
+) -> Result<MessageAttachment, Error> {
+    let size = attachment.size();
+    let attachment_id = match stripped_reason {
+        Some(_) => None,
+        None => {
+            let digest = Sha256::digest(&attachment.data).to_vec();
+            let (id,): (i32,) = sqlx::query_as(
+                r"
+                INSERT INTO attachments (sha256, size, data) VALUES ($1, $2, $3)
+                ON CONFLICT (sha256) DO UPDATE SET sha256 = excluded.sha256
+                RETURNING id
+                ",
+            )
+            .bind(digest)
+            .bind(size)
+            .bind(attachment.data)
+            .fetch_one(&mut *conn)
+            .await?;
+
+            Some(id)
+        }
+    };
+
+    let result = sqlx::query_as(
+        r"
+        INSERT INTO message_attachments (
+            message_id, attachment_id, filename, content_type, size, stripped_reason
+        ) VALUES (
+            $1, $2, $3, $4, $5, $6
+        ) RETURNING *
+        ",
+    )
+    .bind(message_id)
+    .bind(attachment_id)
+    .bind(attachment.filename)
+    .bind(attachment.content_type)
+    .bind(size)
+    .bind(stripped_reason)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    Ok(result)
+}
+
+/// Returns the ids of the stored contents of the attachments of the messages with `message_ids`.
+pub async fn get_messages_attachment_ids(
+    message_ids: &[i32],
+    conn: &mut PgConnection,
+) -> Result<Vec<i32>, Error> {
+    let attachment_ids: Vec<(i32,)> = sqlx::query_as(
+        r"
+        SELECT DISTINCT attachment_id FROM message_attachments
+        WHERE message_id = ANY($1) AND attachment_id IS NOT NULL
+        ",
+    )
+    .bind(message_ids)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok(attachment_ids.into_iter().map(|(id,)| id).collect())
+}
+
+/// Deletes the stored contents with `attachment_ids` that are no longer referenced by any
+/// message.
+///
+/// Contents are shared between messages, so they have to be deleted separately once the last
+/// message referencing them is gone.
+pub async fn delete_orphaned_attachments(
+    attachment_ids: &[i32],
+    conn: &mut PgConnection,
+) -> Result<(), Error> {
+    sqlx::query(
+        r"
+        DELETE FROM attachments
+        WHERE id = ANY($1) AND NOT EXISTS (
+            SELECT 1 FROM message_attachments
+            WHERE message_attachments.attachment_id = attachments.id
+        )
+        ",
+    )
+    .bind(attachment_ids)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Returns the attachments of the message with `message_id`.
+pub async fn get_message_attachments(
+    message_id: i32,
+    db: &crate::Database,
+) -> Result<Vec<MessageAttachment>, Error> {
+    let attachments =
+        sqlx::query_as("SELECT * FROM message_attachments WHERE message_id = $1 ORDER BY id")
+            .bind(message_id)
+            .fetch_all(db)
+            .await?;
+
+    Ok(attachments)
+}
+
+/// Returns the stored contents of the attachment with `attachment_id`.
+pub async fn get_attachment_data(
+    attachment_id: i32,
+    db: &crate::Database,
+) -> Result<Option<Vec<u8>>, Error> {
+    let data: Option<(Vec<u8>,)> = sqlx::query_as("SELECT data FROM attachments WHERE id = $1")
+        .bind(attachment_id)
+        .fetch_optional(db)
+        .await?;
+
+    Ok(data.map(|(data,)| data))
+}
+
+/// Returns the contents of the attachment with `attachment_id` if it belongs to mail received by
+/// `user_id` and was not stripped.
+pub async fn get_user_attachment_contents(
+    user_id: i32,
+    attachment_id: i32,
+    db: &crate::Database,
+) -> Result<Option<AttachmentContents>, Error> {
+    let contents = sqlx::query_as(
+        r"
+        SELECT message_attachments.filename, message_attachments.content_type, attachments.data
+        FROM message_attachments
+        INNER JOIN attachments ON attachments.id = message_attachments.attachment_id
+        INNER JOIN messages ON messages.id = message_attachments.message_id
+        INNER JOIN addresses ON addresses.id = messages.address_id
+        WHERE addresses.user_id = $1 AND message_attachments.id = $2
+        ",
+    )
+    .bind(user_id)
+    .bind(attachment_id)
+    .fetch_optional(db)
+    .await?;
+
+    Ok(contents)
+}
+
+/// Returns the stored contents of the attachment with `attachment_id` for a signed download link,
+/// regardless of its owner.
+pub async fn get_signed_attachment_contents(
+    attachment_id: i32,
+    db: &crate::Database,
+) -> Result<Option<SignedAttachmentContents>, Error> {
+    let contents = sqlx::query_as(
+        r"
+        SELECT
+            message_attachments.filename,
+            message_attachments.content_type,
+            attachments.data,
+            addresses.destination_secret
+        FROM message_attachments
+        INNER JOIN attachments ON attachments.id = message_attachments.attachment_id
+        INNER JOIN messages ON messages.id = message_attachments.message_id
+        INNER JOIN addresses ON addresses.id = messages.address_id
+        WHERE message_attachments.id = $1
+        ",
+    )
+    .bind(attachment_id)
+    .fetch_optional(db)
+    .await?;
+
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attachment(filename: Option<&str>, content_type: &str, size: usize) -> NewAttachment {
+        NewAttachment {
+            filename: filename.map(str::to_string),
+            content_type: content_type.to_string(),
+            data: vec![0; size],
+        }
+    }
+
+    #[test]
+    fn detects_dangerous_attachments() {
+        assert!(attachment(Some("invoice.EXE"), "application/octet-stream", 1).is_dangerous());
+        assert!(attachment(Some("report.docm"), "application/octet-stream", 1).is_dangerous());
+        assert!(attachment(None, "application/x-msdownload", 1).is_dangerous());
+        assert!(attachment(Some("setup"), "application/x-sh", 1).is_dangerous());
+
+        assert!(!attachment(Some("invoice.pdf"), "application/pdf", 1).is_dangerous());
+        assert!(!attachment(Some("exe"), "text/plain", 1).is_dangerous());
+        assert!(!attachment(None, "image/png", 1).is_dangerous());
+    }
+
+    #[test]
+    fn evaluates_policies() {
+        let large = attachment(Some("photo.jpg"), "image/jpeg", 2048);
+        let dangerous = attachment(Some("run.bat"), "text/plain", 16);
+
+        assert_eq!(AttachmentPolicy::Forward.evaluate(&large, Some(1024)), None);
+        assert_eq!(AttachmentPolicy::Forward.evaluate(&dangerous, None), None);
+
+        assert_eq!(
+            AttachmentPolicy::StripLarge.evaluate(&large, Some(1024)),
+            Some(StrippedReason::TooLarge)
+        );
+        assert_eq!(
+            AttachmentPolicy::StripLarge.evaluate(&large, Some(2048)),
+            None
+        );
+        assert_eq!(AttachmentPolicy::StripLarge.evaluate(&large, None), None);
+        assert_eq!(
+            AttachmentPolicy::StripLarge.evaluate(&dangerous, Some(1024)),
+            None
+        );
+
+        assert_eq!(
+            AttachmentPolicy::StripDangerous.evaluate(&dangerous, None),
+            Some(StrippedReason::Dangerous)
+        );
+        assert_eq!(
+            AttachmentPolicy::StripDangerous.evaluate(&large, Some(1024)),
+            None
+        );
+    }
+
+    #[test]
+    fn sanitizes_download_filenames() {
+        let contents = |filename: Option<&str>| AttachmentContents {
+            filename: filename.map(str::to_string),
+            content_type: "text/plain".to_string(),
+            data: Vec::new(),
+        };
+
+        assert_eq!(
+            contents(Some("notes.txt")).content_disposition(),
+            r#"attachment; filename="notes.txt""#
+        );
+        assert_eq!(
+            contents(Some("a\"b\\c\r\nSet-Cookie: x")).content_disposition(),
+            r#"attachment; filename="a_b_c__Set-Cookie: x""#
+        );
+        assert_eq!(
+            contents(None).content_disposition(),
+            r#"attachment; filename="attachment""#
+        );
+    }
+}
@@ -0,0 +1,334 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use crate::Error;
+
+use super::address::Address;
+
+/// A user-defined label for grouping addresses, e.g. `shopping` or `newsletters`.
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema)]
+pub struct Label {
+    pub id: i32,
+    pub name: String,
+    pub created_at: time::OffsetDateTime,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct CreateLabel {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct SetLabelMailbox {
+    /// The mailbox mail received on the addresses with the label is forwarded to.
+    pub mailbox: String,
+}
+
+/// Returns all labels of `user_id`, ordered by name.
+pub async fn get_user_labels(user_id: i32, db: &crate::Database) -> Result<Vec<Label>, Error> {
+    let labels = sqlx::query_as("SELECT * FROM labels WHERE user_id = $1 ORDER BY name")
+        .bind(user_id)
+        .fetch_all(db)
+        .await?;
+
+    Ok(labels)
+}
+
+/// Returns the label with `label_id` if it belongs to `user_id`.
+pub async fn get_user_label(
+    user_id: i32,
+    label_id: i32,
+    db: &crate::Database,
+) -> Result<Option<Label>, Error> {
+    let label = sqlx::query_as("SELECT * FROM labels WHERE user_id = $1 AND id = $2")
+        .bind(user_id)
+        .bind(label_id)
+        .fetch_optional(db)
+        .await?;
+
+    Ok(label)
+}
+
+/// Creates a label for `user_id`, or returns `None` if the user already has a label with the same
+/// name.
+pub async fn create_user_label(
+    user_id: i32,
+    label: CreateLabel,
+    db: &crate::Database,
+) -> Result<Option<Label>, Error> {
+    let label = sqlx::query_as(
+        r"
+        INSERT INTO labels (user_id, name) VALUES ($1, $2)
+        ON CONFLICT (user_id, name) DO NOTHING
+        RETURNING *
+        ",
+    )
+    .bind(user_id)
+    .bind(label.name)
+    .fetch_optional(db)
+    .await?;
+
+    Ok(label)
+}
+
+/// Deletes the label with `label_id` belonging to `user_id`. The labeled addresses are kept.
+pub async fn delete_user_label(
+    user_id: i32,
+    label_id: i32,
+    db: &crate::Database,
+) -> Result<Option<Label>, Error> {
+    let label = sqlx::query_as("DELETE FROM labels WHERE user_id = $1 AND id = $2 RETURNING *")
+        .bind(user_id)
+        .bind(label_id)
+        .fetch_optional(db)
+        .await?;
+
+    Ok(label)
+}
+
+/// Returns the labels of the address with `address_id` belonging to `user_id`.
+pub async fn get_user_address_labels(
+    user_id: i32,
+    address_id: i32,
+    db: &crate::Database,
+) -> Result<Vec<Label>, Error> {
+    let labels = sqlx::query_as(
+        r"
+        SELECT labels.* FROM labels
+        INNER JOIN address_labels ON address_labels.label_id = labels.id
+        WHERE labels.user_id = $1 AND address_labels.address_id = $2
+        ORDER BY labels.name
+        ",
+    )
+    .bind(user_id)
+    .bind(address_id)
+    .fetch_all(db)
+    .await?;
+
+    Ok(labels)
+}
+
+/// Attaches the label with `label_id` to the address with `address_id`.
+///
+/// Returns `false` if either does not belong to `user_id`. Attaching a label twice is a no-op.
+pub async fn add_user_address_label(
+    user_id: i32,
+    address_id: i32,
+    label_id: i32,
+    db: &crate::Database,
+) -> Result<bool, Error> {
+    let (found,): (bool,) = sqlx::query_as(
+        r"
+        WITH target AS (
+            SELECT addresses.id AS address_id, labels.id AS label_id
+            FROM addresses, labels
+            WHERE addresses.user_id = $1 AND addresses.id = $2 AND addresses.deleted_at IS NULL
+                AND labels.user_id = $1 AND labels.id = $3
+        ), inserted AS (
+            INSERT INTO address_labels (address_id, label_id)
+            SELECT address_id, label_id FROM target
+            ON CONFLICT DO NOTHING
+        )
+        SELECT EXISTS (SELECT 1 FROM target)
+        ",
+    )
+    .bind(user_id)
+    .bind(address_id)
+    .bind(label_id)
+    .fetch_one(db)
+    .await?;
+
+    Ok(found)
+}
+
+/// Detaches the label with `label_id` from the address with `address_id`, returning whether the
+/// address had the label.
+pub async fn remove_user_address_label(
+    user_id: i32,
+    address_id: i32,
+    label_id: i32,
+    db: &crate::Database,
+) -> Result<bool, Error> {
+    let result = sqlx::query(
+        r"
+        DELETE FROM address_labels
+        USING labels
+        WHERE address_labels.label_id = labels.id
+            AND labels.user_id = $1
+            AND address_labels.address_id = $2
+            AND address_labels.label_id = $3
+        ",
+    )
+    .bind(user_id)
+    .bind(address_id)
+    .bind(label_id)
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Enables or disables all addresses of `user_id` with the label `label_id` and returns them.
+pub async fn set_label_addresses_enabled(
+    user_id: i32,
+    label_id: i32,
+    enabled: bool,
+    db: &crate::Database,
+) -> Result<Vec<Address>, Error> {
+    let addrs = sqlx::query_as(
+        r"
+        UPDATE addresses SET
+            enabled = $3,
+            updated_at = NOW()
+        FROM address_labels
+        WHERE address_labels.address_id = addresses.id
+            AND addresses.user_id = $1
+            AND address_labels.label_id = $2
+            AND addresses.deleted_at IS NULL
+        RETURNING addresses.*
+        ",
+    )
+    .bind(user_id)
+    .bind(label_id)
+    .bind(enabled)
+    .fetch_all(db)
+    .await?;
+
+    Ok(addrs)
+}
+
+/// Sets the mailbox that mail received on all addresses of `user_id` with the label `label_id` is
+/// forwarded to, and returns the addresses. `None` restores forwarding to the e-mail address of the owner.
+pub async fn set_label_addresses_mailbox(
+    user_id: i32,
+    label_id: i32,
+    mailbox: Option<&str>,
+    db: &crate::Database,
+) -> Result<Vec<Address>, Error> {
+    let addrs = sqlx::query_as(
+        r"
+        UPDATE addresses SET
+            forward_to = $3,
+            updated_at = NOW()
+        FROM address_labels
+        WHERE address_labels.address_id = addresses.id
+            AND addresses.user_id = $1
+            AND address_labels.label_id = $2
+            AND addresses.deleted_at IS NULL
+        RETURNING addresses.*
+        ",
+    )
+    .bind(user_id)
+    .bind(label_id)
+    .bind(mailbox)
+    .fetch_all(db)
+    .await?;
+
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{create_test_address, create_test_user, test_database};
+
+    /// Creates a label of `user_id` that is attached to an address of the user and to an address
+    /// of another user, and returns the label along with both addresses.
+    async fn label_shared_with_another_user(
+        db: &crate::Database,
+        user_id: i32,
+    ) -> (Label, Address, Address) {
+        let other_user_id = create_test_user(db).await;
+        let own = create_test_address(db, user_id).await;
+        let other = create_test_address(db, other_user_id).await;
+        let label = create_user_label(
+            user_id,
+            CreateLabel {
+                name: "shopping".to_string(),
+            },
+            db,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        for address_id in [own.id, other.id] {
+            sqlx::query("INSERT INTO address_labels (address_id, label_id) VALUES ($1, $2)")
+                .bind(address_id)
+                .bind(label.id)
+                .execute(db)
+                .await
+                .unwrap();
+        }
+
+        (label, own, other)
+    }
+
+    async fn get_address(address_id: i32, db: &crate::Database) -> Address {
+        sqlx::query_as("SELECT * FROM addresses WHERE id = $1")
+            .bind(address_id)
+            .fetch_one(db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a database at DATABASE_URL"]
+    async fn enables_and_disables_the_addresses_of_the_owner_only() {
+        let db = test_database().await;
+        let user_id = create_test_user(&db).await;
+        let (label, own, other) = label_shared_with_another_user(&db, user_id).await;
+
+        let disabled = set_label_addresses_enabled(user_id, label.id, false, &db)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            disabled.iter().map(|addr| addr.id).collect::<Vec<_>>(),
+            [own.id]
+        );
+        assert!(!get_address(own.id, &db).await.enabled);
+        assert!(get_address(other.id, &db).await.enabled);
+
+        sqlx::query("UPDATE addresses SET enabled = FALSE WHERE id = $1")
+            .bind(other.id)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let enabled = set_label_addresses_enabled(user_id, label.id, true, &db)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            enabled.iter().map(|addr| addr.id).collect::<Vec<_>>(),
+            [own.id]
+        );
+        assert!(get_address(own.id, &db).await.enabled);
+        assert!(!get_address(other.id, &db).await.enabled);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a database at DATABASE_URL"]
+    async fn sets_the_mailbox_of_the_addresses_of_the_owner_only() {
+        let db = test_database().await;
+        let user_id = create_test_user(&db).await;
+        let (label, own, other) = label_shared_with_another_user(&db, user_id).await;
+
+        let updated =
+            set_label_addresses_mailbox(user_id, label.id, Some("inbox@example.org"), &db)
+                .await
+                .unwrap();
+
+        assert_eq!(
+            updated.iter().map(|addr| addr.id).collect::<Vec<_>>(),
+            [own.id]
+        );
+        assert_eq!(
+            get_address(own.id, &db).await.forward_to.as_deref(),
+            Some("inbox@example.org")
+        );
+        assert_eq!(get_address(other.id, &db).await.forward_to, None);
+    }
+}
@@ -0,0 +1,170 @@
+//! Mailboxes
+//!
+//! Mail can be forwarded to mailboxes other than the account e-mail address, e.g. for all
+//! addresses with a label. A mailbox has to be verified through a confirmation link sent to it
+//! before it can be used, so that mail cannot be forwarded to mailboxes the user does not control.
+
+use std::time::Duration;
+
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::FromRow;
+use time::OffsetDateTime;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::Error;
+
+/// The length of the tokens sent in confirmation links.
+const TOKEN_LENGTH: usize = 40;
+/// The duration after which a confirmation link can no longer be used.
+const TOKEN_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema)]
+pub struct Mailbox {
+    pub id: i32,
+    pub email: String,
+    /// When the confirmation link sent to the mailbox was followed, if it was.
+    pub verified_at: Option<OffsetDateTime>,
+    pub created_at: OffsetDateTime,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct AddMailbox {
+    /// The e-mail address of the mailbox.
+    pub email: String,
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+pub struct VerifyMailbox {
+    /// The token from the confirmation link.
+    pub token: String,
+}
+
+fn hash_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+/// Returns all mailboxes of `user_id`.
+pub async fn get_user_mailboxes(user_id: i32, db: &crate::Database) -> Result<Vec<Mailbox>, Error> {
+    let mailboxes = sqlx::query_as("SELECT * FROM mailboxes WHERE user_id = $1 ORDER BY id")
+        .bind(user_id)
+        .fetch_all(db)
+        .await?;
+
+    Ok(mailboxes)
+}
+
+/// Adds `email` as an unverified mailbox of `user_id` and returns it along with the token for its
+/// confirmation link.
+///
+/// Adding a mailbox that has not been verified yet replaces the token of its previous
+/// confirmation link. Returns `None` if the user already verified the mailbox.
+pub async fn create_user_mailbox(
+    user_id: i32,
+    email: &str,
+    db: &crate::Database,
+) -> Result<Option<(Mailbox, String)>, Error> {
+    let token = Alphanumeric.sample_string(&mut rand::thread_rng(), TOKEN_LENGTH);
+
+    let mailbox = sqlx::query_as(
+        r"
+        INSERT INTO mailboxes (user_id, email, token_hash, token_expires_at)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (user_id, email) DO UPDATE SET
+            token_hash = EXCLUDED.token_hash,
+            token_expires_at = EXCLUDED.token_expires_at
+        WHERE mailboxes.verified_at IS NULL
+        RETURNING *
+        ",
+    )
+    .bind(user_id)
+    .bind(email)
+    .bind(hash_token(&token))
+    .bind(OffsetDateTime::now_utc() + TOKEN_LIFETIME)
+    .fetch_optional(db)
+    .await?;
+
+    Ok(mailbox.map(|mailbox| (mailbox, token)))
+}
+
+/// Marks the mailbox the confirmation link with `token` was sent to as verified and returns it,
+/// or `None` if the token is unknown or expired.
+pub async fn verify_mailbox(token: &str, db: &crate::Database) -> Result<Option<Mailbox>, Error> {
+    let mailbox = sqlx::query_as(
+        r"
+        UPDATE mailboxes SET
+            token_hash = NULL,
+            token_expires_at = NULL,
+            verified_at = NOW()
+        WHERE token_hash = $1 AND token_expires_at > NOW()
+        RETURNING *
+        ",
+    )
+    .bind(hash_token(token))
+    .fetch_optional(db)
+    .await?;
+
+    Ok(mailbox)
+}
+
+/// Returns whether `user_id` may have mail forwarded to `email`, which is the case for verified
+/// mailboxes and the account e-mail address.
+pub async fn is_verified_user_mailbox(
+    user_id: i32,
+    email: &str,
+    db: &crate::Database,
+) -> Result<bool, Error> {
+    let (verified,): (bool,) = sqlx::query_as(
+        r"
+        SELECT EXISTS (
+            SELECT 1 FROM mailboxes
+            WHERE user_id = $1 AND email = $2 AND verified_at IS NOT NULL
+        ) OR EXISTS (
+            SELECT 1 FROM users WHERE id = $1 AND email = $2
+        )
+        ",
+    )
+    .bind(user_id)
+    .bind(email)
+    .fetch_one(db)
+    .await?;
+
+    Ok(verified)
+}
+
+/// Deletes the mailbox with `mailbox_id` belonging to `user_id` and restores forwarding to the
+/// account e-mail address for the addresses that were forwarded to it.
+pub async fn delete_user_mailbox(
+    user_id: i32,
+    mailbox_id: i32,
+    db: &crate::Database,
+) -> Result<Option<Mailbox>, Error> {
+    let mut tx = db.begin().await?;
+
+    let mailbox: Option<Mailbox> =
+        sqlx::query_as("DELETE FROM mailboxes WHERE user_id = $1 AND id = $2 RETURNING *")
+            .bind(user_id)
+            .bind(mailbox_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+    if let Some(ref mailbox) = mailbox {
+        sqlx::query(
+            r"
+            UPDATE addresses SET
+                forward_to = NULL,
+                updated_at = NOW()
+            WHERE user_id = $1 AND forward_to = $2
+            ",
+        )
+        .bind(user_id)
+        .bind(&mailbox.email)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(mailbox)
+}
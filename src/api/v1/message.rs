@@ -0,0 +1,354 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, FromRow, PgConnection};
+use utoipa::ToSchema;
+
+use crate::{
+    spam::{self, SpamAction},
+    Error,
+};
+
+use super::{
+    address::Address,
+    attachment::{self, MessageAttachment, NewAttachment},
+    quarantine::{self, QuarantineReason},
+};
+
+/// The forwarding state of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+pub enum MessageStatus {
+    /// The message is queued for forwarding.
+    Pending,
+    /// The message was delivered to the relay.
+    Forwarded,
+    /// The message could not be delivered after the maximum number of attempts.
+    Failed,
+    /// The message is held back and will not be forwarded.
+    Quarantined,
+}
+
+/// Whether the owner of the address reported a message as spam.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+pub enum SpamVerdict {
+    Spam,
+    Ham,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct ReportMessage {
+    pub verdict: SpamVerdict,
+}
+
+/// A header of a received message, with its raw value unfolded.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct MessageHeader {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema)]
+pub struct Message {
+    pub id: i32,
+    pub address_id: i32,
+    pub message_id: Option<String>,
+    pub sender: Option<String>,
+    pub subject: Option<String>,
+    pub body_text: Option<String>,
+    pub body_html: Option<String>,
+    #[schema(value_type = Vec<MessageHeader>)]
+    pub headers: Json<Vec<MessageHeader>>,
+    pub status: MessageStatus,
+    pub attempts: i32,
+    pub forwarded_at: Option<time::OffsetDateTime>,
+    pub last_error: Option<String>,
+    pub spam_score: Option<f64>,
+    pub spam_verdict: Option<SpamVerdict>,
+    pub received_at: time::OffsetDateTime,
+}
+
+#[derive(Debug, Clone)]
+pub struct CreateMessage {
+    pub message_id: Option<String>,
+    pub sender: Option<String>,
+    pub subject: Option<String>,
+    pub body_text: Option<String>,
+    pub body_html: Option<String>,
+    pub headers: Vec<MessageHeader>,
+    pub attachments: Vec<NewAttachment>,
+    pub spam_score: Option<f64>,
+    /// Why the message is quarantined instead of being forwarded, if it is.
+    pub quarantine_reason: Option<QuarantineReason>,
+    pub quarantine_details: Option<String>,
+}
+
+impl CreateMessage {
+    /// Extracts the message headers, bodies and attachments from a parsed e-mail.
+    pub fn from_parsed(parsed: &mail_parser::Message<'_>) -> Self {
+        CreateMessage {
+            message_id: parsed.message_id().map(str::to_string),
+            sender: parsed
+                .from()
+                .and_then(|from| from.first())
+                .and_then(|addr| addr.address())
+                .map(str::to_string),
+            subject: parsed.subject().map(str::to_string),
+            body_text: parsed.body_text(0).map(|body| body.into_owned()),
+            body_html: parsed.body_html(0).map(|body| body.into_owned()),
+            headers: parsed
+                .headers_raw()
+                .map(|(name, value)| MessageHeader {
+                    name: name.to_string(),
+                    value: value.replace(['\r', '\n'], "").trim().to_string(),
+                })
+                .collect(),
+            attachments: parsed.attachments().map(NewAttachment::from_part).collect(),
+            spam_score: None,
+            quarantine_reason: None,
+            quarantine_details: None,
+        }
+    }
+
+    /// Marks the message to be quarantined instead of forwarded.
+    ///
+    /// A message that is already marked keeps the reason it was first quarantined for.
+    pub fn quarantine(&mut self, reason: QuarantineReason, details: Option<String>) {
+        if self.quarantine_reason.is_some() {
+            return;
+        }
+
+        self.quarantine_reason = Some(reason);
+        self.quarantine_details = details;
+    }
+
+    /// Records the spam score of the message and applies the resulting action.
+    pub fn apply_spam_score(&mut self, score: f64, action: SpamAction) {
+        self.spam_score = Some(score);
+
+        match action {
+            SpamAction::Forward => {}
+            SpamAction::Tag => {
+                self.subject = Some(format!(
+                    "{}{}",
+                    spam::SUBJECT_TAG,
+                    self.subject.as_deref().unwrap_or_default()
+                ));
+            }
+            SpamAction::Quarantine => self.quarantine(
+                QuarantineReason::Spam,
+                Some(format!("spam score {score:.1}")),
+            ),
+        }
+    }
+}
+
+/// Stores a message received on `address` along with its attachments, applying the attachment
+/// policy of the address.
+///
+/// `conn` should be a transaction, so that no message is stored without its attachments.
+pub async fn create_message(
+    address: &Address,
+    msg: CreateMessage,
+    conn: &mut PgConnection,
+) -> Result<(Message, Vec<MessageAttachment>), Error> {
+    let status = if msg.quarantine_reason.is_some() {
+        MessageStatus::Quarantined
+    } else {
+        MessageStatus::Pending
+    };
+
+    let message: Message = sqlx::query_as(
+        r"
+        INSERT INTO messages (
+            address_id, message_id, sender, subject, body_text, body_html, headers, status,
+            spam_score
+        ) VALUES (
+            $1, $2, $3, $4, $5, $6, $7, $8, $9
+        ) RETURNING *
+        ",
+    )
+    .bind(address.id)
+    .bind(msg.message_id)
+    .bind(msg.sender)
+    .bind(msg.subject)
+    .bind(msg.body_text)
+    .bind(msg.body_html)
+    .bind(Json(msg.headers))
+    .bind(status)
+    .bind(msg.spam_score)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    if let Some(reason) = msg.quarantine_reason {
+        quarantine::quarantine_message(message.id, reason, msg.quarantine_details, &mut *conn)
+            .await?;
+    }
+
+    let mut attachments = Vec::with_capacity(msg.attachments.len());
+
+    for new_attachment in msg.attachments {
+        let stripped_reason = address
+            .attachment_policy
+            .evaluate(&new_attachment, address.attachment_size_limit);
+
+        attachments.push(
+            attachment::create_message_attachment(
+                message.id,
+                new_attachment,
+                stripped_reason,
+                &mut *conn,
+            )
+            .await?,
+        );
+    }
+
+    Ok((message, attachments))
+}
+
+/// Returns the message with `message_id` if it was received on an address belonging to `user_id`.
+pub async fn get_user_message(
+    user_id: i32,
+    message_id: i32,
+    db: &crate::Database,
+) -> Result<Option<Message>, Error> {
+    let msg = sqlx::query_as(
+        r"
+        SELECT messages.* FROM messages
+        INNER JOIN addresses ON addresses.id = messages.address_id
+        WHERE addresses.user_id = $1 AND messages.id = $2
+        ",
+    )
+    .bind(user_id)
+    .bind(message_id)
+    .fetch_optional(db)
+    .await?;
+
+    Ok(msg)
+}
+
+/// Records the spam verdict of the message with `message_id` if it was received on an address
+/// belonging to `user_id` and has not been reported yet, and returns the updated message.
+///
+/// The check is part of the update, so that concurrent reports cannot both succeed.
+pub async fn set_spam_verdict(
+    user_id: i32,
+    message_id: i32,
+    verdict: SpamVerdict,
+    conn: &mut PgConnection,
+) -> Result<Option<Message>, Error> {
+    let msg = sqlx::query_as(
+        r"
+        UPDATE messages SET spam_verdict = $3
+        FROM addresses
+        WHERE addresses.id = messages.address_id
+            AND addresses.user_id = $1
+            AND messages.id = $2
+            AND messages.spam_verdict IS NULL
+        RETURNING messages.*
+        ",
+    )
+    .bind(user_id)
+    .bind(message_id)
+    .bind(verdict)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    Ok(msg)
+}
+
+/// Deletes all messages that were forwarded, or failed to be forwarded, more than `retention` ago
+/// along with the attachments no longer referenced by other messages and returns the number of
+/// deleted messages.
+///
+/// Quarantined messages are left to the retention of the quarantine.
+pub async fn purge_delivered(retention: Duration, db: &crate::Database) -> Result<u64, Error> {
+    let mut tx = db.begin().await?;
+
+    // Failed messages have no forwarding time, so they are kept for `retention` after they were
+    // received.
+    let message_ids: Vec<(i32,)> = sqlx::query_as(
+        r"
+        SELECT id FROM messages
+        WHERE status IN ('forwarded', 'failed')
+            AND COALESCE(forwarded_at, received_at) < NOW() - $1
+        ",
+    )
+    .bind(retention)
+    .fetch_all(&mut *tx)
+    .await?;
+    let message_ids: Vec<i32> = message_ids.into_iter().map(|(id,)| id).collect();
+    let attachment_ids = attachment::get_messages_attachment_ids(&message_ids, &mut tx).await?;
+
+    let result = sqlx::query("DELETE FROM messages WHERE id = ANY($1)")
+        .bind(&message_ids)
+        .execute(&mut *tx)
+        .await?;
+
+    attachment::delete_orphaned_attachments(&attachment_ids, &mut tx).await?;
+    tx.commit().await?;
+
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{create_test_address, create_test_user, test_database};
+
+    #[test]
+    fn keeps_the_first_quarantine_reason() {
+        let parsed = mail_parser::MessageParser::default()
+            .parse(b"From: sender@example.com\r\nSubject: Hello\r\n\r\nHi\r\n")
+            .unwrap();
+        let mut msg = CreateMessage::from_parsed(&parsed);
+
+        msg.apply_spam_score(12.0, SpamAction::Quarantine);
+        msg.quarantine(
+            QuarantineReason::DisabledAddress,
+            Some("address is disabled".to_string()),
+        );
+
+        assert_eq!(msg.quarantine_reason, Some(QuarantineReason::Spam));
+        assert_eq!(msg.quarantine_details.as_deref(), Some("spam score 12.0"));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a database at DATABASE_URL"]
+    async fn records_the_spam_verdict_once_and_for_the_owner_only() {
+        let db = test_database().await;
+        let user_id = create_test_user(&db).await;
+        let other_user_id = create_test_user(&db).await;
+        let address = create_test_address(&db, user_id).await;
+        let parsed = mail_parser::MessageParser::default()
+            .parse(b"From: sender@example.com\r\nSubject: Hello\r\n\r\nHi\r\n")
+            .unwrap();
+        let mut conn = db.acquire().await.unwrap();
+        let (msg, _) = create_message(&address, CreateMessage::from_parsed(&parsed), &mut conn)
+            .await
+            .unwrap();
+
+        assert!(
+            set_spam_verdict(other_user_id, msg.id, SpamVerdict::Spam, &mut conn)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        let reported = set_spam_verdict(user_id, msg.id, SpamVerdict::Spam, &mut conn)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(reported.spam_verdict, Some(SpamVerdict::Spam));
+        assert!(
+            set_spam_verdict(user_id, msg.id, SpamVerdict::Ham, &mut conn)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,111 @@
+//! Passkeys
+//!
+//! Users register passkeys to confirm sensitive operations with a recent strong authentication,
+//! without logging in at the provider again. The credentials are stored as serialized by
+//! webauthn-rs, since their signature counters are updated after each use.
+
+use serde::Serialize;
+use sqlx::{types::Json, FromRow};
+use utoipa::ToSchema;
+use webauthn_rs::prelude::{AuthenticationResult, Passkey, Uuid};
+
+use crate::Error;
+
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema)]
+pub struct UserPasskey {
+    pub id: i32,
+    /// The name the user gave the passkey, e.g. the device it is stored on.
+    pub name: String,
+    pub created_at: time::OffsetDateTime,
+    pub last_used_at: Option<time::OffsetDateTime>,
+    #[serde(skip)]
+    pub credential: Json<Passkey>,
+}
+
+/// Returns the user handle that passkeys of `user_id` are registered for.
+pub async fn get_webauthn_user_id(
+    user_id: i32,
+    db: &crate::Database,
+) -> Result<Option<Uuid>, Error> {
+    let webauthn_user_id: Option<(Uuid,)> =
+        sqlx::query_as("SELECT webauthn_user_id FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(db)
+            .await?;
+
+    Ok(webauthn_user_id.map(|(id,)| id))
+}
+
+/// Returns all passkeys of `user_id`.
+pub async fn get_user_passkeys(
+    user_id: i32,
+    db: &crate::Database,
+) -> Result<Vec<UserPasskey>, Error> {
+    let passkeys = sqlx::query_as("SELECT * FROM passkeys WHERE user_id = $1 ORDER BY created_at")
+        .bind(user_id)
+        .fetch_all(db)
+        .await?;
+
+    Ok(passkeys)
+}
+
+/// Stores the newly registered `passkey` of `user_id`.
+pub async fn create_user_passkey(
+    user_id: i32,
+    name: &str,
+    passkey: Passkey,
+    db: &crate::Database,
+) -> Result<UserPasskey, Error> {
+    let passkey = sqlx::query_as(
+        "INSERT INTO passkeys (user_id, name, credential) VALUES ($1, $2, $3) RETURNING *",
+    )
+    .bind(user_id)
+    .bind(name)
+    .bind(Json(passkey))
+    .fetch_one(db)
+    .await?;
+
+    Ok(passkey)
+}
+
+/// Deletes the passkey with `passkey_id` belonging to `user_id`.
+pub async fn delete_user_passkey(
+    user_id: i32,
+    passkey_id: i32,
+    db: &crate::Database,
+) -> Result<Option<UserPasskey>, Error> {
+    let passkey = sqlx::query_as("DELETE FROM passkeys WHERE user_id = $1 AND id = $2 RETURNING *")
+        .bind(user_id)
+        .bind(passkey_id)
+        .fetch_optional(db)
+        .await?;
+
+    Ok(passkey)
+}
+
+/// Records the use of the passkey of `user_id` that produced `result`, and returns whether the
+/// passkey belongs to the user.
+pub async fn record_user_passkey_use(
+    user_id: i32,
+    result: &AuthenticationResult,
+    db: &crate::Database,
+) -> Result<bool, Error> {
+    let passkeys = get_user_passkeys(user_id, db).await?;
+
+    let Some(mut passkey) = passkeys
+        .into_iter()
+        .find(|passkey| passkey.credential.cred_id() == result.cred_id())
+    else {
+        return Ok(false);
+    };
+
+    passkey.credential.update_credential(result);
+
+    sqlx::query("UPDATE passkeys SET credential = $2, last_used_at = NOW() WHERE id = $1")
+        .bind(passkey.id)
+        .bind(passkey.credential)
+        .execute(db)
+        .await?;
+
+    Ok(true)
+}
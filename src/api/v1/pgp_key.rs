@@ -0,0 +1,70 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use crate::Error;
+
+/// The OpenPGP public key that mail forwarded to a user is encrypted with.
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema)]
+pub struct PgpKey {
+    pub pgp_fingerprint: String,
+    pub pgp_public_key: String,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct UploadPgpKey {
+    /// The ASCII-armored public key.
+    pub public_key: String,
+}
+
+/// Returns the public key of `user_id`, if any.
+pub async fn get_user_pgp_key(user_id: i32, db: &crate::Database) -> Result<Option<PgpKey>, Error> {
+    let key = sqlx::query_as(
+        r"
+        SELECT pgp_fingerprint, pgp_public_key FROM users
+        WHERE id = $1 AND pgp_public_key IS NOT NULL
+        ",
+    )
+    .bind(user_id)
+    .fetch_optional(db)
+    .await?;
+
+    Ok(key)
+}
+
+/// Sets the public key of `user_id`, replacing any existing key.
+pub async fn set_user_pgp_key(
+    user_id: i32,
+    fingerprint: String,
+    public_key: String,
+    db: &crate::Database,
+) -> Result<PgpKey, Error> {
+    let key = sqlx::query_as(
+        r"
+        UPDATE users SET pgp_fingerprint = $2, pgp_public_key = $3 WHERE id = $1
+        RETURNING pgp_fingerprint, pgp_public_key
+        ",
+    )
+    .bind(user_id)
+    .bind(fingerprint)
+    .bind(public_key)
+    .fetch_one(db)
+    .await?;
+
+    Ok(key)
+}
+
+/// Removes the public key of `user_id` and returns the removed key, if any.
+pub async fn delete_user_pgp_key(
+    user_id: i32,
+    db: &crate::Database,
+) -> Result<Option<PgpKey>, Error> {
+    let key = get_user_pgp_key(user_id, db).await?;
+
+    sqlx::query("UPDATE users SET pgp_fingerprint = NULL, pgp_public_key = NULL WHERE id = $1")
+        .bind(user_id)
+        .execute(db)
+        .await?;
+
+    Ok(key)
+}
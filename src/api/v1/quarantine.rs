@@ -0,0 +1,240 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection};
+use utoipa::ToSchema;
+
+use crate::Error;
+
+use super::{
+    attachment::{self, MessageAttachment},
+    message::Message,
+};
+
+/// Why a message was quarantined instead of being forwarded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+pub enum QuarantineReason {
+    /// The spam score of the message reached the quarantine threshold of the address.
+    Spam,
+    /// The sending domain failed DMARC authentication.
+    AuthenticationFailure,
+    /// The address the message was sent to is disabled.
+    DisabledAddress,
+}
+
+/// A quarantined message, without its body.
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema)]
+pub struct QuarantinedMessage {
+    pub message_id: i32,
+    pub address_id: i32,
+    pub sender: Option<String>,
+    pub subject: Option<String>,
+    pub spam_score: Option<f64>,
+    pub reason: QuarantineReason,
+    pub details: Option<String>,
+    pub received_at: time::OffsetDateTime,
+    pub quarantined_at: time::OffsetDateTime,
+}
+
+/// A quarantined message along with its body and attachments, for review by its owner.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct QuarantinePreview {
+    pub message: Message,
+    pub attachments: Vec<MessageAttachment>,
+    pub reason: QuarantineReason,
+    pub details: Option<String>,
+    pub quarantined_at: time::OffsetDateTime,
+}
+
+#[derive(Debug, Clone, FromRow)]
+struct QuarantineEntry {
+    reason: QuarantineReason,
+    details: Option<String>,
+    quarantined_at: time::OffsetDateTime,
+}
+
+/// Places the message with `message_id` in quarantine.
+pub async fn quarantine_message(
+    message_id: i32,
+    reason: QuarantineReason,
+    details: Option<String>,
+    conn: &mut PgConnection,
+) -> Result<(), Error> {
+    sqlx::query("INSERT INTO quarantine (message_id, reason, details) VALUES ($1, $2, $3)")
+        .bind(message_id)
+        .bind(reason)
+        .bind(details)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Returns all quarantined messages received on addresses of `user_id`, newest first.
+pub async fn list_user_quarantine(
+    user_id: i32,
+    db: &crate::Database,
+) -> Result<Vec<QuarantinedMessage>, Error> {
+    let messages = sqlx::query_as(
+        r"
+        SELECT
+            messages.id AS message_id,
+            messages.address_id,
+            messages.sender,
+            messages.subject,
+            messages.spam_score,
+            quarantine.reason,
+            quarantine.details,
+            messages.received_at,
+            quarantine.quarantined_at
+        FROM quarantine
+        INNER JOIN messages ON messages.id = quarantine.message_id
+        INNER JOIN addresses ON addresses.id = messages.address_id
+        WHERE addresses.user_id = $1
+        ORDER BY quarantine.quarantined_at DESC
+        ",
+    )
+    .bind(user_id)
+    .fetch_all(db)
+    .await?;
+
+    Ok(messages)
+}
+
+/// Returns the quarantined message with `message_id` if it belongs to `user_id`.
+pub async fn get_user_quarantined_message(
+    user_id: i32,
+    message_id: i32,
+    db: &crate::Database,
+) -> Result<Option<QuarantinePreview>, Error> {
+    let Some(message) = super::message::get_user_message(user_id, message_id, db).await? else {
+        return Ok(None);
+    };
+
+    let entry: Option<QuarantineEntry> = sqlx::query_as(
+        "SELECT reason, details, quarantined_at FROM quarantine WHERE message_id = $1",
+    )
+    .bind(message_id)
+    .fetch_optional(db)
+    .await?;
+
+    let Some(entry) = entry else {
+        return Ok(None);
+    };
+
+    let attachments = super::attachment::get_message_attachments(message_id, db).await?;
+
+    Ok(Some(QuarantinePreview {
+        message,
+        attachments,
+        reason: entry.reason,
+        details: entry.details,
+        quarantined_at: entry.quarantined_at,
+    }))
+}
+
+/// Removes the message with `message_id` from quarantine and queues it for forwarding.
+///
+/// Returns the released message, or `None` if it is not quarantined or does not belong to
+/// `user_id`.
+pub async fn release_user_quarantined_message(
+    user_id: i32,
+    message_id: i32,
+    db: &crate::Database,
+) -> Result<Option<Message>, Error> {
+    let message = sqlx::query_as(
+        r"
+        WITH released AS (
+            DELETE FROM quarantine
+            USING messages, addresses
+            WHERE quarantine.message_id = messages.id
+                AND messages.address_id = addresses.id
+                AND addresses.user_id = $1
+                AND quarantine.message_id = $2
+            RETURNING quarantine.message_id
+        )
+        UPDATE messages SET
+            status = 'pending',
+            next_attempt_at = NOW()
+        FROM released
+        WHERE messages.id = released.message_id
+        RETURNING messages.*
+        ",
+    )
+    .bind(user_id)
+    .bind(message_id)
+    .fetch_optional(db)
+    .await?;
+
+    Ok(message)
+}
+
+/// Deletes the quarantined message with `message_id` and returns its id, or `None` if it is not
+/// quarantined or does not belong to `user_id`.
+///
+/// Attachments no longer referenced by other messages are deleted along with the message.
+pub async fn delete_user_quarantined_message(
+    user_id: i32,
+    message_id: i32,
+    db: &crate::Database,
+) -> Result<Option<i32>, Error> {
+    let mut tx = db.begin().await?;
+    let attachment_ids = attachment::get_messages_attachment_ids(&[message_id], &mut tx).await?;
+
+    let id: Option<(i32,)> = sqlx::query_as(
+        r"
+        DELETE FROM messages
+        USING quarantine, addresses
+        WHERE quarantine.message_id = messages.id
+            AND messages.address_id = addresses.id
+            AND addresses.user_id = $1
+            AND messages.id = $2
+        RETURNING messages.id
+        ",
+    )
+    .bind(user_id)
+    .bind(message_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if id.is_some() {
+        attachment::delete_orphaned_attachments(&attachment_ids, &mut tx).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(id.map(|(id,)| id))
+}
+
+/// Deletes all messages that have been quarantined for longer than `retention` along with the
+/// attachments no longer referenced by other messages and returns the number of deleted messages.
+pub async fn purge_expired(retention: Duration, db: &crate::Database) -> Result<u64, Error> {
+    let mut tx = db.begin().await?;
+
+    // `NOW()` is the start of the transaction, so all statements see the same expired messages.
+    let message_ids: Vec<(i32,)> =
+        sqlx::query_as("SELECT message_id FROM quarantine WHERE quarantined_at < NOW() - $1")
+            .bind(retention)
+            .fetch_all(&mut *tx)
+            .await?;
+    let message_ids: Vec<i32> = message_ids.into_iter().map(|(id,)| id).collect();
+    let attachment_ids = attachment::get_messages_attachment_ids(&message_ids, &mut tx).await?;
+
+    let result = sqlx::query(
+        r"
+        DELETE FROM messages
+        USING quarantine
+        WHERE quarantine.message_id = messages.id AND messages.id = ANY($1)
+        ",
+    )
+    .bind(&message_ids)
+    .execute(&mut *tx)
+    .await?;
+
+    attachment::delete_orphaned_attachments(&attachment_ids, &mut tx).await?;
+    tx.commit().await?;
+
+    Ok(result.rows_affected())
+}
@@ -0,0 +1,327 @@
+//! Login sessions
+//!
+//! The session store of tower-sessions keeps sessions in `tower_sessions.session` without a
+//! reference to the user, so the sessions each user logged in with are recorded in
+//! `user_sessions`. Sessions that expired or were deleted from the store are no longer listed.
+
+use serde::Serialize;
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use crate::Error;
+
+/// A session the user logged in with.
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema)]
+pub struct UserSession {
+    pub id: i32,
+    /// The user agent the user logged in with.
+    pub user_agent: Option<String>,
+    pub created_at: time::OffsetDateTime,
+    /// When the session expires unless it is used again.
+    pub expires_at: time::OffsetDateTime,
+    /// Whether this is the session of the request.
+    pub current: bool,
+}
+
+/// Records that `user_id` logged in with the session `session_id`.
+///
+/// The records of sessions no longer in the session store are removed along the way.
+pub async fn create_user_session(
+    user_id: i32,
+    session_id: &str,
+    user_agent: Option<&str>,
+    db: &crate::Database,
+) -> Result<(), Error> {
+    let mut tx = db.begin().await?;
+
+    sqlx::query(
+        r"
+        DELETE FROM user_sessions
+        WHERE user_id = $1 AND NOT EXISTS (
+            SELECT 1 FROM tower_sessions.session
+            WHERE session.id = user_sessions.session_id
+        )
+        ",
+    )
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query(
+        r"
+        INSERT INTO user_sessions (user_id, session_id, user_agent) VALUES ($1, $2, $3)
+        ON CONFLICT (session_id) DO UPDATE SET user_id = $1, user_agent = $3, created_at = NOW()
+        ",
+    )
+    .bind(user_id)
+    .bind(session_id)
+    .bind(user_agent)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Returns the active sessions of `user_id`, marking `current_session_id` as the current one.
+pub async fn get_user_sessions(
+    user_id: i32,
+    current_session_id: Option<&str>,
+    db: &crate::Database,
+) -> Result<Vec<UserSession>, Error> {
+    let sessions = sqlx::query_as(
+        r"
+        SELECT
+            user_sessions.id,
+            user_sessions.user_agent,
+            user_sessions.created_at,
+            session.expiry_date AS expires_at,
+            user_sessions.session_id IS NOT DISTINCT FROM $2 AS current
+        FROM user_sessions
+        INNER JOIN tower_sessions.session ON session.id = user_sessions.session_id
+        WHERE user_sessions.user_id = $1 AND session.expiry_date > NOW()
+        ORDER BY user_sessions.created_at DESC
+        ",
+    )
+    .bind(user_id)
+    .bind(current_session_id)
+    .fetch_all(db)
+    .await?;
+
+    Ok(sessions)
+}
+
+/// Returns the number of unexpired sessions users are logged in with.
+pub async fn count_active_sessions(db: &crate::Database) -> Result<i64, Error> {
+    let (count,): (i64,) = sqlx::query_as(
+        r"
+        SELECT COUNT(*)
+        FROM user_sessions
+        INNER JOIN tower_sessions.session ON session.id = user_sessions.session_id
+        WHERE session.expiry_date > NOW()
+        ",
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(count)
+}
+
+/// Deletes the session `id` of `user_id` from the session store and returns whether it existed.
+pub async fn delete_user_session(
+    user_id: i32,
+    id: i32,
+    db: &crate::Database,
+) -> Result<bool, Error> {
+    let mut tx = db.begin().await?;
+
+    let session_id: Option<(String,)> = sqlx::query_as(
+        "DELETE FROM user_sessions WHERE id = $1 AND user_id = $2 RETURNING session_id",
+    )
+    .bind(id)
+    .bind(user_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some((session_id,)) = session_id else {
+        return Ok(false);
+    };
+
+    let result = sqlx::query("DELETE FROM tower_sessions.session WHERE id = $1")
+        .bind(session_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Deletes all sessions of `user_id` from the session store and returns their number.
+///
+/// The session key of the user is replaced as well, which also ends sessions that were not
+/// recorded, and the refresh token is forgotten so that no session outlives the logout at the
+/// provider. API tokens and device authorizations that could still be exchanged for one are
+/// revoked too, since they grant access just like sessions.
+pub async fn delete_user_sessions(user_id: i32, db: &crate::Database) -> Result<u64, Error> {
+    let mut tx = db.begin().await?;
+
+    let result = sqlx::query(
+        r"
+        DELETE FROM tower_sessions.session
+        USING user_sessions
+        WHERE user_sessions.session_id = session.id AND user_sessions.user_id = $1
+        ",
+    )
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query("DELETE FROM user_sessions WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("DELETE FROM api_tokens WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("DELETE FROM device_authorizations WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        r"
+        UPDATE users SET
+            session_key = GEN_RANDOM_UUID()::TEXT,
+            refresh_token = NULL,
+            access_token_expires_at = NULL
+        WHERE id = $1
+        ",
+    )
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::distributions::{Alphanumeric, DistString};
+
+    use super::*;
+    use crate::{
+        api::v1::api_token::{self, ApiTokenScope},
+        database::{create_test_user, test_database},
+    };
+
+    /// Stores a session expiring after `lifetime` seconds, which may be negative, in the session
+    /// store and records it for `user_id`. Returns the session id.
+    async fn login(user_id: i32, lifetime: i64, db: &crate::Database) -> String {
+        let session_id = Alphanumeric.sample_string(&mut rand::thread_rng(), 22);
+
+        sqlx::query(
+            r"
+            INSERT INTO tower_sessions.session (id, data, expiry_date)
+            VALUES ($1, '', NOW() + MAKE_INTERVAL(secs => $2))
+            ",
+        )
+        .bind(&session_id)
+        .bind(lifetime as f64)
+        .execute(db)
+        .await
+        .unwrap();
+        create_user_session(user_id, &session_id, Some("curl/8.5.0"), db)
+            .await
+            .unwrap();
+
+        session_id
+    }
+
+    async fn is_stored(session_id: &str, db: &crate::Database) -> bool {
+        sqlx::query("SELECT 1 FROM tower_sessions.session WHERE id = $1")
+            .bind(session_id)
+            .fetch_optional(db)
+            .await
+            .unwrap()
+            .is_some()
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a database at DATABASE_URL"]
+    async fn lists_the_active_sessions_of_a_user() {
+        let db = test_database().await;
+        let user_id = create_test_user(&db).await;
+        let other_user_id = create_test_user(&db).await;
+
+        let current = login(user_id, 3600, &db).await;
+        let other = login(user_id, 3600, &db).await;
+        login(user_id, -60, &db).await;
+        login(other_user_id, 3600, &db).await;
+
+        let sessions = get_user_sessions(user_id, Some(&current), &db)
+            .await
+            .unwrap();
+
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions.iter().filter(|session| session.current).count(), 1);
+        assert!(sessions
+            .iter()
+            .all(|session| session.user_agent.as_deref() == Some("curl/8.5.0")));
+        assert!(is_stored(&other, &db).await);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a database at DATABASE_URL"]
+    async fn revokes_a_session_of_its_user_only() {
+        let db = test_database().await;
+        let user_id = create_test_user(&db).await;
+        let other_user_id = create_test_user(&db).await;
+
+        let session_id = login(user_id, 3600, &db).await;
+        let id = get_user_sessions(user_id, None, &db).await.unwrap()[0].id;
+
+        assert!(!delete_user_session(other_user_id, id, &db).await.unwrap());
+        assert!(is_stored(&session_id, &db).await);
+
+        assert!(delete_user_session(user_id, id, &db).await.unwrap());
+        assert!(!is_stored(&session_id, &db).await);
+        assert!(get_user_sessions(user_id, None, &db)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a database at DATABASE_URL"]
+    async fn revokes_all_sessions_of_a_user() {
+        let db = test_database().await;
+        let user_id = create_test_user(&db).await;
+        let other_user_id = create_test_user(&db).await;
+
+        let first = login(user_id, 3600, &db).await;
+        let second = login(user_id, 3600, &db).await;
+        let other = login(other_user_id, 3600, &db).await;
+        let session_key = |user_id| {
+            sqlx::query_as::<_, (String,)>("SELECT session_key FROM users WHERE id = $1")
+                .bind(user_id)
+                .fetch_one(&db)
+        };
+        let (previous_key,) = session_key(user_id).await.unwrap();
+
+        for user_id in [user_id, other_user_id] {
+            api_token::create_api_token(
+                user_id,
+                "laptop",
+                vec![ApiTokenScope::Read],
+                None,
+                &mut db.acquire().await.unwrap(),
+            )
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(delete_user_sessions(user_id, &db).await.unwrap(), 2);
+        assert!(!is_stored(&first, &db).await);
+        assert!(!is_stored(&second, &db).await);
+        assert!(is_stored(&other, &db).await);
+        assert_ne!(session_key(user_id).await.unwrap().0, previous_key);
+        assert!(api_token::get_user_api_tokens(user_id, &db)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            api_token::get_user_api_tokens(other_user_id, &db)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,556 @@
+//! Import and export of addresses
+//!
+//! Besides our own CSV and JSON exports, the CSV exports of SimpleLogin and AnonAddy can be
+//! imported to ease migrating from those providers.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::Error;
+
+use super::address::Address;
+
+/// The maximum number of rows imported at once.
+pub const MAX_IMPORT_ROWS: usize = 5000;
+
+/// The maximum length of a local part in octets ([RFC 5321, section 4.5.3.1.1]).
+///
+/// [RFC 5321, section 4.5.3.1.1]: https://www.rfc-editor.org/rfc/rfc5321#section-4.5.3.1.1
+const MAX_LOCAL_PART_LENGTH: usize = 64;
+
+/// Local parts that cannot be imported, since the domains are shared and mail to these is
+/// expected to reach their operator, e.g. for abuse reports or certificate validation
+/// ([RFC 2142]).
+///
+/// [RFC 2142]: https://www.rfc-editor.org/rfc/rfc2142
+const RESERVED_LOCAL_PARTS: &[&str] = &[
+    "abuse",
+    "admin",
+    "administrator",
+    "hostmaster",
+    "info",
+    "mailer-daemon",
+    "marketing",
+    "no-reply",
+    "noc",
+    "noreply",
+    "postmaster",
+    "root",
+    "sales",
+    "security",
+    "ssl-admin",
+    "support",
+    "webmaster",
+    "www",
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportFormat {
+    /// The CSV format of our own exports.
+    #[default]
+    Csv,
+    /// The CSV export of SimpleLogin, with the columns `alias`, `note` and `enabled`.
+    #[serde(rename = "simplelogin")]
+    SimpleLogin,
+    /// The CSV export of AnonAddy, with the columns `email`, `description` and `active`.
+    #[serde(rename = "anonaddy")]
+    AnonAddy,
+}
+
+impl ImportFormat {
+    /// Returns the names of the address, description and enabled columns.
+    fn columns(&self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            ImportFormat::Csv => ("address", "description", "enabled"),
+            ImportFormat::SimpleLogin => ("alias", "note", "enabled"),
+            ImportFormat::AnonAddy => ("email", "description", "active"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportQuery {
+    #[serde(default)]
+    #[param(inline)]
+    pub format: ExportFormat,
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImportQuery {
+    #[serde(default)]
+    #[param(inline)]
+    pub format: ImportFormat,
+}
+
+/// An address as it appears in exports.
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema)]
+pub struct ExportedAddress {
+    /// The full e-mail address.
+    pub address: String,
+    pub domain: String,
+    pub description: Option<String>,
+    pub enabled: bool,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: time::OffsetDateTime,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImportError {
+    #[error("invalid csv")]
+    Csv(#[from] csv::Error),
+    #[error("the `{0}` column is missing")]
+    MissingColumn(&'static str),
+    #[error("at most {MAX_IMPORT_ROWS} rows can be imported at once")]
+    TooManyRows,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportStatus {
+    Created,
+    /// The address already exists.
+    Conflict,
+    /// The domain of the address is not served by this instance.
+    UnknownDomain,
+    Invalid,
+}
+
+/// The outcome of importing a single row.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ImportResult {
+    /// The row number, starting at 1 for the first row after the header.
+    pub row: usize,
+    /// The e-mail address as given in the row.
+    pub email: String,
+    pub status: ImportStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// The created address.
+    pub address: Option<Address>,
+}
+
+impl ImportResult {
+    fn new(row: usize, email: String, status: ImportStatus) -> Self {
+        ImportResult {
+            row,
+            email,
+            status,
+            detail: None,
+            address: None,
+        }
+    }
+
+    fn invalid(row: usize, email: String, detail: impl Into<String>) -> Self {
+        ImportResult {
+            detail: Some(detail.into()),
+            ..ImportResult::new(row, email, ImportStatus::Invalid)
+        }
+    }
+}
+
+/// A row of an import that passed validation.
+#[derive(Debug, Clone)]
+pub struct ImportRow {
+    row: usize,
+    email: String,
+    local_part: String,
+    domain: String,
+    description: Option<String>,
+    enabled: bool,
+}
+
+/// Returns all addresses of `user_id` in the export format.
+pub async fn export_user_addresses(
+    user_id: i32,
+    db: &crate::Database,
+) -> Result<Vec<ExportedAddress>, Error> {
+    let addrs = sqlx::query_as(
+        r"
+        SELECT
+            addresses.address || '@' || domains.name AS address,
+            domains.name AS domain,
+            addresses.description,
+            addresses.enabled,
+            addresses.created_at
+        FROM addresses
+        INNER JOIN domains ON domains.id = addresses.domain_id
+        WHERE addresses.user_id = $1 AND addresses.deleted_at IS NULL
+        ORDER BY addresses.id
+        ",
+    )
+    .bind(user_id)
+    .fetch_all(db)
+    .await?;
+
+    Ok(addrs)
+}
+
+/// Serializes `addrs` as CSV with a header row.
+pub fn to_csv(addrs: &[ExportedAddress]) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+
+    for addr in addrs {
+        writer.serialize(addr)?;
+    }
+
+    writer.into_inner().map_err(|err| err.into_error().into())
+}
+
+/// Parses the CSV `data` in `format`.
+///
+/// Rows that cannot be imported are returned as invalid results, while errors affecting the whole
+/// file fail the import.
+pub fn parse_import(
+    format: ImportFormat,
+    data: &[u8],
+) -> Result<Vec<Result<ImportRow, ImportResult>>, ImportError> {
+    let (address_column, description_column, enabled_column) = format.columns();
+
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(data);
+    let headers = reader.headers()?.clone();
+    let position = |name: &str| {
+        headers
+            .iter()
+            .position(|header| header.eq_ignore_ascii_case(name))
+    };
+
+    let address_index =
+        position(address_column).ok_or(ImportError::MissingColumn(address_column))?;
+    let description_index = position(description_column);
+    let enabled_index = position(enabled_column);
+
+    let mut rows = Vec::new();
+
+    for (index, record) in reader.records().enumerate() {
+        if index >= MAX_IMPORT_ROWS {
+            return Err(ImportError::TooManyRows);
+        }
+
+        let record = record?;
+        let row = index + 1;
+        let email = record.get(address_index).unwrap_or_default().to_string();
+
+        let Some((local_part, domain)) = email.rsplit_once('@') else {
+            rows.push(Err(ImportResult::invalid(
+                row,
+                email,
+                "not an e-mail address",
+            )));
+            continue;
+        };
+
+        if local_part.len() > MAX_LOCAL_PART_LENGTH {
+            let detail = format!("local part longer than {MAX_LOCAL_PART_LENGTH} octets");
+            rows.push(Err(ImportResult::invalid(row, email, detail)));
+            continue;
+        }
+
+        if !is_dot_atom(local_part) {
+            let detail = "invalid local part";
+            rows.push(Err(ImportResult::invalid(row, email, detail)));
+            continue;
+        }
+
+        if is_reserved(local_part) {
+            let detail = "reserved local part";
+            rows.push(Err(ImportResult::invalid(row, email, detail)));
+            continue;
+        }
+
+        let enabled = enabled_index
+            .and_then(|index| record.get(index))
+            .map_or(Some(true), parse_bool);
+        let Some(enabled) = enabled else {
+            let detail = format!("invalid value in the `{enabled_column}` column");
+            rows.push(Err(ImportResult::invalid(row, email, detail)));
+            continue;
+        };
+
+        let description = description_index
+            .and_then(|index| record.get(index))
+            .filter(|description| !description.is_empty())
+            .map(str::to_string);
+
+        rows.push(Ok(ImportRow {
+            row,
+            local_part: local_part.to_string(),
+            domain: domain.to_ascii_lowercase(),
+            email,
+            description,
+            enabled,
+        }));
+    }
+
+    Ok(rows)
+}
+
+/// Returns whether `local_part` is a dot-atom as defined by [RFC 5321], i.e. one or more atoms of
+/// printable ASCII characters other than specials, separated by single dots.
+///
+/// Quoted local parts are valid as well, but are not imported since few mail servers accept them.
+///
+/// [RFC 5321]: https://www.rfc-editor.org/rfc/rfc5321#section-4.1.2
+fn is_dot_atom(local_part: &str) -> bool {
+    local_part.split('.').all(|atom| {
+        !atom.is_empty()
+            && atom
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c))
+    })
+}
+
+/// Returns whether `local_part` is reserved, ignoring case and subaddresses like `abuse+tag`.
+fn is_reserved(local_part: &str) -> bool {
+    let (local_part, _subaddress) = local_part.split_once('+').unwrap_or((local_part, ""));
+
+    RESERVED_LOCAL_PARTS
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(local_part))
+}
+
+/// Parses the boolean spellings used by the supported exports. Empty values default to `true`.
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "" | "true" | "t" | "yes" | "1" => Some(true),
+        "false" | "f" | "no" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Creates the addresses in `rows` for `user_id` and returns the result of each row. `conn`
+/// should be a transaction, so that no rows are imported if any of them fails.
+///
+/// Rows whose domain is unknown or whose address already exists, regardless of the case of its
+/// local part, are skipped.
+pub async fn import_user_addresses(
+    user_id: i32,
+    rows: Vec<Result<ImportRow, ImportResult>>,
+    conn: &mut PgConnection,
+) -> Result<Vec<ImportResult>, Error> {
+    let domains: HashMap<String, i32> =
+        sqlx::query_as("SELECT name, id FROM domains WHERE enabled")
+            .fetch_all(&mut *conn)
+            .await?
+            .into_iter()
+            .collect();
+
+    let mut results = Vec::with_capacity(rows.len());
+
+    for row in rows {
+        let row = match row {
+            Ok(row) => row,
+            Err(result) => {
+                results.push(result);
+                continue;
+            }
+        };
+
+        let Some(&domain_id) = domains.get(&row.domain) else {
+            results.push(ImportResult::new(
+                row.row,
+                row.email,
+                ImportStatus::UnknownDomain,
+            ));
+            continue;
+        };
+
+        let addr: Option<Address> = sqlx::query_as(
+            r"
+            INSERT INTO addresses (address, description, enabled, domain_id, user_id)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (LOWER(address), domain_id) DO NOTHING
+            RETURNING *
+            ",
+        )
+        .bind(&row.local_part)
+        .bind(row.description)
+        .bind(row.enabled)
+        .bind(domain_id)
+        .bind(user_id)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        results.push(match addr {
+            Some(addr) => ImportResult {
+                address: Some(addr),
+                ..ImportResult::new(row.row, row.email, ImportStatus::Created)
+            },
+            None => ImportResult::new(row.row, row.email, ImportStatus::Conflict),
+        });
+    }
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::v1::address::find_address,
+        database::{create_test_address, create_test_user, test_database},
+    };
+
+    #[test]
+    fn rejects_reserved_local_parts() {
+        let data =
+            b"address\nPostmaster@example.com\nabuse+reports@example.com\nshop@example.com\n";
+        let rows = parse_import(ImportFormat::Csv, data).unwrap();
+
+        assert!(matches!(rows[0], Err(ImportResult { ref detail, .. })
+            if detail.as_deref() == Some("reserved local part")));
+        assert!(rows[1].is_err());
+        assert!(matches!(rows[2], Ok(ImportRow { ref local_part, .. }) if local_part == "shop"));
+    }
+
+    #[test]
+    fn accepts_dot_atoms() {
+        for local_part in [
+            "shop",
+            "first.last",
+            "o'brien",
+            "a+b",
+            "x_y-z",
+            "{tag}~!#$%&*/=?^`|",
+        ] {
+            assert!(is_dot_atom(local_part), "{local_part}");
+        }
+    }
+
+    #[test]
+    fn rejects_local_parts_that_are_not_dot_atoms() {
+        for local_part in [
+            "",
+            ".shop",
+            "shop.",
+            "first..last",
+            "two words",
+            "\"quoted\"",
+            "a@b",
+            "comma,separated",
+            "(comment)",
+            "münchen",
+        ] {
+            assert!(!is_dot_atom(local_part), "{local_part}");
+        }
+
+        let data = b"address\nfirst..last@example.com\n.shop@example.com\n(shop)@example.com\n";
+        let rows = parse_import(ImportFormat::Csv, data).unwrap();
+
+        for row in rows {
+            assert!(matches!(row, Err(ImportResult { ref detail, .. })
+                if detail.as_deref() == Some("invalid local part")));
+        }
+    }
+
+    #[test]
+    fn rejects_long_local_parts() {
+        let longest = "a".repeat(MAX_LOCAL_PART_LENGTH);
+        let data = format!("address\n{longest}@example.com\n{longest}a@example.com\n");
+        let rows = parse_import(ImportFormat::Csv, data.as_bytes()).unwrap();
+
+        assert!(rows[0].is_ok());
+        assert!(matches!(rows[1], Err(ImportResult { ref detail, .. })
+            if detail.as_deref() == Some("local part longer than 64 octets")));
+    }
+
+    #[test]
+    fn parses_simplelogin_exports() {
+        let data = b"alias,note,enabled\nShop@Example.com,Online shop,False\nnews@example.com,,\n";
+        let rows = parse_import(ImportFormat::SimpleLogin, data).unwrap();
+
+        assert!(matches!(rows[0], Ok(ImportRow {
+            ref local_part,
+            ref domain,
+            ref description,
+            enabled: false,
+            ..
+        }) if local_part == "Shop"
+            && domain == "example.com"
+            && description.as_deref() == Some("Online shop")));
+        assert!(matches!(rows[1], Ok(ImportRow {
+            ref description,
+            enabled: true,
+            ..
+        }) if description.is_none()));
+    }
+
+    #[test]
+    fn parses_anonaddy_exports() {
+        let data = b"id,email,description,active\n\
+            1,shop@example.com,Online shop,0\n\
+            2,news@example.com,,1\n\
+            3,spam@example.com,,maybe\n";
+        let rows = parse_import(ImportFormat::AnonAddy, data).unwrap();
+
+        assert!(matches!(rows[0], Ok(ImportRow {
+            ref local_part,
+            ref description,
+            enabled: false,
+            ..
+        }) if local_part == "shop" && description.as_deref() == Some("Online shop")));
+        assert!(matches!(rows[1], Ok(ImportRow { enabled: true, .. })));
+        assert!(matches!(rows[2], Err(ImportResult { ref detail, .. })
+            if detail.as_deref() == Some("invalid value in the `active` column")));
+        assert!(matches!(
+            parse_import(ImportFormat::AnonAddy, b"alias,note\nshop@example.com,\n"),
+            Err(ImportError::MissingColumn("email"))
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a database at DATABASE_URL"]
+    async fn skips_addresses_that_differ_in_case_only() {
+        let db = test_database().await;
+        let user_id = create_test_user(&db).await;
+        let address = create_test_address(&db, user_id).await;
+        let (domain,): (String,) = sqlx::query_as("SELECT name FROM domains WHERE id = $1")
+            .bind(address.domain_id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        let upper = address.address.to_uppercase();
+        let data = format!(
+            "address\n{upper}@{domain}\nnew{upper}@{domain}\nnew{}@{domain}\n",
+            address.address
+        );
+        let rows = parse_import(ImportFormat::Csv, data.as_bytes()).unwrap();
+
+        let mut tx = db.begin().await.unwrap();
+        let results = import_user_addresses(user_id, rows, &mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+
+        let statuses: Vec<_> = results.iter().map(|result| result.status).collect();
+        assert_eq!(
+            statuses,
+            [
+                ImportStatus::Conflict,
+                ImportStatus::Created,
+                ImportStatus::Conflict
+            ]
+        );
+        assert_eq!(
+            find_address(&format!("{upper}@{domain}"), &db)
+                .await
+                .unwrap()
+                .map(|found| found.id),
+            Some(address.id)
+        );
+    }
+}
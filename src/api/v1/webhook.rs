@@ -0,0 +1,271 @@
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    postgres::{PgHasArrayType, PgTypeInfo},
+    types::Json,
+    FromRow, PgConnection,
+};
+use url::Url;
+use utoipa::ToSchema;
+
+use crate::Error;
+
+use super::{address::Address, message::Message};
+
+/// The length of generated webhook signing secrets.
+const SECRET_LENGTH: usize = 32;
+
+/// The events a webhook can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[sqlx(type_name = "varchar")]
+pub enum WebhookEvent {
+    #[serde(rename = "address.created")]
+    #[sqlx(rename = "address.created")]
+    AddressCreated,
+    #[serde(rename = "address.deleted")]
+    #[sqlx(rename = "address.deleted")]
+    AddressDeleted,
+    /// A message was received and queued for forwarding.
+    #[serde(rename = "mail.received")]
+    #[sqlx(rename = "mail.received")]
+    MailReceived,
+    /// A message was received and quarantined.
+    #[serde(rename = "mail.blocked")]
+    #[sqlx(rename = "mail.blocked")]
+    MailBlocked,
+    /// A message could not be forwarded after the maximum number of attempts.
+    #[serde(rename = "mail.forward_failed")]
+    #[sqlx(rename = "mail.forward_failed")]
+    MailForwardFailed,
+}
+
+impl PgHasArrayType for WebhookEvent {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_varchar")
+    }
+}
+
+impl WebhookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::AddressCreated => "address.created",
+            WebhookEvent::AddressDeleted => "address.deleted",
+            WebhookEvent::MailReceived => "mail.received",
+            WebhookEvent::MailBlocked => "mail.blocked",
+            WebhookEvent::MailForwardFailed => "mail.forward_failed",
+        }
+    }
+}
+
+/// The delivery state of a webhook event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema)]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    /// The secret used to sign the payloads sent to this webhook, which is only returned when
+    /// the webhook is created.
+    #[serde(skip)]
+    pub secret: String,
+    pub events: Vec<WebhookEvent>,
+    pub enabled: bool,
+    pub created_at: time::OffsetDateTime,
+}
+
+/// A newly created webhook along with the secret used to sign the payloads sent to it.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct CreatedWebhook {
+    #[serde(flatten)]
+    pub webhook: Webhook,
+    pub secret: String,
+}
+
+impl CreatedWebhook {
+    pub fn new(mut webhook: Webhook) -> Self {
+        CreatedWebhook {
+            secret: std::mem::take(&mut webhook.secret),
+            webhook,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct CreateWebhook {
+    pub url: Url,
+    pub events: Vec<WebhookEvent>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema)]
+pub struct WebhookDelivery {
+    pub id: i32,
+    pub webhook_id: i32,
+    pub event: WebhookEvent,
+    #[schema(value_type = Object)]
+    pub payload: Json<serde_json::Value>,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    pub response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: time::OffsetDateTime,
+    pub delivered_at: Option<time::OffsetDateTime>,
+}
+
+/// The payload of `address.*` events.
+#[derive(Debug, Clone, Serialize)]
+pub struct AddressEvent {
+    pub address_id: i32,
+    pub address: String,
+    pub domain_id: i32,
+    pub description: Option<String>,
+    pub enabled: bool,
+    pub created_at: time::OffsetDateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<time::OffsetDateTime>,
+}
+
+impl AddressEvent {
+    pub fn new(addr: &Address) -> Self {
+        AddressEvent {
+            address_id: addr.id,
+            address: addr.address.clone(),
+            domain_id: addr.domain_id,
+            description: addr.description.clone(),
+            enabled: addr.enabled,
+            created_at: addr.created_at,
+            deleted_at: addr.deleted_at,
+        }
+    }
+}
+
+/// The payload of `mail.*` events.
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageEvent {
+    pub message_id: i32,
+    pub address_id: i32,
+    pub sender: Option<String>,
+    pub subject: Option<String>,
+    pub spam_score: Option<f64>,
+    pub received_at: time::OffsetDateTime,
+    /// Why the message was blocked or could not be forwarded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl MessageEvent {
+    pub fn new(msg: &Message, reason: Option<String>) -> Self {
+        MessageEvent {
+            message_id: msg.id,
+            address_id: msg.address_id,
+            sender: msg.sender.clone(),
+            subject: msg.subject.clone(),
+            spam_score: msg.spam_score,
+            received_at: msg.received_at,
+            reason,
+        }
+    }
+}
+
+/// Returns all webhooks of `user_id`.
+pub async fn get_user_webhooks(user_id: i32, db: &crate::Database) -> Result<Vec<Webhook>, Error> {
+    let webhooks = sqlx::query_as("SELECT * FROM webhooks WHERE user_id = $1 ORDER BY id")
+        .bind(user_id)
+        .fetch_all(db)
+        .await?;
+
+    Ok(webhooks)
+}
+
+/// Registers a webhook for `user_id` with a newly generated signing secret.
+pub async fn create_user_webhook(
+    user_id: i32,
+    webhook: CreateWebhook,
+    db: &crate::Database,
+) -> Result<Webhook, Error> {
+    let secret = Alphanumeric.sample_string(&mut rand::thread_rng(), SECRET_LENGTH);
+    let webhook = sqlx::query_as(
+        r"
+        INSERT INTO webhooks (user_id, url, secret, events) VALUES ($1, $2, $3, $4)
+        RETURNING *
+        ",
+    )
+    .bind(user_id)
+    .bind(webhook.url.as_str())
+    .bind(secret)
+    .bind(webhook.events)
+    .fetch_one(db)
+    .await?;
+
+    Ok(webhook)
+}
+
+/// Deletes the webhook with `webhook_id` belonging to `user_id` along with its delivery log.
+pub async fn delete_user_webhook(
+    user_id: i32,
+    webhook_id: i32,
+    db: &crate::Database,
+) -> Result<Option<Webhook>, Error> {
+    let webhook = sqlx::query_as("DELETE FROM webhooks WHERE user_id = $1 AND id = $2 RETURNING *")
+        .bind(user_id)
+        .bind(webhook_id)
+        .fetch_optional(db)
+        .await?;
+
+    Ok(webhook)
+}
+
+/// Returns the most recent deliveries of the webhook with `webhook_id` belonging to `user_id`.
+pub async fn get_user_webhook_deliveries(
+    user_id: i32,
+    webhook_id: i32,
+    db: &crate::Database,
+) -> Result<Vec<WebhookDelivery>, Error> {
+    let deliveries = sqlx::query_as(
+        r"
+        SELECT webhook_deliveries.* FROM webhook_deliveries
+        INNER JOIN webhooks ON webhooks.id = webhook_deliveries.webhook_id
+        WHERE webhooks.user_id = $1 AND webhooks.id = $2
+        ORDER BY webhook_deliveries.id DESC
+        LIMIT 100
+        ",
+    )
+    .bind(user_id)
+    .bind(webhook_id)
+    .fetch_all(db)
+    .await?;
+
+    Ok(deliveries)
+}
+
+/// Queues `event` for delivery to every enabled webhook of `user_id` subscribed to it.
+///
+/// `conn` should be the transaction of the change the event is about, so that the event is only
+/// delivered if the change is committed.
+pub async fn emit<T: Serialize + Sync>(
+    user_id: i32,
+    event: WebhookEvent,
+    data: &T,
+    conn: &mut PgConnection,
+) -> Result<(), Error> {
+    sqlx::query(
+        r"
+        INSERT INTO webhook_deliveries (webhook_id, event, payload)
+        SELECT id, $2, $3 FROM webhooks
+        WHERE user_id = $1 AND enabled AND $2 = ANY(events)
+        ",
+    )
+    .bind(user_id)
+    .bind(event)
+    .bind(Json(data))
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
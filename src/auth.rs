@@ -1,32 +1,84 @@
 //! OIDC authentication
 
+use std::{collections::HashMap, sync::Arc};
+
 use async_trait::async_trait;
 use axum_login::{AuthUser, AuthnBackend, UserId};
 use openidconnect::{
-    core::{CoreAuthenticationFlow, CoreClient, CoreProviderMetadata},
+    core::{
+        CoreGenderClaim, CoreJsonWebKeyType, CoreJweContentEncryptionAlgorithm,
+        CoreJwsSigningAlgorithm,
+    },
     reqwest::AsyncHttpClientError,
-    url::Url,
-    AccessTokenHash, AuthorizationCode, ClaimsVerificationError, ClientId, ClientSecret, CsrfToken,
-    IssuerUrl, Nonce, OAuth2TokenResponse, RedirectUrl, Scope,
+    AccessTokenHash, AdditionalClaims, AuthorizationCode, ClaimsVerificationError, CsrfToken,
+    IdToken, Nonce, OAuth2TokenResponse, RefreshToken, RequestTokenError,
 };
 use openidconnect::{
-    core::{CoreIdTokenClaims, CoreRequestTokenError},
+    core::{CoreIdToken, CoreIdTokenClaims, CoreRequestTokenError},
     reqwest::async_http_client,
 };
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
-use tracing::debug;
+use sqlx::{FromRow, PgConnection};
+use time::OffsetDateTime;
+use tracing::{debug, warn};
+use url::Url;
+use webauthn_rs::{Webauthn, WebauthnBuilder};
 
+use crate::config::AuthConfig;
 use crate::Database;
 use crate::Error;
 
+pub use self::{mock::MockIssuer, provider::Provider, token::TokenCipher};
+
+pub mod device;
+pub mod mock;
+mod provider;
+mod token;
+
 pub type AuthSession = axum_login::AuthSession<Authenticator>;
 
+/// The event a logout token must contain to be accepted for back-channel logout.
+const BACKCHANNEL_LOGOUT_EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+
+/// How long before its expiry an access token is refreshed.
+const REFRESH_LEEWAY: time::Duration = time::Duration::seconds(30);
+
+/// The name of this service shown by authenticators when registering a passkey.
+const WEBAUTHN_RP_NAME: &str = "Masked Mails";
+
+pub const DEFAULT_STEP_UP_MAX_AGE: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
 #[derive(Clone, Serialize, Deserialize, FromRow)]
 pub struct User {
     pub id: i32,
     pub email: String,
     pub access_token: String,
+    /// The name of the provider the user last logged in with.
+    pub provider: Option<String>,
+    #[serde(skip)]
+    pub refresh_token: Option<Vec<u8>>,
+    pub access_token_expires_at: Option<OffsetDateTime>,
+    pub session_key: String,
+    /// The ID token issued when the user logged in, which is only set after authenticating. It is
+    /// kept in the session as the hint for RP-initiated logout.
+    #[sqlx(skip)]
+    #[serde(skip)]
+    pub id_token: Option<CoreIdToken>,
+    /// When the user last authenticated at the provider according to the ID token, which is only
+    /// set after authenticating.
+    #[sqlx(skip)]
+    #[serde(skip)]
+    pub auth_time: Option<OffsetDateTime>,
+}
+
+impl User {
+    /// Returns whether the access token is about to expire and can be refreshed.
+    fn needs_refresh(&self) -> bool {
+        self.refresh_token.is_some()
+            && self
+                .access_token_expires_at
+                .is_some_and(|expires_at| expires_at - REFRESH_LEEWAY <= OffsetDateTime::now_utc())
+    }
 }
 
 impl std::fmt::Debug for User {
@@ -34,7 +86,10 @@ impl std::fmt::Debug for User {
         f.debug_struct("User")
             .field("id", &self.id)
             .field("email", &self.email)
+            .field("provider", &self.provider)
             .field("access_token", &"[redacted]")
+            .field("refresh_token", &"[redacted]")
+            .field("access_token_expires_at", &self.access_token_expires_at)
             .finish()
     }
 }
@@ -46,19 +101,42 @@ impl AuthUser for User {
         self.id
     }
 
+    // The session key is stable across token refreshes, unlike the access token, and is replaced
+    // to revoke all sessions of the user.
     fn session_auth_hash(&self) -> &[u8] {
-        self.access_token.as_bytes()
+        self.session_key.as_bytes()
     }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Credentials {
+    /// The name of the provider the user logged in with, or `None` for the default provider.
+    pub provider: Option<String>,
     pub code: String,
     pub nonce: Nonce,
     pub old_state: CsrfToken,
     pub new_state: CsrfToken,
 }
 
+/// The claims of a logout token besides the standard ID token claims.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct LogoutClaims {
+    events: HashMap<String, serde_json::Value>,
+}
+
+impl AdditionalClaims for LogoutClaims {}
+
+/// A logout token sent by the provider for OIDC back-channel logout.
+///
+/// Logout tokens are signed like ID tokens, so they are verified with the ID token verifier.
+type LogoutToken = IdToken<
+    LogoutClaims,
+    CoreGenderClaim,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJwsSigningAlgorithm,
+    CoreJsonWebKeyType,
+>;
+
 #[derive(Debug, thiserror::Error)]
 pub enum BackendError {
     #[error(transparent)]
@@ -71,60 +149,370 @@ pub enum BackendError {
     InvalidTokenNonce(#[source] ClaimsVerificationError),
     #[error("invalid access token")]
     InvalidAccessToken,
+    #[error("could not encrypt or decrypt a stored token")]
+    TokenEncryption(#[source] aes_gcm::Error),
+    #[error("malformed logout token")]
+    MalformedLogoutToken,
+    #[error("invalid logout token")]
+    InvalidLogoutToken(#[source] ClaimsVerificationError),
+    #[error("unknown identity provider")]
+    UnknownProvider,
+    #[error("the provider did not share the e-mail address of the user")]
+    MissingEmail,
+    #[error("the provider did not verify the e-mail address of the user")]
+    UnverifiedEmail,
+    #[error("the e-mail address belongs to another account")]
+    EmailInUse,
 }
 
 #[derive(Clone, Debug)]
 pub struct Authenticator {
     db: Database,
-    client: CoreClient,
+    providers: Arc<[Provider]>,
+    mock: Option<Arc<MockIssuer>>,
+    cipher: TokenCipher,
+    webauthn: Arc<Webauthn>,
 }
 
 impl Authenticator {
-    /// Create an Authenticator based on the properties of an OpenID Connect Discovery document.
-    pub(crate) async fn discover(
-        db: Database,
-        url: Url,
-        client_id: String,
-        client_secret: String,
-        redirect_url: Url,
-    ) -> Result<Self, Error> {
-        debug!("running openid connect discovery");
-
-        let issuer_url = IssuerUrl::from_url(url);
-        let provider_metadata = CoreProviderMetadata::discover_async(issuer_url, async_http_client)
+    /// Create an Authenticator for the configured providers, running OpenID Connect Discovery for
+    /// each of them.
+    pub(crate) async fn discover(db: Database, config: &AuthConfig) -> Result<Self, Error> {
+        let cipher = TokenCipher::from_base64(&config.token_key)?;
+        let webauthn = build_webauthn(&config.redirect_url)?;
+
+        // Anyone can log in as any e-mail address at the mock issuer, so it must never share a
+        // server with real accounts.
+        if config.mock && !config.providers.is_empty() {
+            return Err(Error::MockWithProviders);
+        }
+
+        let mut providers = Vec::with_capacity(config.providers.len() + 1);
+
+        for provider in &config.providers {
+            providers.push(Provider::discover(provider, &config.redirect_url).await?);
+        }
+
+        let mock = if config.mock {
+            warn!("the mock openid connect issuer is enabled, anyone can log in as any user");
+
+            let issuer = MockIssuer::new(&config.redirect_url)?;
+            providers.push(Provider::mock(&issuer, &config.redirect_url));
+
+            Some(Arc::new(issuer))
+        } else {
+            None
+        };
+
+        if providers.is_empty() {
+            return Err(Error::NoAuthProviders);
+        }
+
+        Ok(Authenticator {
+            db,
+            providers: providers.into(),
+            mock,
+            cipher,
+            webauthn: Arc::new(webauthn),
+        })
+    }
+
+    /// Returns the names of all providers, starting with the default provider.
+    pub fn provider_names(&self) -> impl Iterator<Item = &str> {
+        self.providers.iter().map(Provider::name)
+    }
+
+    /// Returns the provider with `name`, or the default provider if `name` is `None`.
+    pub fn provider(&self, name: Option<&str>) -> Option<&Provider> {
+        match name {
+            Some(name) => self
+                .providers
+                .iter()
+                .find(|provider| provider.name() == name),
+            None => self.providers.first(),
+        }
+    }
+
+    /// Returns the mock issuer, if it is enabled.
+    pub fn mock(&self) -> Option<&MockIssuer> {
+        self.mock.as_deref()
+    }
+
+    /// Returns the WebAuthn relying party that passkeys are registered with and verified by.
+    pub fn webauthn(&self) -> &Webauthn {
+        &self.webauthn
+    }
+
+    /// Ends all sessions of the user identified by the `logout_token` issued by `provider` and
+    /// returns the number of affected users.
+    ///
+    /// Users are identified by the `iss` and `sub` claims, so `sub` is required even though the
+    /// specification allows logout tokens with only a `sid`. Replayed tokens are not rejected,
+    /// since ending the sessions again is harmless.
+    pub async fn backchannel_logout(
+        &self,
+        provider: Option<&str>,
+        logout_token: &str,
+    ) -> Result<u64, BackendError> {
+        let provider = self
+            .provider(provider)
+            .ok_or(BackendError::UnknownProvider)?;
+        let token: LogoutToken = logout_token
+            .parse()
+            .map_err(|_| BackendError::MalformedLogoutToken)?;
+        let claims = token
+            .claims(
+                &provider.id_token_verifier(),
+                |nonce: Option<&Nonce>| match nonce {
+                    Some(_) => Err("logout tokens must not contain a nonce".to_string()),
+                    None => Ok(()),
+                },
+            )
+            .map_err(BackendError::InvalidLogoutToken)?;
+
+        if !claims
+            .additional_claims()
+            .events
+            .contains_key(BACKCHANNEL_LOGOUT_EVENT)
+        {
+            return Err(BackendError::MalformedLogoutToken);
+        }
+
+        let issuer = claims.issuer().as_str();
+        let subject = claims.subject().as_str();
+        let result = sqlx::query(
+            r"
+            update users set
+                session_key = gen_random_uuid()::text,
+                refresh_token = null,
+                access_token_expires_at = null
+            where id in (
+                select user_id from user_identities where issuer = $1 and subject = $2
+            )
+            ",
+        )
+        .bind(issuer)
+        .bind(subject)
+        .execute(&self.db)
+        .await
+        .map_err(BackendError::Sqlx)?;
+
+        debug!(%issuer, %subject, users = result.rows_affected(), "processed back-channel logout");
+
+        Ok(result.rows_affected())
+    }
+
+    /// Refreshes the access token of `user_id` if it is about to expire.
+    ///
+    /// Returns `None` and ends all sessions of the user if the provider rejects the refresh
+    /// token, e.g. because the user was disabled. If the provider cannot be reached, the user is
+    /// returned unchanged so an outage of the provider does not log out everyone.
+    async fn refresh(&self, user_id: i32) -> Result<Option<User>, BackendError> {
+        let mut tx = self.db.begin().await.map_err(BackendError::Sqlx)?;
+
+        // The row stays locked until the refreshed token is stored, so that concurrent requests
+        // do not use a refresh token the provider already rotated.
+        let user: Option<User> = sqlx::query_as("select * from users where id = $1 for update")
+            .bind(user_id)
+            .fetch_optional(&mut *tx)
             .await
-            .map_err(|_| Error::DiscoverOidcFailed)?;
+            .map_err(BackendError::Sqlx)?;
 
-        let client = CoreClient::from_provider_metadata(
-            provider_metadata,
-            ClientId::new(client_id),
-            Some(ClientSecret::new(client_secret)),
+        let Some(user) = user else {
+            return Ok(None);
+        };
+
+        // Another request may have refreshed the token while waiting for the lock.
+        let Some(encrypted) = user.refresh_token.as_ref().filter(|_| user.needs_refresh()) else {
+            return Ok(Some(user));
+        };
+
+        let Some(provider) = self.provider(user.provider.as_deref()) else {
+            return Ok(Some(user));
+        };
+
+        let refresh_token = self
+            .cipher
+            .decrypt(encrypted)
+            .map_err(BackendError::TokenEncryption)?;
+
+        let token_response = match provider
+            .client()
+            .exchange_refresh_token(&RefreshToken::new(refresh_token))
+            .request_async(async_http_client)
+            .await
+        {
+            Ok(token_response) => token_response,
+            Err(RequestTokenError::ServerResponse(err)) => {
+                warn!(user_id, error = %err, "refresh token was rejected, ending sessions");
+
+                revoke_sessions(user_id, &mut tx).await?;
+                tx.commit().await.map_err(BackendError::Sqlx)?;
+
+                return Ok(None);
+            }
+            Err(err) => {
+                warn!(?err, user_id, "could not refresh access token");
+
+                return Ok(Some(user));
+            }
+        };
+
+        // Providers that do not rotate refresh tokens omit them from the response.
+        let refresh_token = token_response
+            .refresh_token()
+            .map(|token| self.cipher.encrypt(token.secret()))
+            .transpose()
+            .map_err(BackendError::TokenEncryption)?;
+        let expires_at = token_response
+            .expires_in()
+            .map(|expires_in| OffsetDateTime::now_utc() + expires_in);
+
+        let user = sqlx::query_as(
+            r"
+            update users set
+                access_token = $2,
+                access_token_expires_at = $3,
+                refresh_token = coalesce($4, refresh_token)
+            where id = $1
+            returning *
+            ",
         )
-        .set_redirect_uri(RedirectUrl::from_url(redirect_url));
+        .bind(user_id)
+        .bind(token_response.access_token().secret())
+        .bind(expires_at)
+        .bind(refresh_token)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(BackendError::Sqlx)?;
+
+        tx.commit().await.map_err(BackendError::Sqlx)?;
 
-        debug!("finished openid connect discovery");
+        debug!(user_id, "refreshed access token");
 
-        Ok(Authenticator { db, client })
+        Ok(Some(user))
     }
+}
 
-    pub fn authorize_url(&self) -> (Url, CsrfToken, Nonce) {
-        // Generate the full authorization URL
-        let (auth_url, csrf_token, nonce) = self
-            .client
-            .authorize_url(
-                CoreAuthenticationFlow::AuthorizationCode,
-                CsrfToken::new_random,
-                Nonce::new_random,
-            )
-            // Set the desired scopes.
-            .add_scope(Scope::new("email".to_string()))
-            .add_scope(Scope::new("profile".to_string()))
-            .url();
+/// Creates the WebAuthn relying party for the origin of `redirect_url`, on which the API is served.
+fn build_webauthn(redirect_url: &Url) -> Result<Webauthn, Error> {
+    let rp_id = redirect_url.host_str().ok_or(Error::InvalidRedirectUrl)?;
+    let rp_origin = Url::parse(&redirect_url.origin().ascii_serialization())
+        .map_err(|_| Error::InvalidRedirectUrl)?;
+
+    WebauthnBuilder::new(rp_id, &rp_origin)
+        .and_then(|builder| builder.rp_name(WEBAUTHN_RP_NAME).build())
+        .map_err(Error::InvalidWebauthnOrigin)
+}
 
-        (auth_url, csrf_token, nonce)
+/// The identity of a user at a provider, as stated by a verified ID token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Identity {
+    issuer: String,
+    subject: String,
+    email: String,
+    email_verified: bool,
+}
+
+impl Identity {
+    /// Returns the identity of verified ID token `claims`, which must include the e-mail address.
+    fn from_claims(claims: &CoreIdTokenClaims) -> Result<Self, BackendError> {
+        let email = claims.email().ok_or(BackendError::MissingEmail)?;
+
+        Ok(Identity {
+            issuer: claims.issuer().to_string(),
+            subject: claims.subject().to_string(),
+            email: email.to_string(),
+            email_verified: claims.email_verified().unwrap_or(false),
+        })
+    }
+
+    /// Returns the e-mail address if the provider verified it.
+    fn verified_email(&self) -> Result<&str, BackendError> {
+        if self.email_verified {
+            Ok(&self.email)
+        } else {
+            Err(BackendError::UnverifiedEmail)
+        }
     }
 }
 
+/// Returns the id of the user with `identity`.
+///
+/// New identities are keyed by their e-mail address, so they are only accepted if the provider
+/// verified the address. Anyone could otherwise take over an account by registering its address
+/// with another provider, or claim an address before its owner signs up. A new identity is
+/// linked to the account with the same address, unless the provider may not `link_by_email`,
+/// like the mock issuer, and a new account is created otherwise.
+async fn find_or_create_user(
+    identity: &Identity,
+    link_by_email: bool,
+    conn: &mut PgConnection,
+) -> Result<i32, BackendError> {
+    let user_id: Option<(i32,)> =
+        sqlx::query_as("select user_id from user_identities where issuer = $1 and subject = $2")
+            .bind(&identity.issuer)
+            .bind(&identity.subject)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(BackendError::Sqlx)?;
+
+    if let Some((user_id,)) = user_id {
+        return Ok(user_id);
+    }
+
+    let email = identity.verified_email()?;
+
+    let existing: Option<(i32,)> = sqlx::query_as("select id from users where email = $1")
+        .bind(email)
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(BackendError::Sqlx)?;
+
+    let (user_id,): (i32,) = match existing {
+        Some(_) if !link_by_email => return Err(BackendError::EmailInUse),
+        Some(existing) => existing,
+        None => {
+            sqlx::query_as("insert into users (email, access_token) values ($1, '') returning id")
+                .bind(email)
+                .fetch_one(&mut *conn)
+                .await
+                .map_err(BackendError::Sqlx)?
+        }
+    };
+
+    sqlx::query("insert into user_identities (user_id, issuer, subject) values ($1, $2, $3)")
+        .bind(user_id)
+        .bind(&identity.issuer)
+        .bind(&identity.subject)
+        .execute(&mut *conn)
+        .await
+        .map_err(BackendError::Sqlx)?;
+
+    debug!(user_id, issuer = %identity.issuer, "linked new identity");
+
+    Ok(user_id)
+}
+
+/// Ends all sessions of `user_id` and forgets its tokens.
+async fn revoke_sessions(user_id: i32, conn: &mut PgConnection) -> Result<(), BackendError> {
+    sqlx::query(
+        r"
+        update users set
+            session_key = gen_random_uuid()::text,
+            refresh_token = null,
+            access_token_expires_at = null
+        where id = $1
+        ",
+    )
+    .bind(user_id)
+    .execute(conn)
+    .await
+    .map_err(BackendError::Sqlx)?;
+
+    Ok(())
+}
+
 #[async_trait]
 impl AuthnBackend for Authenticator {
     type User = User;
@@ -140,10 +528,18 @@ impl AuthnBackend for Authenticator {
             return Ok(None);
         };
 
-        debug!(code = %creds.code, "requesting access token using authorization token");
+        let provider = self
+            .provider(creds.provider.as_deref())
+            .ok_or(BackendError::UnknownProvider)?;
 
-        let token_response = self
-            .client
+        debug!(
+            provider = %provider.name(),
+            code = %creds.code,
+            "requesting access token using authorization token"
+        );
+
+        let token_response = provider
+            .client()
             .exchange_code(AuthorizationCode::new(creds.code))
             .request_async(async_http_client)
             .await
@@ -151,7 +547,7 @@ impl AuthnBackend for Authenticator {
 
         debug!(?token_response);
 
-        let id_token_verifier = self.client.id_token_verifier();
+        let id_token_verifier = provider.id_token_verifier();
         let id_token = token_response
             .extra_fields()
             .id_token()
@@ -177,44 +573,223 @@ impl AuthnBackend for Authenticator {
                 return Err(BackendError::InvalidAccessToken);
             }
         }
-        let email = id_token_claims.email().expect("missing email").as_str();
         let access_token = token_response.access_token().secret();
+        let refresh_token = token_response
+            .refresh_token()
+            .map(|token| self.cipher.encrypt(token.secret()))
+            .transpose()
+            .map_err(BackendError::TokenEncryption)?;
+        let expires_at = token_response
+            .expires_in()
+            .map(|expires_in| OffsetDateTime::now_utc() + expires_in);
 
         // Persist user in our database so we can use `get_user`.
-        let user = sqlx::query_as(
+        let mut tx = self.db.begin().await.map_err(Self::Error::Sqlx)?;
+
+        let identity = Identity::from_claims(id_token_claims)?;
+        let user_id = find_or_create_user(&identity, !provider.is_mock(), &mut tx).await?;
+
+        let mut user: User = sqlx::query_as(
             r"
-            insert into users (email, access_token)
-            values ($1, $2)
-            on conflict(email) do update
-            set access_token = excluded.access_token
+            update users set
+                provider = $2,
+                access_token = $3,
+                refresh_token = $4,
+                access_token_expires_at = $5
+            where id = $1
             returning *
             ",
         )
-        .bind(email)
+        .bind(user_id)
+        .bind(provider.name())
         .bind(access_token)
-        .fetch_one(&self.db)
+        .bind(refresh_token)
+        .bind(expires_at)
+        .fetch_one(&mut *tx)
         .await
         .map_err(Self::Error::Sqlx)?;
 
+        tx.commit().await.map_err(Self::Error::Sqlx)?;
+
+        user.id_token = Some(id_token.clone());
+        user.auth_time = id_token_claims
+            .auth_time()
+            .and_then(|auth_time| OffsetDateTime::from_unix_timestamp(auth_time.timestamp()).ok());
+
         debug!("finished authenticating");
 
         Ok(Some(user))
     }
 
     async fn get_user(&self, user_id: &UserId<Self>) -> Result<Option<Self::User>, Self::Error> {
-        Ok(sqlx::query_as("select * from users where id = $1")
+        let user: Option<User> = sqlx::query_as("select * from users where id = $1")
             .bind(user_id)
             .fetch_optional(&self.db)
             .await
-            .map_err(Self::Error::Sqlx)?)
+            .map_err(Self::Error::Sqlx)?;
+
+        match user {
+            Some(user) if user.needs_refresh() => self.refresh(user.id).await,
+            user => Ok(user),
+        }
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct Claims(CoreIdTokenClaims);
+#[cfg(test)]
+mod tests {
+    use openidconnect::{
+        core::{CoreHmacKey, CoreIdToken, CoreIdTokenClaims, CoreJwsSigningAlgorithm},
+        Audience, EmptyAdditionalClaims, EndUserEmail, IssuerUrl, Nonce, StandardClaims,
+        SubjectIdentifier,
+    };
+    use url::Url;
+
+    use super::{BackendError, Identity, MockIssuer, Provider};
+
+    const NONCE: &str = "nonce";
+
+    fn provider() -> (MockIssuer, Provider) {
+        let redirect_url = Url::parse("http://localhost:3000/api/auth/callback").unwrap();
+        let issuer = MockIssuer::new(&redirect_url).unwrap();
+        let provider = Provider::mock(&issuer, &redirect_url);
+
+        (issuer, provider)
+    }
+
+    fn claims(
+        issuer: &MockIssuer,
+        email: Option<&str>,
+        email_verified: Option<bool>,
+    ) -> CoreIdTokenClaims {
+        let now = chrono::Utc::now();
+
+        CoreIdTokenClaims::new(
+            issuer.metadata().issuer().clone(),
+            vec![Audience::new(issuer.client_id().to_string())],
+            now + chrono::Duration::minutes(5),
+            now,
+            StandardClaims::new(SubjectIdentifier::new("subject".to_string()))
+                .set_email(email.map(|email| EndUserEmail::new(email.to_string())))
+                .set_email_verified(email_verified),
+            EmptyAdditionalClaims {},
+        )
+        .set_nonce(Some(Nonce::new(NONCE.to_string())))
+    }
+
+    fn sign(claims: CoreIdTokenClaims, key: &str) -> CoreIdToken {
+        CoreIdToken::new(
+            claims,
+            &CoreHmacKey::new(key.as_bytes()),
+            CoreJwsSigningAlgorithm::HmacSha256,
+            None,
+            None,
+        )
+        .unwrap()
+    }
+
+    /// Verifies `token` like a login does and returns the identity it states.
+    fn identity(provider: &Provider, token: &CoreIdToken) -> Result<Identity, BackendError> {
+        let claims = token
+            .claims(
+                &provider.id_token_verifier(),
+                &Nonce::new(NONCE.to_string()),
+            )
+            .map_err(BackendError::InvalidTokenNonce)?;
+
+        Identity::from_claims(claims)
+    }
+
+    #[test]
+    fn accepts_verified_email() {
+        let (issuer, provider) = provider();
+        let token = sign(
+            claims(&issuer, Some("user@example.com"), Some(true)),
+            issuer.client_secret(),
+        );
+
+        let identity = identity(&provider, &token).unwrap();
+
+        assert_eq!(identity.subject, "subject");
+        assert_eq!(identity.verified_email().unwrap(), "user@example.com");
+    }
+
+    #[test]
+    fn rejects_missing_email() {
+        let (issuer, provider) = provider();
+        let token = sign(claims(&issuer, None, Some(true)), issuer.client_secret());
+
+        assert!(matches!(
+            identity(&provider, &token),
+            Err(BackendError::MissingEmail)
+        ));
+    }
+
+    #[test]
+    fn does_not_trust_unverified_email() {
+        let (issuer, provider) = provider();
+
+        for email_verified in [Some(false), None] {
+            let token = sign(
+                claims(&issuer, Some("user@example.com"), email_verified),
+                issuer.client_secret(),
+            );
+            let identity = identity(&provider, &token).unwrap();
+
+            assert!(matches!(
+                identity.verified_email(),
+                Err(BackendError::UnverifiedEmail)
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_forged_signature() {
+        let (issuer, provider) = provider();
+        let token = sign(
+            claims(&issuer, Some("user@example.com"), Some(true)),
+            "not-the-client-secret",
+        );
+
+        assert!(identity(&provider, &token).is_err());
+    }
+
+    #[test]
+    fn rejects_other_issuer() {
+        let (issuer, provider) = provider();
+        let claims = claims(&issuer, Some("user@example.com"), Some(true))
+            .set_issuer(IssuerUrl::new("https://evil.example.com".to_string()).unwrap());
+        let token = sign(claims, issuer.client_secret());
+
+        assert!(identity(&provider, &token).is_err());
+    }
+
+    #[test]
+    fn rejects_other_audience() {
+        let (issuer, provider) = provider();
+        let claims = claims(&issuer, Some("user@example.com"), Some(true))
+            .set_audiences(vec![Audience::new("another-client".to_string())]);
+        let token = sign(claims, issuer.client_secret());
+
+        assert!(identity(&provider, &token).is_err());
+    }
+
+    #[test]
+    fn rejects_expired_token() {
+        let (issuer, provider) = provider();
+        let claims = claims(&issuer, Some("user@example.com"), Some(true))
+            .set_expiration(chrono::Utc::now() - chrono::Duration::minutes(5));
+        let token = sign(claims, issuer.client_secret());
+
+        assert!(identity(&provider, &token).is_err());
+    }
+
+    #[test]
+    fn rejects_other_nonce() {
+        let (issuer, provider) = provider();
+        let claims = claims(&issuer, Some("user@example.com"), Some(true))
+            .set_nonce(Some(Nonce::new("replayed".to_string())));
+        let token = sign(claims, issuer.client_secret());
 
-impl AsRef<CoreIdTokenClaims> for Claims {
-    fn as_ref(&self) -> &CoreIdTokenClaims {
-        &self.0
+        assert!(identity(&provider, &token).is_err());
     }
 }
@@ -0,0 +1,318 @@
+//! OAuth 2.0 Device Authorization Grant ([RFC 8628](https://www.rfc-editor.org/rfc/rfc8628))
+//!
+//! Clients without a browser, like the command line client, request a device code and show the
+//! accompanying user code. The user approves the request in the browser while logged in, and the
+//! client polls with the device code until it receives an API token.
+
+use std::time::Duration;
+
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    Rng,
+};
+use sha2::{Digest, Sha256};
+use sqlx::FromRow;
+use time::OffsetDateTime;
+
+use crate::{
+    api::v1::api_token::{self, ApiToken, ApiTokenScope},
+    Error,
+};
+
+pub const DEFAULT_CODE_LIFETIME: Duration = Duration::from_secs(10 * 60);
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+pub const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+/// The length of generated device codes.
+const DEVICE_CODE_LENGTH: usize = 40;
+/// The characters of user codes. Vowels are left out so that codes do not spell words, as
+/// recommended by RFC 8628.
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+/// The number of characters of user codes, which are shown in two groups.
+const USER_CODE_LENGTH: usize = 8;
+/// The number of attempts to generate a user code that is not in use.
+const USER_CODE_ATTEMPTS: usize = 3;
+
+/// The state of a device authorization request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+pub enum DeviceAuthorizationStatus {
+    Pending,
+    Approved,
+    Denied,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct DeviceAuthorization {
+    pub id: i32,
+    pub user_code: String,
+    /// The name the client identified itself with, which is shown to the user.
+    pub client_name: Option<String>,
+    pub scopes: Vec<ApiTokenScope>,
+    pub status: DeviceAuthorizationStatus,
+    pub user_id: Option<i32>,
+    pub last_polled_at: Option<OffsetDateTime>,
+    pub expires_at: OffsetDateTime,
+}
+
+/// The codes of a new device authorization request.
+#[derive(Debug, Clone)]
+pub struct DeviceCodes {
+    /// The secret code the client polls with.
+    pub device_code: String,
+    /// The code the user enters in the browser.
+    pub user_code: String,
+}
+
+/// The outcome of polling a device authorization request.
+#[derive(Debug, Clone)]
+pub enum PollResult {
+    /// The user has not decided yet.
+    Pending,
+    /// The client polled more often than the poll interval.
+    SlowDown,
+    /// The user denied the request.
+    Denied,
+    /// The request expired before the user approved it.
+    Expired,
+    /// The user approved the request, and the API token along with the secret token were issued.
+    Approved(ApiToken, String),
+}
+
+fn hash_device_code(device_code: &str) -> Vec<u8> {
+    Sha256::digest(device_code.as_bytes()).to_vec()
+}
+
+fn generate_user_code() -> String {
+    let mut rng = rand::thread_rng();
+    let code: String = (0..USER_CODE_LENGTH)
+        .map(|_| char::from(USER_CODE_ALPHABET[rng.gen_range(0..USER_CODE_ALPHABET.len())]))
+        .collect();
+
+    format!(
+        "{}-{}",
+        &code[..USER_CODE_LENGTH / 2],
+        &code[USER_CODE_LENGTH / 2..]
+    )
+}
+
+/// Normalizes a user code entered by the user, who may leave out the dash or use lowercase.
+pub fn normalize_user_code(user_code: &str) -> String {
+    let code: String = user_code
+        .chars()
+        .map(|c| c.to_ascii_uppercase())
+        .filter(|c| c.is_ascii() && USER_CODE_ALPHABET.contains(&(*c as u8)))
+        .collect();
+
+    if code.len() == USER_CODE_LENGTH {
+        format!(
+            "{}-{}",
+            &code[..USER_CODE_LENGTH / 2],
+            &code[USER_CODE_LENGTH / 2..]
+        )
+    } else {
+        code
+    }
+}
+
+/// Starts a device authorization request for `scopes` that expires after `lifetime`.
+///
+/// Expired requests are removed along the way.
+pub async fn create_device_authorization(
+    client_name: Option<&str>,
+    scopes: Vec<ApiTokenScope>,
+    lifetime: Duration,
+    db: &crate::Database,
+) -> Result<DeviceCodes, Error> {
+    sqlx::query("DELETE FROM device_authorizations WHERE expires_at <= NOW()")
+        .execute(db)
+        .await?;
+
+    let device_code = Alphanumeric.sample_string(&mut rand::thread_rng(), DEVICE_CODE_LENGTH);
+    let expires_at = OffsetDateTime::now_utc() + lifetime;
+
+    for _ in 0..USER_CODE_ATTEMPTS {
+        let user_code = generate_user_code();
+
+        let result = sqlx::query(
+            r"
+            INSERT INTO device_authorizations
+                (device_code_hash, user_code, client_name, scopes, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (user_code) DO NOTHING
+            ",
+        )
+        .bind(hash_device_code(&device_code))
+        .bind(&user_code)
+        .bind(client_name)
+        .bind(&scopes)
+        .bind(expires_at)
+        .execute(db)
+        .await?;
+
+        if result.rows_affected() > 0 {
+            return Ok(DeviceCodes {
+                device_code,
+                user_code,
+            });
+        }
+    }
+
+    Err(Error::UserCodeCollisionLimit)
+}
+
+/// Returns the pending device authorization request with `user_code`, if it has not expired.
+pub async fn get_pending_device_authorization(
+    user_code: &str,
+    db: &crate::Database,
+) -> Result<Option<DeviceAuthorization>, Error> {
+    let authorization = sqlx::query_as(
+        r"
+        SELECT * FROM device_authorizations
+        WHERE user_code = $1 AND status = 'pending' AND expires_at > NOW()
+        ",
+    )
+    .bind(normalize_user_code(user_code))
+    .fetch_optional(db)
+    .await?;
+
+    Ok(authorization)
+}
+
+/// Approves or denies the pending device authorization request with `user_code` on behalf of
+/// `user_id`, and returns whether the request was pending.
+pub async fn decide_device_authorization(
+    user_code: &str,
+    user_id: i32,
+    approved: bool,
+    db: &crate::Database,
+) -> Result<bool, Error> {
+    let status = if approved {
+        DeviceAuthorizationStatus::Approved
+    } else {
+        DeviceAuthorizationStatus::Denied
+    };
+
+    let result = sqlx::query(
+        r"
+        UPDATE device_authorizations SET status = $3, user_id = $2
+        WHERE user_code = $1 AND status = 'pending' AND expires_at > NOW()
+        ",
+    )
+    .bind(normalize_user_code(user_code))
+    .bind(user_id)
+    .bind(status)
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Polls the device authorization request with `device_code`, issuing an API token that expires
+/// after `token_lifetime` once the user approved the request.
+///
+/// Returns `None` if there is no such request. Requests are removed once they are approved,
+/// denied or expired, so each device code is exchanged for a token at most once.
+pub async fn poll_device_authorization(
+    device_code: &str,
+    interval: Duration,
+    token_lifetime: Option<Duration>,
+    db: &crate::Database,
+) -> Result<Option<PollResult>, Error> {
+    let mut tx = db.begin().await?;
+
+    let authorization: Option<DeviceAuthorization> = sqlx::query_as(
+        "SELECT * FROM device_authorizations WHERE device_code_hash = $1 FOR UPDATE",
+    )
+    .bind(hash_device_code(device_code))
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(authorization) = authorization else {
+        return Ok(None);
+    };
+
+    let now = OffsetDateTime::now_utc();
+
+    let result = match (authorization.status, authorization.user_id) {
+        _ if authorization.expires_at <= now => PollResult::Expired,
+        (DeviceAuthorizationStatus::Approved, Some(user_id)) => {
+            let name = match authorization.client_name {
+                Some(ref client_name) => format!("Device login ({client_name})"),
+                None => "Device login".to_string(),
+            };
+            let (api_token, token) = api_token::create_api_token(
+                user_id,
+                &name,
+                authorization.scopes,
+                token_lifetime,
+                &mut tx,
+            )
+            .await?;
+
+            PollResult::Approved(api_token, token)
+        }
+        (DeviceAuthorizationStatus::Denied, _) => PollResult::Denied,
+        _ => {
+            sqlx::query("UPDATE device_authorizations SET last_polled_at = NOW() WHERE id = $1")
+                .bind(authorization.id)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+
+            let too_early = authorization
+                .last_polled_at
+                .is_some_and(|last_polled_at| last_polled_at + interval > now);
+
+            return Ok(Some(if too_early {
+                PollResult::SlowDown
+            } else {
+                PollResult::Pending
+            }));
+        }
+    };
+
+    sqlx::query("DELETE FROM device_authorizations WHERE id = $1")
+        .bind(authorization.id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(Some(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_user_codes_in_two_groups() {
+        for _ in 0..100 {
+            let user_code = generate_user_code();
+            let (first, second) = user_code.split_once('-').unwrap();
+
+            assert_eq!(first.len(), USER_CODE_LENGTH / 2);
+            assert_eq!(second.len(), USER_CODE_LENGTH / 2);
+            assert!(first
+                .bytes()
+                .chain(second.bytes())
+                .all(|c| USER_CODE_ALPHABET.contains(&c)));
+            assert_eq!(normalize_user_code(&user_code), user_code);
+        }
+    }
+
+    #[test]
+    fn normalizes_user_codes() {
+        assert_eq!(normalize_user_code("BCDF-GHJK"), "BCDF-GHJK");
+        assert_eq!(normalize_user_code("bcdfghjk"), "BCDF-GHJK");
+        assert_eq!(normalize_user_code(" bcd f-gh jk "), "BCDF-GHJK");
+        assert_eq!(normalize_user_code("BCDF–GHJK"), "BCDF-GHJK");
+    }
+
+    #[test]
+    fn leaves_user_codes_of_the_wrong_length_incomplete() {
+        assert_eq!(normalize_user_code("bcdf-ghj"), "BCDFGHJ");
+        assert_eq!(normalize_user_code("BCDF-GHJKL"), "BCDFGHJKL");
+        assert_eq!(normalize_user_code("AEIO-UBCD"), "BCD");
+    }
+}
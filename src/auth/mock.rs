@@ -0,0 +1,218 @@
+//! A mock OpenID Connect issuer for local development and integration tests
+//!
+//! The issuer is served by this server at [`MOUNT_PATH`] and logs in any e-mail address without
+//! a password. Authorization codes carry the claims of the user, and ID tokens are signed with
+//! the client secret, so the issuer keeps no state and needs no signing keys.
+
+use std::time::Duration;
+
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use openidconnect::{
+    core::{
+        CoreHmacKey, CoreIdToken, CoreIdTokenClaims, CoreIdTokenFields, CoreJwsSigningAlgorithm,
+        CoreResponseType, CoreSubjectIdentifierType, CoreTokenResponse, CoreTokenType,
+    },
+    url::Url,
+    AccessToken, Audience, AuthUrl, EmptyAdditionalClaims, EmptyAdditionalProviderMetadata,
+    EmptyExtraTokenFields, EndSessionUrl, EndUserEmail, IssuerUrl, JsonWebKeySetUrl,
+    JsonWebTokenError, LogoutProviderMetadata, Nonce, ProviderMetadataWithLogout, ResponseTypes,
+    Scope, StandardClaims, SubjectIdentifier, TokenUrl,
+};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+/// The name of the provider backed by the mock issuer.
+pub const PROVIDER_NAME: &str = "mock";
+/// The path the mock issuer is served at.
+pub const MOUNT_PATH: &str = "/api/auth/mock";
+
+/// The client id of this server at the mock issuer.
+const CLIENT_ID: &str = "masked-mails";
+/// The length of generated client secrets and access tokens.
+const SECRET_LENGTH: usize = 32;
+/// The lifetime of issued access and ID tokens.
+const TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// The claims carried by an authorization code.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MockCode {
+    email: String,
+    nonce: Option<String>,
+}
+
+/// The parameters of an authorization request to the mock issuer.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthorizeRequest {
+    pub redirect_uri: Url,
+    pub state: String,
+    pub nonce: Option<String>,
+    /// The e-mail address to log in as. A login form is shown if it is missing.
+    pub login_hint: Option<String>,
+}
+
+/// The parameters of an RP-initiated logout request to the mock issuer.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EndSessionRequest {
+    pub post_logout_redirect_uri: Option<Url>,
+    pub state: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct MockIssuer {
+    issuer_url: IssuerUrl,
+    auth_url: AuthUrl,
+    token_url: TokenUrl,
+    jwks_url: JsonWebKeySetUrl,
+    end_session_url: EndSessionUrl,
+    redirect_url: Url,
+    client_secret: String,
+}
+
+impl std::fmt::Debug for MockIssuer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MockIssuer")
+            .field("issuer_url", &self.issuer_url)
+            .field("client_secret", &"[redacted]")
+            .finish()
+    }
+}
+
+impl MockIssuer {
+    /// Creates a mock issuer served on the same origin as `redirect_url`, with a newly generated
+    /// client secret.
+    pub fn new(redirect_url: &Url) -> Result<Self, Error> {
+        let url = |path: &str| {
+            redirect_url
+                .join(&format!("{MOUNT_PATH}{path}"))
+                .map_err(|_| Error::InvalidRedirectUrl)
+        };
+
+        Ok(MockIssuer {
+            issuer_url: IssuerUrl::from_url(url("")?),
+            auth_url: AuthUrl::from_url(url("/authorize")?),
+            token_url: TokenUrl::from_url(url("/token")?),
+            jwks_url: JsonWebKeySetUrl::from_url(url("/jwks")?),
+            end_session_url: EndSessionUrl::from_url(url("/logout")?),
+            redirect_url: redirect_url.clone(),
+            client_secret: Alphanumeric.sample_string(&mut rand::thread_rng(), SECRET_LENGTH),
+        })
+    }
+
+    pub fn client_id(&self) -> &str {
+        CLIENT_ID
+    }
+
+    pub fn client_secret(&self) -> &str {
+        &self.client_secret
+    }
+
+    /// Returns the discovery document of the issuer.
+    pub fn metadata(&self) -> ProviderMetadataWithLogout {
+        ProviderMetadataWithLogout::new(
+            self.issuer_url.clone(),
+            self.auth_url.clone(),
+            self.jwks_url.clone(),
+            vec![ResponseTypes::new(vec![CoreResponseType::Code])],
+            vec![CoreSubjectIdentifierType::Public],
+            vec![CoreJwsSigningAlgorithm::HmacSha256],
+            LogoutProviderMetadata {
+                end_session_endpoint: Some(self.end_session_url.clone()),
+                additional_metadata: EmptyAdditionalProviderMetadata {},
+            },
+        )
+        .set_token_endpoint(Some(self.token_url.clone()))
+        .set_scopes_supported(Some(
+            ["openid", "email", "profile"]
+                .map(|scope| Scope::new(scope.to_string()))
+                .to_vec(),
+        ))
+    }
+
+    /// Logs in as `email` and returns the URL the user agent is redirected to with the
+    /// authorization code.
+    ///
+    /// Returns `None` if the request is not redirected to the redirect url of this server.
+    pub fn authorize(&self, request: &AuthorizeRequest, email: &str) -> Option<Url> {
+        if request.redirect_uri != self.redirect_url {
+            return None;
+        }
+
+        let code = MockCode {
+            email: email.to_string(),
+            nonce: request.nonce.clone(),
+        };
+        let code = BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(&code).ok()?);
+
+        let mut url = request.redirect_uri.clone();
+        url.query_pairs_mut()
+            .append_pair("code", &code)
+            .append_pair("state", &request.state);
+
+        Some(url)
+    }
+
+    /// Returns the URL the user agent is redirected to after logging out.
+    ///
+    /// The issuer keeps no sessions, so there is nothing to end. Returns `None` if the request is
+    /// not redirected to the origin of this server.
+    pub fn end_session(&self, request: &EndSessionRequest) -> Option<Url> {
+        let mut url = request.post_logout_redirect_uri.clone()?;
+
+        if url.origin() != self.redirect_url.origin() {
+            return None;
+        }
+
+        if let Some(ref state) = request.state {
+            url.query_pairs_mut().append_pair("state", state);
+        }
+
+        Some(url)
+    }
+
+    /// Exchanges an authorization code for an access token and an ID token.
+    ///
+    /// Returns `None` if the code was not issued by [`MockIssuer::authorize`].
+    pub fn token(&self, code: &str) -> Result<Option<CoreTokenResponse>, JsonWebTokenError> {
+        let Some(code) = BASE64_URL_SAFE_NO_PAD
+            .decode(code)
+            .ok()
+            .and_then(|code| serde_json::from_slice::<MockCode>(&code).ok())
+        else {
+            return Ok(None);
+        };
+
+        let now = chrono::Utc::now();
+        let claims = CoreIdTokenClaims::new(
+            self.issuer_url.clone(),
+            vec![Audience::new(CLIENT_ID.to_string())],
+            now + chrono::Duration::seconds(TOKEN_LIFETIME.as_secs() as i64),
+            now,
+            StandardClaims::new(SubjectIdentifier::new(code.email.clone()))
+                .set_email(Some(EndUserEmail::new(code.email)))
+                .set_email_verified(Some(true)),
+            EmptyAdditionalClaims {},
+        )
+        .set_nonce(code.nonce.map(Nonce::new));
+
+        let access_token =
+            AccessToken::new(Alphanumeric.sample_string(&mut rand::thread_rng(), SECRET_LENGTH));
+        let id_token = CoreIdToken::new(
+            claims,
+            &CoreHmacKey::new(self.client_secret.as_bytes()),
+            CoreJwsSigningAlgorithm::HmacSha256,
+            Some(&access_token),
+            None,
+        )?;
+
+        let mut response = CoreTokenResponse::new(
+            access_token,
+            CoreTokenType::Bearer,
+            CoreIdTokenFields::new(Some(id_token), EmptyExtraTokenFields {}),
+        );
+        response.set_expires_in(Some(&TOKEN_LIFETIME));
+
+        Ok(Some(response))
+    }
+}
@@ -0,0 +1,168 @@
+//! OpenID Connect providers
+
+use std::time::Duration;
+
+use openidconnect::{
+    core::{
+        CoreAuthenticationFlow, CoreClient, CoreIdToken, CoreIdTokenVerifier,
+        CoreJwsSigningAlgorithm,
+    },
+    reqwest::async_http_client,
+    url::Url,
+    ClientId, ClientSecret, CsrfToken, EndSessionUrl, IssuerUrl, LogoutRequest, Nonce,
+    PostLogoutRedirectUrl, ProviderMetadataWithLogout, RedirectUrl, Scope,
+};
+use tracing::debug;
+
+use crate::{config::ProviderConfig, Error};
+
+use super::mock::MockIssuer;
+
+/// An OpenID Connect provider users can log in with.
+#[derive(Clone, Debug)]
+pub struct Provider {
+    name: String,
+    client: CoreClient,
+    client_id: ClientId,
+    /// The endpoint for RP-initiated logout, if the provider supports it.
+    end_session_url: Option<EndSessionUrl>,
+    offline_access: bool,
+    /// Whether ID tokens are signed with the client secret, as done by the mock issuer.
+    hmac_signed: bool,
+    /// Whether this is the built-in mock issuer, which logs in any e-mail address.
+    mock: bool,
+}
+
+impl Provider {
+    /// Creates a provider based on the properties of its OpenID Connect Discovery document.
+    pub async fn discover(config: &ProviderConfig, redirect_url: &Url) -> Result<Self, Error> {
+        debug!(provider = %config.name, "running openid connect discovery");
+
+        let issuer_url = IssuerUrl::from_url(config.issuer_url.clone());
+        let provider_metadata =
+            ProviderMetadataWithLogout::discover_async(issuer_url, async_http_client)
+                .await
+                .map_err(|_| Error::DiscoverOidcFailed)?;
+        let end_session_url = provider_metadata
+            .additional_metadata()
+            .end_session_endpoint
+            .clone();
+
+        let client_id = ClientId::new(config.client_id.clone());
+        let client = CoreClient::from_provider_metadata(
+            provider_metadata,
+            client_id.clone(),
+            Some(ClientSecret::new(config.client_secret.clone())),
+        )
+        .set_redirect_uri(RedirectUrl::from_url(redirect_url.clone()));
+
+        debug!(provider = %config.name, "finished openid connect discovery");
+
+        Ok(Provider {
+            name: config.name.clone(),
+            client,
+            client_id,
+            end_session_url,
+            offline_access: config.offline_access,
+            hmac_signed: false,
+            mock: false,
+        })
+    }
+
+    /// Creates the provider for the built-in mock issuer, which needs no discovery.
+    pub fn mock(issuer: &MockIssuer, redirect_url: &Url) -> Self {
+        let metadata = issuer.metadata();
+        let end_session_url = metadata.additional_metadata().end_session_endpoint.clone();
+        let client_id = ClientId::new(issuer.client_id().to_string());
+        let client = CoreClient::from_provider_metadata(
+            metadata,
+            client_id.clone(),
+            Some(ClientSecret::new(issuer.client_secret().to_string())),
+        )
+        .set_redirect_uri(RedirectUrl::from_url(redirect_url.clone()));
+
+        Provider {
+            name: super::mock::PROVIDER_NAME.to_string(),
+            client,
+            client_id,
+            end_session_url,
+            offline_access: false,
+            hmac_signed: true,
+            mock: true,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns whether this is the built-in mock issuer.
+    pub fn is_mock(&self) -> bool {
+        self.mock
+    }
+
+    pub(super) fn client(&self) -> &CoreClient {
+        &self.client
+    }
+
+    /// Returns the verifier for ID tokens and logout tokens issued by this provider.
+    pub(super) fn id_token_verifier(&self) -> CoreIdTokenVerifier<'_> {
+        let verifier = self.client.id_token_verifier();
+
+        if self.hmac_signed {
+            verifier.set_allowed_algs([CoreJwsSigningAlgorithm::HmacSha256])
+        } else {
+            verifier
+        }
+    }
+
+    /// Returns the authorization URL, which asks the user to authenticate again even if they are
+    /// logged in at the provider if `reauthenticate` is set.
+    pub fn authorize_url(&self, reauthenticate: bool) -> (Url, CsrfToken, Nonce) {
+        // Generate the full authorization URL
+        let mut request = self
+            .client
+            .authorize_url(
+                CoreAuthenticationFlow::AuthorizationCode,
+                CsrfToken::new_random,
+                Nonce::new_random,
+            )
+            // Set the desired scopes.
+            .add_scope(Scope::new("email".to_string()))
+            .add_scope(Scope::new("profile".to_string()));
+
+        if self.offline_access {
+            request = request.add_scope(Scope::new("offline_access".to_string()));
+        }
+
+        // A maximum authentication age of zero requires a new authentication, whose time is
+        // then included in the ID token.
+        if reauthenticate {
+            request = request.set_max_age(Duration::ZERO);
+        }
+
+        request.url()
+    }
+
+    /// Returns the URL the user agent is redirected to for logging out at the provider as well,
+    /// or `None` if the provider does not support RP-initiated logout.
+    pub fn end_session_url(
+        &self,
+        id_token_hint: Option<&CoreIdToken>,
+        post_logout_redirect_url: Option<&Url>,
+    ) -> Option<Url> {
+        let mut request = LogoutRequest::from(self.end_session_url.clone()?)
+            .set_client_id(self.client_id.clone());
+
+        if let Some(id_token) = id_token_hint {
+            request = request.set_id_token_hint(id_token);
+        }
+
+        if let Some(url) = post_logout_redirect_url {
+            request =
+                request.set_post_logout_redirect_uri(PostLogoutRedirectUrl::from_url(url.clone()));
+        }
+
+        Some(request.http_get_url())
+    }
+}
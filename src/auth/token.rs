@@ -0,0 +1,116 @@
+//! Encryption of OAuth tokens at rest
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use base64::prelude::{Engine, BASE64_STANDARD};
+
+use crate::Error;
+
+/// The length of the nonce prepended to encrypted tokens.
+const NONCE_LENGTH: usize = 12;
+
+/// Encrypts tokens with AES-256-GCM before they are stored in the database.
+#[derive(Clone)]
+pub struct TokenCipher(Aes256Gcm);
+
+impl std::fmt::Debug for TokenCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("TokenCipher").field(&"[redacted]").finish()
+    }
+}
+
+impl TokenCipher {
+    /// Creates a cipher from a base64-encoded 256-bit key.
+    pub fn from_base64(key: &str) -> Result<Self, Error> {
+        let key = BASE64_STANDARD
+            .decode(key)
+            .map_err(|_| Error::InvalidTokenKey)?;
+        let cipher = Aes256Gcm::new_from_slice(&key).map_err(|_| Error::InvalidTokenKey)?;
+
+        Ok(TokenCipher(cipher))
+    }
+
+    /// Encrypts `token` with a random nonce, which is prepended to the ciphertext.
+    pub fn encrypt(&self, token: &str) -> Result<Vec<u8>, aes_gcm::Error> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self.0.encrypt(&nonce, token.as_bytes())?;
+
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    /// Decrypts a token encrypted by [`TokenCipher::encrypt`].
+    pub fn decrypt(&self, data: &[u8]) -> Result<String, aes_gcm::Error> {
+        if data.len() < NONCE_LENGTH {
+            return Err(aes_gcm::Error);
+        }
+
+        let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+        let token = self.0.decrypt(Nonce::from_slice(nonce), ciphertext)?;
+
+        String::from_utf8(token).map_err(|_| aes_gcm::Error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+    const OTHER_KEY: &str = "HxAdHBsaGRgXFhUUExIREA8ODQwLCgkIBwYFBAMCAQA=";
+
+    #[test]
+    fn decrypts_encrypted_tokens() {
+        let cipher = TokenCipher::from_base64(KEY).unwrap();
+        let encrypted = cipher.encrypt("refresh-token").unwrap();
+
+        assert_ne!(&encrypted[NONCE_LENGTH..], b"refresh-token");
+        assert_eq!(cipher.decrypt(&encrypted).unwrap(), "refresh-token");
+    }
+
+    #[test]
+    fn uses_a_new_nonce_for_each_token() {
+        let cipher = TokenCipher::from_base64(KEY).unwrap();
+
+        assert_ne!(
+            cipher.encrypt("refresh-token").unwrap(),
+            cipher.encrypt("refresh-token").unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_tokens_encrypted_with_another_key() {
+        let encrypted = TokenCipher::from_base64(OTHER_KEY)
+            .unwrap()
+            .encrypt("refresh-token")
+            .unwrap();
+
+        assert!(TokenCipher::from_base64(KEY)
+            .unwrap()
+            .decrypt(&encrypted)
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_tampered_tokens() {
+        let cipher = TokenCipher::from_base64(KEY).unwrap();
+        let encrypted = cipher.encrypt("refresh-token").unwrap();
+
+        for index in [0, NONCE_LENGTH, encrypted.len() - 1] {
+            let mut tampered = encrypted.clone();
+            tampered[index] ^= 1;
+
+            assert!(cipher.decrypt(&tampered).is_err(), "byte {index}");
+        }
+
+        assert!(cipher.decrypt(&encrypted[..NONCE_LENGTH - 1]).is_err());
+        assert!(cipher.decrypt(&encrypted[..encrypted.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_keys() {
+        assert!(TokenCipher::from_base64("not base64!").is_err());
+        assert!(TokenCipher::from_base64("AAECAwQFBgcICQoLDA0ODw==").is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
 use serde::{Deserialize, Serialize};
 use url::Url;
@@ -13,18 +13,95 @@ pub struct Config {
     pub tracing: TracingConfig,
     /// Ingestion configuration
     pub ingestion: IngestionConfig,
+    /// Forwarding configuration
+    #[serde(default)]
+    pub forwarding: ForwardingConfig,
+    /// Spam scoring configuration
+    #[serde(default)]
+    pub spam: SpamConfig,
+    /// Quarantine configuration
+    #[serde(default)]
+    pub quarantine: QuarantineConfig,
+    /// Webhook delivery configuration
+    #[serde(default)]
+    pub webhooks: WebhooksConfig,
+    /// Address configuration
+    #[serde(default)]
+    pub addresses: AddressesConfig,
+    /// Session and session cookie configuration
+    #[serde(default)]
+    pub session: SessionConfig,
+    /// HTTP server configuration
+    #[serde(default)]
+    pub http: HttpConfig,
+    /// Metrics configuration
+    #[serde(default)]
+    pub metrics: MetricsConfig,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct AuthConfig {
+    /// OAuth redirect (callback) url, shared by all providers
+    pub redirect_url: Url,
+    /// URL providers redirect to after logging out, which must be registered at each provider.
+    /// Providers show their own page after logging out if unset.
+    pub post_logout_redirect_url: Option<Url>,
+    /// Base64-encoded 256-bit key that refresh tokens are encrypted with
+    pub token_key: String,
+    /// OpenID Connect providers users can log in with. The first one is used when the login does
+    /// not select a provider.
+    #[serde(default)]
+    pub providers: Vec<ProviderConfig>,
+    /// Serve a mock OpenID Connect issuer at `/api/auth/mock` that logs in any e-mail address,
+    /// for local development and integration tests. Never enable this in production. It cannot
+    /// be combined with other providers.
+    #[serde(default)]
+    pub mock: bool,
+    /// Device authorization flow used by the command line client
+    #[serde(default)]
+    pub device: DeviceConfig,
+    /// Duration after authenticating at the provider or with a passkey during which sensitive
+    /// operations, like deleting addresses or exporting data, are allowed
+    #[serde(default = "default_step_up_max_age", with = "humantime_serde")]
+    pub step_up_max_age: Duration,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeviceConfig {
+    /// Duration during which the user can approve a device authorization request
+    #[serde(default = "default_device_code_lifetime", with = "humantime_serde")]
+    pub code_lifetime: Duration,
+    /// Minimum interval at which clients poll for the outcome of a request
+    #[serde(default = "default_device_poll_interval", with = "humantime_serde")]
+    pub poll_interval: Duration,
+    /// Duration after which issued API tokens expire. Tokens never expire if unset.
+    #[serde(default = "default_api_token_lifetime", with = "humantime_serde")]
+    pub token_lifetime: Option<Duration>,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        DeviceConfig {
+            code_lifetime: default_device_code_lifetime(),
+            poll_interval: default_device_poll_interval(),
+            token_lifetime: default_api_token_lifetime(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProviderConfig {
+    /// Name of the provider, used to select it at `/api/auth/login?provider=<name>`
+    pub name: String,
     /// OpenID issuer
     pub issuer_url: Url,
     /// OAuth client id
     pub client_id: String,
     /// OAuth client secret
     pub client_secret: String,
-    /// OAuth redirect (callback) url
-    pub redirect_url: Url,
+    /// Request the `offline_access` scope, which some providers require to issue refresh tokens
+    #[serde(default)]
+    pub offline_access: bool,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -45,6 +122,265 @@ pub struct IngestionConfig {
     pub api_token: String,
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ForwardingConfig {
+    /// SMTP relay connection URL, e.g. `smtp://localhost:25`
+    #[serde(default = "default_forwarding_relay_url")]
+    pub relay_url: String,
+    /// Interval at which the forwarding queue is polled
+    #[serde(default = "default_forwarding_poll_interval", with = "humantime_serde")]
+    pub poll_interval: Duration,
+    /// Maximum number of delivery attempts before a message is marked as failed
+    #[serde(default = "default_forwarding_max_attempts")]
+    pub max_attempts: i32,
+    /// Web Key Directory to query for OpenPGP keys instead of the domain of the user's e-mail
+    pub wkd_url: Option<Url>,
+    /// Maximum duration of a request to an HTTP destination
+    #[serde(default = "default_forwarding_http_timeout", with = "humantime_serde")]
+    pub http_timeout: Duration,
+    /// Public base URL of this server, used for attachment links sent to HTTP destinations and
+    /// mailbox confirmation links
+    pub public_url: Option<Url>,
+    /// Address that mailbox confirmation links are sent from
+    #[serde(default = "default_forwarding_sender")]
+    pub sender: String,
+    /// Duration after which forwarded mail, and mail that could not be forwarded, is deleted along
+    /// with its attachments. Attachment links sent to HTTP destinations stop working once the mail
+    /// is deleted.
+    #[serde(default = "default_forwarding_retention", with = "humantime_serde")]
+    pub retention: Duration,
+    /// Interval at which expired forwarded mail is deleted
+    #[serde(
+        default = "default_forwarding_purge_interval",
+        with = "humantime_serde"
+    )]
+    pub purge_interval: Duration,
+}
+
+impl Default for ForwardingConfig {
+    fn default() -> Self {
+        ForwardingConfig {
+            relay_url: default_forwarding_relay_url(),
+            poll_interval: default_forwarding_poll_interval(),
+            max_attempts: default_forwarding_max_attempts(),
+            wkd_url: None,
+            http_timeout: default_forwarding_http_timeout(),
+            public_url: None,
+            sender: default_forwarding_sender(),
+            retention: default_forwarding_retention(),
+            purge_interval: default_forwarding_purge_interval(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SpamConfig {
+    /// Score mail using header heuristics
+    #[serde(default = "default_true")]
+    pub headers: bool,
+    /// The authserv-id of the ingress, e.g. `mx.cloudflare.net`, whose `Authentication-Results`
+    /// headers are trusted. Other `Authentication-Results` headers are ignored, since they may
+    /// have been added by the sender.
+    pub authserv_id: Option<String>,
+    /// Score mail using a Bayesian classifier trained from spam reports of each user
+    #[serde(default = "default_true")]
+    pub bayes: bool,
+    /// The `host:port` of a spamd or rspamd server to score mail with
+    pub spamd_address: Option<String>,
+    /// Maximum duration of a spamd request
+    #[serde(default = "default_spamd_timeout", with = "humantime_serde")]
+    pub spamd_timeout: Duration,
+    /// Score at which mail is tagged as spam, unless overridden by the address
+    #[serde(default = "default_spam_tag_threshold")]
+    pub tag_threshold: f64,
+    /// Score at which mail is quarantined, unless overridden by the address
+    #[serde(default = "default_spam_quarantine_threshold")]
+    pub quarantine_threshold: f64,
+}
+
+impl Default for SpamConfig {
+    fn default() -> Self {
+        SpamConfig {
+            headers: true,
+            authserv_id: None,
+            bayes: true,
+            spamd_address: None,
+            spamd_timeout: default_spamd_timeout(),
+            tag_threshold: default_spam_tag_threshold(),
+            quarantine_threshold: default_spam_quarantine_threshold(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct QuarantineConfig {
+    /// Duration after which quarantined mail is deleted
+    #[serde(default = "default_quarantine_retention", with = "humantime_serde")]
+    pub retention: Duration,
+    /// Interval at which expired quarantined mail is deleted
+    #[serde(
+        default = "default_quarantine_purge_interval",
+        with = "humantime_serde"
+    )]
+    pub purge_interval: Duration,
+}
+
+impl Default for QuarantineConfig {
+    fn default() -> Self {
+        QuarantineConfig {
+            retention: default_quarantine_retention(),
+            purge_interval: default_quarantine_purge_interval(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WebhooksConfig {
+    /// Interval at which the webhook outbox is polled
+    #[serde(default = "default_webhooks_poll_interval", with = "humantime_serde")]
+    pub poll_interval: Duration,
+    /// Maximum number of delivery attempts before a webhook event is marked as failed
+    #[serde(default = "default_webhooks_max_attempts")]
+    pub max_attempts: i32,
+    /// Maximum duration of a webhook request
+    #[serde(default = "default_webhooks_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        WebhooksConfig {
+            poll_interval: default_webhooks_poll_interval(),
+            max_attempts: default_webhooks_max_attempts(),
+            timeout: default_webhooks_timeout(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AddressesConfig {
+    /// Duration during which a deleted address can be restored
+    #[serde(default = "default_addresses_restore_period", with = "humantime_serde")]
+    pub restore_period: Duration,
+}
+
+impl Default for AddressesConfig {
+    fn default() -> Self {
+        AddressesConfig {
+            restore_period: default_addresses_restore_period(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SessionConfig {
+    /// Only send the session cookie over HTTPS. Disable this for local development over HTTP.
+    #[serde(default = "default_true")]
+    pub secure: bool,
+    /// Domain of the session cookie. The cookie is only sent to the host that set it if unset.
+    pub domain: Option<String>,
+    /// Name of the session cookie
+    #[serde(default = "default_session_cookie_name")]
+    pub name: String,
+    /// SameSite attribute of the session cookie, `strict`, `lax` or `none`
+    #[serde(default)]
+    pub same_site: SameSite,
+    /// Duration of inactivity after which a session expires
+    #[serde(default = "default_session_idle_timeout", with = "humantime_serde")]
+    pub idle_timeout: Duration,
+    /// Duration after logging in after which a session expires, even if it is in use
+    #[serde(default = "default_session_lifetime", with = "humantime_serde")]
+    pub lifetime: Duration,
+    /// Interval at which expired sessions are deleted from the database
+    #[serde(default = "default_session_cleanup_interval", with = "humantime_serde")]
+    pub cleanup_interval: Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            secure: true,
+            domain: None,
+            name: default_session_cookie_name(),
+            same_site: SameSite::default(),
+            idle_timeout: default_session_idle_timeout(),
+            lifetime: default_session_lifetime(),
+            cleanup_interval: default_session_cleanup_interval(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HttpConfig {
+    /// Addresses to listen on
+    #[serde(default = "default_http_listen")]
+    pub listen: Vec<SocketAddr>,
+    /// Path of a Unix domain socket to listen on in addition to the addresses
+    pub unix_socket: Option<PathBuf>,
+    /// Terminate TLS on the listen addresses. The Unix domain socket always serves plain HTTP.
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            listen: default_http_listen(),
+            unix_socket: None,
+            tls: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TlsConfig {
+    /// Path of the PEM-encoded certificate chain
+    pub certificate: PathBuf,
+    /// Path of the PEM-encoded private key
+    pub key: PathBuf,
+    /// Interval at which the certificate and key are reloaded if they changed on disk
+    #[serde(default = "default_tls_reload_interval", with = "humantime_serde")]
+    pub reload_interval: Duration,
+}
+
+/// The SameSite attribute of a cookie.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SameSite {
+    Strict,
+    #[default]
+    Lax,
+    None,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MetricsConfig {
+    /// Serve metrics in the Prometheus text format at `/metrics` on `listen`
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Address to serve metrics on. Metrics are not served on the addresses of the API, so that
+    /// only the network of this address can read them.
+    #[serde(default = "default_metrics_listen")]
+    pub listen: SocketAddr,
+    /// Export metrics via OTLP, to the same collector as traces
+    #[serde(default)]
+    pub otlp: bool,
+    /// Interval at which metrics kept in the database, like the forwarding queue depth, are
+    /// sampled
+    #[serde(default = "default_metrics_sample_interval", with = "humantime_serde")]
+    pub sample_interval: Duration,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enabled: true,
+            listen: default_metrics_listen(),
+            otlp: false,
+            sample_interval: default_metrics_sample_interval(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct TracingConfig {
     /// Enable tracing
@@ -58,3 +394,119 @@ pub const fn default_max_db_connections() -> usize {
 pub const fn default_db_idle_timeout() -> Duration {
     crate::database::DEFAULT_IDLE_TIMEOUT
 }
+
+pub fn default_forwarding_relay_url() -> String {
+    crate::forwarding::DEFAULT_RELAY_URL.to_string()
+}
+
+pub const fn default_forwarding_poll_interval() -> Duration {
+    crate::forwarding::DEFAULT_POLL_INTERVAL
+}
+
+pub const fn default_forwarding_max_attempts() -> i32 {
+    crate::forwarding::DEFAULT_MAX_ATTEMPTS
+}
+
+pub const fn default_forwarding_http_timeout() -> Duration {
+    crate::forwarding::DEFAULT_HTTP_TIMEOUT
+}
+
+pub fn default_forwarding_sender() -> String {
+    crate::forwarding::DEFAULT_SENDER.to_string()
+}
+
+pub const fn default_forwarding_retention() -> Duration {
+    crate::forwarding::DEFAULT_RETENTION
+}
+
+pub const fn default_forwarding_purge_interval() -> Duration {
+    crate::forwarding::DEFAULT_PURGE_INTERVAL
+}
+
+pub const fn default_step_up_max_age() -> Duration {
+    crate::auth::DEFAULT_STEP_UP_MAX_AGE
+}
+
+pub const fn default_device_code_lifetime() -> Duration {
+    crate::auth::device::DEFAULT_CODE_LIFETIME
+}
+
+pub const fn default_device_poll_interval() -> Duration {
+    crate::auth::device::DEFAULT_POLL_INTERVAL
+}
+
+pub const fn default_api_token_lifetime() -> Option<Duration> {
+    Some(crate::auth::device::DEFAULT_TOKEN_LIFETIME)
+}
+
+pub fn default_session_cookie_name() -> String {
+    crate::http::DEFAULT_SESSION_COOKIE_NAME.to_string()
+}
+
+pub const fn default_session_idle_timeout() -> Duration {
+    crate::http::DEFAULT_SESSION_IDLE_TIMEOUT
+}
+
+pub const fn default_session_lifetime() -> Duration {
+    crate::http::DEFAULT_SESSION_LIFETIME
+}
+
+pub const fn default_session_cleanup_interval() -> Duration {
+    crate::http::DEFAULT_SESSION_CLEANUP_INTERVAL
+}
+
+pub fn default_http_listen() -> Vec<SocketAddr> {
+    vec![crate::http::DEFAULT_LISTEN_ADDRESS]
+}
+
+pub const fn default_tls_reload_interval() -> Duration {
+    crate::http::tls::DEFAULT_RELOAD_INTERVAL
+}
+
+pub const fn default_metrics_listen() -> SocketAddr {
+    crate::metrics::DEFAULT_LISTEN_ADDRESS
+}
+
+pub const fn default_metrics_sample_interval() -> Duration {
+    crate::metrics::DEFAULT_SAMPLE_INTERVAL
+}
+
+pub const fn default_true() -> bool {
+    true
+}
+
+pub const fn default_spamd_timeout() -> Duration {
+    crate::spam::spamd::DEFAULT_TIMEOUT
+}
+
+pub const fn default_spam_tag_threshold() -> f64 {
+    crate::spam::DEFAULT_TAG_THRESHOLD
+}
+
+pub const fn default_spam_quarantine_threshold() -> f64 {
+    crate::spam::DEFAULT_QUARANTINE_THRESHOLD
+}
+
+pub const fn default_quarantine_retention() -> Duration {
+    crate::quarantine::DEFAULT_RETENTION
+}
+
+pub const fn default_quarantine_purge_interval() -> Duration {
+    crate::quarantine::DEFAULT_PURGE_INTERVAL
+}
+
+pub const fn default_webhooks_poll_interval() -> Duration {
+    crate::webhooks::DEFAULT_POLL_INTERVAL
+}
+
+pub const fn default_webhooks_max_attempts() -> i32 {
+    crate::webhooks::DEFAULT_MAX_ATTEMPTS
+}
+
+pub const fn default_webhooks_timeout() -> Duration {
+    crate::webhooks::DEFAULT_TIMEOUT
+}
+
+pub const fn default_addresses_restore_period() -> Duration {
+    crate::api::v1::address::DEFAULT_RESTORE_PERIOD
+}
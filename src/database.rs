@@ -24,6 +24,25 @@ pub async fn connect(url: &str, config: &crate::config::DbConfig) -> Result<Data
     Ok(pool)
 }
 
+/// Returns whether the database answers queries.
+pub async fn ping(pool: &Database) -> Result<bool, Error> {
+    sqlx::query("SELECT 1").execute(pool).await?;
+
+    Ok(true)
+}
+
+/// Returns whether all migrations were applied successfully.
+pub async fn migrations_applied(pool: &Database) -> Result<bool, Error> {
+    let applied: Vec<(i64,)> = sqlx::query_as("SELECT version FROM _sqlx_migrations WHERE success")
+        .fetch_all(pool)
+        .await?;
+
+    Ok(MIGRATOR
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .all(|migration| applied.contains(&(migration.version,))))
+}
+
 pub async fn migrate(pool: Database) -> Result<(), Error> {
     let mut conn = pool.acquire().await.map_err(Error::DatabaseConnAcqError)?;
 
@@ -32,3 +51,70 @@ pub async fn migrate(pool: Database) -> Result<(), Error> {
         .await
         .map_err(Error::DatabaseMigrationError)
 }
+
+/// Connects to the database at `DATABASE_URL` for tests that need one, applying the migrations
+/// and creating the session store on first use.
+///
+/// Tests using it are ignored by default and run with `cargo test -- --ignored`. They do not
+/// clean up after themselves, so the database should be a scratch one.
+#[cfg(test)]
+pub async fn test_database() -> Database {
+    static MIGRATED: tokio::sync::OnceCell<()> = tokio::sync::OnceCell::const_new();
+
+    let url = std::env::var("DATABASE_URL").expect("DATABASE_URL should be set");
+    let pool = PgPoolOptions::new().connect(&url).await.unwrap();
+
+    MIGRATED
+        .get_or_init(|| async {
+            migrate(pool.clone()).await.unwrap();
+            tower_sessions_sqlx_store::PostgresStore::new(pool.clone())
+                .migrate()
+                .await
+                .unwrap();
+        })
+        .await;
+
+    pool
+}
+
+/// Creates a user with a random e-mail address for tests and returns its id.
+#[cfg(test)]
+pub async fn create_test_user(db: &Database) -> i32 {
+    use rand::distributions::{Alphanumeric, DistString};
+
+    let email = format!(
+        "{}@example.com",
+        Alphanumeric.sample_string(&mut rand::thread_rng(), 16)
+    );
+    let (user_id,): (i32,) =
+        sqlx::query_as("INSERT INTO users (email, access_token) VALUES ($1, '') RETURNING id")
+            .bind(email)
+            .fetch_one(db)
+            .await
+            .unwrap();
+
+    user_id
+}
+
+/// Creates an address with a random local part on the first domain for `user_id` in tests.
+#[cfg(test)]
+pub async fn create_test_address(db: &Database, user_id: i32) -> crate::api::v1::address::Address {
+    use rand::distributions::{Alphanumeric, DistString};
+
+    sqlx::query_as(
+        r"
+        INSERT INTO addresses (address, enabled, domain_id, user_id)
+        SELECT $1, TRUE, id, $2 FROM domains ORDER BY id LIMIT 1
+        RETURNING *
+        ",
+    )
+    .bind(
+        Alphanumeric
+            .sample_string(&mut rand::thread_rng(), 16)
+            .to_lowercase(),
+    )
+    .bind(user_id)
+    .fetch_one(db)
+    .await
+    .unwrap()
+}
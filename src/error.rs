@@ -20,8 +20,26 @@ pub enum Error {
     HttpBindFailed(#[source] io::Error),
     #[error("Could not discover openid client information")]
     DiscoverOidcFailed,
+    #[error("No OpenID Connect provider is configured")]
+    NoAuthProviders,
+    #[error("The mock issuer cannot be served on the origin of the redirect url")]
+    InvalidRedirectUrl,
+    #[error("The mock issuer cannot be enabled along with other OpenID Connect providers")]
+    MockWithProviders,
+    #[error("The token encryption key must be a base64-encoded 256-bit key")]
+    InvalidTokenKey,
+    #[error("Passkeys cannot be used on the origin of the redirect url")]
+    InvalidWebauthnOrigin(#[source] webauthn_rs::prelude::WebauthnError),
     #[error("sql error")]
     Sqlx(#[source] sqlx::Error),
+    #[error("Could not configure the smtp relay for forwarding")]
+    SmtpRelayInvalid(#[source] lettre::transport::smtp::Error),
+    #[error("Could not connect to the smtp relay")]
+    SmtpRelayUnreachable(#[source] lettre::transport::smtp::Error),
+    #[error("Could not write the account export")]
+    AccountExportFailed(#[source] zip::result::ZipError),
     #[error("addresses kept colliding when trying to generate unique address")]
     NameCollisionLimit,
+    #[error("user codes kept colliding when trying to generate a unique device authorization")]
+    UserCodeCollisionLimit,
 }
@@ -0,0 +1,501 @@
+//! Forwarding of received mail to the mailbox of the address owner
+
+use std::time::{Duration, Instant};
+
+use lettre::{
+    address::{Address, AddressError},
+    message::{
+        header::{ContentType, ContentTypeErr, HeaderName, HeaderValue},
+        Attachment, Mailbox, MultiPart, SinglePart,
+    },
+    AsyncSmtpTransport, AsyncTransport, Tokio1Executor,
+};
+use sqlx::{FromRow, PgConnection};
+use tracing::{debug, error, instrument, warn};
+use url::Url;
+
+use crate::{
+    api::v1::{
+        attachment,
+        message::{self, Message, MessageStatus},
+        webhook::{self, MessageEvent, WebhookEvent},
+    },
+    config::ForwardingConfig,
+    metrics, Database, Error,
+};
+
+pub mod http;
+pub mod pgp;
+pub mod trackers;
+
+pub const DEFAULT_RELAY_URL: &str = "smtp://localhost:25";
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+pub const DEFAULT_MAX_ATTEMPTS: i32 = 10;
+pub const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_SENDER: &str = "masked-mails@localhost";
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+pub const DEFAULT_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The maximum number of messages claimed from the queue at once.
+const BATCH_SIZE: i64 = 20;
+/// The time a claimed message is hidden from other workers while it is being forwarded.
+const CLAIM_DURATION: Duration = Duration::from_secs(300);
+
+const ADDRESS_HEADER: HeaderName = HeaderName::new_from_ascii_str("X-Masked-Mails-Address");
+const TRACKERS_REMOVED_HEADER: HeaderName =
+    HeaderName::new_from_ascii_str("X-Masked-Mails-Trackers-Removed");
+
+#[derive(Debug, thiserror::Error)]
+pub enum ForwardError {
+    #[error("invalid e-mail address")]
+    InvalidAddress(#[from] AddressError),
+    #[error("invalid attachment content type")]
+    InvalidContentType(#[from] ContentTypeErr),
+    #[error("could not build forwarded message")]
+    BuildFailed(#[from] lettre::error::Error),
+    #[error("could not remove trackers from html body")]
+    RewriteFailed(#[from] lol_html::errors::RewritingError),
+    #[error("could not deliver message to the relay")]
+    DeliveryFailed(#[from] lettre::transport::smtp::Error),
+    #[error("could not deliver message to the http destination")]
+    HttpDeliveryFailed(#[from] crate::webhooks::DeliveryError),
+    #[error("could not encrypt forwarded message")]
+    EncryptionFailed(#[from] pgp::PgpError),
+    #[error("attachment contents are missing")]
+    MissingAttachment,
+    #[error(transparent)]
+    Database(#[from] Error),
+}
+
+/// A message claimed from the forwarding queue, along with where it should be delivered.
+#[derive(Debug, Clone, FromRow)]
+struct Delivery {
+    #[sqlx(flatten)]
+    message: Message,
+    /// The owner of the address the message was sent to.
+    user_id: i32,
+    /// The full masked address the message was sent to.
+    recipient: String,
+    /// The mailbox the message is forwarded to.
+    destination: String,
+    /// The OpenPGP key the message is encrypted with, if the owner has uploaded one.
+    destination_pgp_key: Option<String>,
+    /// The URL the message is posted to instead of being delivered to `destination`.
+    destination_url: Option<String>,
+    destination_secret: Option<String>,
+    remove_trackers: bool,
+}
+
+/// Returns whether the SMTP relay of `config` accepts connections, or why it could not be reached.
+pub async fn relay_reachable(config: &ForwardingConfig) -> Result<bool, Error> {
+    let transport: AsyncSmtpTransport<Tokio1Executor> =
+        AsyncSmtpTransport::<Tokio1Executor>::from_url(&config.relay_url)
+            .map_err(Error::SmtpRelayInvalid)?
+            .build();
+
+    transport
+        .test_connection()
+        .await
+        .map_err(Error::SmtpRelayUnreachable)
+}
+
+/// Sends the confirmation link `link` for a new mailbox to `email` through the SMTP relay.
+pub async fn send_mailbox_confirmation(
+    config: &ForwardingConfig,
+    email: &str,
+    link: &Url,
+) -> Result<(), ForwardError> {
+    let transport: AsyncSmtpTransport<Tokio1Executor> =
+        AsyncSmtpTransport::<Tokio1Executor>::from_url(&config.relay_url)?.build();
+    let message = lettre::Message::builder()
+        .from(Mailbox::new(None, config.sender.parse()?))
+        .to(Mailbox::new(None, email.parse()?))
+        .subject("Confirm your mailbox")
+        .singlepart(SinglePart::plain(format!(
+            "Open the following link to have mail to your masked addresses forwarded to this \
+             mailbox:\r\n\r\n{link}\r\n\r\n\
+             Ignore this message if you did not add this mailbox.\r\n"
+        )))?;
+
+    transport.send(message).await?;
+
+    Ok(())
+}
+
+/// Returns the number of messages waiting to be forwarded, including those waiting for a retry.
+pub async fn count_queued_messages(db: &Database) -> Result<i64, Error> {
+    let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM messages WHERE status = 'pending'")
+        .fetch_one(db)
+        .await?;
+
+    Ok(count)
+}
+
+/// Delivers queued messages to an SMTP relay or HTTP destinations.
+pub struct Forwarder {
+    db: Database,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    client: reqwest::Client,
+    public_url: Option<Url>,
+    poll_interval: Duration,
+    max_attempts: i32,
+}
+
+impl Forwarder {
+    pub fn new(db: Database, config: &ForwardingConfig) -> Result<Self, Error> {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(&config.relay_url)
+            .map_err(Error::SmtpRelayInvalid)?
+            .build();
+        let client = crate::webhooks::resolver::client_builder()
+            .timeout(config.http_timeout)
+            .build()
+            .expect("could not build http client");
+
+        Ok(Forwarder {
+            db,
+            transport,
+            client,
+            public_url: config.public_url.clone(),
+            poll_interval: config.poll_interval,
+            max_attempts: config.max_attempts,
+        })
+    }
+
+    /// Continuously forwards messages from the queue until the task is aborted.
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.poll_interval);
+
+        loop {
+            interval.tick().await;
+
+            match self.process_queue().await {
+                Ok(0) => {}
+                Ok(count) => debug!(%count, "processed forwarding queue"),
+                Err(err) => error!(?err, "could not process forwarding queue"),
+            }
+        }
+    }
+
+    /// Claims a batch of due messages and attempts to forward each of them, returning the number
+    /// of messages that were claimed.
+    async fn process_queue(&self) -> Result<usize, Error> {
+        let deliveries: Vec<Delivery> = sqlx::query_as(
+            r"
+            WITH claimed AS (
+                UPDATE messages SET next_attempt_at = NOW() + $2 * INTERVAL '1 second'
+                WHERE id IN (
+                    SELECT id FROM messages
+                    WHERE status = 'pending' AND next_attempt_at <= NOW()
+                    ORDER BY next_attempt_at
+                    LIMIT $1
+                    FOR UPDATE SKIP LOCKED
+                )
+                RETURNING *
+            )
+            SELECT
+                claimed.*,
+                addresses.user_id,
+                addresses.address || '@' || domains.name AS recipient,
+                COALESCE(mailboxes.email, users.email) AS destination,
+                users.pgp_public_key AS destination_pgp_key,
+                addresses.destination_url,
+                addresses.destination_secret,
+                addresses.remove_trackers
+            FROM claimed
+            INNER JOIN addresses ON addresses.id = claimed.address_id
+            INNER JOIN domains ON domains.id = addresses.domain_id
+            INNER JOIN users ON users.id = addresses.user_id
+            LEFT JOIN mailboxes ON mailboxes.user_id = addresses.user_id
+                AND mailboxes.email = addresses.forward_to
+                AND mailboxes.verified_at IS NOT NULL
+            ",
+        )
+        .bind(BATCH_SIZE)
+        .bind(CLAIM_DURATION.as_secs_f64())
+        .fetch_all(&self.db)
+        .await?;
+
+        let count = deliveries.len();
+
+        for delivery in deliveries {
+            let start = Instant::now();
+            let result = self.forward(&delivery).await;
+            let destination =
+                if delivery.destination_url.is_some() && delivery.destination_secret.is_some() {
+                    "http"
+                } else {
+                    "smtp"
+                };
+            metrics::record_forward(destination, result.is_ok(), start.elapsed());
+
+            // The message is claimed again once its claim expires if its outcome could not be
+            // recorded, which must not keep the rest of the batch from being forwarded.
+            if let Err(err) = self.record_result(&delivery, result).await {
+                error!(
+                    ?err,
+                    message_id = delivery.message.id,
+                    "could not record forwarding result"
+                );
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Records the outcome of forwarding `delivery`, notifying the owner once it has failed for
+    /// good.
+    async fn record_result(
+        &self,
+        delivery: &Delivery,
+        result: Result<(), ForwardError>,
+    ) -> Result<(), Error> {
+        let err = match result {
+            Ok(()) => return self.mark_forwarded(delivery.message.id).await,
+            Err(err) => err,
+        };
+
+        warn!(
+            ?err,
+            message_id = delivery.message.id,
+            "could not forward message"
+        );
+
+        let mut tx = self.db.begin().await?;
+        let status = self.mark_failed(delivery.message.id, &err, &mut tx).await?;
+
+        if status == MessageStatus::Failed {
+            let event = MessageEvent::new(&delivery.message, Some(err.to_string()));
+
+            webhook::emit(
+                delivery.user_id,
+                WebhookEvent::MailForwardFailed,
+                &event,
+                &mut tx,
+            )
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(message_id = delivery.message.id))]
+    async fn forward(&self, delivery: &Delivery) -> Result<(), ForwardError> {
+        if let (Some(url), Some(secret)) = (&delivery.destination_url, &delivery.destination_secret)
+        {
+            return self.post(delivery, url, secret).await;
+        }
+
+        let message = self.build_message(delivery).await?;
+
+        self.transport.send(message).await?;
+
+        debug!("forwarded message");
+
+        Ok(())
+    }
+
+    /// Posts the message as JSON to the HTTP destination of its address.
+    async fn post(&self, delivery: &Delivery, url: &str, secret: &str) -> Result<(), ForwardError> {
+        let msg = &delivery.message;
+        let html = match msg.body_html {
+            Some(ref body) if delivery.remove_trackers => Some(trackers::remove_trackers(body)?.0),
+            ref html => html.clone(),
+        };
+        let attachments = attachment::get_message_attachments(msg.id, &self.db).await?;
+        let payload = http::Payload::new(
+            msg,
+            &delivery.recipient,
+            html.as_deref(),
+            attachments,
+            self.public_url.as_ref(),
+            secret,
+        );
+
+        http::deliver(&self.client, url, secret, &payload).await?;
+
+        debug!(%url, "posted message");
+
+        Ok(())
+    }
+
+    /// Builds the message that is delivered to the destination mailbox.
+    ///
+    /// The message is sent from the masked address, with replies going to the original sender.
+    /// If the owner has an OpenPGP key the message is encrypted, and the headers revealing the
+    /// original sender and subject are only included in its encrypted part.
+    async fn build_message(&self, delivery: &Delivery) -> Result<lettre::Message, ForwardError> {
+        let msg = &delivery.message;
+        let recipient: Address = delivery.recipient.parse()?;
+        let from = Mailbox::new(msg.sender.clone(), recipient.clone());
+        let to = Mailbox::new(None, delivery.destination.parse()?);
+        let reply_to = msg
+            .sender
+            .as_deref()
+            .and_then(|s| s.parse().ok())
+            .map(|address| Mailbox::new(None, address));
+        let subject = msg.subject.clone().unwrap_or_default();
+        let mut raw_headers = Vec::new();
+        let mut html = msg.body_html.clone();
+
+        if delivery.remove_trackers {
+            if let Some(ref body) = html {
+                let (cleaned, removed) = trackers::remove_trackers(body)?;
+
+                if !removed.is_empty() {
+                    debug!(%removed, "removed trackers");
+
+                    raw_headers.push(HeaderValue::new(
+                        TRACKERS_REMOVED_HEADER,
+                        removed.to_string(),
+                    ));
+                }
+
+                html = Some(cleaned);
+            }
+        }
+
+        let body = match (msg.body_text.clone(), html) {
+            (Some(text), Some(html)) => MultiPart::alternative_plain_html(text, html),
+            (None, Some(html)) => MultiPart::alternative().singlepart(SinglePart::html(html)),
+            (text, None) => {
+                MultiPart::alternative().singlepart(SinglePart::plain(text.unwrap_or_default()))
+            }
+        };
+        let mut parts = MultiPart::mixed().multipart(body);
+
+        for attachment in attachment::get_message_attachments(msg.id, &self.db).await? {
+            if let Some(notice) = attachment.notice() {
+                parts = parts.singlepart(SinglePart::plain(notice));
+                continue;
+            }
+
+            let data = match attachment.attachment_id {
+                Some(id) => attachment::get_attachment_data(id, &self.db).await?,
+                None => None,
+            }
+            .ok_or(ForwardError::MissingAttachment)?;
+            let filename = attachment
+                .filename
+                .unwrap_or_else(|| "attachment".to_string());
+            let content_type = ContentType::parse(&attachment.content_type)?;
+
+            parts = parts.singlepart(Attachment::new(filename).body(data, content_type));
+        }
+
+        let builder = lettre::Message::builder()
+            .to(to.clone())
+            .raw_header(HeaderValue::new(ADDRESS_HEADER, delivery.recipient.clone()));
+
+        match delivery.destination_pgp_key {
+            Some(ref armored) => {
+                let key = pgp::parse_public_key(armored)?;
+                let protected = pgp::ProtectedHeaders {
+                    from,
+                    to,
+                    reply_to,
+                    subject,
+                    raw: raw_headers,
+                };
+                let encrypted = pgp::encrypt(parts, &key, protected)?;
+
+                Ok(builder
+                    .from(Mailbox::new(None, recipient))
+                    .subject(pgp::PROTECTED_SUBJECT)
+                    .multipart(encrypted)?)
+            }
+            None => {
+                let mut builder = builder.from(from).subject(subject);
+
+                if let Some(reply_to) = reply_to {
+                    builder = builder.reply_to(reply_to);
+                }
+
+                for header in raw_headers {
+                    builder = builder.raw_header(header);
+                }
+
+                Ok(builder.multipart(parts)?)
+            }
+        }
+    }
+
+    async fn mark_forwarded(&self, message_id: i32) -> Result<(), Error> {
+        sqlx::query(
+            r"
+            UPDATE messages SET
+                status = 'forwarded',
+                attempts = attempts + 1,
+                forwarded_at = NOW(),
+                last_error = NULL
+            WHERE id = $1
+            ",
+        )
+        .bind(message_id)
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Records a failed attempt and schedules a retry with exponential backoff, or gives up once
+    /// the maximum number of attempts has been reached. Returns the new status of the message.
+    async fn mark_failed(
+        &self,
+        message_id: i32,
+        err: &ForwardError,
+        conn: &mut PgConnection,
+    ) -> Result<MessageStatus, Error> {
+        let (status,) = sqlx::query_as(
+            r"
+            UPDATE messages SET
+                attempts = attempts + 1,
+                last_error = $2,
+                status = CASE WHEN attempts + 1 >= $3 THEN 'failed' ELSE status END,
+                next_attempt_at = NOW() + LEAST(POWER(2, attempts) * 60, 21600) * INTERVAL '1 second'
+            WHERE id = $1
+            RETURNING status
+            ",
+        )
+        .bind(message_id)
+        .bind(err.to_string())
+        .bind(self.max_attempts)
+        .fetch_one(conn)
+        .await?;
+
+        Ok(status)
+    }
+}
+
+/// Periodically deletes forwarded and failed messages that have exceeded the retention period.
+pub struct Purger {
+    db: Database,
+    retention: Duration,
+    interval: Duration,
+}
+
+impl Purger {
+    pub fn new(db: Database, config: &ForwardingConfig) -> Self {
+        Purger {
+            db,
+            retention: config.retention,
+            interval: config.purge_interval,
+        }
+    }
+
+    /// Continuously purges expired messages until the task is aborted.
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.interval);
+
+        loop {
+            interval.tick().await;
+
+            match message::purge_delivered(self.retention, &self.db).await {
+                Ok(0) => {}
+                Ok(count) => debug!(%count, "purged forwarded messages"),
+                Err(err) => error!(?err, "could not purge forwarded messages"),
+            }
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Delivery of received mail as JSON to HTTP endpoints
+
+use std::time::Duration;
+
+use serde::Serialize;
+use url::Url;
+
+use crate::{
+    api::v1::{
+        attachment::{MessageAttachment, StrippedReason},
+        message::{Message, MessageHeader},
+    },
+    webhooks,
+};
+
+/// How long the attachment download links in a payload remain valid.
+const DOWNLOAD_LINK_VALIDITY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The JSON body posted to HTTP destinations.
+#[derive(Debug, Serialize)]
+pub struct Payload<'a> {
+    pub id: i32,
+    /// The masked address the message was sent to.
+    pub address: &'a str,
+    pub message_id: Option<&'a str>,
+    pub from: Option<&'a str>,
+    pub subject: Option<&'a str>,
+    pub headers: &'a [MessageHeader],
+    pub text: Option<&'a str>,
+    pub html: Option<&'a str>,
+    pub spam_score: Option<f64>,
+    pub received_at: time::OffsetDateTime,
+    pub attachments: Vec<PayloadAttachment>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PayloadAttachment {
+    pub filename: Option<String>,
+    pub content_type: String,
+    pub size: i64,
+    pub stripped_reason: Option<StrippedReason>,
+    /// A signed link to download the attachment, unless it was stripped or no public URL is
+    /// configured.
+    pub download_url: Option<Url>,
+}
+
+impl<'a> Payload<'a> {
+    pub fn new(
+        msg: &'a Message,
+        address: &'a str,
+        html: Option<&'a str>,
+        attachments: Vec<MessageAttachment>,
+        public_url: Option<&Url>,
+        secret: &str,
+    ) -> Self {
+        let attachments = attachments
+            .into_iter()
+            .map(|attachment| PayloadAttachment {
+                download_url: match (public_url, attachment.stripped_reason) {
+                    (Some(base), None) => download_url(base, attachment.id, secret),
+                    _ => None,
+                },
+                filename: attachment.filename,
+                content_type: attachment.content_type,
+                size: attachment.size,
+                stripped_reason: attachment.stripped_reason,
+            })
+            .collect();
+
+        Payload {
+            id: msg.id,
+            address,
+            message_id: msg.message_id.as_deref(),
+            from: msg.sender.as_deref(),
+            subject: msg.subject.as_deref(),
+            headers: &msg.headers,
+            text: msg.body_text.as_deref(),
+            html,
+            spam_score: msg.spam_score,
+            received_at: msg.received_at,
+            attachments,
+        }
+    }
+}
+
+/// Returns a link to download the attachment with `attachment_id` that is signed with the
+/// destination secret of its address.
+fn download_url(base: &Url, attachment_id: i32, secret: &str) -> Option<Url> {
+    let expires = (time::OffsetDateTime::now_utc() + DOWNLOAD_LINK_VALIDITY).unix_timestamp();
+    let signature = webhooks::sign(secret, expires, attachment_id.to_string().as_bytes());
+    let mut url = base
+        .join(&format!("api/v1/attachments/{attachment_id}/signed"))
+        .ok()?;
+
+    url.query_pairs_mut()
+        .append_pair("expires", &expires.to_string())
+        .append_pair("signature", &signature);
+
+    Some(url)
+}
+
+/// Posts `payload` to `url`, signed with `secret`.
+pub async fn deliver(
+    client: &reqwest::Client,
+    url: &str,
+    secret: &str,
+    payload: &Payload<'_>,
+) -> Result<(), webhooks::DeliveryError> {
+    let body = serde_json::to_vec(payload).expect("payload is serializable");
+
+    webhooks::ensure_public_url(url).await?;
+    let response = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(
+            webhooks::SIGNATURE_HEADER,
+            webhooks::signature_header(secret, &body),
+        )
+        .body(body)
+        .send()
+        .await?;
+
+    let status = response.status();
+
+    if !status.is_success() {
+        return Err(webhooks::DeliveryError::UnexpectedStatus(status.as_u16()));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,515 @@
+//! OpenPGP encryption of forwarded mail
+//!
+//! Messages are wrapped in PGP/MIME ([RFC 3156]) with the original subject and addresses moved
+//! into the encrypted part as protected headers.
+//!
+//! [RFC 3156]: https://www.rfc-editor.org/rfc/rfc3156
+
+use std::{io::Cursor, time::Duration};
+
+use lettre::message::{
+    header::{self, ContentDisposition, ContentType, ContentTypeErr, HeaderValue},
+    Mailbox, Mailboxes, MultiPart, SinglePart,
+};
+use pgp::{
+    composed::{Deserializable, Message, SignedPublicKey, SignedPublicSubKey},
+    crypto::sym::SymmetricKeyAlgorithm,
+    types::KeyTrait,
+    ArmorOptions,
+};
+use sha1::{Digest, Sha1};
+use tracing::debug;
+use url::Url;
+
+use crate::webhooks;
+
+/// The subject of the outer, unencrypted message.
+pub const PROTECTED_SUBJECT: &str = "...";
+
+/// The z-base-32 alphabet used to encode Web Key Directory hashes.
+const ZBASE32_ALPHABET: &[u8; 32] = b"ybndrfg8ejkmcpqxot1uwisza345h769";
+/// The time after which a Web Key Directory request is given up on.
+const WKD_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, thiserror::Error)]
+pub enum PgpError {
+    #[error("invalid openpgp key")]
+    InvalidKey(#[from] pgp::errors::Error),
+    #[error("the openpgp key has no key that can be used for encryption")]
+    NoEncryptionKey,
+    #[error("invalid e-mail address")]
+    InvalidEmail,
+    #[error("the openpgp key has no user id with the e-mail address")]
+    UserIdMismatch,
+    #[error("web key directory lookup failed")]
+    LookupFailed(#[from] reqwest::Error),
+    #[error("invalid content type")]
+    InvalidContentType(#[from] ContentTypeErr),
+}
+
+/// Parses and verifies an ASCII-armored public key, ensuring it can be used for encryption.
+pub fn parse_public_key(armored: &str) -> Result<SignedPublicKey, PgpError> {
+    let (key, _headers) = SignedPublicKey::from_string(armored)?;
+
+    key.verify()?;
+    encryption_key(&key)?;
+
+    Ok(key)
+}
+
+/// Returns the fingerprint of `key` as upper case hex.
+pub fn fingerprint(key: &SignedPublicKey) -> String {
+    hex::encode_upper(key.fingerprint())
+}
+
+/// Returns the ASCII-armored representation of `key`.
+pub fn armor(key: &SignedPublicKey) -> Result<String, PgpError> {
+    Ok(key.to_armored_string(ArmorOptions::default())?)
+}
+
+/// The key that messages are encrypted to.
+enum EncryptionKey<'a> {
+    Subkey(&'a SignedPublicSubKey),
+    Primary(&'a SignedPublicKey),
+}
+
+/// Returns the key to encrypt to, preferring an encryption subkey over the primary key.
+fn encryption_key(key: &SignedPublicKey) -> Result<EncryptionKey<'_>, PgpError> {
+    if let Some(subkey) = key
+        .public_subkeys
+        .iter()
+        .find(|subkey| subkey.is_encryption_key())
+    {
+        return Ok(EncryptionKey::Subkey(subkey));
+    }
+
+    if key.is_encryption_key() {
+        return Ok(EncryptionKey::Primary(key));
+    }
+
+    Err(PgpError::NoEncryptionKey)
+}
+
+/// The headers of a message that are only included in its encrypted part, since they reveal who
+/// sent it and what it is about.
+#[derive(Debug, Clone)]
+pub struct ProtectedHeaders {
+    pub from: Mailbox,
+    pub to: Mailbox,
+    pub reply_to: Option<Mailbox>,
+    pub subject: String,
+    /// Other headers, like the trackers removed from the message.
+    pub raw: Vec<HeaderValue>,
+}
+
+/// Encrypts `parts` to `key` and returns the PGP/MIME body of the outer message.
+///
+/// `headers` are included as protected headers of the encrypted part, so the outer message should
+/// only be from the masked address and use [`PROTECTED_SUBJECT`] as its subject.
+pub fn encrypt(
+    mut parts: MultiPart,
+    key: &SignedPublicKey,
+    protected: ProtectedHeaders,
+) -> Result<MultiPart, PgpError> {
+    let content_type = ContentType::parse(&format!(
+        "multipart/mixed; boundary=\"{}\"; protected-headers=\"v1\"",
+        parts.boundary()
+    ))?;
+    let headers = parts.headers_mut();
+
+    headers.set(content_type);
+    headers.set(header::From::from(Mailboxes::from(protected.from)));
+    headers.set(header::To::from(Mailboxes::from(protected.to)));
+    headers.set(header::Subject::from(protected.subject));
+
+    if let Some(reply_to) = protected.reply_to {
+        headers.set(header::ReplyTo::from(Mailboxes::from(reply_to)));
+    }
+
+    for value in protected.raw {
+        headers.insert_raw(value);
+    }
+
+    let message = Message::new_literal_bytes("", &parts.formatted());
+    let mut rng = rand::thread_rng();
+    let encrypted = match encryption_key(key)? {
+        EncryptionKey::Subkey(subkey) => {
+            message.encrypt_to_keys(&mut rng, SymmetricKeyAlgorithm::AES256, &[subkey])?
+        }
+        EncryptionKey::Primary(primary) => {
+            message.encrypt_to_keys(&mut rng, SymmetricKeyAlgorithm::AES256, &[primary])?
+        }
+    }
+    .to_armored_string(ArmorOptions::default())?;
+
+    let control = SinglePart::builder()
+        .header(ContentType::parse("application/pgp-encrypted")?)
+        .body(String::from("Version: 1\r\n"));
+    let payload = SinglePart::builder()
+        .header(ContentType::parse(
+            "application/octet-stream; name=\"encrypted.asc\"",
+        )?)
+        .header(ContentDisposition::inline_with_name("encrypted.asc"))
+        .body(encrypted);
+
+    Ok(
+        MultiPart::encrypted("application/pgp-encrypted".to_string())
+            .singlepart(control)
+            .singlepart(payload),
+    )
+}
+
+/// Looks up the public key of `email` in a Web Key Directory and returns it if found.
+///
+/// When `base_url` is set it is queried using the direct method instead of the domain of `email`,
+/// otherwise the advanced method is tried before falling back to the direct method.
+pub async fn discover(
+    email: &str,
+    base_url: Option<&Url>,
+) -> Result<Option<SignedPublicKey>, PgpError> {
+    let (local_part, domain) = email.rsplit_once('@').ok_or(PgpError::InvalidEmail)?;
+    let domain = domain.to_ascii_lowercase();
+    let hash = zbase32(&Sha1::digest(local_part.to_lowercase().as_bytes()));
+    let path = format!("/.well-known/openpgpkey/hu/{hash}");
+
+    let urls = match base_url {
+        Some(base) => vec![base.join(&path).map_err(|_| PgpError::InvalidEmail)?],
+        None => [
+            format!("https://openpgpkey.{domain}/.well-known/openpgpkey/{domain}/hu/{hash}"),
+            format!("https://{domain}{path}"),
+        ]
+        .iter()
+        .filter_map(|url| Url::parse(url).ok())
+        .collect(),
+    };
+
+    // The configured directory is trusted, but the domain of the e-mail address is chosen by the
+    // user, so requests to it must not reach internal hosts.
+    let client = match base_url {
+        Some(_) => reqwest::Client::builder(),
+        None => webhooks::resolver::client_builder(),
+    }
+    .timeout(WKD_TIMEOUT)
+    .build()?;
+
+    lookup(&client, urls, email).await
+}
+
+/// Queries `urls` in order and returns the first valid key of `email` found.
+///
+/// A URL that does not respond successfully does not publish a key. An invalid key is only
+/// reported if none of the following URLs has a valid one, so that an advanced method that serves
+/// something else than a key does not keep the direct method from being tried.
+async fn lookup(
+    client: &reqwest::Client,
+    urls: Vec<Url>,
+    email: &str,
+) -> Result<Option<SignedPublicKey>, PgpError> {
+    let (local_part, _domain) = email.rsplit_once('@').ok_or(PgpError::InvalidEmail)?;
+    let mut result = Ok(None);
+
+    for mut url in urls {
+        url.query_pairs_mut().append_pair("l", local_part);
+
+        let response = match client.get(url.clone()).send().await {
+            Ok(response) if response.status().is_success() => response,
+            _ => continue,
+        };
+
+        match read_key(response, email).await {
+            Ok(key) => return Ok(Some(key)),
+            Err(err) => {
+                debug!(?err, %url, "web key directory returned an invalid key");
+
+                result = Err(err);
+            }
+        }
+    }
+
+    result
+}
+
+/// Reads the binary public key in the body of `response`, ensuring it belongs to `email` and can
+/// be used for encryption.
+///
+/// A directory could serve any key, so keys without a user id for `email` are rejected, lest
+/// mail be encrypted to someone else.
+async fn read_key(response: reqwest::Response, email: &str) -> Result<SignedPublicKey, PgpError> {
+    let bytes = response.bytes().await?;
+    let key = SignedPublicKey::from_bytes(Cursor::new(bytes))?;
+
+    key.verify()?;
+    encryption_key(&key)?;
+
+    if !key
+        .details
+        .users
+        .iter()
+        .any(|user| user_id_matches(&String::from_utf8_lossy(user.id.id()), email))
+    {
+        return Err(PgpError::UserIdMismatch);
+    }
+
+    Ok(key)
+}
+
+/// Returns whether the user id `user_id`, e.g. `Joe Doe <joe.doe@example.org>`, has the address
+/// `email`.
+///
+/// The local part is compared ignoring case as well, since Web Key Directory looks up keys by the
+/// lower case local part and cannot tell its spellings apart.
+fn user_id_matches(user_id: &str, email: &str) -> bool {
+    let address = match (user_id.rfind('<'), user_id.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &user_id[start + 1..end],
+        _ => user_id,
+    };
+
+    address.trim().eq_ignore_ascii_case(email)
+}
+
+/// Encodes `data` with the z-base-32 encoding used by Web Key Directory.
+fn zbase32(data: &[u8]) -> String {
+    let mut output = String::with_capacity((data.len() * 8).div_ceil(5));
+    let mut buffer: u16 = 0;
+    let mut bits = 0;
+
+    for byte in data {
+        buffer = (buffer << 8) | u16::from(*byte);
+        bits += 8;
+
+        while bits >= 5 {
+            bits -= 5;
+            output.push(ZBASE32_ALPHABET[usize::from((buffer >> bits) & 0x1f)] as char);
+        }
+    }
+
+    if bits > 0 {
+        output.push(ZBASE32_ALPHABET[usize::from((buffer << (5 - bits)) & 0x1f)] as char);
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::get, Router};
+    use pgp::{
+        crypto::ecc_curve::ECCCurve, ser::Serialize, types::SecretKeyTrait, KeyType,
+        SecretKeyParamsBuilder, SignedSecretKey, SubkeyParamsBuilder,
+    };
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Generates a secret key for `Joe Doe <joe.doe@example.org>`, with an encryption subkey if
+    /// `encryption` is set, and returns it along with its public key.
+    fn generate_key(encryption: bool) -> (SignedSecretKey, SignedPublicKey) {
+        generate_key_for("Joe Doe <joe.doe@example.org>", encryption)
+    }
+
+    fn generate_key_for(user_id: &str, encryption: bool) -> (SignedSecretKey, SignedPublicKey) {
+        let mut params = SecretKeyParamsBuilder::default();
+
+        params
+            .key_type(KeyType::EdDSA)
+            .can_certify(true)
+            .can_sign(true)
+            .primary_user_id(user_id.to_string());
+
+        if encryption {
+            params.subkey(
+                SubkeyParamsBuilder::default()
+                    .key_type(KeyType::ECDH(ECCCurve::Curve25519))
+                    .can_encrypt(true)
+                    .build()
+                    .unwrap(),
+            );
+        }
+
+        let secret = params
+            .build()
+            .unwrap()
+            .generate()
+            .unwrap()
+            .sign(String::new)
+            .unwrap();
+        let public = secret.public_key().sign(&secret, String::new).unwrap();
+
+        (secret, public)
+    }
+
+    /// Starts a stand-in Web Key Directory serving `routes` and returns its base URL.
+    async fn serve(routes: Router) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+
+        tokio::spawn(async move { axum::serve(listener, routes).await.unwrap() });
+
+        Url::parse(&url).unwrap()
+    }
+
+    #[test]
+    fn encodes_zbase32() {
+        // The example of the Web Key Directory draft for `Joe.Doe@Example.ORG`.
+        assert_eq!(
+            zbase32(&Sha1::digest(b"joe.doe")),
+            "iy9q119eutrkn8s1mk4r39qejnbu3n5q"
+        );
+        assert_eq!(zbase32(&[]), "");
+        assert_eq!(zbase32(&[0xff]), "9h");
+    }
+
+    #[test]
+    fn requires_an_encryption_key() {
+        let (_, public) = generate_key(false);
+        let armored = armor(&public).unwrap();
+
+        assert!(matches!(
+            parse_public_key(&armored),
+            Err(PgpError::NoEncryptionKey)
+        ));
+    }
+
+    #[test]
+    fn encrypts_parts() {
+        let (secret, public) = generate_key(true);
+        let key = parse_public_key(&armor(&public).unwrap()).unwrap();
+        let from: Mailbox = "shop@masked.example".parse().unwrap();
+        let to: Mailbox = "joe.doe@example.org".parse().unwrap();
+        let parts =
+            MultiPart::mixed().singlepart(SinglePart::plain("Your order has shipped".to_string()));
+
+        let protected = ProtectedHeaders {
+            from,
+            to,
+            reply_to: Some("orders@shop.example".parse().unwrap()),
+            subject: "Order confirmation".to_string(),
+            raw: vec![HeaderValue::new(
+                header::HeaderName::new_from_ascii_str("X-Trackers-Removed"),
+                "1".to_string(),
+            )],
+        };
+
+        let encrypted = encrypt(parts, &key, protected).unwrap();
+        let formatted = String::from_utf8(encrypted.formatted()).unwrap();
+
+        assert!(formatted.contains("multipart/encrypted"));
+        assert!(!formatted.contains("Order confirmation"));
+        assert!(!formatted.contains("orders@shop.example"));
+
+        let start = formatted.find("-----BEGIN PGP MESSAGE-----").unwrap();
+        let end = formatted.find("-----END PGP MESSAGE-----").unwrap();
+        let (message, _) =
+            Message::from_string(&formatted[start..end + "-----END PGP MESSAGE-----".len()])
+                .unwrap();
+        let (decrypted, _) = message.decrypt(String::new, &[&secret]).unwrap();
+        let content = String::from_utf8(decrypted.get_content().unwrap().unwrap()).unwrap();
+
+        assert!(content.contains("protected-headers=\"v1\""));
+        assert!(content.contains("Subject: Order confirmation"));
+        assert!(content.contains("Reply-To: orders@shop.example"));
+        assert!(content.contains("X-Trackers-Removed: 1"));
+        assert!(content.contains("Your order has shipped"));
+    }
+
+    #[tokio::test]
+    async fn discovers_keys() {
+        let (_, public) = generate_key(true);
+        let fingerprint = fingerprint(&public);
+        let body = public.to_bytes().unwrap();
+        let base_url = serve(Router::new().route(
+            "/.well-known/openpgpkey/hu/iy9q119eutrkn8s1mk4r39qejnbu3n5q",
+            get(move || async move { body }),
+        ))
+        .await;
+
+        let key = discover("Joe.Doe@Example.ORG", Some(&base_url))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(super::fingerprint(&key), fingerprint);
+        assert!(discover("jane.doe@example.org", Some(&base_url))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_direct_method() {
+        let (_, public) = generate_key(true);
+        let body = public.to_bytes().unwrap();
+        let base_url = serve(
+            Router::new()
+                .route("/advanced", get(|| async { "not a key" }))
+                .route("/direct", get(move || async move { body })),
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let urls = |paths: &[&str]| {
+            paths
+                .iter()
+                .map(|path| base_url.join(path).unwrap())
+                .collect()
+        };
+
+        let key = lookup(
+            &client,
+            urls(&["advanced", "direct"]),
+            "joe.doe@example.org",
+        )
+        .await
+        .unwrap();
+
+        assert!(key.is_some());
+        assert!(matches!(
+            lookup(
+                &client,
+                urls(&["advanced", "missing"]),
+                "joe.doe@example.org"
+            )
+            .await,
+            Err(PgpError::InvalidKey(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_keys_of_other_addresses() {
+        let (_, public) = generate_key_for("Jane Doe <jane.doe@example.org>", true);
+        let body = public.to_bytes().unwrap();
+        let base_url = serve(Router::new().route(
+            "/.well-known/openpgpkey/hu/iy9q119eutrkn8s1mk4r39qejnbu3n5q",
+            get(move || async move { body }),
+        ))
+        .await;
+
+        assert!(matches!(
+            discover("joe.doe@example.org", Some(&base_url)).await,
+            Err(PgpError::UserIdMismatch)
+        ));
+    }
+
+    #[test]
+    fn matches_user_ids() {
+        assert!(user_id_matches(
+            "Joe Doe <joe.doe@example.org>",
+            "joe.doe@example.org"
+        ));
+        assert!(user_id_matches(
+            "joe.doe@example.org",
+            "Joe.Doe@Example.ORG"
+        ));
+        assert!(user_id_matches(
+            "<joe.doe@EXAMPLE.org>",
+            "joe.doe@example.org"
+        ));
+        assert!(!user_id_matches(
+            "Joe Doe <joe.doe@example.org.evil>",
+            "joe.doe@example.org"
+        ));
+        assert!(!user_id_matches(
+            "joe.doe@example.org <jane.doe@example.org>",
+            "joe.doe@example.org"
+        ));
+    }
+}
@@ -0,0 +1,473 @@
+//! Removal of tracking pixels and link trackers from HTML
+
+use std::{borrow::Cow, cell::Cell, fmt};
+
+use lol_html::{element, errors::RewritingError, html_content::Element, RewriteStrSettings};
+use url::Url;
+
+/// Domains used by newsletter and marketing services to track opens and clicks.
+const TRACKER_DOMAINS: &[&str] = &[
+    "awstrack.me",
+    "bananatag.com",
+    "click.convertkit-mail.com",
+    "ct.sendgrid.net",
+    "doubleclick.net",
+    "emltrk.com",
+    "exct.net",
+    "google-analytics.com",
+    "hs-analytics.net",
+    "hubspotemail.net",
+    "hubspotlinks.com",
+    "klclick.com",
+    "klclick1.com",
+    "links.iterable.com",
+    "list-manage.com",
+    "mailfoogae.appspot.com",
+    "mailtrack.io",
+    "mandrillapp.com",
+    "mlsend.com",
+    "pixel.watch",
+    "rs6.net",
+    "sailthru.com",
+    "yesware.com",
+];
+
+/// Query parameters that only serve to identify a campaign or a recipient.
+const TRACKING_PARAMS: &[&str] = &[
+    "_hsenc",
+    "_hsmi",
+    "_ke",
+    "__hsfp",
+    "__hssc",
+    "__hstc",
+    "ck_subscriber_id",
+    "dclid",
+    "fbclid",
+    "gbraid",
+    "gclid",
+    "gclsrc",
+    "igshid",
+    "li_fat_id",
+    "mc_cid",
+    "mc_eid",
+    "mkt_tok",
+    "ml_subscriber",
+    "ml_subscriber_hash",
+    "msclkid",
+    "oly_anon_id",
+    "oly_enc_id",
+    "rb_clickid",
+    "s_cid",
+    "trk",
+    "ttclid",
+    "twclid",
+    "vero_conv",
+    "vero_id",
+    "wbraid",
+    "wickedid",
+    "yclid",
+];
+
+/// Query parameters that tracker redirects use to carry the actual link destination.
+const REDIRECT_PARAMS: &[&str] = &[
+    "destination",
+    "dest",
+    "redirect",
+    "redirect_url",
+    "target",
+    "u",
+    "url",
+];
+
+/// A summary of the trackers that were removed from an HTML document.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Removed {
+    /// Invisible images, usually 1x1 pixels, that are used to track opens.
+    pub pixels: usize,
+    /// Images loaded from tracker domains.
+    pub images: usize,
+    /// Links through tracker redirects that were replaced with their destination.
+    pub links: usize,
+    /// Campaign and click-ID parameters stripped from links.
+    pub params: usize,
+}
+
+impl Removed {
+    /// Returns `true` if nothing was removed.
+    pub fn is_empty(&self) -> bool {
+        *self == Removed::default()
+    }
+}
+
+impl fmt::Display for Removed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let counts = [
+            (self.pixels, "tracking pixel"),
+            (self.images, "tracker image"),
+            (self.links, "tracker link"),
+            (self.params, "tracking parameter"),
+        ];
+        let mut first = true;
+
+        for (count, name) in counts.into_iter().filter(|(count, _)| *count > 0) {
+            if !first {
+                write!(f, ", ")?;
+            }
+
+            write!(f, "{count} {name}{}", if count == 1 { "" } else { "s" })?;
+            first = false;
+        }
+
+        Ok(())
+    }
+}
+
+/// Removes tracking pixels and images from tracker domains, unwraps tracker redirects and strips
+/// tracking parameters from links in `html`.
+pub fn remove_trackers(html: &str) -> Result<(String, Removed), RewritingError> {
+    let pixels = Cell::new(0);
+    let images = Cell::new(0);
+    let links = Cell::new(0);
+    let params = Cell::new(0);
+
+    let output = lol_html::rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![
+                element!("img", |el| {
+                    if is_tracking_pixel(el) {
+                        pixels.set(pixels.get() + 1);
+                        el.remove();
+                    } else if el
+                        .get_attribute("src")
+                        .and_then(|src| Url::parse(&decode_attribute(&src)).ok())
+                        .is_some_and(|url| is_tracker_url(&url))
+                    {
+                        images.set(images.get() + 1);
+                        el.remove();
+                    }
+
+                    Ok(())
+                }),
+                element!("a[href]", |el| {
+                    let Some(mut url) = el
+                        .get_attribute("href")
+                        .and_then(|href| Url::parse(&decode_attribute(&href)).ok())
+                    else {
+                        return Ok(());
+                    };
+
+                    if !matches!(url.scheme(), "http" | "https") {
+                        return Ok(());
+                    }
+
+                    let mut changed = false;
+
+                    if let Some(destination) = redirect_destination(&url) {
+                        links.set(links.get() + 1);
+                        url = destination;
+                        changed = true;
+                    }
+
+                    let stripped = strip_tracking_params(&mut url);
+
+                    if stripped > 0 {
+                        params.set(params.get() + stripped);
+                        changed = true;
+                    }
+
+                    if changed {
+                        el.set_attribute("href", &url.as_str().replace('&', "&amp;"))?;
+                    }
+
+                    Ok(())
+                }),
+            ],
+            ..RewriteStrSettings::default()
+        },
+    )?;
+
+    let removed = Removed {
+        pixels: pixels.get(),
+        images: images.get(),
+        links: links.get(),
+        params: params.get(),
+    };
+
+    Ok((output, removed))
+}
+
+/// Decodes the character references in an attribute value, e.g. the `&amp;` separating query
+/// parameters, since lol_html returns attribute values as they appear in the document.
+///
+/// Unknown or malformed references are kept as they are.
+fn decode_attribute(value: &str) -> Cow<'_, str> {
+    if !value.contains('&') {
+        return Cow::Borrowed(value);
+    }
+
+    let mut decoded = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let reference = rest[1..].split_once(';').and_then(|(name, _)| {
+            let c = match name {
+                "amp" => '&',
+                "quot" => '"',
+                "apos" => '\'',
+                "lt" => '<',
+                "gt" => '>',
+                _ => {
+                    let code = match name.strip_prefix('#')? {
+                        hex if hex.starts_with(['x', 'X']) => u32::from_str_radix(&hex[1..], 16),
+                        dec => dec.parse(),
+                    };
+
+                    char::from_u32(code.ok()?)?
+                }
+            };
+
+            Some((c, name.len() + 2))
+        });
+
+        match reference {
+            Some((c, len)) => {
+                decoded.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    decoded.push_str(rest);
+
+    Cow::Owned(decoded)
+}
+
+/// Returns `true` if the image is invisible or at most 1x1 pixels large.
+fn is_tracking_pixel(el: &Element) -> bool {
+    let dimension = |name: &str| {
+        el.get_attribute(name)
+            .and_then(|value| value.trim().trim_end_matches("px").parse::<u32>().ok())
+    };
+
+    if matches!((dimension("width"), dimension("height")), (Some(w), Some(h)) if w <= 1 && h <= 1) {
+        return true;
+    }
+
+    el.get_attribute("style")
+        .is_some_and(|style| is_invisible_style(&style))
+}
+
+/// Returns `true` if the inline `style` hides the element or sizes it to at most 1x1 pixels.
+///
+/// Only the `width` and `height` properties themselves count, so that declarations like
+/// `border-width:0` or `line-height:0` on regular images do not match.
+fn is_invisible_style(style: &str) -> bool {
+    let mut width = None;
+    let mut height = None;
+
+    for declaration in style.split(';') {
+        let Some((property, value)) = declaration.split_once(':') else {
+            continue;
+        };
+        let property = property.trim().to_ascii_lowercase();
+        let value = value.trim().to_ascii_lowercase();
+        let value = value.strip_suffix("!important").unwrap_or(&value).trim();
+
+        match property.as_str() {
+            "display" if value == "none" => return true,
+            "visibility" if value == "hidden" => return true,
+            "width" => width = pixel_length(value),
+            "height" => height = pixel_length(value),
+            _ => {}
+        }
+    }
+
+    matches!((width, height), (Some(w), Some(h)) if w <= 1.0 && h <= 1.0)
+}
+
+/// Parses a CSS length in pixels, e.g. `1px` or `0`.
+fn pixel_length(value: &str) -> Option<f64> {
+    match value.strip_suffix("px") {
+        Some(value) => value.trim().parse().ok(),
+        None if value == "0" => Some(0.0),
+        None => None,
+    }
+}
+
+/// Returns `true` if the host of `url` is a known tracker domain or one of its subdomains.
+fn is_tracker_url(url: &Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    let host = host.to_ascii_lowercase();
+
+    TRACKER_DOMAINS.iter().any(|domain| {
+        host == *domain
+            || host
+                .strip_suffix(domain)
+                .is_some_and(|prefix| prefix.ends_with('.'))
+    })
+}
+
+/// Returns the destination of a tracker redirect, if `url` is one and carries its destination.
+fn redirect_destination(url: &Url) -> Option<Url> {
+    if !is_tracker_url(url) {
+        return None;
+    }
+
+    url.query_pairs()
+        .filter(|(key, _)| REDIRECT_PARAMS.contains(&key.as_ref()))
+        .filter_map(|(_, value)| Url::parse(&value).ok())
+        .find(|destination| matches!(destination.scheme(), "http" | "https"))
+}
+
+/// Removes tracking query parameters from `url` and returns the number of removed parameters.
+fn strip_tracking_params(url: &mut Url) -> usize {
+    let pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+    let kept: Vec<&(String, String)> = pairs
+        .iter()
+        .filter(|(key, _)| !is_tracking_param(key))
+        .collect();
+    let removed = pairs.len() - kept.len();
+
+    if removed > 0 {
+        if kept.is_empty() {
+            url.set_query(None);
+        } else {
+            url.query_pairs_mut()
+                .clear()
+                .extend_pairs(kept.iter().map(|(k, v)| (k, v)));
+        }
+    }
+
+    removed
+}
+
+fn is_tracking_param(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+
+    key.starts_with("utm_") || TRACKING_PARAMS.contains(&key.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Removes the trackers from the fixture `name` and checks what was removed.
+    fn clean(name: &str, html: &str, expected: Removed) -> String {
+        let (output, removed) = remove_trackers(html).unwrap();
+
+        assert_eq!(removed, expected, "removed from {name}");
+
+        output
+    }
+
+    #[test]
+    fn mailchimp() {
+        let output = clean(
+            "mailchimp.html",
+            include_str!("../../tests/fixtures/trackers/mailchimp.html"),
+            Removed {
+                pixels: 1,
+                params: 5,
+                ..Removed::default()
+            },
+        );
+
+        assert!(!output.contains("track/open.php"));
+        assert!(output.contains(r#"href="https://shop.example.com/sale""#));
+        assert!(output.contains("images/logo.png"));
+        assert!(output.contains("list-manage.com/unsubscribe"));
+    }
+
+    #[test]
+    fn hubspot() {
+        let output = clean(
+            "hubspot.html",
+            include_str!("../../tests/fixtures/trackers/hubspot.html"),
+            Removed {
+                pixels: 1,
+                images: 1,
+                links: 1,
+                params: 6,
+            },
+        );
+
+        assert!(!output.contains("t.hubspotemail.net/e2t"));
+        assert!(!output.contains("google-analytics.com/collect"));
+        assert!(!output.contains("hubspotlinks.com/Ctc"));
+        assert!(output.contains(r#"href="https://blog.example.com/faster-reports""#));
+        assert!(output.contains("https://www.example.com/pricing?hsCtaTracking=5e4d3c2b"));
+        assert!(output.contains("spacer.gif"));
+    }
+
+    #[test]
+    fn sendgrid() {
+        let output = clean(
+            "sendgrid.html",
+            include_str!("../../tests/fixtures/trackers/sendgrid.html"),
+            Removed {
+                pixels: 2,
+                params: 3,
+                ..Removed::default()
+            },
+        );
+
+        assert!(!output.contains("/wf/open"));
+        assert!(!output.contains("email.example.com/o/"));
+        assert!(output.contains("email/hero.jpg"));
+        assert!(output.contains(r#"href="https://www.example.com/summer""#));
+    }
+
+    #[test]
+    fn keeps_newsletters_without_trackers() {
+        let html = include_str!("../../tests/fixtures/trackers/plain.html");
+        let output = clean("plain.html", html, Removed::default());
+
+        assert_eq!(output, html);
+    }
+
+    #[test]
+    fn decodes_attribute_values() {
+        assert_eq!(decode_attribute("a=1&amp;b=2"), "a=1&b=2");
+        assert_eq!(decode_attribute("&#38;&#x26;&quot;"), "&&\"");
+        assert_eq!(decode_attribute("a=1&b=2"), "a=1&b=2");
+        assert_eq!(
+            decode_attribute("&unknown; &#xzz; &amp"),
+            "&unknown; &#xzz; &amp"
+        );
+    }
+
+    #[test]
+    fn parses_style_declarations() {
+        assert!(is_invisible_style("display: none"));
+        assert!(is_invisible_style("DISPLAY:NONE !important"));
+        assert!(is_invisible_style("visibility:hidden"));
+        assert!(is_invisible_style("width:1px;height:1px"));
+        assert!(is_invisible_style("height: 0; width: 0; border: 0"));
+        assert!(!is_invisible_style("border-width:0;line-height:0"));
+        assert!(!is_invisible_style("max-width:1px;max-height:1px"));
+        assert!(!is_invisible_style("width:1px"));
+        assert!(!is_invisible_style("width:100%;height:1px"));
+        assert!(!is_invisible_style("display:block"));
+    }
+
+    #[test]
+    fn summarizes_removed_trackers() {
+        let removed = Removed {
+            pixels: 1,
+            links: 2,
+            ..Removed::default()
+        };
+
+        assert_eq!(removed.to_string(), "1 tracking pixel, 2 tracker links");
+    }
+}
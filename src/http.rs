@@ -1,35 +1,56 @@
-use std::net::SocketAddr;
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener},
+    time::Duration,
+};
 
-use axum::{extract::FromRef, http::StatusCode, response::IntoResponse, Router};
+use axum::{extract::FromRef, middleware, Router};
 use axum_login::AuthManagerLayerBuilder;
 use miette::IntoDiagnostic as _;
-use time::Duration;
-use tokio::{signal, task::AbortHandle};
+use tokio::{
+    signal,
+    sync::watch,
+    task::{AbortHandle, JoinSet},
+};
 use tower_http::{
     compression::{CompressionLayer, CompressionLevel},
     trace::TraceLayer,
 };
-use tower_sessions::{session_store::ExpiredDeletion, Expiry, SessionManagerLayer};
+use tower_sessions::{cookie, session_store::ExpiredDeletion, Expiry, SessionManagerLayer};
 use tower_sessions_sqlx_store::PostgresStore;
 use tracing::{debug, instrument};
 
 use crate::Database;
-use crate::{api, auth::Authenticator, Config};
+use crate::{
+    api, api::error::ApiError, auth::Authenticator, config::SameSite, metrics, metrics::Metrics,
+    spam::SpamFilter, Config,
+};
+
+mod listener;
+pub mod tls;
+
+pub const DEFAULT_LISTEN_ADDRESS: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 3000));
+pub const DEFAULT_SESSION_COOKIE_NAME: &str = "id";
+pub const DEFAULT_SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(360);
+pub const DEFAULT_SESSION_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+pub const DEFAULT_SESSION_CLEANUP_INTERVAL: Duration = Duration::from_secs(360);
 
 #[derive(Clone, FromRef)]
 pub(crate) struct AppState {
     pub authenticator: Authenticator,
     pub session_store: PostgresStore,
     pub database: Database,
+    pub spam_filter: SpamFilter,
+    pub metrics: Metrics,
     pub config: Config,
 }
 
 #[instrument]
-async fn not_found() -> impl IntoResponse {
-    (StatusCode::NOT_FOUND, "404 page not found")
+async fn not_found() -> ApiError {
+    ApiError::not_found("page not found")
 }
 
-async fn shutdown_signal(deletion_task_abort_handle: AbortHandle) {
+async fn shutdown_signal(deletion_task_abort_handle: AbortHandle, shutdown: watch::Sender<bool>) {
     let ctrl_c = async {
         signal::ctrl_c()
             .await
@@ -52,6 +73,8 @@ async fn shutdown_signal(deletion_task_abort_handle: AbortHandle) {
         () = terminate => { deletion_task_abort_handle.abort() },
     }
 
+    shutdown.send_replace(true);
+
     println!("signal received, gracefully shutting down");
 }
 
@@ -59,36 +82,59 @@ async fn shutdown_signal(deletion_task_abort_handle: AbortHandle) {
 pub async fn start_server(
     db: Database,
     authenticator: Authenticator,
+    metrics: Metrics,
     config: Config,
 ) -> miette::Result<()> {
     debug!("starting http server");
 
-    let auth_router = api::auth::router();
-    let api_v1_router = api::v1::router();
-
     // Set up the session layer
     debug!("creating session store");
     let session_store = PostgresStore::new(db.clone());
     debug!("migrating session store");
     session_store.migrate().await.into_diagnostic()?;
 
+    let session_config = config.session.clone();
+    let http_config = config.http.clone();
+    let metrics_config = config.metrics.clone();
+
     let app_state = AppState {
         authenticator: authenticator.clone(),
         session_store: session_store.clone(),
         database: db.clone(),
+        spam_filter: SpamFilter::new(&config.spam, db.clone()),
+        metrics,
         config,
     };
 
+    let auth_router = api::auth::router();
+    // API tokens are only accepted by the v1 API, and replace the session there.
+    let api_v1_router = api::v1::router().route_layer(middleware::from_fn_with_state(
+        app_state.clone(),
+        api::v1::authenticate_api_token,
+    ));
+
     let deletion_task = tokio::task::spawn(
         session_store
             .clone()
-            .continuously_delete_expired(tokio::time::Duration::from_secs(360)),
+            .continuously_delete_expired(session_config.cleanup_interval),
     );
 
-    let session_layer = SessionManagerLayer::new(session_store.clone())
-        .with_secure(false)
-        .with_same_site(tower_sessions::cookie::SameSite::Lax)
-        .with_expiry(Expiry::OnInactivity(Duration::seconds(360)));
+    let same_site = match session_config.same_site {
+        SameSite::Strict => cookie::SameSite::Strict,
+        SameSite::Lax => cookie::SameSite::Lax,
+        SameSite::None => cookie::SameSite::None,
+    };
+    let idle_timeout = session_config.idle_timeout.try_into().into_diagnostic()?;
+
+    let mut session_layer = SessionManagerLayer::new(session_store.clone())
+        .with_name(session_config.name)
+        .with_secure(session_config.secure)
+        .with_same_site(same_site)
+        .with_expiry(Expiry::OnInactivity(idle_timeout));
+
+    if let Some(domain) = session_config.domain {
+        session_layer = session_layer.with_domain(domain);
+    }
 
     let auth_layer = AuthManagerLayerBuilder::new(authenticator, session_layer).build();
 
@@ -96,22 +142,56 @@ pub async fn start_server(
         .fallback(not_found)
         .nest("/api/v1", api_v1_router)
         .nest("/api/auth", auth_router)
-        .with_state(app_state)
+        .route_layer(middleware::from_fn(metrics::track_http_requests))
+        .with_state(app_state.clone())
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            api::auth::expire_session,
+        ))
         .layer(auth_layer)
+        .layer(middleware::from_fn(api::error::request_id))
         .layer(TraceLayer::new_for_http())
         .layer(CompressionLayer::new().quality(CompressionLevel::Fastest));
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
-
-    debug!("binding to {}", addr);
-    let listener = tokio::net::TcpListener::bind(addr)
-        .await
-        .into_diagnostic()?;
-    debug!("listening on {}", addr);
-    axum::serve(listener, app.into_make_service())
-        .with_graceful_shutdown(shutdown_signal(deletion_task.abort_handle()))
-        .await
-        .into_diagnostic()?;
+    // Probes are merged after the layers, so they bypass the session, auth and trace layers.
+    let app = api::health::router()
+        .with_state(app_state.clone())
+        .merge(app);
+
+    let tls = match http_config.tls {
+        Some(ref tls_config) => Some(tls::load(tls_config).await?),
+        None => None,
+    };
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let mut servers = JoinSet::new();
+    for listener in listener::listeners(&http_config)? {
+        servers.spawn(listener::serve(
+            listener,
+            app.clone(),
+            tls.clone(),
+            shutdown_rx.clone(),
+        ));
+    }
+
+    if metrics_config.enabled {
+        debug!("binding metrics to {}", metrics_config.listen);
+        let listener = TcpListener::bind(metrics_config.listen).into_diagnostic()?;
+
+        servers.spawn(listener::serve(
+            listener::Listener::Tcp(listener),
+            api::health::metrics_router().with_state(app_state.metrics.clone()),
+            None,
+            shutdown_rx.clone(),
+        ));
+    }
+
+    tokio::spawn(shutdown_signal(deletion_task.abort_handle(), shutdown_tx));
+
+    while let Some(result) = servers.join_next().await {
+        result.into_diagnostic()??;
+    }
 
     deletion_task.await.into_diagnostic()?.into_diagnostic()?;
 
@@ -0,0 +1,231 @@
+//! Listeners of the HTTP server
+//!
+//! The server listens on the configured addresses and Unix domain socket, unless it was started
+//! through systemd socket activation, in which case it listens on the sockets passed by systemd
+//! instead.
+
+use std::net::TcpListener;
+
+#[cfg(unix)]
+use std::{
+    os::unix::{fs::FileTypeExt as _, net::UnixListener},
+    time::Duration,
+};
+
+use axum::Router;
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use listenfd::ListenFd;
+use miette::IntoDiagnostic as _;
+use tokio::sync::watch;
+use tracing::{debug, instrument, warn};
+
+use crate::config::HttpConfig;
+
+/// The time to wait before accepting connections again after accepting one failed, e.g. because
+/// the process ran out of file descriptors.
+#[cfg(unix)]
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// Returns the sockets passed by systemd, or binds the addresses and Unix domain socket of
+/// `config` if there are none.
+#[instrument(skip_all)]
+pub fn listeners(config: &HttpConfig) -> miette::Result<Vec<Listener>> {
+    let inherited = inherited_listeners()?;
+    if !inherited.is_empty() {
+        debug!(count = inherited.len(), "using sockets passed by systemd");
+        return Ok(inherited);
+    }
+
+    let mut listeners = Vec::new();
+
+    for addr in &config.listen {
+        debug!("binding to {}", addr);
+        listeners.push(Listener::Tcp(TcpListener::bind(addr).into_diagnostic()?));
+    }
+
+    #[cfg(unix)]
+    if let Some(ref path) = config.unix_socket {
+        debug!("binding to {}", path.display());
+        listeners.push(Listener::Unix(bind_unix(path)?));
+    }
+
+    #[cfg(not(unix))]
+    if config.unix_socket.is_some() {
+        miette::bail!("unix domain sockets are not supported on this platform");
+    }
+
+    if listeners.is_empty() {
+        miette::bail!("no addresses or unix domain socket to listen on");
+    }
+
+    Ok(listeners)
+}
+
+/// Binds the Unix domain socket at `path`, replacing a socket left behind by a previous run, which
+/// would make binding fail. Other files are never removed.
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path) -> miette::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            std::fs::remove_file(path).into_diagnostic()?;
+        }
+        _ => {}
+    }
+
+    UnixListener::bind(path).into_diagnostic()
+}
+
+/// Takes the stream sockets passed by systemd socket activation.
+fn inherited_listeners() -> miette::Result<Vec<Listener>> {
+    let mut listenfd = ListenFd::from_env();
+    let mut listeners = Vec::new();
+
+    for idx in 0..listenfd.len() {
+        if let Ok(Some(listener)) = listenfd.take_tcp_listener(idx) {
+            listeners.push(Listener::Tcp(listener));
+            continue;
+        }
+
+        #[cfg(unix)]
+        if let Some(listener) = listenfd.take_unix_listener(idx).into_diagnostic()? {
+            listeners.push(Listener::Unix(listener));
+            continue;
+        }
+
+        miette::bail!("socket {idx} passed by systemd is not a stream socket");
+    }
+
+    Ok(listeners)
+}
+
+/// Waits until the server is shut down.
+async fn shutdown_requested(mut shutdown: watch::Receiver<bool>) {
+    // The server also shuts down if the sender is gone.
+    let _ = shutdown.wait_for(|&shutdown| shutdown).await;
+}
+
+/// Serves `app` on `listener` until `shutdown` is set, terminating TLS with `tls` on TCP
+/// listeners.
+pub async fn serve(
+    listener: Listener,
+    app: Router,
+    tls: Option<RustlsConfig>,
+    shutdown: watch::Receiver<bool>,
+) -> miette::Result<()> {
+    match listener {
+        Listener::Tcp(listener) => {
+            listener.set_nonblocking(true).into_diagnostic()?;
+            let addr = listener.local_addr().into_diagnostic()?;
+
+            if let Some(tls) = tls {
+                let handle = Handle::new();
+                tokio::spawn({
+                    let handle = handle.clone();
+                    async move {
+                        shutdown_requested(shutdown).await;
+                        handle.graceful_shutdown(None);
+                    }
+                });
+
+                debug!("listening on https://{}", addr);
+                axum_server::from_tcp_rustls(listener, tls)
+                    .handle(handle)
+                    .serve(app.into_make_service())
+                    .await
+                    .into_diagnostic()
+            } else {
+                let listener = tokio::net::TcpListener::from_std(listener).into_diagnostic()?;
+
+                debug!("listening on http://{}", addr);
+                axum::serve(listener, app.into_make_service())
+                    .with_graceful_shutdown(shutdown_requested(shutdown))
+                    .await
+                    .into_diagnostic()
+            }
+        }
+        #[cfg(unix)]
+        Listener::Unix(listener) => serve_unix(listener, app, shutdown).await,
+    }
+}
+
+/// Serves `app` on a Unix domain socket, which `axum::serve` does not support.
+///
+/// Connections are accepted until `shutdown` is set, ongoing requests are not waited for.
+#[cfg(unix)]
+async fn serve_unix(
+    listener: UnixListener,
+    app: Router,
+    shutdown: watch::Receiver<bool>,
+) -> miette::Result<()> {
+    use axum::extract::Request;
+    use hyper::body::Incoming;
+    use hyper_util::{
+        rt::{TokioExecutor, TokioIo},
+        server::conn::auto,
+    };
+    use tower::Service as _;
+
+    listener.set_nonblocking(true).into_diagnostic()?;
+    let listener = tokio::net::UnixListener::from_std(listener).into_diagnostic()?;
+    debug!(addr = ?listener.local_addr().ok(), "listening on unix domain socket");
+
+    let shutdown = shutdown_requested(shutdown);
+    tokio::pin!(shutdown);
+
+    loop {
+        let stream = tokio::select! {
+            result = listener.accept() => match result {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    warn!(?err, "could not accept connection");
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            },
+            () = &mut shutdown => return Ok(()),
+        };
+
+        let app = app.clone();
+        tokio::spawn(async move {
+            let service = hyper::service::service_fn(move |request: Request<Incoming>| {
+                app.clone().call(request)
+            });
+
+            if let Err(err) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await
+            {
+                debug!(?err, "could not serve connection");
+            }
+        });
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_stale_unix_sockets() {
+        let path = std::env::temp_dir().join(format!("masked-mails-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // The socket of a listener is left behind once it is dropped.
+        drop(bind_unix(&path).unwrap());
+        assert!(path.exists());
+
+        drop(bind_unix(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(bind_unix(&path).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+//! TLS termination
+//!
+//! The certificate and key are read from disk at startup and reloaded whenever either file
+//! changes, so renewed certificates are picked up without restarting the server.
+
+use std::time::{Duration, SystemTime};
+
+use axum_server::tls_rustls::RustlsConfig;
+use miette::IntoDiagnostic as _;
+use tracing::{debug, error, info, instrument};
+
+use crate::config::TlsConfig;
+
+pub const DEFAULT_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Loads the certificate and key of `config`, and spawns a task that reloads them when they
+/// change on disk.
+#[instrument(skip_all)]
+pub async fn load(config: &TlsConfig) -> miette::Result<RustlsConfig> {
+    debug!(certificate = ?config.certificate, key = ?config.key, "loading tls certificate");
+    let rustls_config = RustlsConfig::from_pem_file(&config.certificate, &config.key)
+        .await
+        .into_diagnostic()?;
+
+    tokio::spawn(reload(rustls_config.clone(), config.clone()));
+
+    Ok(rustls_config)
+}
+
+/// Returns the modification times of the certificate and key.
+async fn modified(config: &TlsConfig) -> Option<(SystemTime, SystemTime)> {
+    let certificate = tokio::fs::metadata(&config.certificate).await.ok()?;
+    let key = tokio::fs::metadata(&config.key).await.ok()?;
+
+    Some((certificate.modified().ok()?, key.modified().ok()?))
+}
+
+/// Reloads the certificate and key every reload interval in which they changed. The previous
+/// certificate stays in use if they cannot be loaded, e.g. while only one of them was replaced.
+async fn reload(rustls_config: RustlsConfig, config: TlsConfig) {
+    let mut last_modified = modified(&config).await;
+    let mut interval = tokio::time::interval(config.reload_interval);
+    // The first tick completes immediately.
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let modified = modified(&config).await;
+        if modified.is_none() || modified == last_modified {
+            continue;
+        }
+
+        match rustls_config
+            .reload_from_pem_file(&config.certificate, &config.key)
+            .await
+        {
+            Ok(()) => {
+                info!("reloaded tls certificate");
+                last_modified = modified;
+            }
+            Err(err) => error!(?err, "could not reload tls certificate"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc};
+
+    use super::*;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls");
+
+    fn copy_fixture(name: &str, certificate: &Path, key: &Path) {
+        std::fs::copy(format!("{FIXTURES}/{name}.crt"), certificate).unwrap();
+        std::fs::copy(format!("{FIXTURES}/{name}.key"), key).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reloads_rewritten_certificates() {
+        let dir = std::env::temp_dir().join(format!("masked-mails-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = TlsConfig {
+            certificate: dir.join("tls.crt"),
+            key: dir.join("tls.key"),
+            reload_interval: Duration::from_millis(50),
+        };
+        copy_fixture("first", &config.certificate, &config.key);
+
+        let rustls_config = load(&config).await.unwrap();
+        let loaded = rustls_config.get_inner();
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(Arc::ptr_eq(&loaded, &rustls_config.get_inner()));
+
+        copy_fixture("second", &config.certificate, &config.key);
+
+        let mut reloaded = false;
+        for _ in 0..40 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            if !Arc::ptr_eq(&loaded, &rustls_config.get_inner()) {
+                reloaded = true;
+                break;
+            }
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(reloaded);
+    }
+}
@@ -11,14 +11,22 @@ mod cli;
 mod config;
 mod database;
 mod error;
+mod forwarding;
 mod http;
+mod metrics;
+mod quarantine;
+mod spam;
 mod tracing;
+mod webhooks;
 
 pub use config::Config;
 pub use database::Database;
 pub use error::Error;
 
-use crate::auth::Authenticator;
+use crate::{
+    auth::Authenticator, forwarding::Forwarder, metrics::Sampler, quarantine::Purger,
+    webhooks::Dispatcher,
+};
 
 #[tokio::main]
 async fn main() -> miette::Result<()> {
@@ -39,18 +47,33 @@ async fn main() -> miette::Result<()> {
     database::migrate(db.clone()).await?;
     debug!("database migrations complete");
 
+    debug!("initializing metrics");
+    let metrics = metrics::init(&config.metrics)?;
+
+    // The sampled values are only read by the exporters.
+    if config.metrics.enabled || config.metrics.otlp {
+        debug!("starting metrics sampler");
+        tokio::spawn(Sampler::new(db.clone(), &config.metrics).run());
+    }
+
     debug!("configuring authenticator");
-    let authenticator = Authenticator::discover(
-        db.clone(),
-        config.auth.issuer_url.clone(),
-        config.auth.client_id.clone(),
-        config.auth.client_secret.clone(),
-        config.auth.redirect_url.clone(),
-    )
-    .await?;
+    let authenticator = Authenticator::discover(db.clone(), &config.auth).await?;
     debug!("finished configuration authenticator");
 
-    http::start_server(db.clone(), authenticator, config).await?;
+    debug!("starting forwarder");
+    let forwarder = Forwarder::new(db.clone(), &config.forwarding)?;
+    tokio::spawn(forwarder.run());
+
+    debug!("starting forwarded mail purger");
+    tokio::spawn(forwarding::Purger::new(db.clone(), &config.forwarding).run());
+
+    debug!("starting quarantine purger");
+    tokio::spawn(Purger::new(db.clone(), &config.quarantine).run());
+
+    debug!("starting webhook dispatcher");
+    tokio::spawn(Dispatcher::new(db.clone(), &config.webhooks).run());
+
+    http::start_server(db.clone(), authenticator, metrics, config).await?;
 
     Ok(())
 }
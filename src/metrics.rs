@@ -0,0 +1,287 @@
+//! Metrics
+//!
+//! Metrics are recorded with OpenTelemetry instruments and served in the Prometheus text format
+//! at `/metrics` on a listener of their own, and are optionally exported via OTLP. Values that
+//! only the database knows, like the depth of the forwarding queue, are sampled periodically.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::Next,
+    response::Response,
+};
+use miette::IntoDiagnostic as _;
+use opentelemetry::{
+    global,
+    metrics::{Counter, Histogram, ObservableGauge, Unit},
+    KeyValue,
+};
+use opentelemetry_sdk::{
+    metrics::{
+        reader::{DefaultAggregationSelector, DefaultTemporalitySelector},
+        PeriodicReader, SdkMeterProvider,
+    },
+    runtime,
+};
+use prometheus::Registry;
+use tracing::error;
+
+use crate::{api::v1::session, config::MetricsConfig, forwarding, Database};
+
+/// The address metrics are served on by default: the usual port of OpenTelemetry Prometheus
+/// exporters, on the loopback interface.
+pub const DEFAULT_LISTEN_ADDRESS: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9464));
+pub const DEFAULT_SAMPLE_INTERVAL: Duration = Duration::from_secs(15);
+
+static INSTRUMENTS: OnceLock<Instruments> = OnceLock::new();
+
+/// The instruments that are recorded as things happen.
+struct Instruments {
+    http_requests: Counter<u64>,
+    http_request_duration: Histogram<f64>,
+    mails_received: Counter<u64>,
+    mails_ingested: Counter<u64>,
+    forward_duration: Histogram<f64>,
+}
+
+impl Instruments {
+    fn new() -> Self {
+        let meter = global::meter(env!("CARGO_PKG_NAME"));
+
+        Instruments {
+            http_requests: meter
+                .u64_counter("http.server.requests")
+                .with_description("Number of HTTP requests")
+                .init(),
+            http_request_duration: meter
+                .f64_histogram("http.server.request.duration")
+                .with_description("Duration of HTTP requests")
+                .with_unit(Unit::new("s"))
+                .init(),
+            mails_received: meter
+                .u64_counter("mails.received")
+                .with_description("Number of mails posted to the ingestion endpoint")
+                .init(),
+            mails_ingested: meter
+                .u64_counter("mails.ingested")
+                .with_description("Number of ingested mails by status and reason")
+                .init(),
+            forward_duration: meter
+                .f64_histogram("forward.duration")
+                .with_description("Duration of attempts to deliver a message to its destination")
+                .with_unit(Unit::new("s"))
+                .init(),
+        }
+    }
+}
+
+fn instruments() -> &'static Instruments {
+    INSTRUMENTS.get_or_init(Instruments::new)
+}
+
+/// The registry that `/metrics` is rendered from.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    registry: Option<Registry>,
+}
+
+impl Metrics {
+    /// Returns the Prometheus registry, or `None` if `/metrics` is disabled.
+    pub fn registry(&self) -> Option<&Registry> {
+        self.registry.as_ref()
+    }
+}
+
+/// Installs the global meter provider with the readers enabled in `config`.
+///
+/// This must be called before anything is recorded, since instruments are bound to the meter
+/// provider at their first use.
+pub fn init(config: &MetricsConfig) -> miette::Result<Metrics> {
+    let mut builder = SdkMeterProvider::builder().with_resource(crate::tracing::resource());
+
+    let registry = if config.enabled {
+        let registry = Registry::new();
+        let exporter = opentelemetry_prometheus::exporter()
+            .with_registry(registry.clone())
+            .build()
+            .into_diagnostic()?;
+        builder = builder.with_reader(exporter);
+
+        Some(registry)
+    } else {
+        None
+    };
+
+    if config.otlp {
+        let exporter = opentelemetry_otlp::new_exporter()
+            .tonic()
+            .build_metrics_exporter(
+                Box::new(DefaultAggregationSelector::new()),
+                Box::new(DefaultTemporalitySelector::new()),
+            )
+            .into_diagnostic()?;
+        builder = builder.with_reader(PeriodicReader::builder(exporter, runtime::Tokio).build());
+    }
+
+    global::set_meter_provider(builder.build());
+
+    Ok(Metrics { registry })
+}
+
+/// Records the number and duration of requests by route, method and status.
+///
+/// This is a route layer, so that requests are recorded by their matched route instead of their
+/// path.
+pub async fn track_http_requests(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string());
+    let method = request.method().to_string();
+    let start = Instant::now();
+
+    let response = next.run(request).await;
+
+    let mut attributes = vec![
+        KeyValue::new("http.request.method", method),
+        KeyValue::new(
+            "http.response.status_code",
+            i64::from(response.status().as_u16()),
+        ),
+    ];
+    if let Some(route) = route {
+        attributes.push(KeyValue::new("http.route", route));
+    }
+
+    let instruments = instruments();
+    instruments.http_requests.add(1, &attributes);
+    instruments
+        .http_request_duration
+        .record(start.elapsed().as_secs_f64(), &attributes);
+
+    response
+}
+
+/// Records that a mail was posted to the ingestion endpoint.
+pub fn record_mail_received() {
+    instruments().mails_received.add(1, &[]);
+}
+
+/// Records what happened to an ingested mail, e.g. `dropped` because of an `unknown_address`.
+pub fn record_mail_ingested(status: &'static str, reason: &'static str) {
+    instruments().mails_ingested.add(
+        1,
+        &[
+            KeyValue::new("status", status),
+            KeyValue::new("reason", reason),
+        ],
+    );
+}
+
+/// Records an attempt to deliver a message to a `destination`, either `smtp` or `http`.
+pub fn record_forward(destination: &'static str, succeeded: bool, duration: Duration) {
+    instruments().forward_duration.record(
+        duration.as_secs_f64(),
+        &[
+            KeyValue::new("destination", destination),
+            KeyValue::new("outcome", if succeeded { "success" } else { "failure" }),
+        ],
+    );
+}
+
+/// Periodically samples metrics from the database, and observes the connection pool.
+pub struct Sampler {
+    db: Database,
+    interval: Duration,
+    queued_messages: Arc<AtomicI64>,
+    active_sessions: Arc<AtomicI64>,
+    // The gauges are kept alive for as long as the sampler runs.
+    _gauges: Vec<ObservableGauge<i64>>,
+}
+
+impl Sampler {
+    pub fn new(db: Database, config: &MetricsConfig) -> Self {
+        let meter = global::meter(env!("CARGO_PKG_NAME"));
+        let queued_messages = Arc::new(AtomicI64::new(0));
+        let active_sessions = Arc::new(AtomicI64::new(0));
+
+        let gauges = vec![
+            meter
+                .i64_observable_gauge("forward.queue.depth")
+                .with_description("Number of messages waiting to be forwarded")
+                .with_callback({
+                    let queued_messages = queued_messages.clone();
+                    move |observer| observer.observe(queued_messages.load(Ordering::Relaxed), &[])
+                })
+                .init(),
+            meter
+                .i64_observable_gauge("sessions.active")
+                .with_description("Number of unexpired sessions users are logged in with")
+                .with_callback({
+                    let active_sessions = active_sessions.clone();
+                    move |observer| observer.observe(active_sessions.load(Ordering::Relaxed), &[])
+                })
+                .init(),
+            meter
+                .i64_observable_gauge("db.pool.connections")
+                .with_description("Number of database connections by state")
+                .with_callback({
+                    let db = db.clone();
+                    move |observer| {
+                        let idle = db.num_idle() as i64;
+                        let used = i64::from(db.size()) - idle;
+
+                        observer.observe(used, &[KeyValue::new("state", "used")]);
+                        observer.observe(idle, &[KeyValue::new("state", "idle")]);
+                    }
+                })
+                .init(),
+            meter
+                .i64_observable_gauge("db.pool.connections.max")
+                .with_description("Maximum number of database connections")
+                .with_callback({
+                    let db = db.clone();
+                    move |observer| {
+                        observer.observe(i64::from(db.options().get_max_connections()), &[])
+                    }
+                })
+                .init(),
+        ];
+
+        Sampler {
+            db,
+            interval: config.sample_interval,
+            queued_messages,
+            active_sessions,
+            _gauges: gauges,
+        }
+    }
+
+    /// Continuously samples metrics until the task is aborted.
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.interval);
+
+        loop {
+            interval.tick().await;
+
+            match forwarding::count_queued_messages(&self.db).await {
+                Ok(count) => self.queued_messages.store(count, Ordering::Relaxed),
+                Err(err) => error!(?err, "could not count queued messages"),
+            }
+
+            match session::count_active_sessions(&self.db).await {
+                Ok(count) => self.active_sessions.store(count, Ordering::Relaxed),
+                Err(err) => error!(?err, "could not count active sessions"),
+            }
+        }
+    }
+}
@@ -0,0 +1,42 @@
+//! Automatic removal of quarantined mail after its retention period
+
+use std::time::Duration;
+
+use tracing::{debug, error};
+
+use crate::{api::v1::quarantine, config::QuarantineConfig, Database};
+
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+pub const DEFAULT_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Periodically deletes quarantined messages that have exceeded the retention period.
+pub struct Purger {
+    db: Database,
+    retention: Duration,
+    interval: Duration,
+}
+
+impl Purger {
+    pub fn new(db: Database, config: &QuarantineConfig) -> Self {
+        Purger {
+            db,
+            retention: config.retention,
+            interval: config.purge_interval,
+        }
+    }
+
+    /// Continuously purges expired messages until the task is aborted.
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.interval);
+
+        loop {
+            interval.tick().await;
+
+            match quarantine::purge_expired(self.retention, &self.db).await {
+                Ok(0) => {}
+                Ok(count) => debug!(%count, "purged expired quarantined messages"),
+                Err(err) => error!(?err, "could not purge quarantined messages"),
+            }
+        }
+    }
+}
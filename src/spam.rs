@@ -0,0 +1,157 @@
+//! Spam scoring of ingested mail
+//!
+//! Scores use the same scale as SpamAssassin: the scores of all classifiers are summed, and the
+//! total is compared against the thresholds of the receiving address.
+
+use std::{fmt, sync::Arc};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{config::SpamConfig, Database};
+
+pub mod bayes;
+pub mod headers;
+pub mod spamd;
+
+pub const DEFAULT_TAG_THRESHOLD: f64 = 5.0;
+pub const DEFAULT_QUARANTINE_THRESHOLD: f64 = 10.0;
+
+/// The prefix added to the subject of mail that is tagged as spam.
+pub const SUBJECT_TAG: &str = "[SPAM] ";
+
+#[derive(Debug, thiserror::Error)]
+pub enum SpamError {
+    #[error("spamd request failed")]
+    Io(#[from] std::io::Error),
+    #[error("spamd request timed out")]
+    Timeout,
+    #[error("invalid spamd response")]
+    InvalidResponse,
+    #[error(transparent)]
+    Database(#[from] crate::Error),
+}
+
+/// A received e-mail that is being scored.
+pub struct Mail<'a> {
+    /// The raw contents of the e-mail.
+    pub raw: &'a [u8],
+    /// The parsed e-mail.
+    pub parsed: &'a mail_parser::Message<'a>,
+    /// The owner of the address the e-mail was sent to.
+    pub user_id: i32,
+}
+
+#[async_trait]
+pub trait SpamClassifier: Send + Sync {
+    /// A short name identifying the classifier in logs.
+    fn name(&self) -> &'static str;
+
+    /// Returns the spam score of `mail`. Positive scores indicate spam, negative scores ham.
+    async fn classify(&self, mail: &Mail<'_>) -> Result<f64, SpamError>;
+}
+
+/// What happens to mail based on its spam score.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpamAction {
+    /// Forward the mail unchanged.
+    Forward,
+    /// Forward the mail with [`SUBJECT_TAG`] prepended to the subject.
+    Tag,
+    /// Hold the mail in quarantine instead of forwarding it.
+    Quarantine,
+}
+
+impl SpamAction {
+    /// Returns the action for `score` given the thresholds of the receiving address.
+    pub fn for_score(score: f64, tag_threshold: f64, quarantine_threshold: f64) -> Self {
+        if score >= quarantine_threshold {
+            SpamAction::Quarantine
+        } else if score >= tag_threshold {
+            SpamAction::Tag
+        } else {
+            SpamAction::Forward
+        }
+    }
+}
+
+/// Runs all configured classifiers against incoming mail.
+#[derive(Clone)]
+pub struct SpamFilter {
+    classifiers: Arc<Vec<Box<dyn SpamClassifier>>>,
+    pub tag_threshold: f64,
+    pub quarantine_threshold: f64,
+}
+
+impl fmt::Debug for SpamFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpamFilter")
+            .field(
+                "classifiers",
+                &self
+                    .classifiers
+                    .iter()
+                    .map(|c| c.name())
+                    .collect::<Vec<_>>(),
+            )
+            .field("tag_threshold", &self.tag_threshold)
+            .field("quarantine_threshold", &self.quarantine_threshold)
+            .finish()
+    }
+}
+
+impl SpamFilter {
+    pub fn new(config: &SpamConfig, db: Database) -> Self {
+        let mut classifiers: Vec<Box<dyn SpamClassifier>> = Vec::new();
+
+        if config.headers {
+            classifiers.push(Box::new(headers::HeaderHeuristics::new(
+                config.authserv_id.clone(),
+            )));
+        }
+
+        if config.bayes {
+            classifiers.push(Box::new(bayes::BayesClassifier::new(db)));
+        }
+
+        if let Some(ref addr) = config.spamd_address {
+            classifiers.push(Box::new(spamd::SpamdClient::new(
+                addr.clone(),
+                config.spamd_timeout,
+            )));
+        }
+
+        SpamFilter {
+            classifiers: Arc::new(classifiers),
+            tag_threshold: config.tag_threshold,
+            quarantine_threshold: config.quarantine_threshold,
+        }
+    }
+
+    /// Returns the total score of `mail` across all classifiers.
+    ///
+    /// Classifiers that fail are logged and do not contribute to the score.
+    pub async fn score(&self, mail: &Mail<'_>) -> f64 {
+        let mut total = 0.0;
+
+        for classifier in self.classifiers.iter() {
+            match classifier.classify(mail).await {
+                Ok(score) => {
+                    debug!(classifier = classifier.name(), %score, "classified mail");
+                    total += score;
+                }
+                Err(err) => {
+                    warn!(
+                        ?err,
+                        classifier = classifier.name(),
+                        "spam classifier failed"
+                    );
+                }
+            }
+        }
+
+        total
+    }
+}
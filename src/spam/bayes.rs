@@ -0,0 +1,247 @@
+//! Per-user Bayesian spam classifier trained from spam reports
+
+use std::collections::HashSet;
+
+use async_trait::async_trait;
+
+use sqlx::PgConnection;
+
+use super::{Mail, SpamClassifier, SpamError};
+use crate::{Database, Error};
+
+/// The minimum number of trained spam and ham messages before the classifier is used.
+const MIN_TRAINING_MESSAGES: i32 = 10;
+/// The number of tokens with the most extreme probabilities that are combined.
+const INTERESTING_TOKENS: usize = 15;
+/// The score contributed when a message is certainly spam. Certain ham contributes the negative.
+const MAX_SCORE: f64 = 3.5;
+
+/// Scores mail using token probabilities learned from the recipient's spam reports.
+pub struct BayesClassifier {
+    db: Database,
+}
+
+impl BayesClassifier {
+    pub fn new(db: Database) -> Self {
+        BayesClassifier { db }
+    }
+}
+
+#[async_trait]
+impl SpamClassifier for BayesClassifier {
+    fn name(&self) -> &'static str {
+        "bayes"
+    }
+
+    async fn classify(&self, mail: &Mail<'_>) -> Result<f64, SpamError> {
+        let parsed = mail.parsed;
+        let tokens = tokenize(
+            parsed
+                .from()
+                .and_then(|from| from.first())
+                .and_then(|addr| addr.address()),
+            parsed.subject(),
+            parsed.body_text(0).as_deref(),
+        );
+
+        let probability = spam_probability(mail.user_id, tokens, &self.db).await?;
+
+        Ok(probability.map_or(0.0, |p| (p - 0.5) * 2.0 * MAX_SCORE))
+    }
+}
+
+/// Splits the sender, subject and body of a message into the unique tokens used for training and
+/// classification.
+pub fn tokenize(sender: Option<&str>, subject: Option<&str>, body: Option<&str>) -> Vec<String> {
+    let mut tokens = HashSet::new();
+
+    if let Some((_, domain)) = sender.and_then(|sender| sender.rsplit_once('@')) {
+        tokens.insert(format!("from:{}", domain.to_lowercase()));
+    }
+
+    let words = |text: &str, prefix: &str, tokens: &mut HashSet<String>| {
+        for word in text.split(|c: char| !c.is_alphanumeric() && c != '\'' && c != '$') {
+            if (3..=20).contains(&word.chars().count()) {
+                tokens.insert(format!("{prefix}{}", word.to_lowercase()));
+            }
+        }
+    };
+
+    if let Some(subject) = subject {
+        words(subject, "subject:", &mut tokens);
+    }
+
+    if let Some(body) = body {
+        words(body, "", &mut tokens);
+    }
+
+    tokens.into_iter().collect()
+}
+
+/// Returns the probability that a message with `tokens` is spam for `user_id`, or `None` if the
+/// user has not trained enough messages.
+async fn spam_probability(
+    user_id: i32,
+    tokens: Vec<String>,
+    db: &Database,
+) -> Result<Option<f64>, Error> {
+    let training: Option<(i32, i32)> =
+        sqlx::query_as("SELECT spam_messages, ham_messages FROM spam_training WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_optional(db)
+            .await?;
+
+    let Some((spam_messages, ham_messages)) = training else {
+        return Ok(None);
+    };
+
+    if spam_messages < MIN_TRAINING_MESSAGES || ham_messages < MIN_TRAINING_MESSAGES {
+        return Ok(None);
+    }
+
+    let counts: Vec<(i32, i32)> = sqlx::query_as(
+        r"
+        SELECT spam_count, ham_count FROM spam_tokens
+        WHERE user_id = $1 AND token = ANY($2)
+        ",
+    )
+    .bind(user_id)
+    .bind(tokens)
+    .fetch_all(db)
+    .await?;
+
+    Ok(combine(&counts, spam_messages, ham_messages))
+}
+
+/// Combines the spam and ham counts of the tokens of a message into the probability that it is
+/// spam, given the number of trained spam and ham messages.
+fn combine(counts: &[(i32, i32)], spam_messages: i32, ham_messages: i32) -> Option<f64> {
+    // Robinson's adjusted token probabilities, which avoid extreme values for rare tokens.
+    let mut probabilities: Vec<f64> = counts
+        .iter()
+        .map(|&(spam, ham)| {
+            let spam_freq = f64::from(spam) / f64::from(spam_messages);
+            let ham_freq = f64::from(ham) / f64::from(ham_messages);
+            let p = spam_freq / (spam_freq + ham_freq);
+            let n = f64::from(spam + ham);
+
+            (0.5 + n * p) / (1.0 + n)
+        })
+        .collect();
+
+    if probabilities.is_empty() {
+        return None;
+    }
+
+    probabilities.sort_by(|a, b| (b - 0.5).abs().total_cmp(&(a - 0.5).abs()));
+    probabilities.truncate(INTERESTING_TOKENS);
+
+    let (log_spam, log_ham) = probabilities
+        .iter()
+        .map(|p| p.clamp(0.01, 0.99))
+        .fold((0.0, 0.0), |(spam, ham), p| {
+            (spam + p.ln(), ham + (1.0 - p).ln())
+        });
+
+    Some(1.0 / (1.0 + (log_ham - log_spam).exp()))
+}
+
+/// Updates the token counts of `user_id` with a message that was reported as spam or ham.
+///
+/// This should run in the transaction recording the report, so that a message is trained once.
+pub async fn train(
+    user_id: i32,
+    tokens: Vec<String>,
+    spam: bool,
+    conn: &mut PgConnection,
+) -> Result<(), Error> {
+    let (spam_increment, ham_increment) = if spam { (1, 0) } else { (0, 1) };
+
+    sqlx::query(
+        r"
+        INSERT INTO spam_tokens (user_id, token, spam_count, ham_count)
+        SELECT $1, token, $3, $4 FROM UNNEST($2::VARCHAR []) AS token
+        ON CONFLICT (user_id, token) DO UPDATE SET
+            spam_count = spam_tokens.spam_count + excluded.spam_count,
+            ham_count = spam_tokens.ham_count + excluded.ham_count
+        ",
+    )
+    .bind(user_id)
+    .bind(tokens)
+    .bind(spam_increment)
+    .bind(ham_increment)
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query(
+        r"
+        INSERT INTO spam_training (user_id, spam_messages, ham_messages) VALUES ($1, $2, $3)
+        ON CONFLICT (user_id) DO UPDATE SET
+            spam_messages = spam_training.spam_messages + excluded.spam_messages,
+            ham_messages = spam_training.ham_messages + excluded.ham_messages
+        ",
+    )
+    .bind(user_id)
+    .bind(spam_increment)
+    .bind(ham_increment)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenizes_messages() {
+        let mut tokens = tokenize(
+            Some("Deals@Shop.Example.com"),
+            Some("Cheap WATCHES"),
+            Some("Buy now, it's only $5 at a-b.example!"),
+        );
+        tokens.sort();
+
+        assert_eq!(
+            tokens,
+            [
+                "buy",
+                "example",
+                "from:shop.example.com",
+                "it's",
+                "now",
+                "only",
+                "subject:cheap",
+                "subject:watches",
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_short_and_long_words() {
+        let tokens = tokenize(None, None, Some("a an supercalifragilisticexpialidocious"));
+
+        assert!(tokens.is_empty());
+    }
+
+    #[test]
+    fn combines_token_probabilities() {
+        let spam = combine(&[(40, 1), (35, 0), (20, 2)], 50, 50).unwrap();
+        let ham = combine(&[(0, 30), (1, 45)], 50, 50).unwrap();
+        let neutral = combine(&[(10, 10)], 50, 50).unwrap();
+
+        assert!(spam > 0.99, "{spam}");
+        assert!(ham < 0.01, "{ham}");
+        assert!((neutral - 0.5).abs() < 1e-9, "{neutral}");
+        assert_eq!(combine(&[], 50, 50), None);
+    }
+
+    #[test]
+    fn only_combines_the_most_interesting_tokens() {
+        let mut counts = vec![(50, 0); INTERESTING_TOKENS];
+        counts.extend(vec![(10, 10); 100]);
+
+        assert!(combine(&counts, 50, 50).unwrap() > 0.99);
+    }
+}
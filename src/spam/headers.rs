@@ -0,0 +1,200 @@
+//! Spam heuristics based on message headers
+//!
+//! `Authentication-Results` headers are only trusted when they carry the authserv-id of the
+//! ingress, since any other such header may have been added by the sender.
+
+use async_trait::async_trait;
+use mail_parser::HeaderValue;
+
+use super::{Mail, SpamClassifier, SpamError};
+
+/// The maximum amount of time a `Date` header may lie in the future before it is suspicious.
+const MAX_CLOCK_SKEW_SECS: i64 = 24 * 60 * 60;
+
+/// Scores mail based on missing, malformed or inconsistent headers.
+pub struct HeaderHeuristics {
+    /// The authserv-id of the trusted `Authentication-Results` headers.
+    authserv_id: Option<String>,
+}
+
+impl HeaderHeuristics {
+    pub fn new(authserv_id: Option<String>) -> Self {
+        HeaderHeuristics { authserv_id }
+    }
+}
+
+#[async_trait]
+impl SpamClassifier for HeaderHeuristics {
+    fn name(&self) -> &'static str {
+        "headers"
+    }
+
+    async fn classify(&self, mail: &Mail<'_>) -> Result<f64, SpamError> {
+        let parsed = mail.parsed;
+        let mut score = 0.0;
+
+        if parsed.message_id().is_none() {
+            score += 1.0;
+        }
+
+        match parsed.date() {
+            None => score += 1.0,
+            Some(date) => {
+                let now = time::OffsetDateTime::now_utc().unix_timestamp();
+
+                if date.to_timestamp() > now + MAX_CLOCK_SKEW_SECS {
+                    score += 1.0;
+                }
+            }
+        }
+
+        match parsed.subject().map(str::trim) {
+            None | Some("") => score += 0.5,
+            Some(subject) => {
+                let letters: Vec<char> = subject.chars().filter(|c| c.is_alphabetic()).collect();
+
+                if letters.len() >= 10 && letters.iter().all(|c| c.is_uppercase()) {
+                    score += 1.5;
+                }
+            }
+        }
+
+        if parsed.text_body.is_empty() && !parsed.html_body.is_empty() {
+            score += 0.5;
+        }
+
+        let from = parsed.from().and_then(|from| from.first());
+        let from_address = from.and_then(|addr| addr.address());
+
+        // A display name containing a different e-mail address is a common spoofing technique.
+        if let (Some(name), Some(address)) = (from.and_then(|addr| addr.name()), from_address) {
+            if name.contains('@') && !name.to_lowercase().contains(&address.to_lowercase()) {
+                score += 1.5;
+            }
+        }
+
+        let reply_to = parsed
+            .reply_to()
+            .and_then(|reply_to| reply_to.first())
+            .and_then(|addr| addr.address());
+
+        if let (Some(from), Some(reply_to)) =
+            (from_address.and_then(domain), reply_to.and_then(domain))
+        {
+            if !from.eq_ignore_ascii_case(reply_to) {
+                score += 0.5;
+            }
+        }
+
+        for results in authentication_results(parsed, self.authserv_id.as_deref()) {
+            if results.contains("dmarc=fail") {
+                score += 2.0;
+            }
+
+            if results.contains("spf=fail") {
+                score += 1.0;
+            }
+
+            if results.contains("dkim=fail") {
+                score += 1.0;
+            }
+        }
+
+        Ok(score)
+    }
+}
+
+/// Returns whether an `Authentication-Results` header of `parsed` with `authserv_id` reports a
+/// DMARC failure.
+pub fn dmarc_failed(parsed: &mail_parser::Message<'_>, authserv_id: Option<&str>) -> bool {
+    authentication_results(parsed, authserv_id).any(|results| results.contains("dmarc=fail"))
+}
+
+/// Returns the lowercased values of the `Authentication-Results` headers of `parsed` with
+/// `authserv_id`, or none if no authserv-id is trusted.
+fn authentication_results<'a>(
+    parsed: &'a mail_parser::Message<'a>,
+    authserv_id: Option<&'a str>,
+) -> impl Iterator<Item = String> + 'a {
+    parsed.headers().iter().filter_map(move |header| {
+        if !header
+            .name
+            .as_str()
+            .eq_ignore_ascii_case("Authentication-Results")
+        {
+            return None;
+        }
+
+        let results = match header.value {
+            HeaderValue::Text(ref results) => results,
+            _ => return None,
+        };
+
+        authserv_id
+            .filter(|id| result_authserv_id(results).eq_ignore_ascii_case(id))
+            .map(|_| results.to_ascii_lowercase())
+    })
+}
+
+/// Returns the authserv-id of the `Authentication-Results` header value `results`, which precedes
+/// the optional version and the first `;`.
+fn result_authserv_id(results: &str) -> &str {
+    results
+        .split(';')
+        .next()
+        .and_then(|id| id.split_whitespace().next())
+        .unwrap_or_default()
+}
+
+fn domain(address: &str) -> Option<&str> {
+    address.rsplit_once('@').map(|(_, domain)| domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::MessageParser;
+
+    use super::*;
+
+    const MAIL: &str =
+        "Authentication-Results: spoofed.example; dmarc=fail header.from=bank.example\r
+Authentication-Results: mx.ingress.example 1; spf=pass; dmarc=pass header.from=shop.example\r
+From: Shop <news@shop.example>\r
+Subject: Hello\r
+\r
+Hello\r
+";
+
+    #[test]
+    fn trusts_results_of_the_ingress() {
+        let parsed = MessageParser::default().parse(MAIL).unwrap();
+        let results: Vec<String> =
+            authentication_results(&parsed, Some("MX.ingress.example")).collect();
+
+        assert_eq!(results.len(), 1);
+        assert!(results[0].contains("dmarc=pass"));
+        assert!(!dmarc_failed(&parsed, Some("mx.ingress.example")));
+    }
+
+    #[test]
+    fn ignores_results_of_others() {
+        let parsed = MessageParser::default().parse(MAIL).unwrap();
+
+        assert!(dmarc_failed(&parsed, Some("spoofed.example")));
+        assert!(!dmarc_failed(&parsed, Some("ingress.example")));
+        assert!(!dmarc_failed(&parsed, None));
+    }
+
+    #[test]
+    fn parses_authserv_ids() {
+        assert_eq!(
+            result_authserv_id("mx.example.com; spf=pass"),
+            "mx.example.com"
+        );
+        assert_eq!(
+            result_authserv_id(" mx.example.com 1 ; none"),
+            "mx.example.com"
+        );
+        assert_eq!(result_authserv_id("; none"), "");
+    }
+}
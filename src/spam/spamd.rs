@@ -0,0 +1,190 @@
+//! Client for the spamd protocol, as spoken by SpamAssassin's `spamd` and rspamd
+//!
+//! See <https://spamassassin.apache.org/full/4.0.x/doc/spamd.html> for the protocol.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use super::{Mail, SpamClassifier, SpamError};
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Scores mail by sending it to a spamd-compatible server.
+pub struct SpamdClient {
+    /// The `host:port` of the server.
+    address: String,
+    timeout: Duration,
+}
+
+impl SpamdClient {
+    pub fn new(address: String, timeout: Duration) -> Self {
+        SpamdClient { address, timeout }
+    }
+
+    async fn check(&self, raw: &[u8]) -> Result<f64, SpamError> {
+        let mut stream = TcpStream::connect(&self.address).await?;
+        let request = format!("CHECK SPAMC/1.5\r\nContent-length: {}\r\n\r\n", raw.len());
+
+        stream.write_all(request.as_bytes()).await?;
+        stream.write_all(raw).await?;
+        stream.shutdown().await?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+
+        parse_response(&response)
+    }
+}
+
+#[async_trait]
+impl SpamClassifier for SpamdClient {
+    fn name(&self) -> &'static str {
+        "spamd"
+    }
+
+    async fn classify(&self, mail: &Mail<'_>) -> Result<f64, SpamError> {
+        tokio::time::timeout(self.timeout, self.check(mail.raw))
+            .await
+            .map_err(|_| SpamError::Timeout)?
+    }
+}
+
+/// Extracts the score from a spamd response such as:
+///
+/// ```text
+/// SPAMD/1.1 0 EX_OK
+/// Content-length: 0
+/// Spam: True ; 15.0 / 5.0
+/// ```
+fn parse_response(response: &str) -> Result<f64, SpamError> {
+    let mut lines = response.lines();
+    let status = lines.next().ok_or(SpamError::InvalidResponse)?;
+
+    if !status.starts_with("SPAMD/") || status.split_whitespace().nth(1) != Some("0") {
+        return Err(SpamError::InvalidResponse);
+    }
+
+    lines
+        .find_map(|line| line.strip_prefix("Spam:"))
+        .and_then(|value| value.split_once(';'))
+        .and_then(|(_, scores)| scores.split('/').next())
+        .and_then(|score| score.trim().parse().ok())
+        .ok_or(SpamError::InvalidResponse)
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::MessageParser;
+    use tokio::{net::TcpListener, task::JoinHandle};
+
+    use super::*;
+
+    const MAIL: &[u8] = b"From: sender@example.com\r\nSubject: Cheap watches\r\n\r\nBuy now!\r\n";
+
+    /// Starts a stand-in spamd server that answers a single request with `response`, and returns
+    /// its address along with the request it received.
+    async fn serve(response: &'static str) -> (String, JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+
+            stream.read_to_end(&mut request).await.unwrap();
+            stream.write_all(response.as_bytes()).await.unwrap();
+
+            request
+        });
+
+        (address, server)
+    }
+
+    #[tokio::test]
+    async fn checks_mail() {
+        let (address, server) =
+            serve("SPAMD/1.1 0 EX_OK\r\nContent-length: 0\r\nSpam: True ; 15.0 / 5.0\r\n\r\n")
+                .await;
+        let client = SpamdClient::new(address, DEFAULT_TIMEOUT);
+
+        let score = client.check(MAIL).await.unwrap();
+        let request = server.await.unwrap();
+
+        assert_eq!(score, 15.0);
+
+        let mut expected =
+            format!("CHECK SPAMC/1.5\r\nContent-length: {}\r\n\r\n", MAIL.len()).into_bytes();
+        expected.extend_from_slice(MAIL);
+
+        assert_eq!(request, expected);
+    }
+
+    #[tokio::test]
+    async fn rejects_errors() {
+        let (address, _server) = serve("SPAMD/1.1 76 Bad header line: (EOF)\r\n\r\n").await;
+        let client = SpamdClient::new(address, DEFAULT_TIMEOUT);
+
+        assert!(matches!(
+            client.check(MAIL).await,
+            Err(SpamError::InvalidResponse)
+        ));
+    }
+
+    #[tokio::test]
+    async fn times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = SpamdClient::new(
+            listener.local_addr().unwrap().to_string(),
+            Duration::from_millis(50),
+        );
+        let parsed = MessageParser::default().parse(MAIL).unwrap();
+        let mail = Mail {
+            raw: MAIL,
+            parsed: &parsed,
+            user_id: 1,
+        };
+
+        // The connection is accepted by the backlog of the listener, but never answered.
+        assert!(matches!(
+            client.classify(&mail).await,
+            Err(SpamError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn parses_scores() {
+        let parse = |response: &str| parse_response(response).ok();
+
+        assert_eq!(
+            parse("SPAMD/1.1 0 EX_OK\r\nContent-length: 0\r\nSpam: True ; 15.0 / 5.0\r\n"),
+            Some(15.0)
+        );
+        assert_eq!(
+            parse("SPAMD/1.5 0 EX_OK\r\nSpam: False ; -1.2 / 5.0\r\nContent-length: 0\r\n"),
+            Some(-1.2)
+        );
+        assert_eq!(parse("SPAMD/1.1 0 EX_OK\nSpam: No ; 0 / 5\n"), Some(0.0));
+    }
+
+    #[test]
+    fn rejects_invalid_responses() {
+        for response in [
+            "",
+            "HTTP/1.1 200 OK\r\n\r\n",
+            "SPAMD/1.1 76 EX_PROTOCOL\r\nSpam: True ; 15.0 / 5.0\r\n",
+            "SPAMD/1.1 0 EX_OK\r\nContent-length: 0\r\n",
+            "SPAMD/1.1 0 EX_OK\r\nSpam: True 15.0 / 5.0\r\n",
+            "SPAMD/1.1 0 EX_OK\r\nSpam: True ; high / 5.0\r\n",
+        ] {
+            assert!(
+                matches!(parse_response(response), Err(SpamError::InvalidResponse)),
+                "{response:?}"
+            );
+        }
+    }
+}
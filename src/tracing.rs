@@ -14,7 +14,7 @@ use crate::cli::Format;
 use crate::config;
 
 // Create a Resource that captures information about the entity for which telemetry is recorded.
-fn resource() -> Resource {
+pub(crate) fn resource() -> Resource {
     Resource::from_schema_url(
         [
             KeyValue::new(SERVICE_NAME, env!("CARGO_PKG_NAME")),
@@ -0,0 +1,354 @@
+//! Delivery of webhook events from the outbox
+
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use sqlx::{types::Json, FromRow};
+use tracing::{debug, error, instrument, warn};
+use url::Url;
+
+use crate::{api::v1::webhook::WebhookEvent, config::WebhooksConfig, Database, Error};
+
+pub mod resolver;
+
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+pub const DEFAULT_MAX_ATTEMPTS: i32 = 8;
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The maximum number of deliveries claimed from the outbox at once.
+const BATCH_SIZE: i64 = 50;
+/// The time a claimed delivery is hidden from other workers while it is being sent.
+const CLAIM_DURATION: Duration = Duration::from_secs(120);
+
+pub const EVENT_HEADER: &str = "X-Masked-Mails-Event";
+pub const DELIVERY_HEADER: &str = "X-Masked-Mails-Delivery";
+pub const SIGNATURE_HEADER: &str = "X-Masked-Mails-Signature";
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum SignatureError {
+    #[error("the signature has expired")]
+    Expired,
+    #[error("the signature is invalid")]
+    Invalid,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeliveryError {
+    #[error("request failed")]
+    RequestFailed(#[from] reqwest::Error),
+    #[error("endpoint responded with status {0}")]
+    UnexpectedStatus(u16),
+    #[error("the url does not point at a public address")]
+    ForbiddenAddress,
+}
+
+/// Rejects `url` unless it points at a public address.
+///
+/// The client resolver only sees host names, so this also covers URLs with an IP address.
+pub async fn ensure_public_url(url: &str) -> Result<(), DeliveryError> {
+    match Url::parse(url) {
+        Ok(url) if resolver::is_public_url(&url).await => Ok(()),
+        _ => Err(DeliveryError::ForbiddenAddress),
+    }
+}
+
+/// A delivery claimed from the outbox, along with the webhook it is sent to.
+#[derive(Debug, Clone, FromRow)]
+struct PendingDelivery {
+    id: i32,
+    event: WebhookEvent,
+    payload: Json<serde_json::Value>,
+    created_at: time::OffsetDateTime,
+    url: String,
+    secret: String,
+}
+
+/// The JSON body sent to webhook endpoints.
+#[derive(Debug, Serialize)]
+struct Envelope<'a> {
+    id: i32,
+    event: WebhookEvent,
+    created_at: time::OffsetDateTime,
+    data: &'a serde_json::Value,
+}
+
+/// Signs `body` for `timestamp` with `secret`.
+///
+/// The signature is the hex-encoded HMAC-SHA256 of `{timestamp}.{body}`, which lets receivers
+/// reject replayed requests by checking the timestamp.
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Returns whether the hex-encoded `signature` is valid for `body` and `timestamp`.
+pub fn verify(secret: &str, timestamp: i64, body: &[u8], signature: &str) -> bool {
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Verifies the hex-encoded `signature` of `body` for a link that is valid until `expires`, a
+/// Unix timestamp, which is the timestamp it was signed for.
+pub fn verify_expiring(
+    secret: &str,
+    expires: i64,
+    body: &[u8],
+    signature: &str,
+) -> Result<(), SignatureError> {
+    if expires < time::OffsetDateTime::now_utc().unix_timestamp() {
+        return Err(SignatureError::Expired);
+    }
+
+    if !verify(secret, expires, body, signature) {
+        return Err(SignatureError::Invalid);
+    }
+
+    Ok(())
+}
+
+/// Returns the value of a signature header for `body`, e.g. `t=1716000000,v1=5f2b...`.
+pub fn signature_header(secret: &str, body: &[u8]) -> String {
+    let timestamp = time::OffsetDateTime::now_utc().unix_timestamp();
+
+    format!("t={timestamp},v1={}", sign(secret, timestamp, body))
+}
+
+/// Sends queued webhook events to their endpoints.
+pub struct Dispatcher {
+    db: Database,
+    client: reqwest::Client,
+    poll_interval: Duration,
+    max_attempts: i32,
+}
+
+impl Dispatcher {
+    pub fn new(db: Database, config: &WebhooksConfig) -> Self {
+        let client = resolver::client_builder()
+            .timeout(config.timeout)
+            .build()
+            .expect("could not build http client");
+
+        Dispatcher {
+            db,
+            client,
+            poll_interval: config.poll_interval,
+            max_attempts: config.max_attempts,
+        }
+    }
+
+    /// Continuously delivers events from the outbox until the task is aborted.
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.poll_interval);
+
+        loop {
+            interval.tick().await;
+
+            match self.process_outbox().await {
+                Ok(0) => {}
+                Ok(count) => debug!(%count, "processed webhook outbox"),
+                Err(err) => error!(?err, "could not process webhook outbox"),
+            }
+        }
+    }
+
+    /// Claims a batch of due deliveries and attempts to send each of them, returning the number
+    /// of deliveries that were claimed.
+    async fn process_outbox(&self) -> Result<usize, Error> {
+        let deliveries: Vec<PendingDelivery> = sqlx::query_as(
+            r"
+            WITH claimed AS (
+                UPDATE webhook_deliveries SET next_attempt_at = NOW() + $2 * INTERVAL '1 second'
+                WHERE id IN (
+                    SELECT id FROM webhook_deliveries
+                    WHERE status = 'pending' AND next_attempt_at <= NOW()
+                    ORDER BY next_attempt_at
+                    LIMIT $1
+                    FOR UPDATE SKIP LOCKED
+                )
+                RETURNING *
+            )
+            SELECT
+                claimed.id,
+                claimed.event,
+                claimed.payload,
+                claimed.created_at,
+                webhooks.url,
+                webhooks.secret
+            FROM claimed
+            INNER JOIN webhooks ON webhooks.id = claimed.webhook_id
+            ",
+        )
+        .bind(BATCH_SIZE)
+        .bind(CLAIM_DURATION.as_secs_f64())
+        .fetch_all(&self.db)
+        .await?;
+
+        let count = deliveries.len();
+
+        for delivery in deliveries {
+            let recorded = match self.deliver(&delivery).await {
+                Ok(status) => self.mark_delivered(delivery.id, status).await,
+                Err(err) => {
+                    warn!(?err, delivery_id = delivery.id, "could not deliver webhook");
+
+                    self.mark_failed(delivery.id, &err).await
+                }
+            };
+
+            // The delivery is claimed again once its claim expires if its outcome could not be
+            // recorded, which must not keep the rest of the batch from being delivered.
+            if let Err(err) = recorded {
+                error!(
+                    ?err,
+                    delivery_id = delivery.id,
+                    "could not record webhook delivery result"
+                );
+            }
+        }
+
+        Ok(count)
+    }
+
+    #[instrument(skip_all, fields(delivery_id = delivery.id, event = ?delivery.event))]
+    async fn deliver(&self, delivery: &PendingDelivery) -> Result<u16, DeliveryError> {
+        let body = serde_json::to_vec(&Envelope {
+            id: delivery.id,
+            event: delivery.event,
+            created_at: delivery.created_at,
+            data: &delivery.payload,
+        })
+        .expect("webhook envelope is serializable");
+
+        ensure_public_url(&delivery.url).await?;
+
+        let response = self
+            .client
+            .post(&delivery.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, delivery.event.as_str())
+            .header(DELIVERY_HEADER, delivery.id.to_string())
+            .header(SIGNATURE_HEADER, signature_header(&delivery.secret, &body))
+            .body(body)
+            .send()
+            .await?;
+
+        let status = response.status();
+
+        if !status.is_success() {
+            return Err(DeliveryError::UnexpectedStatus(status.as_u16()));
+        }
+
+        debug!(%status, "delivered webhook");
+
+        Ok(status.as_u16())
+    }
+
+    async fn mark_delivered(&self, delivery_id: i32, status: u16) -> Result<(), Error> {
+        sqlx::query(
+            r"
+            UPDATE webhook_deliveries SET
+                status = 'delivered',
+                attempts = attempts + 1,
+                response_status = $2,
+                delivered_at = NOW(),
+                last_error = NULL
+            WHERE id = $1
+            ",
+        )
+        .bind(delivery_id)
+        .bind(i32::from(status))
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Records a failed attempt and schedules a retry with exponential backoff, or gives up once
+    /// the maximum number of attempts has been reached.
+    async fn mark_failed(&self, delivery_id: i32, err: &DeliveryError) -> Result<(), Error> {
+        let response_status = match err {
+            DeliveryError::UnexpectedStatus(status) => Some(i32::from(*status)),
+            DeliveryError::RequestFailed(_) | DeliveryError::ForbiddenAddress => None,
+        };
+
+        sqlx::query(
+            r"
+            UPDATE webhook_deliveries SET
+                attempts = attempts + 1,
+                response_status = $2,
+                last_error = $3,
+                status = CASE WHEN attempts + 1 >= $4 THEN 'failed' ELSE status END,
+                next_attempt_at = NOW() + LEAST(POWER(2, attempts) * 30, 21600) * INTERVAL '1 second'
+            WHERE id = $1
+            ",
+        )
+        .bind(delivery_id)
+        .bind(response_status)
+        .bind(err.to_string())
+        .bind(self.max_attempts)
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "3q2+7w";
+
+    #[test]
+    fn verifies_its_signatures() {
+        let signature = sign(SECRET, 1716000000, b"{\"id\":1}");
+
+        assert!(verify(SECRET, 1716000000, b"{\"id\":1}", &signature));
+        assert!(!verify("other", 1716000000, b"{\"id\":1}", &signature));
+        assert!(!verify(SECRET, 1716000000, b"{\"id\":1}", "not hex"));
+    }
+
+    #[test]
+    fn rejects_tampered_payloads() {
+        let signature = sign(SECRET, 1716000000, b"{\"id\":1}");
+
+        assert!(!verify(SECRET, 1716000000, b"{\"id\":2}", &signature));
+        assert!(!verify(SECRET, 1716000001, b"{\"id\":1}", &signature));
+    }
+
+    #[test]
+    fn rejects_expired_signatures() {
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+        let expires = now + 60;
+        let signature = sign(SECRET, expires, b"1");
+
+        assert_eq!(verify_expiring(SECRET, expires, b"1", &signature), Ok(()));
+        assert_eq!(
+            verify_expiring(SECRET, expires, b"2", &signature),
+            Err(SignatureError::Invalid)
+        );
+
+        let expired = now - 60;
+        let signature = sign(SECRET, expired, b"1");
+
+        assert_eq!(
+            verify_expiring(SECRET, expired, b"1", &signature),
+            Err(SignatureError::Expired)
+        );
+    }
+}
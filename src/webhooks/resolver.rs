@@ -0,0 +1,196 @@
+//! Resolution of user-supplied URLs to public addresses only
+//!
+//! Webhooks and HTTP destinations point at URLs chosen by users, so requests to them must not
+//! reach the loopback interface, private networks or link-local services of the server, such as
+//! cloud metadata endpoints. Names are checked each time they are resolved, so a name that
+//! resolves to a public address when it is registered cannot be pointed elsewhere later.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use hyper014::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use url::{Host, Url};
+
+/// A DNS resolver that drops all addresses that are not public.
+#[derive(Debug, Clone, Copy)]
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs = public_addrs(name.as_str()).await?;
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Returns a client builder whose requests only reach public addresses.
+///
+/// Redirects are not followed, since they could lead to an address that is not public.
+pub fn client_builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+        .dns_resolver(Arc::new(PublicResolver))
+        .redirect(reqwest::redirect::Policy::none())
+        .no_proxy()
+}
+
+/// Returns whether the host of `url` is a public address or a name resolving only to public
+/// addresses.
+pub async fn is_public_url(url: &Url) -> bool {
+    match url.host() {
+        Some(Host::Ipv4(ip)) => is_public(IpAddr::V4(ip)),
+        Some(Host::Ipv6(ip)) => is_public(IpAddr::V6(ip)),
+        Some(Host::Domain(domain)) => public_addrs(domain).await.is_ok(),
+        None => false,
+    }
+}
+
+/// Resolves `host` and returns its addresses, or an error if any of them is not public.
+///
+/// Names resolving to both public and other addresses are rejected as a whole, since it is up to
+/// the client which of them it connects to.
+async fn public_addrs(host: &str) -> io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, 0)).await?.collect();
+
+    if addrs.is_empty() || !addrs.iter().all(|addr| is_public(addr.ip())) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{host} does not resolve to a public address"),
+        ));
+    }
+
+    Ok(addrs)
+}
+
+/// Returns whether `ip` is a globally routable unicast address.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+/// Returns the IPv4 address embedded in `ip`, which is where packets to `ip` end up, if it is an
+/// IPv4-mapped, IPv4-compatible, NAT64 or 6to4 address.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let octets = ip.octets();
+    let [.., a, b, c, d] = octets;
+
+    match ip.segments() {
+        // ::ffff:0:0/96, IPv4-mapped
+        [0, 0, 0, 0, 0, 0xffff, _, _]
+        // ::/96, IPv4-compatible, which includes the unspecified and loopback addresses
+        | [0, 0, 0, 0, 0, 0, _, _]
+        // 64:ff9b::/96, NAT64 well-known prefix
+        | [0x64, 0xff9b, 0, 0, 0, 0, _, _] => Some(Ipv4Addr::new(a, b, c, d)),
+        // 2002::/16, 6to4, with the address following the prefix
+        [0x2002, ..] => Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+        _ => None,
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // 0.0.0.0/8, "this network"
+        || a == 0
+        // 100.64.0.0/10, shared address space of carrier-grade NAT
+        || (a == 100 && (b & 0xc0) == 64)
+        // 192.0.0.0/24, protocol assignments
+        || (a == 192 && b == 0 && ip.octets()[2] == 0)
+        // 198.18.0.0/15, benchmarking
+        || (a == 198 && (b & 0xfe) == 18)
+        // 240.0.0.0/4, reserved
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let [a, b, ..] = ip.segments();
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // fc00::/7, unique local
+        || (a & 0xfe00) == 0xfc00
+        // fe80::/10, link-local
+        || (a & 0xffc0) == 0xfe80
+        // 64:ff9b:1::/48, local-use NAT64
+        || (a == 0x64 && b == 0xff9b && ip.segments()[2] == 1)
+        // 2001:db8::/32, documentation
+        || (a == 0x2001 && b == 0xdb8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_internal_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "::127.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::7f00:1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b:1::1",
+            "2002:7f00:1::1",
+            "2002:a00:1::",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip} is not public");
+        }
+    }
+
+    #[test]
+    fn accepts_public_addresses() {
+        for ip in [
+            "93.184.215.14",
+            "1.1.1.1",
+            "2606:4700:4700::1111",
+            "::ffff:1.1.1.1",
+            "64:ff9b::101:101",
+            "2002:101:101::1",
+        ] {
+            assert!(is_public(ip.parse().unwrap()), "{ip} is public");
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_internal_urls() {
+        for url in [
+            "http://127.0.0.1:8080/hook",
+            "http://[::1]/hook",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://localhost/hook",
+        ] {
+            assert!(!is_public_url(&Url::parse(url).unwrap()).await, "{url}");
+        }
+    }
+}